@@ -0,0 +1,16 @@
+-- Table of every job request we have seen, used as an idempotency key so the same request
+-- arriving from multiple relays (or again after a restart) is only handled once.
+-- reply_event is the last signed event we sent in response (invoice feedback, error or result)
+CREATE TABLE job_requests
+(
+    event_id    bytea     NOT NULL PRIMARY KEY,
+    reply_event jsonb,
+    created_at  timestamp NOT NULL DEFAULT NOW(),
+    updated_at  timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON job_requests
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
@@ -0,0 +1,14 @@
+-- High-water mark of the job request timestamps we have processed from each relay,
+-- used to catch up on requests published while we were offline or disconnected
+CREATE TABLE relay_checkpoints
+(
+    relay_url    TEXT      NOT NULL PRIMARY KEY,
+    last_seen_at BIGINT    NOT NULL,
+    updated_at   timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON relay_checkpoints
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
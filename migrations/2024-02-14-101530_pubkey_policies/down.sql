@@ -0,0 +1 @@
+drop table pubkey_policies;
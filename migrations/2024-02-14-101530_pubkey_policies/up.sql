@@ -0,0 +1,17 @@
+-- Operator controlled allow and block lists for requesters.
+-- Blocked keys are ignored entirely, allowed keys bypass rate limits
+-- and are the only keys served when the DVM runs in allowlist only mode.
+CREATE TABLE pubkey_policies
+(
+    npub       bytea     NOT NULL PRIMARY KEY,
+    status     TEXT      NOT NULL CHECK (status IN ('allowed', 'blocked')),
+    reason     TEXT,
+    created_at timestamp NOT NULL DEFAULT NOW(),
+    updated_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON pubkey_policies
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
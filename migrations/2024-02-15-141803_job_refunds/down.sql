@@ -0,0 +1,5 @@
+ALTER TABLE jobs
+    DROP COLUMN status,
+    DROP COLUMN price_msats,
+    DROP COLUMN refunded_msats,
+    DROP COLUMN refund_reason;
@@ -0,0 +1,30 @@
+-- Track the payment status and price of jobs so failed jobs can be refunded
+ALTER TABLE jobs
+    ADD COLUMN status         TEXT NOT NULL DEFAULT 'unpaid'
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded')),
+    ADD COLUMN price_msats    BIGINT,
+    ADD COLUMN refunded_msats BIGINT,
+    ADD COLUMN refund_reason  TEXT;
+
+-- jobs that already have a response are done
+UPDATE jobs
+SET status = 'completed'
+WHERE response_id IS NOT NULL;
+
+-- jobs keyed by their request id instead of an invoice payment hash were paid for up front
+UPDATE jobs
+SET status = 'paid'
+WHERE response_id IS NULL
+  AND payment_hash = decode(request ->> 'id', 'hex');
+
+-- the old schema had no marker for a paid invoice and ran every scheduled job that had no response,
+-- so those are paid too. A paid invoice also added the copy keyed by the request id marked above,
+-- the invoice's own row is left out when there is one so the job doesn't run twice.
+UPDATE jobs
+SET status = 'paid'
+WHERE response_id IS NULL
+  AND scheduled_at IS NOT NULL
+  AND status = 'unpaid'
+  AND NOT EXISTS (SELECT 1
+                  FROM jobs copy
+                  WHERE copy.payment_hash = decode(jobs.request ->> 'id', 'hex'));
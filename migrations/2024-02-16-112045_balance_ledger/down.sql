@@ -0,0 +1,10 @@
+drop trigger tr_reject_direct_balance_change on zap_balances;
+drop function reject_direct_balance_change;
+drop table balance_entries;
+drop function reject_balance_entry_change;
+drop function apply_balance_entry;
+ALTER TABLE zap_balances
+    DROP CONSTRAINT zap_balances_non_negative,
+    ALTER COLUMN balance_msats TYPE INTEGER;
+ALTER TABLE zaps
+    ALTER COLUMN amount_msats TYPE INTEGER;
@@ -0,0 +1,88 @@
+-- Balances are now derived from an append-only ledger of signed entries,
+-- every change to a balance is recorded along with the zap or job that caused it.
+ALTER TABLE zap_balances
+    ALTER COLUMN balance_msats TYPE BIGINT,
+    ADD CONSTRAINT zap_balances_non_negative CHECK (balance_msats >= 0);
+
+ALTER TABLE zaps
+    ALTER COLUMN amount_msats TYPE BIGINT;
+
+CREATE TABLE balance_entries
+(
+    id               SERIAL PRIMARY KEY,
+    npub             bytea     NOT NULL REFERENCES zap_balances (npub),
+    kind             TEXT      NOT NULL CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment')),
+    amount_msats     BIGINT    NOT NULL CHECK (amount_msats <> 0),
+    zap_payment_hash bytea REFERENCES zaps (payment_hash),
+    job_id           INTEGER REFERENCES jobs (id),
+    description      TEXT,
+    created_at       timestamp NOT NULL DEFAULT NOW(),
+    CHECK (kind <> 'zap_credit' OR (zap_payment_hash IS NOT NULL AND job_id IS NULL AND amount_msats > 0)),
+    CHECK (kind <> 'job_debit' OR (job_id IS NOT NULL AND zap_payment_hash IS NULL AND amount_msats < 0)),
+    CHECK (kind <> 'refund' OR (job_id IS NOT NULL AND zap_payment_hash IS NULL AND amount_msats > 0)),
+    CHECK (kind <> 'admin_adjustment' OR (description IS NOT NULL AND zap_payment_hash IS NULL AND job_id IS NULL))
+);
+
+CREATE INDEX balance_entries_npub_idx ON balance_entries (npub, created_at);
+
+-- a zap can only be credited once, and a job only debited and refunded once
+CREATE UNIQUE INDEX balance_entries_zap_credit_idx ON balance_entries (zap_payment_hash) WHERE kind = 'zap_credit';
+CREATE UNIQUE INDEX balance_entries_job_idx ON balance_entries (job_id, kind) WHERE job_id IS NOT NULL;
+
+-- carry over existing balances as opening entries, before the trigger exists so they aren't counted twice
+INSERT INTO balance_entries (npub, kind, amount_msats, description)
+SELECT npub, 'admin_adjustment', balance_msats, 'Opening balance'
+FROM zap_balances
+WHERE balance_msats <> 0;
+
+-- keep the cached balance in sync with the ledger, the non-negative check on
+-- zap_balances rejects any entry that would overdraw the balance
+CREATE OR REPLACE FUNCTION apply_balance_entry()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    UPDATE zap_balances
+    SET balance_msats = balance_msats + NEW.amount_msats
+    WHERE npub = NEW.npub;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_apply_balance_entry
+    AFTER INSERT
+    ON balance_entries
+    FOR EACH ROW
+EXECUTE FUNCTION apply_balance_entry();
+
+-- entries are immutable, mistakes are corrected with a new adjustment entry
+CREATE OR REPLACE FUNCTION reject_balance_entry_change()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    RAISE EXCEPTION 'balance entries are append only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_reject_balance_entry_change
+    BEFORE UPDATE OR DELETE
+    ON balance_entries
+    FOR EACH ROW
+EXECUTE FUNCTION reject_balance_entry_change();
+
+-- balances can only be changed through the ledger
+CREATE OR REPLACE FUNCTION reject_direct_balance_change()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    IF pg_trigger_depth() = 1 THEN
+        RAISE EXCEPTION 'balances can only be changed through balance entries';
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_reject_direct_balance_change
+    BEFORE UPDATE OF balance_msats
+    ON zap_balances
+    FOR EACH ROW
+EXECUTE FUNCTION reject_direct_balance_change();
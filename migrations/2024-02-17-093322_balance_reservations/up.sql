@@ -0,0 +1,22 @@
+-- Jobs paid from a balance first hold the maximum price, then settle what they actually cost.
+-- The available balance is the balance minus everything still held.
+CREATE TABLE balance_reservations
+(
+    id            SERIAL PRIMARY KEY,
+    npub          bytea     NOT NULL REFERENCES zap_balances (npub),
+    job_id        INTEGER   NOT NULL UNIQUE REFERENCES jobs (id),
+    amount_msats  BIGINT    NOT NULL CHECK (amount_msats > 0),
+    status        TEXT      NOT NULL DEFAULT 'held' CHECK (status IN ('held', 'settled', 'released')),
+    settled_msats BIGINT CHECK (settled_msats >= 0 AND settled_msats <= amount_msats),
+    created_at    timestamp NOT NULL DEFAULT NOW(),
+    updated_at    timestamp NOT NULL DEFAULT NOW(),
+    CHECK ((status = 'held') = (settled_msats IS NULL))
+);
+
+CREATE INDEX balance_reservations_held_idx ON balance_reservations (npub) WHERE status = 'held';
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON balance_reservations
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
@@ -0,0 +1,9 @@
+ALTER TABLE balance_entries DISABLE TRIGGER tr_reject_balance_entry_change;
+DELETE FROM balance_entries WHERE withdrawal_id IS NOT NULL;
+ALTER TABLE balance_entries ENABLE TRIGGER tr_reject_balance_entry_change;
+ALTER TABLE balance_entries
+    DROP COLUMN withdrawal_id,
+    DROP CONSTRAINT balance_entries_kind_check,
+    ADD CONSTRAINT balance_entries_kind_check
+        CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment'));
+drop table withdrawals;
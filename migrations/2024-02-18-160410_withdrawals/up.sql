@@ -0,0 +1,36 @@
+-- One-time LNURL-withdraw links for paying out unused balance
+CREATE TABLE withdrawals
+(
+    id                SERIAL PRIMARY KEY,
+    npub              bytea     NOT NULL REFERENCES zap_balances (npub),
+    k1                bytea     NOT NULL UNIQUE,
+    -- the signed event that requested the link, so it can't be replayed
+    auth_event_id     bytea     NOT NULL UNIQUE,
+    status            TEXT      NOT NULL DEFAULT 'open' CHECK (status IN ('open', 'pending', 'paid', 'failed')),
+    invoice           TEXT UNIQUE,
+    amount_msats      BIGINT CHECK (amount_msats > 0),
+    fee_reserve_msats BIGINT CHECK (fee_reserve_msats >= 0),
+    fee_msats         BIGINT CHECK (fee_msats >= 0),
+    expires_at        timestamp NOT NULL,
+    created_at        timestamp NOT NULL DEFAULT NOW(),
+    updated_at        timestamp NOT NULL DEFAULT NOW(),
+    CHECK (status = 'open' OR (invoice IS NOT NULL AND amount_msats IS NOT NULL AND fee_reserve_msats IS NOT NULL))
+);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON withdrawals
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
+
+-- withdrawals debit the amount plus the fee reserve, then credit back what wasn't spent
+ALTER TABLE balance_entries
+    ADD COLUMN withdrawal_id INTEGER REFERENCES withdrawals (id),
+    DROP CONSTRAINT balance_entries_kind_check,
+    ADD CONSTRAINT balance_entries_kind_check
+        CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment', 'withdrawal', 'withdrawal_refund')),
+    ADD CHECK (kind <> 'withdrawal' OR (withdrawal_id IS NOT NULL AND amount_msats < 0)),
+    ADD CHECK (kind <> 'withdrawal_refund' OR (withdrawal_id IS NOT NULL AND amount_msats > 0)),
+    ADD CHECK (kind IN ('withdrawal', 'withdrawal_refund') = (withdrawal_id IS NOT NULL));
+
+CREATE UNIQUE INDEX balance_entries_withdrawal_idx ON balance_entries (withdrawal_id, kind) WHERE withdrawal_id IS NOT NULL;
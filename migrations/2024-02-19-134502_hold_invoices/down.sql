@@ -0,0 +1,8 @@
+UPDATE jobs
+SET status = 'refunded'
+WHERE status = 'canceled';
+ALTER TABLE jobs
+    DROP COLUMN preimage,
+    DROP CONSTRAINT jobs_status_check,
+    ADD CONSTRAINT jobs_status_check
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded'));
@@ -0,0 +1,7 @@
+-- Jobs paid with a hold invoice keep the preimage so the payment is only settled once the
+-- result is published, failed jobs cancel the invoice and are marked canceled
+ALTER TABLE jobs
+    ADD COLUMN preimage BYTEA,
+    DROP CONSTRAINT jobs_status_check,
+    ADD CONSTRAINT jobs_status_check
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded', 'canceled'));
@@ -0,0 +1,8 @@
+DROP INDEX jobs_status_created_at_idx;
+UPDATE jobs
+SET status = 'unpaid'
+WHERE status = 'expired';
+ALTER TABLE jobs
+    DROP CONSTRAINT jobs_status_check,
+    ADD CONSTRAINT jobs_status_check
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded', 'canceled'));
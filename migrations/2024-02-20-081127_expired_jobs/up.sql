@@ -0,0 +1,7 @@
+-- Unpaid jobs whose invoice expired are marked expired and later deleted
+ALTER TABLE jobs
+    DROP CONSTRAINT jobs_status_check,
+    ADD CONSTRAINT jobs_status_check
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded', 'canceled', 'expired'));
+
+CREATE INDEX jobs_status_created_at_idx ON jobs (status, created_at);
@@ -0,0 +1,4 @@
+DROP INDEX job_requests_reply_id_idx;
+DROP INDEX jobs_request_id_idx;
+ALTER TABLE zaps
+    DROP COLUMN job_id;
@@ -0,0 +1,6 @@
+-- Zaps of a job request or its payment-required feedback pay for that job
+ALTER TABLE zaps
+    ADD COLUMN job_id INTEGER REFERENCES jobs (id) ON DELETE SET NULL;
+
+CREATE INDEX jobs_request_id_idx ON jobs ((request ->> 'id'));
+CREATE INDEX job_requests_reply_id_idx ON job_requests ((reply_event ->> 'id'));
@@ -0,0 +1,11 @@
+-- Cashu tokens are redeemed by having the mint pay one of our invoices,
+-- the invoice's payment hash makes sure a redemption is only credited once
+ALTER TABLE balance_entries
+    ADD COLUMN cashu_payment_hash bytea,
+    DROP CONSTRAINT balance_entries_kind_check,
+    ADD CONSTRAINT balance_entries_kind_check
+        CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment', 'withdrawal', 'withdrawal_refund', 'cashu_credit')),
+    ADD CHECK (kind <> 'cashu_credit' OR amount_msats > 0),
+    ADD CHECK ((kind = 'cashu_credit') = (cashu_payment_hash IS NOT NULL));
+
+CREATE UNIQUE INDEX balance_entries_cashu_credit_idx ON balance_entries (cashu_payment_hash) WHERE cashu_payment_hash IS NOT NULL;
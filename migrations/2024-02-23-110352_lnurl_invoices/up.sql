@@ -0,0 +1,23 @@
+-- Every invoice handed out over LNURL-pay, so it can be verified (LUD-21) and
+-- payments with an npub in their comment (LUD-12) can be credited to that npub
+CREATE TABLE lnurl_invoices
+(
+    payment_hash bytea PRIMARY KEY,
+    invoice      TEXT      NOT NULL UNIQUE,
+    amount_msats BIGINT    NOT NULL CHECK (amount_msats > 0),
+    comment      TEXT,
+    credit_npub  bytea,
+    settled_at   timestamp,
+    created_at   timestamp NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE balance_entries
+    ADD COLUMN lnurl_payment_hash bytea REFERENCES lnurl_invoices (payment_hash),
+    DROP CONSTRAINT balance_entries_kind_check,
+    ADD CONSTRAINT balance_entries_kind_check
+        CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment', 'withdrawal', 'withdrawal_refund',
+                        'cashu_credit', 'lnurl_credit')),
+    ADD CHECK (kind <> 'lnurl_credit' OR amount_msats > 0),
+    ADD CHECK ((kind = 'lnurl_credit') = (lnurl_payment_hash IS NOT NULL));
+
+CREATE UNIQUE INDEX balance_entries_lnurl_credit_idx ON balance_entries (lnurl_payment_hash) WHERE lnurl_payment_hash IS NOT NULL;
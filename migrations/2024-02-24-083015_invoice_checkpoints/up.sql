@@ -0,0 +1,14 @@
+-- Highest settle index of the invoices we have handled from each node,
+-- used to resume the invoice subscription after a restart or reconnect
+CREATE TABLE invoice_checkpoints
+(
+    node_pubkey  TEXT      NOT NULL PRIMARY KEY,
+    settle_index BIGINT    NOT NULL,
+    updated_at   timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON invoice_checkpoints
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
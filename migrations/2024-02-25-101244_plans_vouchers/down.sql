@@ -0,0 +1,13 @@
+ALTER TABLE balance_entries DISABLE TRIGGER tr_reject_balance_entry_change;
+DELETE FROM balance_entries WHERE kind = 'voucher_credit';
+ALTER TABLE balance_entries ENABLE TRIGGER tr_reject_balance_entry_change;
+DROP INDEX balance_entries_voucher_credit_idx;
+ALTER TABLE balance_entries
+    DROP COLUMN voucher_id,
+    DROP CONSTRAINT balance_entries_kind_check,
+    ADD CONSTRAINT balance_entries_kind_check
+        CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment', 'withdrawal', 'withdrawal_refund',
+                        'cashu_credit', 'lnurl_credit'));
+DROP TABLE vouchers;
+DROP TABLE plan_usages;
+DROP TABLE plans;
@@ -0,0 +1,53 @@
+-- Prepaid plans grant an npub a monthly allowance of job runs and/or compute time
+CREATE TABLE plans
+(
+    id                 SERIAL PRIMARY KEY,
+    npub               bytea     NOT NULL,
+    monthly_runs       INTEGER CHECK (monthly_runs > 0),
+    monthly_compute_ms BIGINT CHECK (monthly_compute_ms > 0),
+    description        TEXT,
+    expires_at         timestamp,
+    revoked_at         timestamp,
+    created_at         timestamp NOT NULL DEFAULT NOW(),
+    CHECK (monthly_runs IS NOT NULL OR monthly_compute_ms IS NOT NULL)
+);
+
+CREATE INDEX plans_npub_idx ON plans (npub);
+
+-- Jobs run under a plan, counted against its allowance for the month they were requested in
+CREATE TABLE plan_usages
+(
+    id         SERIAL PRIMARY KEY,
+    plan_id    INTEGER   NOT NULL REFERENCES plans (id),
+    job_id     INTEGER   NOT NULL UNIQUE REFERENCES jobs (id) ON DELETE CASCADE,
+    compute_ms BIGINT    NOT NULL CHECK (compute_ms >= 0),
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX plan_usages_plan_idx ON plan_usages (plan_id, created_at);
+
+-- Codes that credit a balance, for onboarding and promotions
+CREATE TABLE vouchers
+(
+    id              SERIAL PRIMARY KEY,
+    code            TEXT      NOT NULL UNIQUE,
+    amount_msats    BIGINT    NOT NULL CHECK (amount_msats > 0),
+    max_redemptions INTEGER   NOT NULL DEFAULT 1 CHECK (max_redemptions > 0),
+    redemptions     INTEGER   NOT NULL DEFAULT 0 CHECK (redemptions <= max_redemptions),
+    description     TEXT,
+    expires_at      timestamp,
+    revoked_at      timestamp,
+    created_at      timestamp NOT NULL DEFAULT NOW()
+);
+
+-- each npub can only redeem a voucher once
+ALTER TABLE balance_entries
+    ADD COLUMN voucher_id INTEGER REFERENCES vouchers (id),
+    DROP CONSTRAINT balance_entries_kind_check,
+    ADD CONSTRAINT balance_entries_kind_check
+        CHECK (kind IN ('zap_credit', 'job_debit', 'refund', 'admin_adjustment', 'withdrawal', 'withdrawal_refund',
+                        'cashu_credit', 'lnurl_credit', 'voucher_credit')),
+    ADD CHECK (kind <> 'voucher_credit' OR amount_msats > 0),
+    ADD CHECK ((kind = 'voucher_credit') = (voucher_id IS NOT NULL));
+
+CREATE UNIQUE INDEX balance_entries_voucher_credit_idx ON balance_entries (voucher_id, npub) WHERE voucher_id IS NOT NULL;
@@ -0,0 +1,6 @@
+DROP INDEX jobs_created_at_idx;
+ALTER TABLE jobs
+    DROP COLUMN module_checksum,
+    DROP COLUMN runtime_ms,
+    DROP COLUMN charged_msats,
+    DROP COLUMN error_code;
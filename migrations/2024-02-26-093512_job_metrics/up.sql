@@ -0,0 +1,14 @@
+-- What every job ran, for how long, what it earned and how it ended, for reporting
+ALTER TABLE jobs
+    ADD COLUMN module_checksum TEXT,
+    ADD COLUMN runtime_ms      BIGINT CHECK (runtime_ms >= 0),
+    ADD COLUMN charged_msats   BIGINT CHECK (charged_msats >= 0),
+    ADD COLUMN error_code      TEXT;
+
+-- older jobs kept everything they didn't refund
+UPDATE jobs
+SET charged_msats = price_msats - COALESCE(refunded_msats, 0)
+WHERE price_msats IS NOT NULL
+  AND status IN ('completed', 'refunded');
+
+CREATE INDEX jobs_created_at_idx ON jobs (created_at);
@@ -0,0 +1,28 @@
+-- Scheduled jobs that repeat, every run is its own job paid from the requester's plan or balance
+CREATE TABLE job_series
+(
+    id              SERIAL PRIMARY KEY,
+    request_id      bytea     NOT NULL UNIQUE,
+    npub            bytea     NOT NULL,
+    cron            TEXT,
+    interval_secs   BIGINT CHECK (interval_secs > 0),
+    end_at          timestamp,
+    max_runs        INTEGER CHECK (max_runs > 0),
+    -- runs created so far, including the first
+    runs            INTEGER   NOT NULL DEFAULT 1,
+    run_price_msats BIGINT    NOT NULL CHECK (run_price_msats >= 0),
+    status          TEXT      NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'finished', 'canceled')),
+    stop_reason     TEXT,
+    created_at      timestamp NOT NULL DEFAULT NOW(),
+    updated_at      timestamp NOT NULL DEFAULT NOW(),
+    CHECK ((cron IS NULL) <> (interval_secs IS NULL)),
+    CHECK (end_at IS NOT NULL OR max_runs IS NOT NULL)
+);
+
+CREATE INDEX job_series_npub_idx ON job_series (npub);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON job_series
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
@@ -0,0 +1,6 @@
+drop trigger tr_notify_scheduled_job on jobs;
+drop function notify_scheduled_job;
+DROP INDEX jobs_scheduled_ready_idx;
+ALTER TABLE jobs
+    DROP COLUMN locked_until,
+    DROP COLUMN attempts;
@@ -0,0 +1,24 @@
+-- Scheduled jobs are leased by a worker before running, so instances sharing the
+-- database don't run the same job. A lease that isn't finished in time is taken over.
+ALTER TABLE jobs
+    ADD COLUMN locked_until timestamp,
+    ADD COLUMN attempts     INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX jobs_scheduled_ready_idx ON jobs (scheduled_at) WHERE status = 'paid' AND response_id IS NULL;
+
+-- wake up the schedulers when a scheduled job is queued or rescheduled
+CREATE OR REPLACE FUNCTION notify_scheduled_job()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    PERFORM pg_notify('scheduled_jobs', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_notify_scheduled_job
+    AFTER INSERT OR UPDATE OF status, scheduled_at
+    ON jobs
+    FOR EACH ROW
+    WHEN (NEW.status = 'paid' AND NEW.scheduled_at IS NOT NULL AND NEW.response_id IS NULL)
+EXECUTE FUNCTION notify_scheduled_job();
@@ -0,0 +1,9 @@
+ALTER TABLE jobs
+    DROP COLUMN last_error;
+UPDATE jobs
+SET status = 'refunded'
+WHERE status = 'failed';
+ALTER TABLE jobs
+    DROP CONSTRAINT jobs_status_check,
+    ADD CONSTRAINT jobs_status_check
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded', 'canceled', 'expired'));
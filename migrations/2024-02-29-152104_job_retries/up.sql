@@ -0,0 +1,9 @@
+-- Scheduled jobs that keep failing are retried with a backoff and then marked failed,
+-- keeping the last error so an operator can look into them and replay them
+ALTER TABLE jobs
+    DROP CONSTRAINT jobs_status_check,
+    ADD CONSTRAINT jobs_status_check
+        CHECK (status IN ('unpaid', 'paid', 'completed', 'refunded', 'canceled', 'expired', 'failed'));
+
+ALTER TABLE jobs
+    ADD COLUMN last_error TEXT;
@@ -0,0 +1,7 @@
+drop trigger tr_notify_job_trigger on job_triggers;
+drop function notify_job_trigger;
+DROP INDEX jobs_trigger_id_created_at_idx;
+ALTER TABLE jobs
+    DROP COLUMN trigger_id,
+    DROP COLUMN trigger_event;
+DROP TABLE job_triggers;
@@ -0,0 +1,50 @@
+-- Requests that run their job for every nostr event matching a filter, each run is its own
+-- job paid from the requester's plan or balance
+CREATE TABLE job_triggers
+(
+    id                SERIAL PRIMARY KEY,
+    request_id        bytea     NOT NULL UNIQUE,
+    npub              bytea     NOT NULL,
+    request           jsonb     NOT NULL,
+    filter            jsonb     NOT NULL,
+    expires_at        timestamp NOT NULL,
+    max_runs          INTEGER CHECK (max_runs > 0),
+    max_runs_per_hour INTEGER   NOT NULL CHECK (max_runs_per_hour > 0),
+    runs              INTEGER   NOT NULL DEFAULT 0,
+    run_price_msats   BIGINT    NOT NULL CHECK (run_price_msats >= 0),
+    status            TEXT      NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'finished', 'canceled')),
+    stop_reason       TEXT,
+    created_at        timestamp NOT NULL DEFAULT NOW(),
+    updated_at        timestamp NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX job_triggers_npub_idx ON job_triggers (npub);
+
+CREATE TRIGGER tr_set_dates_after_update
+    BEFORE UPDATE
+    ON job_triggers
+    FOR EACH ROW
+EXECUTE FUNCTION set_updated_at();
+
+-- the run of a trigger keeps the event that triggered it, which is its input
+ALTER TABLE jobs
+    ADD COLUMN trigger_id    INTEGER REFERENCES job_triggers (id),
+    ADD COLUMN trigger_event jsonb;
+
+CREATE INDEX jobs_trigger_id_created_at_idx ON jobs (trigger_id, created_at) WHERE trigger_id IS NOT NULL;
+
+-- resubscribe the trigger listeners when a trigger is added or stopped
+CREATE OR REPLACE FUNCTION notify_job_trigger()
+    RETURNS TRIGGER AS
+$$
+BEGIN
+    PERFORM pg_notify('job_triggers', NEW.id::text);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER tr_notify_job_trigger
+    AFTER INSERT OR UPDATE OF status
+    ON job_triggers
+    FOR EACH ROW
+EXECUTE FUNCTION notify_job_trigger();
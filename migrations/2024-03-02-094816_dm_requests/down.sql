@@ -0,0 +1 @@
+drop table dm_requests;
@@ -0,0 +1,7 @@
+-- DMs we answered, so the same DM arriving from multiple relays is only answered once.
+-- Kept apart from job_requests so DMs and job requests don't share idempotency keys.
+CREATE TABLE dm_requests
+(
+    event_id   bytea     NOT NULL PRIMARY KEY,
+    created_at timestamp NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1 @@
+drop table cashu_redemptions;
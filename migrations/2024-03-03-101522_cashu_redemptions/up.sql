@@ -0,0 +1,12 @@
+-- Invoices a mint was asked to pay to redeem a Cashu token, recorded before the token is
+-- melted so the redemption is credited to the sender even if the mint pays late
+CREATE TABLE cashu_redemptions
+(
+    payment_hash bytea PRIMARY KEY,
+    invoice      TEXT      NOT NULL UNIQUE,
+    amount_msats BIGINT    NOT NULL CHECK (amount_msats > 0),
+    npub         bytea     NOT NULL,
+    mint         TEXT      NOT NULL,
+    settled_at   timestamp,
+    created_at   timestamp NOT NULL DEFAULT NOW()
+);
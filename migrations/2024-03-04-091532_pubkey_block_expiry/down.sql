@@ -0,0 +1,2 @@
+ALTER TABLE pubkey_policies
+    DROP COLUMN blocked_until;
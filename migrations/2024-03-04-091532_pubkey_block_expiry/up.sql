@@ -0,0 +1,4 @@
+-- Keys blocked automatically for exceeding rate limits are only blocked for a while,
+-- blocks set through the admin api have no expiry
+ALTER TABLE pubkey_policies
+    ADD COLUMN blocked_until timestamp;
@@ -0,0 +1,594 @@
+use crate::models::balance_entry::BalanceEntry;
+use crate::models::job::Job;
+use crate::models::job_trigger::{JobTrigger, TriggerStatus};
+use crate::models::plan::Plan;
+use crate::models::pubkey_policy::{PolicyStatus, PubkeyPolicy};
+use crate::models::voucher::Voucher;
+use crate::models::zap_balance::ZapBalance;
+use crate::policy::PolicyLimits;
+use crate::report::{Report, ReportFormat};
+use crate::routes::handle_anyhow_error;
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::rand::rngs::OsRng;
+use bitcoin::secp256k1::rand::RngCore;
+use diesel::Connection;
+use nostr::{FromBech32, PublicKey, ToBech32};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Checks the bearer token of an admin request against the configured admin token
+pub(crate) fn check_admin_auth(
+    state: &State,
+    headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<Value>)> {
+    let unauthorized = || {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "status": "ERROR",
+                "reason": "Unauthorized",
+            })),
+        )
+    };
+
+    let Some(admin_token) = state.admin_token.as_ref() else {
+        return Err(unauthorized());
+    };
+
+    let token = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or_else(unauthorized)?;
+
+    // compare digests so the time taken doesn't tell how much of the token matched
+    let token = sha256::Hash::hash(token.as_bytes());
+    if token != sha256::Hash::hash(admin_token.as_bytes()) {
+        return Err(unauthorized());
+    }
+
+    Ok(())
+}
+
+/// Parses an optional unix timestamp, in seconds
+fn parse_expiry(expires_at: Option<u64>) -> anyhow::Result<Option<chrono::NaiveDateTime>> {
+    expires_at
+        .map(|secs| {
+            chrono::NaiveDateTime::from_timestamp_opt(i64::try_from(secs)?, 0)
+                .ok_or(anyhow!("Invalid expiry: {secs}"))
+        })
+        .transpose()
+}
+
+/// Parses a pubkey given as either an npub or hex
+pub(crate) fn parse_pubkey(str: &str) -> anyhow::Result<PublicKey> {
+    PublicKey::from_bech32(str)
+        .or_else(|_| PublicKey::from_hex(str))
+        .map_err(|_| anyhow!("Invalid pubkey: {str}"))
+}
+
+pub async fn get_policy(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<PolicyLimits>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+    Ok(Json(state.policy.limits()))
+}
+
+pub async fn set_policy(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+    Json(limits): Json<PolicyLimits>,
+) -> Result<Json<PolicyLimits>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+    state.policy.set_limits(limits);
+    Ok(Json(state.policy.limits()))
+}
+
+pub async fn list_pubkey_policies(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let list = || -> anyhow::Result<Vec<Value>> {
+        let mut conn = state.db_pool.get()?;
+        PubkeyPolicy::list(&mut conn)?
+            .into_iter()
+            .map(|p| {
+                Ok(json!({
+                    "npub": p.npub().to_bech32()?,
+                    "status": p.status(),
+                    "reason": p.reason,
+                    "blocked_until": p.blocked_until,
+                }))
+            })
+            .collect()
+    };
+
+    match list() {
+        Ok(policies) => Ok(Json(json!(policies))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SetPubkeyPolicyRequest {
+    pub status: PolicyStatus,
+    pub reason: Option<String>,
+}
+
+pub async fn set_pubkey_policy(
+    headers: HeaderMap,
+    Path(npub): Path<String>,
+    Extension(state): Extension<State>,
+    Json(request): Json<SetPubkeyPolicyRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let set = || -> anyhow::Result<()> {
+        let npub = parse_pubkey(&npub)?;
+        let mut conn = state.db_pool.get()?;
+        PubkeyPolicy::upsert(
+            &mut conn,
+            &npub,
+            request.status,
+            request.reason.as_deref(),
+            None,
+        )?;
+        Ok(())
+    };
+
+    match set() {
+        Ok(()) => Ok(Json(json!({ "status": "OK" }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+pub async fn delete_pubkey_policy(
+    headers: HeaderMap,
+    Path(npub): Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let delete = || -> anyhow::Result<bool> {
+        let npub = parse_pubkey(&npub)?;
+        let mut conn = state.db_pool.get()?;
+        PubkeyPolicy::delete(&mut conn, &npub)
+    };
+
+    match delete() {
+        Ok(deleted) => Ok(Json(json!({ "status": "OK", "deleted": deleted }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+pub async fn get_balance(
+    headers: HeaderMap,
+    Path(npub): Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let get = || -> anyhow::Result<Value> {
+        let npub = parse_pubkey(&npub)?;
+        let mut conn = state.db_pool.get()?;
+        let balance = ZapBalance::get(&mut conn, &npub)?
+            .map(|b| b.balance_msats)
+            .unwrap_or(0);
+        let entries = BalanceEntry::list(&mut conn, &npub)?
+            .into_iter()
+            .map(|e| {
+                json!({
+                    "id": e.id,
+                    "kind": e.kind(),
+                    "amount_msats": e.amount_msats,
+                    "zap_payment_hash": e.zap_payment_hash().map(hex::encode),
+                    "job_id": e.job_id,
+                    "description": e.description,
+                    "created_at": e.created_at,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json!({
+            "npub": npub.to_bech32()?,
+            "balance_msats": balance,
+            "entries": entries,
+        }))
+    };
+
+    match get() {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdjustBalanceRequest {
+    pub amount_msats: i64,
+    pub reason: String,
+}
+
+pub async fn adjust_balance(
+    headers: HeaderMap,
+    Path(npub): Path<String>,
+    Extension(state): Extension<State>,
+    Json(request): Json<AdjustBalanceRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let adjust = || -> anyhow::Result<i64> {
+        let npub = parse_pubkey(&npub)?;
+        if request.amount_msats == 0 {
+            return Err(anyhow!("Adjustment amount must not be zero"));
+        }
+
+        let mut conn = state.db_pool.get()?;
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            ZapBalance::get_or_create(conn, npub)?;
+            BalanceEntry::admin_adjustment(conn, &npub, request.amount_msats, &request.reason)?;
+            let bal = ZapBalance::get(conn, &npub)?.ok_or(anyhow!("Missing balance"))?;
+            Ok(bal.balance_msats)
+        })
+    };
+
+    match adjust() {
+        Ok(balance) => Ok(Json(json!({ "status": "OK", "balance_msats": balance }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReportParams {
+    /// Unix timestamps of the report's range
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub format: Option<ReportFormat>,
+}
+
+pub async fn get_report(
+    headers: HeaderMap,
+    Query(params): Query<ReportParams>,
+    Extension(state): Extension<State>,
+) -> Result<Response, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let generate = || -> anyhow::Result<Report> {
+        let mut conn = state.db_pool.get()?;
+        Report::generate(&mut conn, params.since, params.until)
+    };
+
+    match generate() {
+        Ok(report) => match params.format {
+            Some(ReportFormat::Csv) => {
+                Ok(([(header::CONTENT_TYPE, "text/csv")], report.to_csv()).into_response())
+            }
+            _ => Ok(Json(report).into_response()),
+        },
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+/// Most failed jobs listed at once if no limit is given
+const DEFAULT_FAILED_JOBS_LIMIT: i64 = 100;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FailedJobsParams {
+    pub limit: Option<i64>,
+}
+
+fn failed_job_json(job: &Job) -> anyhow::Result<Value> {
+    let request = job.request();
+    Ok(json!({
+        "id": job.id,
+        "request_id": request.id.to_hex(),
+        "npub": request.pubkey.to_bech32()?,
+        "scheduled_at": job.scheduled_at(),
+        "attempts": job.attempts,
+        "error_code": job.error_code,
+        "last_error": job.last_error,
+        "refunded_msats": job.refunded_msats(),
+        "created_at": job.created_at,
+    }))
+}
+
+pub async fn list_failed_jobs(
+    headers: HeaderMap,
+    Query(params): Query<FailedJobsParams>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let list = || -> anyhow::Result<Vec<Value>> {
+        let mut conn = state.db_pool.get()?;
+        let limit = params.limit.unwrap_or(DEFAULT_FAILED_JOBS_LIMIT);
+        Job::list_failed(&mut conn, limit)?
+            .iter()
+            .map(failed_job_json)
+            .collect()
+    };
+
+    match list() {
+        Ok(jobs) => Ok(Json(json!(jobs))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+/// Queues a failed job to run again, at our expense since the requester was refunded
+pub async fn replay_job(
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let replay = || -> anyhow::Result<Job> {
+        let mut conn = state.db_pool.get()?;
+        Job::replay(&mut conn, id)?.ok_or(anyhow!("Job {id} has not failed"))
+    };
+
+    match replay() {
+        Ok(job) => Ok(Json(json!({ "status": "OK", "id": job.id }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+fn trigger_json(trigger: &JobTrigger) -> anyhow::Result<Value> {
+    Ok(json!({
+        "id": trigger.id,
+        "request_id": trigger.request_id().to_hex(),
+        "npub": trigger.npub().to_bech32()?,
+        "filter": trigger.filter(),
+        "expires_at": trigger.expires_at(),
+        "max_runs": trigger.max_runs(),
+        "max_runs_per_hour": trigger.max_runs_per_hour(),
+        "runs": trigger.runs,
+        "run_price_msats": trigger.run_price_msats(),
+        "status": trigger.status(),
+        "stop_reason": trigger.stop_reason,
+        "created_at": trigger.created_at,
+    }))
+}
+
+pub async fn list_triggers(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let list = || -> anyhow::Result<Vec<Value>> {
+        let mut conn = state.db_pool.get()?;
+        JobTrigger::list(&mut conn)?
+            .iter()
+            .map(trigger_json)
+            .collect()
+    };
+
+    match list() {
+        Ok(triggers) => Ok(Json(json!(triggers))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+pub async fn cancel_trigger(
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let cancel = || -> anyhow::Result<bool> {
+        let mut conn = state.db_pool.get()?;
+        let stopped = JobTrigger::stop(
+            &mut conn,
+            id,
+            TriggerStatus::Canceled,
+            "Canceled by an operator",
+        )?;
+        Ok(stopped.is_some())
+    };
+
+    match cancel() {
+        Ok(canceled) => Ok(Json(json!({ "status": "OK", "canceled": canceled }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+fn plan_json(plan: &Plan) -> anyhow::Result<Value> {
+    Ok(json!({
+        "id": plan.id,
+        "npub": plan.npub().to_bech32()?,
+        "monthly_runs": plan.monthly_runs(),
+        "monthly_compute_ms": plan.monthly_compute_ms(),
+        "description": plan.description,
+        "expires_at": plan.expires_at,
+        "revoked_at": plan.revoked_at,
+        "created_at": plan.created_at,
+    }))
+}
+
+pub async fn list_plans(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let list = || -> anyhow::Result<Vec<Value>> {
+        let mut conn = state.db_pool.get()?;
+        Plan::list(&mut conn)?.iter().map(plan_json).collect()
+    };
+
+    match list() {
+        Ok(plans) => Ok(Json(json!(plans))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreatePlanRequest {
+    pub npub: String,
+    pub monthly_runs: Option<u64>,
+    pub monthly_compute_ms: Option<u64>,
+    pub description: Option<String>,
+    /// Unix timestamp the plan ends at, never if not set
+    pub expires_at: Option<u64>,
+}
+
+pub async fn create_plan(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+    Json(request): Json<CreatePlanRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let create = || -> anyhow::Result<Value> {
+        let npub = parse_pubkey(&request.npub)?;
+        if request.monthly_runs.is_none() && request.monthly_compute_ms.is_none() {
+            return Err(anyhow!("Plan needs a monthly run or compute allowance"));
+        }
+        if request.monthly_runs == Some(0) || request.monthly_compute_ms == Some(0) {
+            return Err(anyhow!("Plan allowances must not be zero"));
+        }
+
+        let mut conn = state.db_pool.get()?;
+        let plan = Plan::create(
+            &mut conn,
+            &npub,
+            request.monthly_runs,
+            request.monthly_compute_ms,
+            request.description.as_deref(),
+            parse_expiry(request.expires_at)?,
+        )?;
+        plan_json(&plan)
+    };
+
+    match create() {
+        Ok(plan) => Ok(Json(plan)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+pub async fn revoke_plan(
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let revoke = || -> anyhow::Result<bool> {
+        let mut conn = state.db_pool.get()?;
+        Ok(Plan::revoke(&mut conn, id)?.is_some())
+    };
+
+    match revoke() {
+        Ok(revoked) => Ok(Json(json!({ "status": "OK", "revoked": revoked }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+fn voucher_json(voucher: &Voucher) -> Value {
+    json!({
+        "id": voucher.id,
+        "code": voucher.code,
+        "amount_msats": voucher.amount_msats,
+        "max_redemptions": voucher.max_redemptions,
+        "redemptions": voucher.redemptions,
+        "description": voucher.description,
+        "expires_at": voucher.expires_at,
+        "revoked_at": voucher.revoked_at,
+        "created_at": voucher.created_at,
+    })
+}
+
+pub async fn list_vouchers(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let list = || -> anyhow::Result<Vec<Value>> {
+        let mut conn = state.db_pool.get()?;
+        Ok(Voucher::list(&mut conn)?.iter().map(voucher_json).collect())
+    };
+
+    match list() {
+        Ok(vouchers) => Ok(Json(json!(vouchers))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateVoucherRequest {
+    /// A random code is generated if not set
+    pub code: Option<String>,
+    pub amount_msats: u64,
+    pub max_redemptions: Option<u32>,
+    pub description: Option<String>,
+    /// Unix timestamp the voucher expires at, never if not set
+    pub expires_at: Option<u64>,
+}
+
+pub async fn create_voucher(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+    Json(request): Json<CreateVoucherRequest>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let create = || -> anyhow::Result<Value> {
+        if request.amount_msats == 0 {
+            return Err(anyhow!("Voucher amount must not be zero"));
+        }
+        let code = match request.code.as_deref().map(str::trim) {
+            Some("") => return Err(anyhow!("Voucher code must not be empty")),
+            Some(code) => code.to_string(),
+            None => {
+                let mut bytes = [0u8; 12];
+                OsRng.fill_bytes(&mut bytes);
+                hex::encode(bytes)
+            }
+        };
+
+        let mut conn = state.db_pool.get()?;
+        let voucher = Voucher::create(
+            &mut conn,
+            &code,
+            request.amount_msats,
+            request.max_redemptions.unwrap_or(1),
+            request.description.as_deref(),
+            parse_expiry(request.expires_at)?,
+        )?;
+        Ok(voucher_json(&voucher))
+    };
+
+    match create() {
+        Ok(voucher) => Ok(Json(voucher)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+pub async fn revoke_voucher(
+    headers: HeaderMap,
+    Path(id): Path<i32>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    check_admin_auth(&state, &headers)?;
+
+    let revoke = || -> anyhow::Result<bool> {
+        let mut conn = state.db_pool.get()?;
+        Ok(Voucher::revoke(&mut conn, id)?.is_some())
+    };
+
+    match revoke() {
+        Ok(revoked) => Ok(Json(json!({ "status": "OK", "revoked": revoked }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
@@ -0,0 +1,317 @@
+use crate::models::balance_entry::{BalanceEntry, EntryKind};
+use crate::models::balance_reservation::BalanceReservation;
+use crate::models::dm_request::DmRequest;
+use crate::models::job::{Job, JobStatus};
+use crate::models::pubkey_policy::{PolicyStatus, PubkeyPolicy};
+use crate::models::zap_balance::ZapBalance;
+use crate::withdraw::create_withdraw_link;
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use diesel::PgConnection;
+use log::{debug, info};
+use nostr::nips::{nip04, nip44};
+use nostr::{Event, EventBuilder, JsonUtil, Keys, Kind, PublicKey, SecretKey, Tag, Timestamp};
+use nostr_sdk::Client;
+use serde::Serialize;
+
+/// How many ledger entries and jobs are included in a statement
+const STATEMENT_LIMIT: i64 = 10;
+
+/// Reply to DMs that aren't a known command
+const DM_HELP: &str = "Unknown command, send \"balance\" to get your balance and recent activity \
+    or \"withdraw\" to get a link to withdraw it";
+
+/// How far, in seconds, a NIP-98 auth event's timestamp can be from now
+const HTTP_AUTH_WINDOW: u64 = 60;
+
+/// How a DM was encrypted, the reply is encrypted the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DmEncryption {
+    Nip04,
+    Nip44,
+}
+
+impl DmEncryption {
+    /// NIP-04 content carries its iv after `?iv=`, NIP-44 content is a single base64 payload
+    fn detect(content: &str) -> Self {
+        if content.contains("?iv=") {
+            DmEncryption::Nip04
+        } else {
+            DmEncryption::Nip44
+        }
+    }
+
+    fn decrypt(
+        self,
+        secret_key: &SecretKey,
+        sender: &PublicKey,
+        content: &str,
+    ) -> anyhow::Result<String> {
+        Ok(match self {
+            DmEncryption::Nip04 => nip04::decrypt(secret_key, sender, content)?,
+            DmEncryption::Nip44 => nip44::decrypt(secret_key, sender, content)?,
+        })
+    }
+
+    fn encrypt(
+        self,
+        secret_key: &SecretKey,
+        receiver: &PublicKey,
+        content: String,
+    ) -> anyhow::Result<String> {
+        Ok(match self {
+            DmEncryption::Nip04 => nip04::encrypt(secret_key, receiver, content)?,
+            DmEncryption::Nip44 => {
+                nip44::encrypt(secret_key, receiver, content, nip44::Version::V2)?
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementEntry {
+    pub kind: EntryKind,
+    pub amount_msats: i64,
+    pub job_id: Option<i32>,
+    pub description: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementJob {
+    pub id: i32,
+    pub request_id: String,
+    pub status: JobStatus,
+    pub price_msats: Option<u64>,
+    pub refunded_msats: Option<u64>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+/// A requester's balance along with their recent credits, debits and jobs
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceStatement {
+    pub balance_msats: i64,
+    /// Held for jobs that haven't finished yet
+    pub held_msats: i64,
+    pub available_msats: i64,
+    pub entries: Vec<StatementEntry>,
+    pub jobs: Vec<StatementJob>,
+}
+
+impl BalanceStatement {
+    pub fn get(conn: &mut PgConnection, npub: &PublicKey) -> anyhow::Result<Self> {
+        let balance_msats = ZapBalance::get(conn, npub)?.map_or(0, |b| b.balance_msats);
+        let held_msats = BalanceReservation::total_held(conn, npub)?;
+
+        let entries = BalanceEntry::list_recent(conn, npub, STATEMENT_LIMIT)?
+            .into_iter()
+            .map(|e| StatementEntry {
+                kind: e.kind(),
+                amount_msats: e.amount_msats,
+                job_id: e.job_id,
+                description: e.description,
+                created_at: e.created_at,
+            })
+            .collect();
+
+        let jobs = Job::list_paid_by_npub(conn, npub, STATEMENT_LIMIT)?
+            .into_iter()
+            .map(|j| StatementJob {
+                id: j.id,
+                request_id: j.request().id.to_hex(),
+                status: j.status(),
+                price_msats: j.price_msats(),
+                refunded_msats: j.refunded_msats(),
+                created_at: j.created_at,
+            })
+            .collect();
+
+        Ok(Self {
+            balance_msats,
+            held_msats,
+            available_msats: balance_msats - held_msats,
+            entries,
+            jobs,
+        })
+    }
+
+    /// Plain text version of the statement, for replying to DMs
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "Balance: {} msats ({} msats available, {} msats held for running jobs)",
+            self.balance_msats, self.available_msats, self.held_msats
+        );
+
+        if !self.entries.is_empty() {
+            text.push_str("\n\nRecent activity:");
+            for entry in self.entries.iter() {
+                text.push_str(&format!(
+                    "\n{} {:+} msats {}",
+                    entry.created_at.format("%Y-%m-%d %H:%M"),
+                    entry.amount_msats,
+                    entry.kind,
+                ));
+                if let Some(job_id) = entry.job_id {
+                    text.push_str(&format!(" (job {job_id})"));
+                }
+            }
+        }
+
+        if !self.jobs.is_empty() {
+            text.push_str("\n\nRecent jobs:");
+            for job in self.jobs.iter() {
+                text.push_str(&format!(
+                    "\n{} job {} {}",
+                    job.created_at.format("%Y-%m-%d %H:%M"),
+                    job.id,
+                    job.status,
+                ));
+                if let Some(price) = job.price_msats {
+                    text.push_str(&format!(" {price} msats"));
+                }
+            }
+        }
+
+        text
+    }
+}
+
+/// Verifies a NIP-98 `Authorization: Nostr <event>` header for the given url and method,
+/// returns the auth event, its pubkey is the authenticated user.
+pub fn verify_http_auth(headers: &HeaderMap, url: &str, method: &str) -> anyhow::Result<Event> {
+    let header = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Nostr "))
+        .ok_or(anyhow!("Missing NIP-98 authorization header"))?;
+
+    let bytes = BASE64.decode(header.trim())?;
+    let event = Event::from_json(bytes)?;
+    event.verify()?;
+
+    if event.kind != Kind::HttpAuth {
+        return Err(anyhow!("Invalid authorization event kind"));
+    }
+
+    let now = Timestamp::now().as_u64();
+    if event.created_at.as_u64().abs_diff(now) > HTTP_AUTH_WINDOW {
+        return Err(anyhow!("Authorization event is too old"));
+    }
+
+    let tag_value = |name: &str| {
+        event.tags.iter().find_map(|t| {
+            let vec = t.as_vec();
+            (vec.first().map(|s| s.as_str()) == Some(name))
+                .then(|| vec.get(1).cloned())
+                .flatten()
+        })
+    };
+
+    let event_url = tag_value("u").ok_or(anyhow!("Missing url tag"))?;
+    if event_url.trim_end_matches('/') != url.trim_end_matches('/') {
+        return Err(anyhow!("Authorization event is for a different url"));
+    }
+
+    let event_method = tag_value("method").ok_or(anyhow!("Missing method tag"))?;
+    if !event_method.eq_ignore_ascii_case(method) {
+        return Err(anyhow!("Authorization event is for a different method"));
+    }
+
+    Ok(event)
+}
+
+/// Answers a NIP-04 or NIP-44 encrypted DM sent to the DVM key, the commands are `balance`
+/// and `withdraw`
+pub async fn handle_dm(
+    conn: &mut PgConnection,
+    client: &Client,
+    keys: &Keys,
+    event: Event,
+    domain: &str,
+) -> anyhow::Result<()> {
+    // the same DM can come from multiple relays, only answer it once
+    if !DmRequest::claim(conn, event.id)? {
+        return Ok(());
+    }
+
+    if PubkeyPolicy::get(conn, &event.pubkey)?.is_some_and(|p| p.status() == PolicyStatus::Blocked)
+    {
+        debug!("Ignoring DM from blocked key: {}", event.id);
+        return Ok(());
+    }
+
+    let secret_key = keys.secret_key()?;
+    let encryption = DmEncryption::detect(&event.content);
+    let message = encryption.decrypt(secret_key, &event.pubkey, &event.content)?;
+
+    let reply = match message.trim().to_lowercase().as_str() {
+        "balance" => BalanceStatement::get(conn, &event.pubkey)?.to_text(),
+        "withdraw" => match create_withdraw_link(conn, &event.pubkey, event.id, domain) {
+            Ok(lnurl) => format!(
+                "Scan this LNURL-withdraw link with your wallet within 10 minutes:\n\n{lnurl}"
+            ),
+            Err(e) => format!("Could not create a withdraw link: {e}"),
+        },
+        _ => DM_HELP.to_string(),
+    };
+
+    let content = encryption.encrypt(secret_key, &event.pubkey, reply)?;
+
+    let builder = EventBuilder::new(
+        Kind::EncryptedDirectMessage,
+        content,
+        [Tag::public_key(event.pubkey), Tag::event(event.id)],
+    );
+    let event_id = client.send_event_builder(builder).await?;
+    info!("Sent DM reply: {event_id}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::DmEncryption;
+    use nostr::nips::{nip04, nip44};
+    use nostr::Keys;
+
+    #[test]
+    fn test_dm_encryption() {
+        let dvm = Keys::generate();
+        let user = Keys::generate();
+        let user_secret = user.secret_key().unwrap();
+        let dvm_secret = dvm.secret_key().unwrap();
+
+        let nip04_dm = nip04::encrypt(user_secret, &dvm.public_key(), "balance").unwrap();
+        let nip44_dm = nip44::encrypt(
+            user_secret,
+            &dvm.public_key(),
+            "balance",
+            nip44::Version::V2,
+        )
+        .unwrap();
+
+        for (dm, expected) in [
+            (nip04_dm, DmEncryption::Nip04),
+            (nip44_dm, DmEncryption::Nip44),
+        ] {
+            let encryption = DmEncryption::detect(&dm);
+            assert_eq!(encryption, expected);
+            let message = encryption
+                .decrypt(dvm_secret, &user.public_key(), &dm)
+                .unwrap();
+            assert_eq!(message, "balance");
+
+            // the reply can be read with the scheme the user wrote in
+            let reply = encryption
+                .encrypt(dvm_secret, &user.public_key(), "reply".to_string())
+                .unwrap();
+            assert_eq!(DmEncryption::detect(&reply), expected);
+            let reply = expected
+                .decrypt(user_secret, &dvm.public_key(), &reply)
+                .unwrap();
+            assert_eq!(reply, "reply");
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::cashu::{MeltQuote, MintClient, Proof};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use lightning_invoice::Bolt11Invoice;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Talks to a Cashu mint over its http api (NUT-05 melting)
+#[derive(Clone)]
+pub struct HttpMint {
+    url: String,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct MeltQuoteResponse {
+    quote: String,
+    amount: u64,
+    fee_reserve: u64,
+}
+
+#[derive(Deserialize)]
+struct MeltResponse {
+    /// Older mints only say whether it was paid
+    paid: Option<bool>,
+    state: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MintErrorResponse {
+    detail: Option<String>,
+}
+
+impl HttpMint {
+    pub fn new(url: &str, http: reqwest::Client) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            http,
+        }
+    }
+
+    async fn post<T: DeserializeOwned>(&self, path: &str, body: Value) -> anyhow::Result<T> {
+        let resp = self
+            .http
+            .post(format!("{}{path}", self.url))
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = resp.status();
+        if !status.is_success() {
+            let detail = resp
+                .json::<MintErrorResponse>()
+                .await
+                .ok()
+                .and_then(|e| e.detail)
+                .unwrap_or_else(|| status.to_string());
+            return Err(anyhow!("Mint error: {detail}"));
+        }
+
+        Ok(resp.json().await?)
+    }
+}
+
+#[async_trait]
+impl MintClient for HttpMint {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn melt_quote(&self, invoice: &Bolt11Invoice) -> anyhow::Result<MeltQuote> {
+        let body = json!({ "request": invoice.to_string(), "unit": "sat" });
+        let resp: MeltQuoteResponse = self.post("/v1/melt/quote/bolt11", body).await?;
+
+        Ok(MeltQuote {
+            id: resp.quote,
+            amount_sats: resp.amount,
+            fee_reserve_sats: resp.fee_reserve,
+        })
+    }
+
+    async fn melt(&self, quote: &MeltQuote, proofs: Vec<Proof>) -> anyhow::Result<bool> {
+        let body = json!({ "quote": quote.id, "inputs": proofs });
+        let resp: MeltResponse = self.post("/v1/melt/bolt11", body).await?;
+
+        Ok(resp.state.as_deref() == Some("PAID") || resp.paid == Some(true))
+    }
+}
@@ -0,0 +1,112 @@
+use crate::cashu::{MeltQuote, MintClient, Proof, Token};
+use crate::lightning::mock::MockLightning;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bitcoin::hashes::Hash;
+use lightning_invoice::Bolt11Invoice;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct MockMintState {
+    /// Secrets of the proofs the mint issued and their amounts
+    issued: HashMap<String, u64>,
+    spent: HashSet<String>,
+    quotes: HashMap<String, (MeltQuote, Bolt11Invoice)>,
+    pay_later: bool,
+}
+
+/// In-memory mint for testing redemptions. Proofs are only checked against what
+/// was passed to [`MockMint::issue`] and melted invoices are paid on a [`MockLightning`].
+#[derive(Clone)]
+pub struct MockMint {
+    url: String,
+    lightning: MockLightning,
+    fee_reserve_sats: u64,
+    state: Arc<Mutex<MockMintState>>,
+}
+
+impl MockMint {
+    pub fn new(url: &str, lightning: MockLightning, fee_reserve_sats: u64) -> Self {
+        Self {
+            url: url.to_string(),
+            lightning,
+            fee_reserve_sats,
+            state: Arc::new(Mutex::new(MockMintState::default())),
+        }
+    }
+
+    /// Makes the token's proofs valid, as if the mint had issued them
+    pub fn issue(&self, token: &Token) {
+        let mut state = self.state.lock().unwrap();
+        for proof in token.proofs() {
+            state.issued.insert(proof.secret, proof.amount);
+        }
+    }
+
+    /// Makes melts spend the proofs without paying the invoice, like a mint that is
+    /// still routing the payment
+    pub fn set_pay_later(&self, pay_later: bool) {
+        self.state.lock().unwrap().pay_later = pay_later;
+    }
+}
+
+#[async_trait]
+impl MintClient for MockMint {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn melt_quote(&self, invoice: &Bolt11Invoice) -> anyhow::Result<MeltQuote> {
+        let amount_msats = invoice
+            .amount_milli_satoshis()
+            .ok_or(anyhow!("Invoice must have an amount"))?;
+        let quote = MeltQuote {
+            id: invoice.payment_hash().to_string(),
+            amount_sats: (amount_msats + 999) / 1_000,
+            fee_reserve_sats: self.fee_reserve_sats,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        state
+            .quotes
+            .insert(quote.id.clone(), (quote.clone(), invoice.clone()));
+
+        Ok(quote)
+    }
+
+    async fn melt(&self, quote: &MeltQuote, proofs: Vec<Proof>) -> anyhow::Result<bool> {
+        let (invoice, pay_later) = {
+            let mut state = self.state.lock().unwrap();
+            let (quote, invoice) = state
+                .quotes
+                .remove(&quote.id)
+                .ok_or(anyhow!("Unknown quote"))?;
+
+            let mut total = 0;
+            for proof in proofs.iter() {
+                if state.spent.contains(&proof.secret) {
+                    return Err(anyhow!("Token already spent"));
+                }
+                match state.issued.get(&proof.secret) {
+                    Some(amount) if *amount == proof.amount => total += amount,
+                    _ => return Err(anyhow!("Invalid proof")),
+                }
+            }
+            if total < quote.amount_sats + quote.fee_reserve_sats {
+                return Err(anyhow!("Not enough inputs for the quote"));
+            }
+            state.spent.extend(proofs.into_iter().map(|p| p.secret));
+
+            (invoice, state.pay_later)
+        };
+
+        if pay_later {
+            return Ok(false);
+        }
+        self.lightning
+            .receive_payment(invoice.payment_hash().to_byte_array())?;
+
+        Ok(true)
+    }
+}
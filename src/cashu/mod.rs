@@ -0,0 +1,355 @@
+use crate::lightning::{InvoiceDescription, InvoiceStatus, Lightning};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bitcoin::hashes::Hash;
+use lightning_invoice::Bolt11Invoice;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub mod http;
+#[cfg(test)]
+pub mod mock;
+
+/// Prefix of a serialized V3 token
+const TOKEN_PREFIX: &str = "cashuA";
+
+/// How long the invoice the mint pays is valid for, in seconds
+const REDEMPTION_INVOICE_EXPIRY: u64 = 600;
+
+/// How many times to ask for a melt quote before giving up on fitting the mint's fees
+const MAX_QUOTES: usize = 3;
+
+/// An ecash proof as sent in a token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Proof {
+    pub amount: u64,
+    /// Keyset id
+    pub id: String,
+    pub secret: String,
+    #[serde(rename = "C")]
+    pub c: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenEntry {
+    pub mint: String,
+    pub proofs: Vec<Proof>,
+}
+
+/// A Cashu V3 token, `cashuA` followed by the base64 encoded json
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    pub token: Vec<TokenEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+}
+
+impl Token {
+    /// Total value of the proofs in sats
+    pub fn amount_sats(&self) -> u64 {
+        self.token
+            .iter()
+            .flat_map(|t| t.proofs.iter())
+            .map(|p| p.amount)
+            .sum()
+    }
+
+    /// The mint the proofs are from, tokens spanning several mints are not accepted
+    pub fn mint(&self) -> anyhow::Result<&str> {
+        let mut mints = self.token.iter().map(|t| normalize_mint_url(&t.mint));
+        let mint = mints.next().ok_or(anyhow!("Token has no proofs"))?;
+        if mints.any(|m| m != mint) {
+            return Err(anyhow!("Token has proofs from more than one mint"));
+        }
+        Ok(mint)
+    }
+
+    pub fn proofs(&self) -> Vec<Proof> {
+        self.token.iter().flat_map(|t| t.proofs.clone()).collect()
+    }
+}
+
+impl FromStr for Token {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let encoded = s
+            .trim()
+            .strip_prefix(TOKEN_PREFIX)
+            .ok_or(anyhow!("Only cashuA tokens are supported"))?;
+
+        // wallets use both base64 alphabets, with and without padding
+        let encoded = encoded
+            .trim_end_matches('=')
+            .replace('+', "-")
+            .replace('/', "_");
+        let json = URL_SAFE_NO_PAD.decode(encoded)?;
+        let token: Token = serde_json::from_slice(&json)?;
+
+        if token.amount_sats() == 0 {
+            return Err(anyhow!("Token has no value"));
+        }
+
+        Ok(token)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_vec(self).map_err(|_| fmt::Error)?;
+        write!(f, "{TOKEN_PREFIX}{}", URL_SAFE.encode(json))
+    }
+}
+
+fn normalize_mint_url(url: &str) -> &str {
+    url.trim().trim_end_matches('/')
+}
+
+/// A mint's offer to pay an invoice in exchange for ecash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeltQuote {
+    pub id: String,
+    /// What the mint needs for the invoice itself
+    pub amount_sats: u64,
+    /// Most the mint will spend on routing fees, unused fees are not returned to us
+    pub fee_reserve_sats: u64,
+}
+
+/// The parts of a Cashu mint's api needed to redeem tokens, so a mock mint can be used in tests
+#[async_trait]
+pub trait MintClient: Send + Sync {
+    /// Url of the mint, only tokens from this mint can be redeemed
+    fn url(&self) -> &str;
+
+    /// Asks the mint what it would take to pay the invoice
+    async fn melt_quote(&self, invoice: &Bolt11Invoice) -> anyhow::Result<MeltQuote>;
+
+    /// Spends the proofs to have the mint pay the quoted invoice, returns whether it was paid.
+    /// Errors if the proofs are invalid or already spent.
+    async fn melt(&self, quote: &MeltQuote, proofs: Vec<Proof>) -> anyhow::Result<bool>;
+}
+
+pub type CashuMint = Arc<dyn MintClient>;
+
+/// A token that was melted to pay an invoice from our node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redemption {
+    /// Hash of the invoice the mint paid
+    pub payment_hash: [u8; 32],
+    pub amount_msats: u64,
+    /// Whether our node has seen the payment yet, the mint may still be paying the invoice
+    pub settled: bool,
+}
+
+/// Redeems the token by having its mint pay an invoice from our node for the token's
+/// value minus the mint's fee reserve, so the fees are paid by whoever sent the token.
+/// `record` is called with the invoice before the token is melted, so a payment that
+/// arrives after we stopped waiting for it can still be credited.
+pub async fn redeem_token(
+    mint: &dyn MintClient,
+    lightning: &dyn Lightning,
+    token: &Token,
+    mut record: impl FnMut(&Bolt11Invoice) -> anyhow::Result<()>,
+) -> anyhow::Result<Redemption> {
+    let token_mint = token.mint()?;
+    if token_mint != normalize_mint_url(mint.url()) {
+        return Err(anyhow!(
+            "Tokens from {token_mint} are not accepted, only from {}",
+            mint.url()
+        ));
+    }
+    if token.unit.as_deref().is_some_and(|u| u != "sat") {
+        return Err(anyhow!("Only sat tokens are accepted"));
+    }
+
+    // the fee reserve depends on the amount, so quote the full value first
+    // and then what is left of it after the mint's fees
+    let total = token.amount_sats();
+    let mut amount = total;
+    for _ in 0..MAX_QUOTES {
+        let invoice = lightning
+            .create_invoice(
+                amount * 1_000,
+                InvoiceDescription::Memo("Cashu token redemption".to_string()),
+                REDEMPTION_INVOICE_EXPIRY,
+            )
+            .await?;
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        let quote = mint.melt_quote(&invoice).await?;
+
+        if quote.amount_sats + quote.fee_reserve_sats <= total {
+            record(&invoice)?;
+            if !mint.melt(&quote, token.proofs()).await? {
+                warn!("Mint did not pay invoice {invoice} for a token redemption");
+            }
+            return check_redemption(lightning, payment_hash).await;
+        }
+
+        if let Err(e) = lightning.cancel_invoice(payment_hash).await {
+            warn!("Could not cancel unused redemption invoice: {e}");
+        }
+        amount = total.saturating_sub(quote.fee_reserve_sats).min(amount - 1);
+        if amount == 0 {
+            break;
+        }
+    }
+
+    Err(anyhow!("Token is too small to cover the mint's fees"))
+}
+
+/// Trusts our node rather than the mint about whether the invoice was paid. An invoice
+/// that is still open after a few seconds is returned unsettled rather than as a failure,
+/// since the mint may still be routing the payment.
+async fn check_redemption(
+    lightning: &dyn Lightning,
+    payment_hash: [u8; 32],
+) -> anyhow::Result<Redemption> {
+    let mut amount_msats = 0;
+    // some backends take a moment to see the payment
+    for _ in 0..5 {
+        if let Some(info) = lightning.lookup_invoice(payment_hash).await? {
+            amount_msats = info.amount_msats;
+            match info.status {
+                InvoiceStatus::Settled => {
+                    return Ok(Redemption {
+                        payment_hash,
+                        amount_msats,
+                        settled: true,
+                    })
+                }
+                InvoiceStatus::Canceled => return Err(anyhow!("Mint did not pay for the token")),
+                InvoiceStatus::Open | InvoiceStatus::Accepted => {}
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    Ok(Redemption {
+        payment_hash,
+        amount_msats,
+        settled: false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::mock::MockMint;
+    use super::{redeem_token, Proof, Token, TokenEntry};
+    use crate::lightning::mock::MockLightning;
+    use crate::lightning::InvoiceStatus;
+    use bitcoin::hashes::Hash;
+    use bitcoin::Network;
+    use std::str::FromStr;
+
+    const MINT_URL: &str = "https://mint.example.com";
+
+    #[test]
+    fn test_parse_token() {
+        // from the NUT-00 spec
+        let encoded = "cashuAeyJ0b2tlbiI6W3sibWludCI6Imh0dHBzOi8vODMzMy5zcGFjZTozMzM4IiwicHJvb2ZzIjpbeyJhbW91bnQiOjIsImlkIjoiMDA5YTFmMjkzMjUzZTQxZSIsInNlY3JldCI6IjQwNzkxNWJjMjEyYmU2MWE3N2UzZTZkMmFlYjRjNzI3OTgwYmRhNTFjZDA2YTZhZmMyOWUyODYxNzY4YTc4MzciLCJDIjoiMDJiYzkwOTc5OTdkODFhZmIyY2M3MzQ2YjVlNDM0NWE5MzQ2YmQyYTUwNmViNzk1ODU5OGE3MmYwY2Y4NTE2M2VhIn0seyJhbW91bnQiOjgsImlkIjoiMDA5YTFmMjkzMjUzZTQxZSIsInNlY3JldCI6ImZlMTUxMDkzMTRlNjFkNzc1NmIwZjhlZTBmMjNhNjI0YWNhYTNmNGUwNDJmNjE0MzNjNzI4YzcwNTdiOTMxYmUiLCJDIjoiMDI5ZThlNTA1MGI4OTBhN2Q2YzA5NjhkYjE2YmMxZDVkNWZhMDQwZWExZGUyODRmNmVjNjlkNjEyOTlmNjcxMDU5In1dfV0sInVuaXQiOiJzYXQiLCJtZW1vIjoiVGhhbmsgeW91LiJ9";
+        let token = Token::from_str(encoded).unwrap();
+        assert_eq!(token.amount_sats(), 10);
+        assert_eq!(token.mint().unwrap(), "https://8333.space:3338");
+        assert_eq!(token.unit.as_deref(), Some("sat"));
+        assert_eq!(token.memo.as_deref(), Some("Thank you."));
+        assert_eq!(token.proofs().len(), 2);
+
+        assert_eq!(Token::from_str(&token.to_string()).unwrap(), token);
+        assert!(Token::from_str("cashuBo2F0").is_err());
+        assert!(Token::from_str("cashuAnotbase64!").is_err());
+    }
+
+    fn test_token(mint: &str, amounts: &[u64]) -> Token {
+        let proofs = amounts
+            .iter()
+            .enumerate()
+            .map(|(i, amount)| Proof {
+                amount: *amount,
+                id: "009a1f293253e41e".to_string(),
+                secret: format!("{mint}-{i}-{amount}"),
+                c: "02bc9097997d81afb2cc7346b5e4345a9346bd2a506eb7958598a72f0cf85163ea".to_string(),
+            })
+            .collect();
+        Token {
+            token: vec![TokenEntry {
+                mint: mint.to_string(),
+                proofs,
+            }],
+            unit: Some("sat".to_string()),
+            memo: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_redeem_token() {
+        let lightning = MockLightning::new(Network::Regtest, false);
+        let mint = MockMint::new(MINT_URL, lightning.clone(), 2);
+
+        let token = test_token(MINT_URL, &[64, 32, 4]);
+        mint.issue(&token);
+
+        // the mint's fee reserve is taken out of the token
+        let mut recorded = vec![];
+        let redemption = redeem_token(&mint, &lightning, &token, |invoice| {
+            recorded.push(invoice.payment_hash().to_byte_array());
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(redemption.amount_msats, 98_000);
+        assert!(redemption.settled);
+        assert_eq!(recorded, vec![redemption.payment_hash]);
+
+        // proofs can't be spent twice
+        assert!(redeem_token(&mint, &lightning, &token, |_| Ok(()))
+            .await
+            .is_err());
+
+        // tokens the mint never issued are rejected
+        let forged = test_token(MINT_URL, &[100]);
+        assert!(redeem_token(&mint, &lightning, &forged, |_| Ok(()))
+            .await
+            .is_err());
+
+        // only the configured mint is used
+        let other = test_token("https://other.mint", &[100]);
+        mint.issue(&other);
+        assert!(redeem_token(&mint, &lightning, &other, |_| Ok(()))
+            .await
+            .is_err());
+
+        // a token that can't cover the fees is worthless
+        let dust = test_token(MINT_URL, &[2]);
+        mint.issue(&dust);
+        assert!(redeem_token(&mint, &lightning, &dust, |_| Ok(()))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redeem_token_paid_late() {
+        let lightning = MockLightning::new(Network::Regtest, false);
+        let mint = MockMint::new(MINT_URL, lightning.clone(), 2);
+        mint.set_pay_later(true);
+
+        let token = test_token(MINT_URL, &[64, 32, 4]);
+        mint.issue(&token);
+
+        // a payment we haven't seen yet isn't a failure, the token is already spent
+        let redemption = redeem_token(&mint, &lightning, &token, |_| Ok(()))
+            .await
+            .unwrap();
+        assert!(!redemption.settled);
+
+        let info = lightning.receive_payment(redemption.payment_hash).unwrap();
+        assert_eq!(info.status, InvoiceStatus::Settled);
+    }
+}
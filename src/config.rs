@@ -1,4 +1,5 @@
-use clap::Parser;
+use crate::report::ReportFormat;
+use clap::{Parser, Subcommand};
 use nostr::bitcoin::Network;
 use nostr::{Event, Keys};
 use serde::{Deserialize, Serialize};
@@ -7,6 +8,31 @@ use std::io::{BufReader, Write};
 use std::path::PathBuf;
 use std::str::FromStr;
 
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightningBackend {
+    /// LND over gRPC
+    Lnd,
+    /// Nostr Wallet Connect (NIP-47) wallet service, see `--nwc-uri`
+    Nwc,
+    /// In-memory node that pays its own invoices, only in builds with the `mock-lightning` feature
+    Mock,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Print revenue and usage per day, module and requester, then exit
+    Report {
+        /// Start of the report as a unix timestamp, defaults to 30 days before its end
+        #[clap(long)]
+        since: Option<u64>,
+        /// End of the report as a unix timestamp, defaults to now
+        #[clap(long)]
+        until: Option<u64>,
+        #[clap(value_enum, default_value_t = ReportFormat::Json, long)]
+        format: ReportFormat,
+    },
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(version, author, about)]
 /// A tool for zapping based on reactions to notes.
@@ -20,6 +46,15 @@ pub struct Config {
     /// Relay to connect to, can be specified multiple times
     #[clap(short, long)]
     pub relay: Vec<String>,
+    /// Lightning backend to use, `mock` runs without a node for testing
+    #[clap(value_enum, default_value_t = LightningBackend::Lnd, long)]
+    pub lightning: LightningBackend,
+    /// Nostr Wallet Connect connection URI, used with `--lightning nwc`
+    #[clap(long)]
+    pub nwc_uri: Option<String>,
+    /// Cashu mint whose tokens are accepted as payment, tokens are not accepted if not set
+    #[clap(long)]
+    pub cashu_mint: Option<String>,
     /// Host of the GRPC server for lnd
     #[clap(default_value_t = String::from("127.0.0.1"), long)]
     pub lnd_host: String,
@@ -38,6 +73,18 @@ pub struct Config {
     /// The domain name you are running the lnurl server on
     #[clap(default_value_t = String::from("localhost:3000"), long)]
     pub domain: String,
+    /// Name to answer LNURL-pay requests for, as in `name@domain`, can be specified multiple times
+    #[clap(default_value = "_", long)]
+    pub lnurl_name: Vec<String>,
+    /// Smallest LNURL-pay payment in millisats
+    #[clap(default_value_t = 1_000, long)]
+    pub min_sendable: u64,
+    /// Largest LNURL-pay payment in millisats
+    #[clap(default_value_t = 11_000_000_000, long)]
+    pub max_sendable: u64,
+    /// Longest LUD-12 comment accepted with an LNURL-pay payment, 0 doesn't allow comments
+    #[clap(default_value_t = 255, long)]
+    pub comment_allowed: u32,
     /// Bind address for webserver
     #[clap(default_value_t = String::from("0.0.0.0"), long)]
     pub bind: String,
@@ -47,6 +94,54 @@ pub struct Config {
     /// How many millisats per millisecond of runtime
     #[clap(default_value_t = 1.0, long)]
     pub price: f64,
+    /// Ignore job requests that do not tag any service provider with a `p` tag
+    #[clap(long)]
+    pub ignore_untargeted: bool,
+    /// How long, in seconds, a repeated job request is answered with its existing invoice or result
+    #[clap(default_value_t = 86_400, long)]
+    pub dedup_window: u64,
+    /// How far back, in seconds, before the last processed request to resubscribe from after a restart or reconnect
+    #[clap(default_value_t = 300, long)]
+    pub catch_up_margin: u64,
+    /// Maximum job requests per pubkey per minute
+    #[clap(default_value_t = 10, long)]
+    pub requests_per_minute: u32,
+    /// Maximum jobs a single pubkey can have running at once
+    #[clap(default_value_t = 3, long)]
+    pub max_concurrent_jobs: u32,
+    /// Maximum scheduled jobs a single pubkey can have waiting to run
+    #[clap(default_value_t = 10, long)]
+    pub max_scheduled_jobs: u32,
+    /// Rejected requests per minute after which a pubkey is blocked
+    #[clap(default_value_t = 20, long)]
+    pub abuse_threshold: u32,
+    /// Only serve pubkeys on the allowlist
+    #[clap(long)]
+    pub allowlist_only: bool,
+    /// Bearer token for the admin api, the admin api is disabled if not set
+    #[clap(long)]
+    pub admin_token: Option<String>,
+    /// Flat fee in millisats kept from the refund when a paid job fails
+    #[clap(default_value_t = 0, long)]
+    pub failure_fee: u64,
+    /// Most times a scheduled job is tried before it is marked failed
+    #[clap(default_value_t = 3, long)]
+    pub job_max_attempts: u32,
+    /// Seconds to wait before retrying a failed scheduled job, doubled for every retry after it
+    #[clap(default_value_t = 30, long)]
+    pub job_retry_backoff: u64,
+    /// Days to keep jobs whose invoice expired unpaid before deleting them, 0 keeps them forever
+    #[clap(default_value_t = 30, long)]
+    pub job_retention_days: u64,
+    /// Smallest balance withdrawal in millisats
+    #[clap(default_value_t = 10_000, long)]
+    pub min_withdrawal: u64,
+    /// Percent of a withdrawal held for routing fees, the unused part is credited back
+    #[clap(default_value_t = 1.0, long)]
+    pub withdrawal_fee_reserve: f64,
+    /// Runs the DVM if not set
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
 impl Config {
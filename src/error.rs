@@ -0,0 +1,124 @@
+use nostr::prelude::DataVendingMachineStatus;
+use nostr::{Event, EventBuilder, Kind, Tag, TagKind};
+use std::fmt;
+
+/// Errors that can happen while handling a job, these are reported back to the
+/// requester in an error feedback event along with a machine-readable code.
+#[derive(Debug)]
+pub enum JobError {
+    /// The downloaded wasm did not match the requested checksum
+    ChecksumMismatch { expected: String, got: String },
+    /// The wasm function ran longer than the requested time
+    Timeout,
+    /// The wasm file could not be downloaded
+    DownloadFailed(String),
+    /// The wasm module does not export the requested function
+    FunctionNotFound(String),
+    /// The requester does not have enough balance to pay for the job
+    InsufficientBalance(String),
+    /// The request is not allowed by the DVM's policy
+    PolicyViolation(String),
+    /// The requester is sending too many requests
+    RateLimited(String),
+    /// The wasm function failed while running
+    ExecutionFailed(String),
+    /// The job's invoice expired before it was paid
+    Expired,
+    /// A payment sent with the request could not be redeemed
+    PaymentFailed(String),
+    /// Something went wrong on our side
+    Internal(anyhow::Error),
+}
+
+impl JobError {
+    /// Machine-readable code for the error, sent in the `code` tag of error feedback
+    pub fn code(&self) -> &'static str {
+        match self {
+            JobError::ChecksumMismatch { .. } => "checksum_mismatch",
+            JobError::Timeout => "timeout",
+            JobError::DownloadFailed(_) => "download_failed",
+            JobError::FunctionNotFound(_) => "function_not_found",
+            JobError::InsufficientBalance(_) => "insufficient_balance",
+            JobError::PolicyViolation(_) => "policy_violation",
+            JobError::RateLimited(_) => "rate_limited",
+            JobError::ExecutionFailed(_) => "execution_failed",
+            JobError::Expired => "expired",
+            JobError::PaymentFailed(_) => "payment_failed",
+            JobError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Whether the error might not happen again, so a scheduled job that failed with it is retried
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, JobError::DownloadFailed(_) | JobError::Internal(_))
+    }
+
+    /// Creates an error job feedback event for the given job request
+    pub fn to_feedback(&self, job_request: &Event) -> EventBuilder {
+        self.feedback(job_request, self.to_string())
+    }
+
+    /// Creates an error job feedback event that also tells the requester they were refunded
+    pub fn to_refund_feedback(&self, job_request: &Event, refunded_msats: u64) -> EventBuilder {
+        let message = format!("{self}, refunded {refunded_msats} msats to your balance");
+        self.feedback(job_request, message)
+    }
+
+    /// Creates an error job feedback event that also tells the requester their held payment was returned
+    pub fn to_canceled_feedback(&self, job_request: &Event) -> EventBuilder {
+        let message = format!("{self}, your payment was returned");
+        self.feedback(job_request, message)
+    }
+
+    /// The `code` tag carrying [`JobError::code`]
+    pub fn code_tag(&self) -> Tag {
+        Tag::Generic(
+            TagKind::Custom("code".to_string()),
+            vec![self.code().to_string()],
+        )
+    }
+
+    fn feedback(&self, job_request: &Event, message: String) -> EventBuilder {
+        let tags = vec![
+            Tag::DataVendingMachineStatus {
+                status: DataVendingMachineStatus::Error,
+                extra_info: Some(message),
+            },
+            self.code_tag(),
+            Tag::event(job_request.id),
+            Tag::public_key(job_request.pubkey),
+        ];
+
+        EventBuilder::new(Kind::JobFeedback, "", tags)
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::ChecksumMismatch { expected, got } => {
+                write!(f, "Checksum mismatch expected: {expected} got: {got}")
+            }
+            JobError::Timeout => write!(f, "Timeout"),
+            JobError::DownloadFailed(e) => write!(f, "Failed to download file: {e}"),
+            JobError::FunctionNotFound(function) => {
+                write!(f, "Function not found in wasm module: {function}")
+            }
+            JobError::InsufficientBalance(e) => write!(f, "{e}"),
+            JobError::PolicyViolation(e) => write!(f, "{e}"),
+            JobError::RateLimited(e) => write!(f, "{e}"),
+            JobError::ExecutionFailed(e) => write!(f, "Execution failed: {e}"),
+            JobError::Expired => write!(f, "Invoice expired before it was paid"),
+            JobError::PaymentFailed(e) => write!(f, "Payment failed: {e}"),
+            JobError::Internal(e) => write!(f, "Internal error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+impl From<anyhow::Error> for JobError {
+    fn from(e: anyhow::Error) -> Self {
+        JobError::Internal(e)
+    }
+}
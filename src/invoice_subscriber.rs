@@ -1,75 +1,114 @@
-use crate::job_listener::get_job_params;
+use crate::error::JobError;
+use crate::job_listener::{cancel_held_job, finish_held_job, finish_job, get_job_params};
+use crate::lightning::{InvoiceInfo, InvoiceStatus, Lightning, LightningClient};
+use crate::models::cashu_redemption::CashuRedemption;
 use crate::models::event_job::EventJob;
+use crate::models::invoice_checkpoint::InvoiceCheckpoint;
 use crate::models::job::Job;
+use crate::models::lnurl_invoice::LnurlInvoice;
 use crate::models::zap::Zap;
-use crate::models::{mark_zap_paid, PostgresStorage};
-use crate::wasm_handler::{download_and_run_wasm, JobParams};
-use bitcoin::hashes::sha256;
-use bitcoin::hashes::Hash;
-use bitcoin::key::Secp256k1;
-use bitcoin::secp256k1::rand::rngs::OsRng;
-use bitcoin::secp256k1::rand::RngCore;
-use bitcoin::secp256k1::SecretKey;
+use crate::models::{
+    mark_zap_paid, settle_cashu_redemption, settle_lnurl_invoice, PostgresStorage,
+};
+use crate::policy::Policy;
+use crate::wasm_handler::{download_and_run_wasm, JobParams, WasmOutput};
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::PgConnection;
 use kormir::Oracle;
-use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
-use log::{error, info};
+use log::{error, info, warn};
 use nostr::nips::nip04;
 use nostr::prelude::DataVendingMachineStatus;
 use nostr::{Event, EventBuilder, Keys, Kind, Tag, TagKind, ToBech32};
 use nostr_sdk::Client;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
-use tonic_openssl_lnd::lnrpc::Invoice;
-use tonic_openssl_lnd::{lnrpc, LndLightningClient};
+use std::time::Duration;
+
+/// How far back to look for invoices paid while we weren't subscribed,
+/// our invoices expire after a day so older ones can't be paid anymore
+const RECONCILE_WINDOW_DAYS: i64 = 2;
 
 pub async fn start_invoice_subscription(
-    mut lnd: LndLightningClient,
+    lightning: LightningClient,
     relays: Vec<String>,
     keys: Keys,
     http: reqwest::Client,
     db_pool: Pool<ConnectionManager<PgConnection>>,
     oracle: Oracle<PostgresStorage>,
+    policy: Policy,
+    failure_fee: u64,
 ) -> anyhow::Result<()> {
-    info!("Starting invoice subscription");
+    let node_pubkey = lightning.node_info().await?.pubkey;
+    let settle_index = {
+        let mut conn = db_pool.get()?;
+        InvoiceCheckpoint::get(&mut conn, &node_pubkey)?
+    };
+    info!("Starting invoice subscription from settle index {settle_index}");
 
-    let sub = lnrpc::InvoiceSubscription::default();
-    let mut invoice_stream = lnd
-        .subscribe_invoices(sub)
-        .await
-        .expect("Failed to start invoice subscription")
-        .into_inner();
+    // subscribe before looking for missed payments so nothing paid in between is lost
+    let mut invoices = lightning.subscribe_invoices(settle_index).await?;
 
     let client = Client::new(&keys);
     client.add_relays(relays).await?;
     client.connect().await;
 
-    while let Some(ln_invoice) = invoice_stream
-        .message()
-        .await
-        .expect("Failed to receive invoices")
-    {
-        match InvoiceState::from_i32(ln_invoice.state) {
-            Some(InvoiceState::Settled) => {
-                let client = client.clone();
-                let http = http.clone();
-                let db_pool = db_pool.clone();
-                let keys = keys.clone();
-                let oracle = oracle.clone();
-
-                tokio::spawn(async move {
-                    if let Err(e) =
-                        handle_invoice(ln_invoice, http, client, &keys, db_pool, oracle).await
-                    {
-                        error!("handle invoice error: {e}");
-                    }
-                });
+    // before handling missed payments, which can mark more held jobs paid
+    let held = find_held_jobs(lightning.as_ref(), &db_pool).await?;
+    if !held.is_empty() {
+        info!("Resuming {} held jobs that never finished", held.len());
+    }
+    for job in held {
+        spawn_held_job(
+            job,
+            &lightning,
+            &client,
+            &keys,
+            &http,
+            &db_pool,
+            &oracle,
+            &policy,
+            failure_fee,
+        );
+    }
+
+    let missed = find_paid_invoices(lightning.as_ref(), &db_pool).await?;
+    if !missed.is_empty() {
+        info!(
+            "Found {} invoices paid while we weren't subscribed",
+            missed.len()
+        );
+    }
+    for ln_invoice in missed {
+        spawn_invoice_handler(
+            ln_invoice,
+            &lightning,
+            &client,
+            &keys,
+            &http,
+            &db_pool,
+            &oracle,
+            &policy,
+            &node_pubkey,
+            failure_fee,
+        );
+    }
+
+    while let Some(ln_invoice) = invoices.recv().await {
+        match ln_invoice.status {
+            // hold invoices are accepted when paid, regular invoices are settled right away
+            InvoiceStatus::Accepted | InvoiceStatus::Settled => {
+                spawn_invoice_handler(
+                    ln_invoice,
+                    &lightning,
+                    &client,
+                    &keys,
+                    &http,
+                    &db_pool,
+                    &oracle,
+                    &policy,
+                    &node_pubkey,
+                    failure_fee,
+                );
             }
-            None
-            | Some(InvoiceState::Canceled)
-            | Some(InvoiceState::Open)
-            | Some(InvoiceState::Accepted) => {}
+            InvoiceStatus::Canceled | InvoiceStatus::Open => {}
         }
     }
 
@@ -78,41 +117,314 @@ pub async fn start_invoice_subscription(
     Ok(())
 }
 
+/// Handles a paid invoice in the background, then moves the settle index checkpoint past it
+fn spawn_invoice_handler(
+    ln_invoice: InvoiceInfo,
+    lightning: &LightningClient,
+    client: &Client,
+    keys: &Keys,
+    http: &reqwest::Client,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    oracle: &Oracle<PostgresStorage>,
+    policy: &Policy,
+    node_pubkey: &str,
+    failure_fee: u64,
+) {
+    let lightning = lightning.clone();
+    let client = client.clone();
+    let http = http.clone();
+    let db_pool = db_pool.clone();
+    let keys = keys.clone();
+    let oracle = oracle.clone();
+    let policy = policy.clone();
+    let node_pubkey = node_pubkey.to_string();
+
+    tokio::spawn(async move {
+        let settle_index = ln_invoice.settle_index;
+        let result = handle_invoice(
+            ln_invoice,
+            lightning.as_ref(),
+            http,
+            client,
+            &keys,
+            db_pool.clone(),
+            oracle,
+            &policy,
+            failure_fee,
+        )
+        .await
+        .and_then(|_| {
+            if settle_index > 0 {
+                let mut conn = db_pool.get()?;
+                InvoiceCheckpoint::advance(&mut conn, &node_pubkey, settle_index)?;
+            }
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            error!("handle invoice error: {e}");
+        }
+    });
+}
+
+/// Runs a held job again that was paid for before we stopped
+fn spawn_held_job(
+    job: Job,
+    lightning: &LightningClient,
+    client: &Client,
+    keys: &Keys,
+    http: &reqwest::Client,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    oracle: &Oracle<PostgresStorage>,
+    policy: &Policy,
+    failure_fee: u64,
+) {
+    let lightning = lightning.clone();
+    let client = client.clone();
+    let http = http.clone();
+    let db_pool = db_pool.clone();
+    let keys = keys.clone();
+    let oracle = oracle.clone();
+    let policy = policy.clone();
+
+    tokio::spawn(async move {
+        let job_id = job.id;
+        let result = match db_pool.get() {
+            Ok(mut conn) => {
+                run_paid_job(
+                    &mut conn,
+                    lightning.as_ref(),
+                    &http,
+                    &client,
+                    &keys,
+                    &oracle,
+                    &policy,
+                    job,
+                    failure_fee,
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+
+        if let Err(e) = result {
+            error!("Failed to resume held job {job_id}: {e}");
+        }
+    });
+}
+
+/// Finds the jobs whose hold invoice was accepted but that never finished. Jobs whose
+/// payment is still held, or was settled before the result went out, are returned to be run
+/// again, the rest lost their payment and are marked canceled.
+async fn find_held_jobs(
+    lightning: &dyn Lightning,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+) -> anyhow::Result<Vec<Job>> {
+    let mut conn = db_pool.get()?;
+    let mut resume = vec![];
+    for job in Job::list_held_unanswered(&mut conn)? {
+        let status = match lightning.lookup_invoice(job.payment_hash()).await {
+            Ok(info) => info.map(|i| i.status),
+            Err(e) => {
+                warn!("Failed to look up invoice of held job {}: {e}", job.id);
+                continue;
+            }
+        };
+        match status {
+            Some(InvoiceStatus::Accepted | InvoiceStatus::Settled) => resume.push(job),
+            _ => {
+                Job::set_canceled(&mut conn, job.id, "Hold invoice timed out")?;
+                info!("Canceled held job {}, its payment is gone", job.id);
+            }
+        }
+    }
+
+    Ok(resume)
+}
+
+/// Looks up every invoice we are still waiting on, returns the ones that were paid
+async fn find_paid_invoices(
+    lightning: &dyn Lightning,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+) -> anyhow::Result<Vec<InvoiceInfo>> {
+    let mut conn = db_pool.get()?;
+    let created_after =
+        chrono::Utc::now().naive_utc() - chrono::Duration::days(RECONCILE_WINDOW_DAYS);
+    let mut payment_hashes: Vec<[u8; 32]> = Job::list_unpaid(&mut conn)?
+        .iter()
+        .map(|j| j.payment_hash())
+        .collect();
+    payment_hashes.extend(
+        Zap::list_unpaid(&mut conn, created_after)?
+            .iter()
+            .map(|z| z.payment_hash()),
+    );
+    payment_hashes.extend(
+        LnurlInvoice::list_unsettled(&mut conn, created_after)?
+            .iter()
+            .map(|i| i.payment_hash()),
+    );
+    payment_hashes.extend(
+        CashuRedemption::list_unsettled(&mut conn, created_after)?
+            .iter()
+            .map(|r| r.payment_hash()),
+    );
+    drop(conn);
+
+    // zap invoices are also LNURL invoices
+    payment_hashes.sort();
+    payment_hashes.dedup();
+
+    let mut paid = vec![];
+    for payment_hash in payment_hashes {
+        match lightning.lookup_invoice(payment_hash).await {
+            Ok(Some(info))
+                if matches!(
+                    info.status,
+                    InvoiceStatus::Accepted | InvoiceStatus::Settled
+                ) =>
+            {
+                paid.push(info)
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "Failed to look up invoice {}: {e}",
+                hex::encode(payment_hash)
+            ),
+        }
+    }
+
+    Ok(paid)
+}
+
 pub async fn handle_invoice(
-    ln_invoice: Invoice,
+    ln_invoice: InvoiceInfo,
+    lightning: &dyn Lightning,
     http: reqwest::Client,
     client: Client,
     keys: &Keys,
     db_pool: Pool<ConnectionManager<PgConnection>>,
     oracle: Oracle<PostgresStorage>,
+    policy: &Policy,
+    failure_fee: u64,
 ) -> anyhow::Result<()> {
     let mut conn = db_pool.get()?;
-    let job = Job::get_by_payment_hash(&mut conn, &ln_invoice.r_hash)?;
+    let payment_hash = ln_invoice.payment_hash.to_vec();
+    let job = Job::get_by_payment_hash(&mut conn, &payment_hash)?;
 
-    if job.is_none() {
+    let Some(job) = job else {
         // if it is not a job, try to handle it as a zap
-        return handle_paid_zap(&mut conn, ln_invoice.r_hash, client).await;
+        if ln_invoice.status != InvoiceStatus::Settled {
+            return Ok(());
+        }
+        settle_lnurl_invoice(&mut conn, ln_invoice.payment_hash)?;
+        // a Cashu token the mint paid for after we stopped waiting on it
+        settle_cashu_redemption(&mut conn, ln_invoice.payment_hash)?;
+        let Some(job) = handle_paid_zap(&mut conn, &ln_invoice, &client, keys).await? else {
+            return Ok(());
+        };
+        // the job's own invoice shouldn't be paid as well
+        if let Err(e) = lightning.cancel_invoice(job.payment_hash()).await {
+            warn!("Could not cancel invoice of zapped job {}: {e}", job.id);
+        }
+        return run_paid_job(
+            &mut conn,
+            lightning,
+            &http,
+            &client,
+            keys,
+            &oracle,
+            policy,
+            job,
+            failure_fee,
+        )
+        .await;
+    };
+
+    // the payment is only ours once a regular invoice settles
+    if job.preimage().is_none() && ln_invoice.status != InvoiceStatus::Settled {
+        return Ok(());
     }
-    let job = job.unwrap();
 
-    let event = job.request();
-    let (params, input) = get_job_params(&event, keys).expect("must have valid params");
-    let relays = client
-        .relays()
-        .await
-        .keys()
-        .map(|r| r.to_string())
-        .collect::<Vec<_>>();
-    let job_result = handle_job_request(
-        &mut conn, event, params, input, keys, &http, &oracle, relays,
+    // only run the job the first time we see the invoice paid,
+    // hold invoices are seen again once we settle them
+    let Some(job) = Job::mark_paid(&mut conn, job.id)? else {
+        // paid some other way already, give the held payment back
+        if ln_invoice.status == InvoiceStatus::Accepted {
+            lightning.cancel_invoice(job.payment_hash()).await?;
+        }
+        return Ok(());
+    };
+
+    run_paid_job(
+        &mut conn,
+        lightning,
+        &http,
+        &client,
+        keys,
+        &oracle,
+        policy,
+        job,
+        failure_fee,
     )
-    .await?;
+    .await
+}
 
-    if let Some(builder) = job_result.reply_event {
-        let event_id = client.send_event_builder(builder).await?;
-        info!("Sent response: {event_id}");
+/// Runs a job that was just paid for, a scheduled job is only set up to run later
+async fn run_paid_job(
+    conn: &mut PgConnection,
+    lightning: &dyn Lightning,
+    http: &reqwest::Client,
+    client: &Client,
+    keys: &Keys,
+    oracle: &Oracle<PostgresStorage>,
+    policy: &Policy,
+    job: Job,
+    failure_fee: u64,
+) -> anyhow::Result<()> {
+    let event = job.request();
+    let (params, input) = get_job_params(&event, keys).expect("must have valid params");
 
-        Job::set_response_id(&mut conn, job.id, event_id)?;
+    // the permit taken for the request was given back once the invoice was sent,
+    // a job that runs now counts towards the requester's running jobs again
+    let _permit = match params.schedule {
+        None => Some(policy.acquire(conn, event.pubkey).await?),
+        Some(_) => None,
+    };
+    let job_result = match handle_job_request(
+        conn,
+        client,
+        &job,
+        event.clone(),
+        params,
+        input,
+        keys,
+        http,
+        oracle,
+    )
+    .await
+    {
+        Ok(job_result) => job_result,
+        Err(e) => {
+            // don't leave the payment stuck until the hold invoice times out
+            if job.preimage().is_some() {
+                cancel_held_job(conn, lightning, &job, &e.to_string()).await?;
+            }
+            return Err(e);
+        }
+    };
+
+    if let Some(output) = job_result.output {
+        let reply = match job.preimage() {
+            Some(preimage) => {
+                finish_held_job(
+                    client, keys, conn, lightning, &job, &event, output, preimage,
+                )
+                .await?
+            }
+            None => finish_job(client, keys, conn, &job, &event, output, failure_fee).await?,
+        };
+        info!("Sent response: {}", reply.id);
     }
 
     if let Some(oracle_announcement) = job_result.oracle_announcement {
@@ -123,26 +435,53 @@ pub async fn handle_invoice(
     Ok(())
 }
 
+/// The result event of a job that ran successfully
+pub struct JobOutput {
+    pub builder: EventBuilder,
+    /// How long the wasm function took to run
+    pub runtime: Duration,
+    /// The time the requester paid for
+    pub time_limit: Duration,
+}
+
+impl JobOutput {
+    /// What the job actually cost given the price of its full time limit,
+    /// the time used is rounded up and never more than the limit.
+    pub fn cost_msats(&self, max_msats: u64) -> u64 {
+        let limit = self.time_limit.as_millis();
+        if limit == 0 {
+            return max_msats;
+        }
+        let used = self.runtime.as_millis().min(limit);
+        (max_msats as u128 * used).div_ceil(limit) as u64
+    }
+}
+
 pub struct HandleJobResult {
-    pub reply_event: Option<EventBuilder>,
+    /// The result of running the job, None if it was scheduled to run later
+    pub output: Option<Result<JobOutput, JobError>>,
     pub oracle_announcement: Option<Event>,
 }
 
 pub async fn handle_job_request(
     conn: &mut PgConnection,
+    client: &Client,
+    job: &Job,
     event: Event,
     params: JobParams,
     input: String,
     keys: &Keys,
     http: &reqwest::Client,
     oracle: &Oracle<PostgresStorage>,
-    relays: Vec<String>,
 ) -> anyhow::Result<HandleJobResult> {
     match params.schedule.as_ref() {
         Some(schedule) => {
-            if schedule.run_date <= SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() {
-                anyhow::bail!("Schedule run date must be in the future");
-            }
+            let relays = client
+                .relays()
+                .await
+                .keys()
+                .map(|r| r.to_string())
+                .collect::<Vec<_>>();
 
             let oracle_data = if let Some(outcomes) = schedule.expected_outputs.clone() {
                 let event_name = schedule.name.clone().unwrap_or(event.id.to_hex());
@@ -150,48 +489,64 @@ pub async fn handle_job_request(
                     .create_enum_event(event_name, outcomes, schedule.run_date as u32)
                     .await?;
 
-                let event = kormir::nostr_events::create_announcement_event(
+                let announcement = kormir::nostr_events::create_announcement_event(
                     &oracle.nostr_keys(),
                     &ann,
                     &relays,
                 )?;
 
-                Some((id, event))
+                Some((id, announcement))
             } else {
                 None
             };
 
-            let job = Job::create_scheduled(conn, &event, schedule.run_date)?;
-            if let Some((event_id, event)) = oracle_data {
+            let mut oracle_announcement = None;
+            if let Some((event_id, announcement)) = oracle_data {
                 EventJob::create(conn, job.id, event_id as i32)?;
                 oracle
                     .storage
-                    .add_announcement_event_id(event_id, event.id)
+                    .add_announcement_event_id(event_id, announcement.id)
                     .await?;
+                oracle_announcement = Some(announcement);
             }
             Ok(HandleJobResult {
-                reply_event: None,
-                oracle_announcement: Some(event),
+                output: None,
+                oracle_announcement,
             })
         }
-        None => run_job_request(event, params, input, keys, http)
-            .await
-            .map(|reply_event| HandleJobResult {
-                reply_event: Some(reply_event),
+        None => {
+            let output = run_job_request(client, event, params, input, keys, http).await;
+            Ok(HandleJobResult {
+                output: Some(output),
                 oracle_announcement: None,
-            }),
+            })
+        }
     }
 }
 
 pub async fn run_job_request(
+    client: &Client,
     event: Event,
     params: JobParams,
     input: String,
     keys: &Keys,
     http: &reqwest::Client,
-) -> anyhow::Result<EventBuilder> {
+) -> Result<JobOutput, JobError> {
+    let time_limit = Duration::from_millis(params.time);
+    let processing = EventBuilder::job_feedback(
+        &event,
+        DataVendingMachineStatus::Processing,
+        None,
+        0,
+        None,
+        None,
+    );
+    if let Err(e) = client.send_event_builder(processing).await {
+        warn!("Failed to send processing feedback for {}: {e}", event.id);
+    }
+
     match download_and_run_wasm(params, event.id, http).await {
-        Ok(result) => {
+        Ok(WasmOutput { output, runtime }) => {
             let mut tags = vec![
                 Tag::public_key(event.pubkey),
                 Tag::event(event.id),
@@ -199,90 +554,323 @@ pub async fn run_job_request(
                 Tag::Request(event.clone()),
             ];
 
-            if event.tags.iter().any(|t| matches!(t, Tag::Encrypted)) {
+            let builder = if event.tags.iter().any(|t| matches!(t, Tag::Encrypted)) {
                 tags.push(Tag::Encrypted);
-                let encrypted = nip04::encrypt(keys.secret_key()?, &event.pubkey, result)?;
-                Ok(EventBuilder::new(Kind::JobResult(6600), encrypted, tags))
+                let secret_key = keys
+                    .secret_key()
+                    .map_err(|e| JobError::Internal(e.into()))?;
+                let encrypted = nip04::encrypt(secret_key, &event.pubkey, output)
+                    .map_err(|e| JobError::Internal(e.into()))?;
+                EventBuilder::new(Kind::JobResult(6600), encrypted, tags)
             } else {
-                Ok(EventBuilder::new(Kind::JobResult(6600), result, tags))
-            }
+                EventBuilder::new(Kind::JobResult(6600), output, tags)
+            };
+
+            Ok(JobOutput {
+                builder,
+                runtime,
+                time_limit,
+            })
         }
         Err(e) => {
             error!("Error running event {}: {e}", event.id);
-            Ok(EventBuilder::job_feedback(
-                &event,
-                DataVendingMachineStatus::Error,
-                Some(e.to_string()),
-                0,
-                None,
-                None,
-            ))
+            Err(e)
         }
     }
 }
 
+/// Credits a paid zap and publishes its receipt, returns the job the zap paid for
 async fn handle_paid_zap(
     conn: &mut PgConnection,
-    payment_hash: Vec<u8>,
-    client: Client,
-) -> anyhow::Result<()> {
-    match Zap::find_by_payment_hash(conn, &payment_hash)? {
-        None => Ok(()),
-        Some(zap) => {
-            if zap.note_id.is_some() {
-                return Ok(());
-            }
+    ln_invoice: &InvoiceInfo,
+    client: &Client,
+    keys: &Keys,
+) -> anyhow::Result<Option<Job>> {
+    let payment_hash = ln_invoice.payment_hash.to_vec();
+    let Some(zap) = Zap::find_by_payment_hash(conn, &payment_hash)? else {
+        return Ok(None);
+    };
+    if zap.note_id.is_some() {
+        return Ok(None);
+    }
 
-            let invoice = zap.invoice();
+    let zap_request = zap.request();
+    info!(
+        "Received zap for {} msats from {}!",
+        zap.amount_msats,
+        zap_request.pubkey.to_bech32()?
+    );
+
+    // the receipt commits to the invoice that was actually paid and its preimage
+    let zap_relays = zap_relays(&zap_request);
+    let receipt = EventBuilder::zap_receipt(
+        zap.invoice().to_string(),
+        ln_invoice.preimage.map(hex::encode),
+        zap_request,
+    )
+    .to_event(keys)?;
 
-            let mut preimage = [0u8; 32];
-            OsRng.fill_bytes(&mut preimage);
-            let invoice_hash = sha256::Hash::hash(&preimage);
+    let paid_job = mark_zap_paid(conn, payment_hash, receipt.id)?;
 
-            let mut payment_secret = [0u8; 32];
-            OsRng.fill_bytes(&mut payment_secret);
+    if let Err(e) = client.send_event(receipt.clone()).await {
+        warn!("Failed to broadcast zap receipt to our relays: {e}");
+    }
+    if !zap_relays.is_empty() {
+        if let Err(e) = send_to_relays(keys, zap_relays, receipt.clone()).await {
+            warn!("Failed to broadcast zap receipt to the zap request's relays: {e}");
+        }
+    }
 
-            let private_key = SecretKey::new(&mut OsRng);
+    info!(
+        "Broadcasted zap event id: {}!",
+        receipt.id.to_bech32().expect("bech32")
+    );
 
-            let amt_msats = invoice
-                .amount_milli_satoshis()
-                .expect("Invoice must have an amount");
+    Ok(paid_job)
+}
 
-            let zap_request = zap.request();
+/// Relays the zap request asks for the receipt to be published to
+fn zap_relays(zap_request: &Event) -> Vec<String> {
+    zap_request
+        .tags
+        .iter()
+        .filter_map(|t| match t {
+            Tag::Relays(relays) => Some(relays.iter().map(|r| r.to_string())),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
 
-            info!(
-                "Received zap for {amt_msats} msats from {}!",
-                zap_request.pubkey.to_bech32()?
-            );
+/// Sends an event to relays we aren't otherwise connected to
+async fn send_to_relays(keys: &Keys, relays: Vec<String>, event: Event) -> anyhow::Result<()> {
+    let client = Client::new(keys);
+    client.add_relays(relays).await?;
+    client.connect().await;
+    let result = client.send_event(event).await;
+    client.disconnect().await?;
+    result?;
 
-            let fake_invoice = InvoiceBuilder::new(Currency::Bitcoin)
-                .amount_milli_satoshis(amt_msats)
-                .invoice_description(invoice.description())
-                .current_timestamp()
-                .payment_hash(invoice_hash)
-                .payment_secret(PaymentSecret(payment_secret))
-                .min_final_cltv_expiry_delta(144)
-                .basic_mpp()
-                .build_signed(|hash| {
-                    Secp256k1::signing_only().sign_ecdsa_recoverable(hash, &private_key)
-                })?;
+    Ok(())
+}
 
-            let event = EventBuilder::zap_receipt(
-                fake_invoice.to_string(),
-                Some(hex::encode(preimage)),
-                zap_request,
-            );
+#[cfg(test)]
+mod test {
+    use super::{find_held_jobs, handle_invoice, zap_relays, JobOutput};
+    use crate::job_listener::handle_event;
+    use crate::lightning::mock::MockLightning;
+    use crate::lightning::{InvoiceDescription, InvoiceStatus, Lightning, LightningClient};
+    use crate::models::job::{Job, JobStatus};
+    use crate::models::job_request::JobRequest;
+    use crate::models::test::test_pool;
+    use crate::models::PostgresStorage;
+    use crate::policy::{Policy, PolicyLimits};
+    use crate::wasm_handler::JobParams;
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::Network;
+    use kormir::Oracle;
+    use nostr::{EventBuilder, Keys, Kind, Tag, TagKind, UncheckedUrl};
+    use nostr_sdk::Client;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn output(runtime_ms: u64, time_limit_ms: u64) -> JobOutput {
+        JobOutput {
+            builder: EventBuilder::new(Kind::JobResult(6600), "", []),
+            runtime: Duration::from_millis(runtime_ms),
+            time_limit: Duration::from_millis(time_limit_ms),
+        }
+    }
 
-            let event_id = client.send_event_builder(event).await?;
+    #[test]
+    fn test_cost_msats() {
+        assert_eq!(output(500, 1_000).cost_msats(10_000), 5_000);
+        // rounded up
+        assert_eq!(output(1, 3_000).cost_msats(1_000), 1);
+        // never more than the reserved price
+        assert_eq!(output(1_500, 1_000).cost_msats(10_000), 10_000);
+        assert_eq!(output(0, 1_000).cost_msats(10_000), 0);
+    }
 
-            info!(
-                "Broadcasted zap event id: {}!",
-                event_id.to_bech32().expect("bech32")
-            );
+    #[test]
+    fn test_zap_relays() {
+        let keys = Keys::generate();
+        let relays = vec![
+            UncheckedUrl::from("wss://relay.damus.io"),
+            UncheckedUrl::from("wss://nos.lol"),
+        ];
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "", [Tag::Relays(relays)])
+            .to_event(&keys)
+            .unwrap();
+        assert_eq!(
+            zap_relays(&zap_request),
+            vec!["wss://relay.damus.io", "wss://nos.lol"]
+        );
+
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "", [])
+            .to_event(&keys)
+            .unwrap();
+        assert!(zap_relays(&zap_request).is_empty());
+    }
 
-            mark_zap_paid(conn, payment_hash, event_id)?;
+    /// Runs a job paid by invoice from request to result, with the mock node standing in for
+    /// a real one. Needs postgres in `DATABASE_URL` and a nostr relay in `TEST_RELAY`.
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL and TEST_RELAY"]
+    async fn test_invoice_payment_flow() {
+        let relay = std::env::var("TEST_RELAY").expect("TEST_RELAY must be set");
+        let pool = test_pool();
+        let keys = Keys::generate();
+        let client = Client::new(&keys);
+        client.add_relay(relay).await.unwrap();
+        client.connect().await;
+
+        let mock = MockLightning::new(Network::Regtest, false);
+        let lightning: LightningClient = Arc::new(mock.clone());
+        let mut invoices = lightning.subscribe_invoices(0).await.unwrap();
+        let oracle = {
+            let signing_key = kormir::bitcoin::secp256k1::SecretKey::from_slice(
+                &keys.secret_key().unwrap().secret_bytes(),
+            )
+            .unwrap();
+            let pubkey =
+                kormir::bitcoin::XOnlyPublicKey::from_slice(&keys.public_key().serialize())
+                    .unwrap();
+            let storage = PostgresStorage::new(pool.clone(), pubkey).unwrap();
+            Oracle::from_signing_key(storage, signing_key).unwrap()
+        };
+        let policy = Policy::new(PolicyLimits {
+            requests_per_minute: 10,
+            max_concurrent_jobs: 1,
+            max_scheduled_jobs: 10,
+            abuse_threshold: 10,
+            allowlist_only: false,
+        });
+        let http = reqwest::Client::new();
+
+        let params = JobParams {
+            url: "https://github.com/extism/plugins/releases/download/v0.5.0/count_vowels.wasm"
+                .to_string(),
+            function: "count_vowels".to_string(),
+            input: "Hello World".to_string(),
+            time: 500,
+            checksum: "93898457953d30d016f712ccf4336ce7e9971db5f7f3aff1edd252764f75d5d7"
+                .to_string(),
+            schedule: None,
+            trigger: None,
+        };
+        let input = Tag::Generic(
+            TagKind::I,
+            vec![serde_json::to_string(&params).unwrap(), "text".to_string()],
+        );
+        let request = EventBuilder::new(Kind::JobRequest(5600), "", [input])
+            .to_event(&Keys::generate())
+            .unwrap();
+
+        // the request is answered with an invoice
+        handle_event(
+            10.0,
+            request.clone(),
+            client.clone(),
+            keys.clone(),
+            lightning.clone(),
+            None,
+            pool.clone(),
+            &http,
+            oracle.clone(),
+            &policy,
+            0,
+        )
+        .await
+        .unwrap();
+        let mut conn = pool.get().unwrap();
+        let job = Job::get_by_request_id(&mut conn, request.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.status(), JobStatus::Unpaid);
+        assert_eq!(job.price_msats(), Some(5_000));
+        let feedback = JobRequest::get(&mut conn, request.id)
+            .unwrap()
+            .and_then(|r| r.reply_event())
+            .unwrap();
+        assert_eq!(feedback.kind, Kind::JobFeedback);
+
+        // paying it runs the job, and the held payment is taken once the result is published
+        mock.receive_payment(job.payment_hash()).unwrap();
+        let paid = invoices.recv().await.unwrap();
+        assert_eq!(paid.status, InvoiceStatus::Accepted);
+        handle_invoice(
+            paid,
+            lightning.as_ref(),
+            http,
+            client.clone(),
+            &keys,
+            pool.clone(),
+            oracle,
+            &policy,
+            0,
+        )
+        .await
+        .unwrap();
+
+        let job = Job::get_by_id(&mut conn, job.id).unwrap();
+        assert_eq!(job.status(), JobStatus::Completed);
+        let result = JobRequest::get(&mut conn, request.id)
+            .unwrap()
+            .and_then(|r| r.reply_event())
+            .unwrap();
+        assert_eq!(result.kind, Kind::JobResult(6600));
+        assert_eq!(
+            result.content,
+            "{\"count\":3,\"total\":3,\"vowels\":\"aeiouAEIOU\"}"
+        );
+        let settled = invoices.recv().await.unwrap();
+        assert_eq!(settled.payment_hash, job.payment_hash());
+        assert_eq!(settled.status, InvoiceStatus::Settled);
+    }
 
-            Ok(())
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_find_held_jobs() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let mock = MockLightning::new(Network::Regtest, false);
+        let keys = Keys::generate();
+
+        let mut jobs = vec![];
+        for _ in 0..2 {
+            let preimage = Keys::generate().secret_key().unwrap().secret_bytes();
+            let payment_hash = sha256::Hash::hash(&preimage).to_byte_array();
+            mock.create_hold_invoice(
+                1_000,
+                InvoiceDescription::Memo("test".to_string()),
+                3_600,
+                payment_hash,
+            )
+            .await
+            .unwrap();
+            mock.receive_payment(payment_hash).unwrap();
+            let request = EventBuilder::new(Kind::JobRequest(5600), "", [])
+                .to_event(&keys)
+                .unwrap();
+            let job = Job::create(
+                &mut conn,
+                payment_hash,
+                Some(preimage),
+                &request,
+                None,
+                1_000,
+            )
+            .unwrap();
+            jobs.push(Job::mark_paid(&mut conn, job.id).unwrap().unwrap());
         }
+        // the payment of the second one timed out while we were down
+        mock.cancel_invoice(jobs[1].payment_hash()).await.unwrap();
+
+        let held = find_held_jobs(&mock, &pool).await.unwrap();
+        assert!(held.iter().any(|j| j.id == jobs[0].id));
+        assert!(held.iter().all(|j| j.id != jobs[1].id));
+        let canceled = Job::get_by_id(&mut conn, jobs[1].id).unwrap();
+        assert_eq!(canceled.status(), JobStatus::Canceled);
     }
 }
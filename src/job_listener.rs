@@ -1,73 +1,169 @@
+use crate::balance::handle_dm;
+use crate::cashu::{redeem_token, CashuMint, MintClient, Token};
 use crate::config::Config;
-use crate::invoice_subscriber::{handle_job_request, run_job_request};
+use crate::error::JobError;
+use crate::invoice_subscriber::{handle_job_request, run_job_request, JobOutput};
+use crate::job_queue::{hold_lease, RetryPolicy, LEASE_BATCH, LEASE_SECS};
+use crate::lightning::{InvoiceDescription, InvoiceStatus, Lightning, LightningClient};
+use crate::models::balance_entry::BalanceEntry;
+use crate::models::cashu_redemption::CashuRedemption;
 use crate::models::event_job::EventJob;
-use crate::models::job::Job;
-use crate::models::zap_balance::ZapBalance;
-use crate::models::PostgresStorage;
+use crate::models::job::{Job, JobStatus};
+use crate::models::job_request::JobRequest;
+use crate::models::job_series::{JobSeries, SeriesStatus};
+use crate::models::job_trigger::JobTrigger;
+use crate::models::relay_checkpoint::RelayCheckpoint;
+use crate::models::{
+    available_balance, redeem_voucher, refund_job, reserve_balance, schedule_next_run,
+    settle_cashu_redemption, settle_reservation, use_plan, NextRun, PostgresStorage,
+};
+use crate::policy::{JobPermit, Policy, PolicyDecision};
+use crate::recurrence::Recurrence;
+use crate::trigger::{validate_trigger, MAX_TRIGGERS_PER_PUBKEY};
 use crate::wasm_handler::JobParams;
 use anyhow::anyhow;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::rand::rngs::OsRng;
+use bitcoin::secp256k1::rand::RngCore;
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::PgConnection;
 use kormir::nostr_events::create_attestation_event;
 use kormir::storage::Storage;
 use kormir::{EventDescriptor, Oracle};
-use lightning_invoice::Bolt11Invoice;
 use log::{debug, error, info, warn};
 use nostr::nips::nip04;
 use nostr::prelude::DataVendingMachineStatus;
 use nostr::secp256k1::ThirtyTwoByteHash;
-use nostr::{Event, EventBuilder, EventId, Filter, Keys, Kind, Tag, TagKind, Timestamp};
-use nostr_sdk::{Client, RelayPoolNotification};
+use nostr::{
+    Event, EventBuilder, EventId, Filter, JsonUtil, Keys, Kind, Tag, TagKind, Timestamp, Url,
+};
+use nostr_sdk::{Client, RelayPoolNotification, RelayStatus};
 use std::collections::HashSet;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::time::Duration;
 use tokio::spawn;
-use tokio::sync::Mutex;
-use tonic_openssl_lnd::{lnrpc, LndLightningClient};
+
+/// How long a job's invoice can be paid for, in seconds
+pub const JOB_INVOICE_EXPIRY: u64 = 86_400;
+
+/// How long to wait for relays when checking if a repeating job's request was deleted
+const DELETION_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn listen_for_jobs(
     config: &Config,
     keys: Keys,
-    lnd: LndLightningClient,
+    lightning: LightningClient,
+    cashu_mint: Option<CashuMint>,
     db_pool: Pool<ConnectionManager<PgConnection>>,
     http: reqwest::Client,
     oracle: Oracle<PostgresStorage>,
+    policy: Policy,
 ) -> anyhow::Result<()> {
     let client = Client::new(&keys);
     client.add_relays(config.relay.clone()).await?;
     client.connect().await;
 
-    let filter = Filter::new()
-        .kind(Kind::JobRequest(5600))
-        .since(Timestamp::now());
-
-    client.subscribe(vec![filter]).await;
+    let filter = job_request_filter(config, &db_pool)?;
+    client.subscribe(vec![filter, dm_filter(&keys)]).await;
 
     let mut notifications = client.notifications();
+    let mut disconnected = HashSet::new();
 
     while let Ok(msg) = notifications.recv().await {
         match msg {
-            RelayPoolNotification::Event { event, .. } => {
+            RelayPoolNotification::Event {
+                relay_url, event, ..
+            } => {
                 if event.kind == Kind::JobRequest(5600) {
                     // spawn thread to handle event
                     let client = client.clone();
                     let keys = keys.clone();
-                    let lnd = lnd.clone();
+                    let lightning = lightning.clone();
+                    let cashu_mint = cashu_mint.clone();
                     let db = db_pool.clone();
                     let http = http.clone();
                     let price = config.price;
+                    let failure_fee = config.failure_fee;
+                    let accept_untargeted = !config.ignore_untargeted;
+                    let dedup_window = config.dedup_window;
                     let oracle = oracle.clone();
+                    let policy = policy.clone();
                     spawn(async move {
-                        if let Err(e) =
-                            handle_event(price, event, client, keys, lnd, db, &http, oracle).await
+                        let event_id = event.id;
+                        let permit = match accept_job_request(
+                            &client,
+                            &keys,
+                            &db,
+                            &policy,
+                            relay_url.as_str(),
+                            &event,
+                            accept_untargeted,
+                            dedup_window,
+                        )
+                        .await
+                        {
+                            Ok(Some(permit)) => permit,
+                            Ok(None) => return,
+                            Err(e) => {
+                                error!("Error accepting job request {event_id}: {e}");
+                                return;
+                            }
+                        };
+
+                        // hold the permit until we are done handling the job
+                        let _permit = permit;
+                        if let Err(e) = handle_event(
+                            price,
+                            event,
+                            client,
+                            keys,
+                            lightning,
+                            cashu_mint,
+                            db.clone(),
+                            &http,
+                            oracle,
+                            &policy,
+                            failure_fee,
+                        )
+                        .await
                         {
                             error!("Error handling event: {e}");
+                            release_claim(&db, event_id);
+                        }
+                    });
+                } else if event.kind == Kind::EncryptedDirectMessage {
+                    let client = client.clone();
+                    let keys = keys.clone();
+                    let db = db_pool.clone();
+                    let domain = config.domain.clone();
+                    spawn(async move {
+                        let result = match db.get() {
+                            Ok(mut conn) => {
+                                handle_dm(&mut conn, &client, &keys, event, &domain).await
+                            }
+                            Err(e) => Err(e.into()),
+                        };
+                        if let Err(e) = result {
+                            error!("Error handling DM: {e}");
                         }
                     });
                 }
             }
             RelayPoolNotification::Message { .. } => {}
-            RelayPoolNotification::RelayStatus { .. } => {}
+            RelayPoolNotification::RelayStatus { relay_url, status } => match status {
+                RelayStatus::Disconnected => {
+                    disconnected.insert(relay_url);
+                }
+                RelayStatus::Connected if disconnected.remove(&relay_url) => {
+                    // catch up on anything we missed while the relay was disconnected
+                    info!("Relay {relay_url} reconnected, resubscribing to job requests");
+                    match job_request_filter(config, &db_pool) {
+                        Ok(filter) => client.subscribe(vec![filter, dm_filter(&keys)]).await,
+                        Err(e) => error!("Error resubscribing to job requests: {e}"),
+                    }
+                }
+                _ => {}
+            },
             RelayPoolNotification::Stop => {}
             RelayPoolNotification::Shutdown => {}
         }
@@ -78,83 +174,326 @@ pub async fn listen_for_jobs(
     Ok(())
 }
 
+/// Decides whether a job request from a relay should be handled, returns the permit to hold
+/// while handling it. Requests that are expired, not for us, already seen or rejected by the
+/// policy are answered here if they need to be and return None.
+async fn accept_job_request(
+    client: &Client,
+    keys: &Keys,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    policy: &Policy,
+    relay_url: &str,
+    event: &Event,
+    accept_untargeted: bool,
+    dedup_window: u64,
+) -> anyhow::Result<Option<JobPermit>> {
+    let mut conn = db_pool.get()?;
+
+    // don't let events from the future push our checkpoint ahead
+    let seen_at = event.created_at.min(Timestamp::now());
+    RelayCheckpoint::advance(&mut conn, relay_url, seen_at)?;
+
+    if event.is_expired() {
+        debug!("Skipping expired job request: {}", event.id);
+        return Ok(None);
+    }
+
+    if !is_targeted_at_us(event, keys, accept_untargeted) {
+        debug!("Skipping job request not targeted at us: {}", event.id);
+        return Ok(None);
+    }
+
+    // make sure we only ever handle a request once, even if multiple relays send it to us
+    if !JobRequest::claim(&mut conn, event.id)? {
+        handle_duplicate_request(&mut conn, client, event.id, dedup_window).await?;
+        return Ok(None);
+    }
+
+    let accepted = check_policy(client, keys, &mut conn, policy, event).await;
+    if accepted.is_err() {
+        JobRequest::release(&mut conn, event.id)?;
+    }
+    accepted
+}
+
+/// Asks the policy whether a claimed request can run, answering it if it is rejected
+async fn check_policy(
+    client: &Client,
+    keys: &Keys,
+    conn: &mut PgConnection,
+    policy: &Policy,
+    event: &Event,
+) -> anyhow::Result<Option<JobPermit>> {
+    match policy.check(conn, event.pubkey)? {
+        PolicyDecision::Allow(permit) => Ok(Some(permit)),
+        PolicyDecision::Reject(reason) => {
+            info!("Rejecting job request {}: {reason}", event.id);
+            reply_error(client, keys, conn, event, JobError::RateLimited(reason)).await?;
+            Ok(None)
+        }
+        PolicyDecision::Ignore => {
+            debug!("Ignoring job request from blocked key: {}", event.id);
+            Ok(None)
+        }
+    }
+}
+
+/// Gives back the claim on a request that failed before it was answered or had a job,
+/// so its copy from another relay, or the next catch up, handles it again
+fn release_claim(db_pool: &Pool<ConnectionManager<PgConnection>>, event_id: EventId) {
+    let released = db_pool
+        .get()
+        .map_err(anyhow::Error::from)
+        .and_then(|mut conn| JobRequest::release(&mut conn, event_id));
+    match released {
+        Ok(true) => info!("Released claim on job request {event_id}"),
+        Ok(false) => {}
+        Err(e) => error!("Error releasing claim on job request {event_id}: {e}"),
+    }
+}
+
+/// Filter for DMs sent to us, used for balance inquiries
+fn dm_filter(keys: &Keys) -> Filter {
+    Filter::new()
+        .kind(Kind::EncryptedDirectMessage)
+        .pubkey(keys.public_key())
+        .since(Timestamp::now())
+}
+
+/// Filter for job requests, starting from the oldest checkpoint of our relays minus the
+/// catch up margin so requests published while we were offline are not lost. Requests we
+/// already handled are dropped by their idempotency key, which is why we never look back
+/// further than the dedup window.
+fn job_request_filter(
+    config: &Config,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+) -> anyhow::Result<Filter> {
+    let urls = config
+        .relay
+        .iter()
+        .filter_map(|r| Url::parse(r).ok())
+        .map(|u| u.to_string())
+        .collect::<Vec<_>>();
+
+    let mut conn = db_pool.get()?;
+    let checkpoints = RelayCheckpoint::get_by_urls(&mut conn, &urls)?;
+
+    let now = Timestamp::now().as_u64();
+    let oldest = checkpoints.iter().map(|c| c.last_seen_at().as_u64()).min();
+    let since = catch_up_since(oldest, now, config.catch_up_margin, config.dedup_window);
+
+    if since < now {
+        info!("Catching up on job requests from the last {}s", now - since);
+    }
+
+    Ok(Filter::new()
+        .kind(Kind::JobRequest(5600))
+        .since(Timestamp::from(since)))
+}
+
+/// Where to start reading job requests given the oldest relay checkpoint, now when there is
+/// none. Never further back than the dedup window.
+fn catch_up_since(oldest: Option<u64>, now: u64, catch_up_margin: u64, dedup_window: u64) -> u64 {
+    match oldest {
+        None => now,
+        Some(last_seen) => last_seen
+            .saturating_sub(catch_up_margin)
+            .max(now.saturating_sub(dedup_window)),
+    }
+}
+
 pub async fn handle_event(
     price: f64,
     event: Event,
     client: Client,
     keys: Keys,
-    mut lnd: LndLightningClient,
+    lightning: LightningClient,
+    cashu_mint: Option<CashuMint>,
     db_pool: Pool<ConnectionManager<PgConnection>>,
     http: &reqwest::Client,
     oracle: Oracle<PostgresStorage>,
+    policy: &Policy,
+    failure_fee: u64,
 ) -> anyhow::Result<()> {
+    let mut conn = db_pool.get()?;
     let (params, input) = get_job_params(&event, &keys)?;
 
     if params.time > 60 * 10 * 1_000 {
-        let builder = EventBuilder::job_feedback(
-            &event,
-            DataVendingMachineStatus::Error,
-            Some("Time must be less than 10 minutes".to_string()),
-            0,
-            None,
-            None,
-        );
-        let event_id = client.send_event_builder(builder).await?;
-        info!("Sent error response: {event_id}");
-        return Ok(());
+        let error = JobError::PolicyViolation("Time must be less than 10 minutes".to_string());
+        return reply_error(&client, &keys, &mut conn, &event, error).await;
     } else if params.time < 10 {
+        let error = JobError::PolicyViolation("Time must be greater than 10ms".to_string());
+        return reply_error(&client, &keys, &mut conn, &event, error).await;
+    }
+
+    let mut recurrence = None;
+    if let Some(schedule) = params.schedule.as_ref() {
+        if schedule.run_date <= Timestamp::now().as_u64() {
+            let error =
+                JobError::PolicyViolation("Schedule run date must be in the future".to_string());
+            return reply_error(&client, &keys, &mut conn, &event, error).await;
+        }
+
+        if let Some(reason) = policy.check_scheduled(&mut conn, &event.pubkey)? {
+            let error = JobError::RateLimited(reason);
+            return reply_error(&client, &keys, &mut conn, &event, error).await;
+        }
+
+        match Recurrence::from_params(schedule) {
+            Ok(r) => recurrence = r,
+            Err(e) => {
+                let error = JobError::PolicyViolation(e.to_string());
+                return reply_error(&client, &keys, &mut conn, &event, error).await;
+            }
+        }
+    }
+
+    let mut max_runs_per_hour = None;
+    if let Some(trigger) = params.trigger.as_ref() {
+        let checked = if params.schedule.is_some() {
+            Err(JobError::PolicyViolation(
+                "A request can't be both scheduled and triggered".to_string(),
+            ))
+        } else if JobTrigger::count_active(&mut conn, &event.pubkey)? >= MAX_TRIGGERS_PER_PUBKEY {
+            Err(JobError::RateLimited(format!(
+                "Too many active triggers, at most {MAX_TRIGGERS_PER_PUBKEY} are allowed"
+            )))
+        } else {
+            validate_trigger(trigger, Timestamp::now().as_u64())
+                .map_err(|e| JobError::PolicyViolation(e.to_string()))
+        };
+
+        match checked {
+            Ok(n) => max_runs_per_hour = Some(n),
+            Err(error) => {
+                return reply_error(&client, &keys, &mut conn, &event, error).await;
+            }
+        }
+    }
+
+    let value_msat = (params.time as f64 * price) as u64;
+
+    // whether the requester sent credit to pay for the job with
+    let mut credited = false;
+
+    // a Cashu token is credited to the requester's balance, which then pays for
+    // the job like any other balance so whatever is left over stays there
+    if let Some(token) = get_payment_param(&event, &keys, "cashu")? {
+        let redeemed = redeem_cashu(
+            &mut conn,
+            cashu_mint.as_deref(),
+            lightning.as_ref(),
+            &event.pubkey,
+            &token,
+        )
+        .await;
+        let error = match redeemed {
+            Ok(Some(_)) => None,
+            Ok(None) => Some(JobError::PaymentFailed(
+                "The mint has not paid for the Cashu token yet, it will be credited to your \
+                balance once it does"
+                    .to_string(),
+            )),
+            Err(e) => {
+                warn!("Failed to redeem Cashu token for {}: {e}", event.id);
+                Some(JobError::PaymentFailed(format!(
+                    "Could not redeem Cashu token: {e}"
+                )))
+            }
+        };
+        if let Some(error) = error {
+            return reply_error(&client, &keys, &mut conn, &event, error).await;
+        }
+        credited = true;
+    }
+
+    // a voucher code is credited to the balance the same way
+    if let Some(code) = get_payment_param(&event, &keys, "voucher")? {
+        if let Err(e) = redeem_voucher(&mut conn, &event.pubkey, &code) {
+            warn!("Failed to redeem voucher for {}: {e}", event.id);
+            let error = JobError::PaymentFailed(format!("Could not redeem voucher: {e}"));
+            return reply_error(&client, &keys, &mut conn, &event, error).await;
+        }
+        credited = true;
+    }
+
+    // a trigger doesn't run now, every event matching it runs the job paid from the
+    // requester's plan or balance
+    if let (Some(trigger), Some(max_runs_per_hour)) = (params.trigger.as_ref(), max_runs_per_hour) {
+        let trigger =
+            JobTrigger::create(&mut conn, &event, trigger, max_runs_per_hour, value_msat)?;
+        info!("Request {} registered trigger {}", event.id, trigger.id);
+
         let builder = EventBuilder::job_feedback(
             &event,
-            DataVendingMachineStatus::Error,
-            Some("Time must be greater than 10ms".to_string()),
+            DataVendingMachineStatus::Processing,
+            Some(format!(
+                "Running on matching events until {}",
+                trigger.expires_at()
+            )),
             0,
             None,
             None,
         );
-        let event_id = client.send_event_builder(builder).await?;
-        info!("Sent error response: {event_id}");
+        let reply = send_reply(&client, &keys, &mut conn, event.id, builder).await?;
+        info!("Sent response: {}", reply.id);
         return Ok(());
     }
 
-    let value_msat = (params.time as f64 * price) as u64;
-
-    let mut conn = db_pool.get()?;
-    let balance = ZapBalance::get(&mut conn, &event.pubkey)?;
-
-    match balance {
-        Some(mut b) if (b.balance_msats as u64) >= value_msat => {
-            info!(
-                "User has enough balance, deducting {value_msat}msats from balance and running job"
-            );
-            // deduct balance
-            let amt = value_msat as i32;
-            b.update_balance(&mut conn, -amt)?;
+    // the first run is paid for like any scheduled job, every later run is paid from
+    // the requester's plan or balance when it is scheduled. If the first run is never
+    // paid the reaper finishes the series when it expires the job.
+    if let (Some(recurrence), Some(schedule)) = (recurrence.as_ref(), params.schedule.as_ref()) {
+        let series = JobSeries::create(
+            &mut conn,
+            &event,
+            recurrence,
+            schedule.end_date,
+            schedule.max_runs,
+            value_msat,
+        )?;
+        info!("Request {} repeats as series {}", event.id, series.id);
+    }
 
-            let relays = client
-                .relays()
-                .await
-                .keys()
-                .map(|r| r.to_string())
-                .collect::<Vec<_>>();
+    // a plan with allowance left covers the job, otherwise hold the full price from
+    // the requester's balance if they have enough, what the job actually used is
+    // charged once it finishes
+    let scheduled_at = params.schedule.as_ref().map(|s| s.run_date);
+    let job = match use_plan(&mut conn, &event, scheduled_at, params.time)? {
+        Some(job) => {
+            info!("Job covered by the user's plan, running job");
+            Some(job)
+        }
+        None => {
+            let job = reserve_balance(&mut conn, &event, scheduled_at, value_msat)?;
+            if job.is_some() {
+                info!("User has enough balance, reserved {value_msat}msats and running job");
+            }
+            job
+        }
+    };
 
+    match job {
+        Some(job) => {
             // handle job
             let job_result = handle_job_request(
                 &mut conn,
+                &client,
+                &job,
                 event.clone(),
                 params,
                 input,
                 &keys,
                 http,
                 &oracle,
-                relays,
             )
             .await?;
 
-            if let Some(builder) = job_result.reply_event {
-                let event_id = client.send_event_builder(builder).await?;
-                info!("Sent response: {event_id}");
-
-                Job::create_completed(&mut conn, &event, &event_id)?;
+            if let Some(output) = job_result.output {
+                let reply =
+                    finish_job(&client, &keys, &mut conn, &job, &event, output, failure_fee)
+                        .await?;
+                info!("Sent response: {}", reply.id);
             }
 
             if let Some(oracle_announcement) = job_result.oracle_announcement {
@@ -162,43 +501,286 @@ pub async fn handle_event(
                 info!("Sent oracle announcement: {event_id}");
             }
         }
-        _ => {
-            let builder = create_job_feedback_invoice(
+        None => {
+            let available = available_balance(&mut conn, &event.pubkey)?;
+            let short = JobError::InsufficientBalance(format!(
+                "Not enough balance, {available} msats available but {value_msat} msats needed"
+            ));
+
+            // credit sent with the request was meant to pay for it, so don't ask for an invoice
+            if credited {
+                return reply_error(&client, &keys, &mut conn, &event, short).await;
+            }
+
+            let mut builder = create_job_feedback_invoice(
                 &event,
                 value_msat,
                 params.schedule.map(|u| u.run_date),
-                &mut lnd,
+                lightning.as_ref(),
                 &mut conn,
             )
             .await?;
-            let event_id = client.send_event_builder(builder).await?;
-            info!("Sent response: {event_id}");
+            // tell a requester who has a balance why they are asked to pay an invoice
+            if available > 0 {
+                builder = builder.add_tags([short.code_tag()]);
+            }
+            let reply = send_reply(&client, &keys, &mut conn, event.id, builder).await?;
+            info!("Sent response: {}", reply.id);
         }
     }
 
     Ok(())
 }
 
+/// A request we have already claimed, either a copy from another relay or a repeat.
+/// If we already replied within the dedup window, send the same reply again
+/// instead of creating a new invoice or running the job again.
+async fn handle_duplicate_request(
+    conn: &mut PgConnection,
+    client: &Client,
+    event_id: EventId,
+    dedup_window: u64,
+) -> anyhow::Result<()> {
+    let reply = JobRequest::get(conn, event_id)?
+        .filter(|r| r.is_within_window(dedup_window))
+        .and_then(|r| r.reply_event());
+
+    match reply {
+        Some(reply) => {
+            let reply_id = client.send_event(reply).await?;
+            debug!("Resent reply {reply_id} for duplicate request {event_id}");
+        }
+        None => debug!("Dropping duplicate request {event_id}"),
+    }
+
+    Ok(())
+}
+
+/// Publishes the output of a paid job and records it on the job, either its result followed
+/// by a success feedback event or an error feedback event. If the job failed the requester
+/// is refunded, minus the failure fee, and told so in the error feedback.
+/// Returns the signed result or error event.
+pub async fn finish_job(
+    client: &Client,
+    keys: &Keys,
+    conn: &mut PgConnection,
+    job: &Job,
+    request: &Event,
+    output: Result<JobOutput, JobError>,
+    failure_fee: u64,
+) -> anyhow::Result<Event> {
+    let module_checksum = get_job_params(request, keys).ok().map(|(p, _)| p.checksum);
+    match output {
+        Ok(output) => {
+            let cost = job.price_msats().map(|p| output.cost_msats(p));
+            let runtime_ms = output.runtime.as_millis() as u64;
+            let mut builder = output.builder;
+            // jobs paid from a balance are charged now, and tell the requester what they have left
+            let charged = match settle_reservation(conn, job, cost.unwrap_or(0))? {
+                Some(charged) => {
+                    debug!("Charged {charged}msats for job {}", job.id);
+                    builder = builder.add_tags([balance_tag(conn, &request.pubkey)?]);
+                    charged
+                }
+                None => {
+                    // a requester who zapped for their own job has whatever the zap paid
+                    // over the price in their balance
+                    let zapped = BalanceEntry::get_job_debit(conn, job.id)?;
+                    if zapped.is_some_and(|debit| debit.npub() == request.pubkey) {
+                        builder = builder.add_tags([balance_tag(conn, &request.pubkey)?]);
+                    }
+                    job.price_msats().unwrap_or(0)
+                }
+            };
+
+            let reply = send_reply(client, keys, conn, request.id, builder).await?;
+            Job::set_completed(conn, job.id, reply.id)?;
+            Job::record_outcome(
+                conn,
+                job.id,
+                module_checksum.as_deref(),
+                Some(runtime_ms),
+                charged,
+                None,
+            )?;
+
+            let success = EventBuilder::job_feedback(
+                request,
+                DataVendingMachineStatus::Success,
+                None,
+                0,
+                None,
+                None,
+            );
+            client.send_event_builder(success).await?;
+            Ok(reply)
+        }
+        Err(e) => {
+            let refunded = refund_job(conn, job, failure_fee, &e.to_string())?;
+            let builder = match refunded {
+                Some(refunded) if refunded > 0 => e.to_refund_feedback(request, refunded),
+                _ => e.to_feedback(request),
+            };
+            let charged = job
+                .price_msats()
+                .unwrap_or(0)
+                .saturating_sub(refunded.unwrap_or(0));
+            let reply = send_reply(client, keys, conn, request.id, builder).await?;
+            Job::set_response_id(conn, job.id, reply.id)?;
+            Job::record_outcome(
+                conn,
+                job.id,
+                module_checksum.as_deref(),
+                None,
+                charged,
+                Some(e.code()),
+            )?;
+            Ok(reply)
+        }
+    }
+}
+
+/// Tag with the balance the pubkey has available, added to the result of jobs paid from it
+fn balance_tag(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<Tag> {
+    let balance = available_balance(conn, npub)?;
+    Ok(Tag::Generic(
+        TagKind::Custom("balance".to_string()),
+        vec![balance.to_string()],
+    ))
+}
+
+/// Publishes the result of a job paid with a hold invoice. The payment is taken right
+/// before the result is published, a failed job cancels the invoice so the requester
+/// gets their payment back. Returns the signed result or error event.
+pub async fn finish_held_job(
+    client: &Client,
+    keys: &Keys,
+    conn: &mut PgConnection,
+    lightning: &dyn Lightning,
+    job: &Job,
+    request: &Event,
+    output: Result<JobOutput, JobError>,
+    preimage: [u8; 32],
+) -> anyhow::Result<Event> {
+    match output {
+        Ok(output) => {
+            // the result isn't published unless the payment was taken, a job resumed after
+            // a restart may have been settled already
+            if let Err(e) = lightning.settle_invoice(preimage).await {
+                let settled = lightning
+                    .lookup_invoice(job.payment_hash())
+                    .await?
+                    .is_some_and(|i| i.status == InvoiceStatus::Settled);
+                if !settled {
+                    return Err(e.context(format!("Could not settle invoice of job {}", job.id)));
+                }
+            }
+            info!("Settled hold invoice for job {}", job.id);
+            finish_job(client, keys, conn, job, request, Ok(output), 0).await
+        }
+        Err(e) => {
+            cancel_held_job(conn, lightning, job, &e.to_string()).await?;
+            let module_checksum = get_job_params(request, keys).ok().map(|(p, _)| p.checksum);
+            Job::record_outcome(
+                conn,
+                job.id,
+                module_checksum.as_deref(),
+                None,
+                0,
+                Some(e.code()),
+            )?;
+            let reply = send_reply(
+                client,
+                keys,
+                conn,
+                request.id,
+                e.to_canceled_feedback(request),
+            )
+            .await?;
+            Job::set_response_id(conn, job.id, reply.id)?;
+            Ok(reply)
+        }
+    }
+}
+
+/// Cancels the hold invoice of a job that can't be completed, giving the payment back
+pub async fn cancel_held_job(
+    conn: &mut PgConnection,
+    lightning: &dyn Lightning,
+    job: &Job,
+    reason: &str,
+) -> anyhow::Result<()> {
+    lightning.cancel_invoice(job.payment_hash()).await?;
+    Job::set_canceled(conn, job.id, reason)?;
+    info!("Canceled hold invoice for failed job {}", job.id);
+    Ok(())
+}
+
+/// Sends an error feedback for a request we won't run.
+async fn reply_error(
+    client: &Client,
+    keys: &Keys,
+    conn: &mut PgConnection,
+    request: &Event,
+    error: JobError,
+) -> anyhow::Result<()> {
+    let reply = send_reply(client, keys, conn, request.id, error.to_feedback(request)).await?;
+    info!("Sent error response: {}", reply.id);
+    Ok(())
+}
+
+/// Signs and sends a reply to a job request, recording it so repeats of
+/// the request get the same reply.
+pub async fn send_reply(
+    client: &Client,
+    keys: &Keys,
+    conn: &mut PgConnection,
+    request_id: EventId,
+    builder: EventBuilder,
+) -> anyhow::Result<Event> {
+    let reply = builder.to_event(keys)?;
+    JobRequest::set_reply(conn, request_id, &reply)?;
+    client.send_event(reply.clone()).await?;
+    Ok(reply)
+}
+
 async fn create_job_feedback_invoice(
     event: &Event,
     value_msat: u64,
     scheduled_at: Option<u64>,
-    lnd: &mut LndLightningClient,
+    lightning: &dyn Lightning,
     conn: &mut PgConnection,
 ) -> anyhow::Result<EventBuilder> {
-    let request = lnrpc::Invoice {
-        value_msat: value_msat as i64,
-        memo: "Wasm DVM Request".to_string(),
-        expiry: 86_400, // one day
-        ..Default::default()
+    let description = InvoiceDescription::Memo("Wasm DVM Request".to_string());
+
+    // hold the payment until the result is published so failed jobs cost nothing,
+    // scheduled jobs run after the payment would time out so they are paid up front
+    let (invoice, preimage) = if scheduled_at.is_none() && lightning.supports_hold_invoices() {
+        let mut preimage = [0u8; 32];
+        OsRng.fill_bytes(&mut preimage);
+        let payment_hash = sha256::Hash::hash(&preimage).to_byte_array();
+        let invoice = lightning
+            .create_hold_invoice(value_msat, description, JOB_INVOICE_EXPIRY, payment_hash)
+            .await?;
+        (invoice, Some(preimage))
+    } else {
+        let invoice = lightning
+            .create_invoice(value_msat, description, JOB_INVOICE_EXPIRY)
+            .await?;
+        (invoice, None)
     };
-    let resp = lnd.add_invoice(request).await?.into_inner();
-    let bolt11 = resp.payment_request;
-    let invoice = Bolt11Invoice::from_str(&bolt11)?;
+    let bolt11 = invoice.to_string();
 
     debug!("Created invoice: {bolt11}");
 
-    Job::create(conn, invoice.payment_hash().into_32(), event, scheduled_at)?;
+    Job::create(
+        conn,
+        invoice.payment_hash().into_32(),
+        preimage,
+        event,
+        scheduled_at,
+        value_msat,
+    )?;
 
     let builder = EventBuilder::job_feedback(
         event,
@@ -211,7 +793,73 @@ async fn create_job_feedback_invoice(
     Ok(builder)
 }
 
-pub fn get_job_params(event: &Event, keys: &Keys) -> anyhow::Result<(JobParams, String)> {
+/// Checks the `p` tags of a job request against our key, following NIP-90 targeting.
+/// Requests that name other service providers are never ours, encrypted requests must
+/// name us because we could not decrypt them otherwise, and untargeted requests are only
+/// accepted when `accept_untargeted` is set.
+pub fn is_targeted_at_us(event: &Event, keys: &Keys, accept_untargeted: bool) -> bool {
+    let targets = event
+        .tags
+        .iter()
+        .filter_map(|t| {
+            if let Tag::PublicKey {
+                public_key,
+                uppercase: false,
+                ..
+            } = t
+            {
+                Some(*public_key)
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if targets.is_empty() {
+        let encrypted = event.tags.iter().any(|t| matches!(t, Tag::Encrypted));
+        return accept_untargeted && !encrypted;
+    }
+
+    targets.contains(&keys.public_key())
+}
+
+/// Redeems the token against our mint and credits it to the requester, returns the amount
+/// credited. Returns None if the mint hasn't paid yet, the invoice subscriber credits the
+/// redemption once it does.
+async fn redeem_cashu(
+    conn: &mut PgConnection,
+    mint: Option<&dyn MintClient>,
+    lightning: &dyn Lightning,
+    npub: &nostr::PublicKey,
+    token: &str,
+) -> anyhow::Result<Option<u64>> {
+    let mint = mint.ok_or(anyhow!("Cashu tokens are not accepted"))?;
+    let token = Token::from_str(token)?;
+    let token_mint = token.mint()?.to_string();
+    let redemption = redeem_token(mint, lightning, &token, |invoice| {
+        CashuRedemption::create(conn, invoice, npub, &token_mint).map(|_| ())
+    })
+    .await?;
+    if !redemption.settled {
+        info!(
+            "Cashu token redemption from {token_mint} not paid yet: {}",
+            hex::encode(redemption.payment_hash)
+        );
+        return Ok(None);
+    }
+
+    // the invoice subscriber may have seen the payment first and credited it already
+    settle_cashu_redemption(conn, redemption.payment_hash)?;
+    info!(
+        "Redeemed Cashu token for {}msats from {token_mint}",
+        redemption.amount_msats,
+    );
+
+    Ok(Some(redemption.amount_msats))
+}
+
+/// The request's tags, decrypted if the params are encrypted to us
+fn job_tags(event: &Event, keys: &Keys) -> anyhow::Result<Vec<Tag>> {
     // if it is encrypted, decrypt the content to a tags array
     let tags = if event.tags.iter().any(|t| matches!(t, Tag::Encrypted)) {
         let p_tag = event
@@ -243,7 +891,26 @@ pub fn get_job_params(event: &Event, keys: &Keys) -> anyhow::Result<(JobParams,
         event.tags.clone()
     };
 
-    let string = tags
+    Ok(tags)
+}
+
+/// A payment sent with the request, like a `cashu` token or a `voucher` code, either as
+/// its own tag or as a param. They should be sent encrypted, anyone who sees one can spend it.
+pub fn get_payment_param(event: &Event, keys: &Keys, name: &str) -> anyhow::Result<Option<String>> {
+    let value = job_tags(event, keys)?.into_iter().find_map(|t| {
+        let vec = t.as_vec();
+        match vec.as_slice() {
+            [tag, value, ..] if tag == name => Some(value.clone()),
+            [tag, param, value, ..] if tag == "param" && param == name => Some(value.clone()),
+            _ => None,
+        }
+    });
+
+    Ok(value)
+}
+
+pub fn get_job_params(event: &Event, keys: &Keys) -> anyhow::Result<(JobParams, String)> {
+    let string = job_tags(event, keys)?
         .into_iter()
         .find_map(|t| {
             if t.kind() == TagKind::I {
@@ -264,26 +931,23 @@ pub fn get_job_params(event: &Event, keys: &Keys) -> anyhow::Result<(JobParams,
     Ok((params, string))
 }
 
+/// Leases the scheduled jobs that are due and runs them, returns how many were leased
 pub async fn process_schedule_jobs_round(
     client: &Client,
     keys: Keys,
     db_pool: Pool<ConnectionManager<PgConnection>>,
     http: reqwest::Client,
     oracle: Oracle<PostgresStorage>,
-    active_jobs: Arc<Mutex<HashSet<i32>>>,
-) -> anyhow::Result<()> {
+    retry: RetryPolicy,
+    failure_fee: u64,
+) -> anyhow::Result<usize> {
     let mut conn = db_pool.get()?;
-    let mut jobs = Job::get_ready_to_run_jobs(&mut conn)?;
+    let jobs = Job::lease_ready_jobs(&mut conn, LEASE_SECS, LEASE_BATCH)?;
     drop(conn);
 
-    // update active jobs
-    let mut active = active_jobs.lock().await;
-    jobs.retain(|j| !active.contains(&j.id));
-    active.extend(jobs.iter().map(|j| j.id));
-    drop(active);
-
-    if !jobs.is_empty() {
-        info!("Running {} scheduled jobs", jobs.len());
+    let leased = jobs.len();
+    if leased > 0 {
+        info!("Running {leased} scheduled jobs");
     }
 
     for job in jobs {
@@ -292,18 +956,34 @@ pub async fn process_schedule_jobs_round(
         let db_pool = db_pool.clone();
         let http = http.clone();
         let oracle = oracle.clone();
-        let active = active_jobs.clone();
 
         spawn(async move {
-            if let Err(e) =
-                run_scheduled_job(client, keys, db_pool, http, oracle, active, job).await
+            let lease = hold_lease(db_pool.clone(), job.id);
+            let job_id = job.id;
+            if let Err(e) = run_scheduled_job(
+                client.clone(),
+                keys.clone(),
+                db_pool.clone(),
+                http,
+                oracle,
+                job,
+                retry,
+                failure_fee,
+            )
+            .await
             {
-                error!("Error running scheduled job: {e}");
+                error!("Error running scheduled job {job_id}: {e}");
+                if let Err(e) =
+                    handle_failed_run(&client, &keys, &db_pool, job_id, e, retry, failure_fee).await
+                {
+                    error!("Failed to handle failure of scheduled job {job_id}: {e}");
+                }
             }
+            lease.abort();
         });
     }
 
-    Ok(())
+    Ok(leased)
 }
 
 async fn run_scheduled_job(
@@ -312,23 +992,54 @@ async fn run_scheduled_job(
     db_pool: Pool<ConnectionManager<PgConnection>>,
     http: reqwest::Client,
     oracle: Oracle<PostgresStorage>,
-    active_jobs: Arc<Mutex<HashSet<i32>>>,
     job: Job,
+    retry: RetryPolicy,
+    failure_fee: u64,
 ) -> anyhow::Result<()> {
     let event = job.request();
-    let (params, input) = get_job_params(&event, &keys)?;
+    let (mut params, input) = get_job_params(&event, &keys)?;
+    let compute_ms = params.time;
+    // a run of a trigger gets the event that triggered it as its input
+    if let Some(trigger_event) = job.trigger_event() {
+        params.input = trigger_event.as_json();
+    }
+
+    let mut conn = db_pool.get()?;
+    let series = JobSeries::get_by_request_id(&mut conn, event.id)?;
+    if series.is_some() && is_request_deleted(&client, &event).await {
+        cancel_series(&mut conn, &job, &event).await?;
+        return Ok(());
+    }
 
-    let builder = run_job_request(event, params, input, &keys, &http).await?;
-    let event = builder.to_event(&keys)?;
-    let outcome = event.content.clone();
-    let event_id = client.send_event(event).await?;
-    info!("Sent response: {event_id}");
+    // the job keeps taking down whoever runs it, give up on it
+    if job.attempts > retry.max_attempts as i32 {
+        return Err(anyhow!("Job was interrupted {} times", job.attempts - 1));
+    }
 
-    let mut active = active_jobs.lock().await;
-    active.remove(&job.id);
+    let output = run_job_request(&client, event.clone(), params, input, &keys, &http).await;
+    // errors that might not happen again are retried before the requester is refunded
+    let output = match output {
+        Err(e) if e.is_retryable() => return Err(e.into()),
+        output => output,
+    };
+    let succeeded = output.is_ok();
+
+    let reply = finish_job(&client, &keys, &mut conn, &job, &event, output, failure_fee).await?;
+    info!("Sent response: {}", reply.id);
+
+    // a failed run doesn't stop the series
+    if series.is_some() {
+        if let Err(e) = schedule_next_run_of(&client, &mut conn, &job, &event, compute_ms).await {
+            error!("Failed to schedule the next run of {}: {e}", event.id);
+        }
+    }
+
+    if !succeeded {
+        warn!("Scheduled job {} failed, skipping attestation", job.id);
+        return Ok(());
+    }
+    let outcome = reply.content;
 
-    let mut conn = db_pool.get()?;
-    Job::set_response_id(&mut conn, job.id, event_id)?;
     // handle oracle stuff
     if let Some(event_job) = EventJob::get_by_job_id(&mut conn, job.id)? {
         if let Some(oracle_event) = oracle.storage.get_event(event_job.event_id as u32).await? {
@@ -366,3 +1077,280 @@ async fn run_scheduled_job(
 
     Ok(())
 }
+
+/// Retries a scheduled job that failed after a backoff. Once it is out of attempts the job
+/// is marked failed with its last error, the requester is refunded and told so.
+async fn handle_failed_run(
+    client: &Client,
+    keys: &Keys,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    job_id: i32,
+    error: anyhow::Error,
+    retry: RetryPolicy,
+    failure_fee: u64,
+) -> anyhow::Result<()> {
+    let mut conn = db_pool.get()?;
+    let job = Job::get_by_id(&mut conn, job_id)?;
+    let message = error.to_string();
+
+    // the job was already finished, something after it failed
+    if job.status() != JobStatus::Paid || job.response_id().is_some() {
+        Job::set_last_error(&mut conn, job.id, &message)?;
+        return Ok(());
+    }
+
+    if retry.should_retry(job.attempts) {
+        let delay = retry.backoff_secs(job.attempts);
+        if Job::retry_later(&mut conn, job.id, &message, delay)?.is_some() {
+            info!(
+                "Retrying scheduled job {} in {delay}s, attempt {} failed",
+                job.id, job.attempts
+            );
+        }
+        return Ok(());
+    }
+
+    warn!(
+        "Scheduled job {} failed after {} attempts: {message}",
+        job.id, job.attempts
+    );
+    let request = job.request();
+    let error = error.downcast::<JobError>().unwrap_or_else(JobError::from);
+    let finished = finish_job(
+        client,
+        keys,
+        &mut conn,
+        &job,
+        &request,
+        Err(error),
+        failure_fee,
+    )
+    .await;
+    // mark it failed even if the feedback couldn't be sent, the refund went through
+    Job::set_failed(&mut conn, job.id, &message)?;
+    finished?;
+
+    // a failed run doesn't stop the series
+    if let Ok((params, _)) = get_job_params(&request, keys) {
+        schedule_next_run_of(client, &mut conn, &job, &request, params.time).await?;
+    }
+
+    Ok(())
+}
+
+/// Whether the requester deleted the request with a NIP-09 deletion event. Relays that can't
+/// be reached are not treated as a deletion.
+async fn is_request_deleted(client: &Client, request: &Event) -> bool {
+    let filter = Filter::new()
+        .kind(Kind::EventDeletion)
+        .author(request.pubkey)
+        .event(request.id);
+
+    match client
+        .get_events_of(vec![filter], Some(DELETION_CHECK_TIMEOUT))
+        .await
+    {
+        Ok(events) => events
+            .iter()
+            .any(|e| e.pubkey == request.pubkey && e.event_ids().any(|id| *id == request.id)),
+        Err(e) => {
+            warn!("Could not check if {} was deleted: {e}", request.id);
+            false
+        }
+    }
+}
+
+/// Stops a repeating job whose request was deleted and gives back what was paid for its
+/// pending run
+async fn cancel_series(conn: &mut PgConnection, job: &Job, request: &Event) -> anyhow::Result<()> {
+    if let Some(series) = JobSeries::get_by_request_id(conn, request.id)? {
+        JobSeries::stop(
+            conn,
+            series.id,
+            SeriesStatus::Canceled,
+            "Request was deleted",
+        )?;
+    }
+    refund_job(conn, job, 0, "Request was deleted")?;
+    info!("Canceled series for deleted request {}", request.id);
+    Ok(())
+}
+
+/// Schedules the run after `job` of a repeating job, or finishes the series if it has run
+/// its course. Tells the requester if the run can't be paid for.
+async fn schedule_next_run_of(
+    client: &Client,
+    conn: &mut PgConnection,
+    job: &Job,
+    request: &Event,
+    compute_ms: u64,
+) -> anyhow::Result<()> {
+    let Some(series) = JobSeries::get_by_request_id(conn, request.id)? else {
+        return Ok(());
+    };
+    // a replayed run already had its next run scheduled
+    if series.status() != SeriesStatus::Active || Job::has_later_run(conn, job)? {
+        return Ok(());
+    }
+
+    let now = Timestamp::now().as_u64();
+    let last_run = job.scheduled_at().unwrap_or(now);
+    let next = series.recurrence()?.next_run(last_run, now);
+
+    let next = match next {
+        None => Err("Schedule has no more runs"),
+        Some(next) if series.end_at().is_some_and(|end| next > end) => Err("Reached the end date"),
+        Some(_)
+            if series
+                .max_runs()
+                .is_some_and(|max| series.runs as u32 >= max) =>
+        {
+            Err("Ran the maximum number of times")
+        }
+        Some(next) => Ok(next),
+    };
+    let next = match next {
+        Ok(next) => next,
+        Err(reason) => {
+            JobSeries::stop(conn, series.id, SeriesStatus::Finished, reason)?;
+            info!("Series {} finished: {reason}", series.id);
+            return Ok(());
+        }
+    };
+
+    match schedule_next_run(conn, series.id, request, next, compute_ms)? {
+        NextRun::Scheduled(job) => {
+            info!(
+                "Scheduled job {} as the next run of series {} for {next}",
+                job.id, series.id
+            )
+        }
+        NextRun::Stopped => debug!("Series {} was stopped", series.id),
+        NextRun::Unpaid => {
+            let error = JobError::InsufficientBalance(
+                "Not enough balance for the next run, the repeating job was stopped".to_string(),
+            );
+            client
+                .send_event_builder(error.to_feedback(request))
+                .await?;
+            info!("Stopped series {}, not enough balance", series.id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{catch_up_since, get_payment_param, is_targeted_at_us};
+    use nostr::nips::nip04;
+    use nostr::{EventBuilder, Keys, Kind, Tag, TagKind};
+
+    fn job_request(tags: Vec<Tag>) -> nostr::Event {
+        EventBuilder::new(Kind::JobRequest(5600), "", tags)
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_targeting() {
+        let keys = Keys::generate();
+        let other = Keys::generate();
+
+        let ours = job_request(vec![Tag::public_key(keys.public_key())]);
+        assert!(is_targeted_at_us(&ours, &keys, false));
+
+        let theirs = job_request(vec![Tag::public_key(other.public_key())]);
+        assert!(!is_targeted_at_us(&theirs, &keys, true));
+
+        let untargeted = job_request(vec![]);
+        assert!(is_targeted_at_us(&untargeted, &keys, true));
+        assert!(!is_targeted_at_us(&untargeted, &keys, false));
+
+        let encrypted = job_request(vec![Tag::Encrypted]);
+        assert!(!is_targeted_at_us(&encrypted, &keys, true));
+    }
+
+    #[test]
+    fn test_payment_params() {
+        let keys = Keys::generate();
+
+        let plain = job_request(vec![Tag::Generic(
+            TagKind::Custom("cashu".to_string()),
+            vec!["cashuAtoken".to_string()],
+        )]);
+        assert_eq!(
+            get_payment_param(&plain, &keys, "cashu")
+                .unwrap()
+                .as_deref(),
+            Some("cashuAtoken")
+        );
+        assert!(get_payment_param(&plain, &keys, "voucher")
+            .unwrap()
+            .is_none());
+        assert!(get_payment_param(&job_request(vec![]), &keys, "cashu")
+            .unwrap()
+            .is_none());
+
+        // sent as an encrypted param
+        let requester = Keys::generate();
+        let params = vec![
+            Tag::Generic(
+                TagKind::Custom("param".to_string()),
+                vec!["cashu".to_string(), "cashuAsecret".to_string()],
+            ),
+            Tag::Generic(
+                TagKind::Custom("param".to_string()),
+                vec!["voucher".to_string(), "WELCOME".to_string()],
+            ),
+        ];
+        let content = nip04::encrypt(
+            requester.secret_key().unwrap(),
+            &keys.public_key(),
+            serde_json::to_string(&params).unwrap(),
+        )
+        .unwrap();
+        let encrypted = EventBuilder::new(
+            Kind::JobRequest(5600),
+            content,
+            [Tag::public_key(keys.public_key()), Tag::Encrypted],
+        )
+        .to_event(&requester)
+        .unwrap();
+        assert_eq!(
+            get_payment_param(&encrypted, &keys, "cashu")
+                .unwrap()
+                .as_deref(),
+            Some("cashuAsecret")
+        );
+        assert_eq!(
+            get_payment_param(&encrypted, &keys, "voucher")
+                .unwrap()
+                .as_deref(),
+            Some("WELCOME")
+        );
+    }
+    #[test]
+    fn test_catch_up_since() {
+        let now = 1_000_000;
+
+        // nothing seen yet, only new requests
+        assert_eq!(catch_up_since(None, now, 300, 3_600), now);
+
+        // back from the oldest checkpoint by the margin
+        assert_eq!(catch_up_since(Some(now - 60), now, 300, 3_600), now - 360);
+
+        // but never further back than the dedup window
+        assert_eq!(
+            catch_up_since(Some(now - 86_400), now, 300, 3_600),
+            now - 3_600
+        );
+        assert_eq!(
+            catch_up_since(Some(now - 3_500), now, 300, 3_600),
+            now - 3_600
+        );
+
+        // doesn't underflow close to the epoch
+        assert_eq!(catch_up_since(Some(100), 200, 300, 3_600), 0);
+    }
+}
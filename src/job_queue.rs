@@ -0,0 +1,152 @@
+use crate::models::job::Job;
+use anyhow::anyhow;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use futures::StreamExt;
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::spawn;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio_postgres::{AsyncMessage, NoTls};
+
+/// Channel the database notifies when a scheduled job is paid for or rescheduled
+pub const QUEUE_CHANNEL: &str = "scheduled_jobs";
+
+/// How long a worker holds a job before another worker can take it, in seconds.
+/// The lease is renewed while the job runs, so this is how long a crashed worker's
+/// jobs wait before they are picked up again.
+pub const LEASE_SECS: u64 = 120;
+
+/// How often a running job's lease is renewed
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(LEASE_SECS / 4);
+
+/// Most jobs a worker leases at once
+pub const LEASE_BATCH: i64 = 10;
+
+/// Longest wait between retries of a failed job, in seconds
+const MAX_BACKOFF_SECS: u64 = 3_600;
+
+/// Longest we wait before checking the queue without a notification, in case one was missed
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How scheduled jobs that fail, or whose worker went away, are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Most times a job is tried before it is marked failed
+    pub max_attempts: u32,
+    /// Wait before the first retry in seconds, doubled for every retry after it
+    pub backoff_secs: u64,
+}
+
+impl RetryPolicy {
+    /// Whether a job that failed on its `attempt`th try is tried again
+    pub fn should_retry(&self, attempt: i32) -> bool {
+        attempt < self.max_attempts as i32
+    }
+
+    /// How long to wait before retrying a job that failed on its `attempt`th try
+    pub fn backoff_secs(&self, attempt: i32) -> u64 {
+        let doublings = attempt.saturating_sub(1).clamp(0, 16) as u32;
+        self.backoff_secs
+            .saturating_mul(1 << doublings)
+            .min(MAX_BACKOFF_SECS)
+    }
+}
+
+/// Listens for notifications on a channel of the database, such as for newly scheduled jobs,
+/// and wakes whoever waits on `notify` for each one. Only returns if the connection to the
+/// database is lost.
+pub async fn listen_for_notifications(
+    pg_url: &str,
+    channel: &str,
+    notify: Arc<Notify>,
+) -> anyhow::Result<()> {
+    let (client, mut connection) = tokio_postgres::connect(pg_url, NoTls).await?;
+
+    // the connection has to be polled for the client to work, notifications come through it
+    let wake = notify.clone();
+    let driver = spawn(async move {
+        let mut messages = futures::stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            if let AsyncMessage::Notification(_) = message? {
+                wake.notify_one();
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+
+    client.batch_execute(&format!("LISTEN {channel}")).await?;
+    info!("Listening for notifications on {channel}");
+
+    // we may have missed some while we weren't listening
+    notify.notify_one();
+
+    driver.await??;
+    Err(anyhow!("Notifications connection for {channel} closed"))
+}
+
+/// Waits until the next scheduled job is due or a new one is queued
+pub async fn wait_for_queued_jobs(
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    notify: &Notify,
+) -> anyhow::Result<()> {
+    let mut conn = db_pool.get()?;
+    let next = Job::next_scheduled_at(&mut conn)?;
+    drop(conn);
+
+    let wait = match next {
+        Some(at) => (at - chrono::Utc::now().naive_utc())
+            .to_std()
+            .unwrap_or(Duration::ZERO)
+            .min(MAX_POLL_INTERVAL),
+        None => MAX_POLL_INTERVAL,
+    };
+
+    tokio::select! {
+        _ = tokio::time::sleep(wait) => {}
+        _ = notify.notified() => {}
+    }
+
+    Ok(())
+}
+
+/// Keeps renewing the job's lease until the returned task is aborted
+pub fn hold_lease(db_pool: Pool<ConnectionManager<PgConnection>>, job_id: i32) -> JoinHandle<()> {
+    spawn(async move {
+        loop {
+            tokio::time::sleep(LEASE_RENEW_INTERVAL).await;
+            let renewed = db_pool
+                .get()
+                .map_err(anyhow::Error::from)
+                .and_then(|mut conn| Job::renew_lease(&mut conn, job_id, LEASE_SECS));
+            if let Err(e) = renewed {
+                warn!("Failed to renew lease on job {job_id}: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::RetryPolicy;
+
+    #[test]
+    fn test_retry_backoff() {
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            backoff_secs: 30,
+        };
+        assert!(retry.should_retry(1));
+        assert!(retry.should_retry(2));
+        assert!(!retry.should_retry(3));
+        // a job whose worker went away can be leased past the limit
+        assert!(!retry.should_retry(4));
+
+        assert_eq!(retry.backoff_secs(1), 30);
+        assert_eq!(retry.backoff_secs(2), 60);
+        assert_eq!(retry.backoff_secs(3), 120);
+        assert_eq!(retry.backoff_secs(20), 3_600);
+    }
+}
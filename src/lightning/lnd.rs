@@ -0,0 +1,291 @@
+use crate::lightning::{
+    InvoiceDescription, InvoiceInfo, InvoiceStatus, Lightning, NodeInfo, PaymentResult,
+    PaymentStatus,
+};
+use async_trait::async_trait;
+use bitcoin::hashes::Hash;
+use lightning_invoice::Bolt11Invoice;
+use log::error;
+use std::str::FromStr;
+use tokio::sync::mpsc;
+use tonic_openssl_lnd::lnrpc::invoice::InvoiceState;
+use tonic_openssl_lnd::lnrpc::payment::PaymentStatus as LndPaymentStatus;
+use tonic_openssl_lnd::{invoicesrpc, lnrpc, LndInvoicesClient, LndLightningClient};
+
+/// How many payments to fetch at a time when looking one up
+const PAYMENTS_PAGE_SIZE: u64 = 100;
+
+/// Lightning backend using LND's gRPC api
+#[derive(Clone)]
+pub struct LndBackend {
+    client: LndLightningClient,
+    invoices: LndInvoicesClient,
+}
+
+impl LndBackend {
+    pub fn new(client: LndLightningClient, invoices: LndInvoicesClient) -> Self {
+        Self { client, invoices }
+    }
+}
+
+impl TryFrom<lnrpc::Invoice> for InvoiceInfo {
+    type Error = anyhow::Error;
+
+    fn try_from(invoice: lnrpc::Invoice) -> Result<Self, Self::Error> {
+        let status = match InvoiceState::from_i32(invoice.state) {
+            Some(InvoiceState::Open) | None => InvoiceStatus::Open,
+            Some(InvoiceState::Accepted) => InvoiceStatus::Accepted,
+            Some(InvoiceState::Settled) => InvoiceStatus::Settled,
+            Some(InvoiceState::Canceled) => InvoiceStatus::Canceled,
+        };
+        let preimage = match status {
+            InvoiceStatus::Settled => invoice.r_preimage.try_into().ok(),
+            _ => None,
+        };
+
+        Ok(InvoiceInfo {
+            payment_hash: invoice
+                .r_hash
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid payment hash"))?,
+            bolt11: invoice.payment_request,
+            amount_msats: invoice.value_msat as u64,
+            status,
+            preimage,
+            settle_index: invoice.settle_index,
+        })
+    }
+}
+
+#[async_trait]
+impl Lightning for LndBackend {
+    async fn create_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let mut request = lnrpc::Invoice {
+            value_msat: amount_msats as i64,
+            expiry: expiry_secs as i64,
+            ..Default::default()
+        };
+        match description {
+            InvoiceDescription::Memo(memo) => request.memo = memo,
+            InvoiceDescription::Hash(hash) => {
+                request.description_hash = hash.to_byte_array().to_vec()
+            }
+        }
+
+        let resp = self.client.clone().add_invoice(request).await?.into_inner();
+
+        Ok(Bolt11Invoice::from_str(&resp.payment_request)?)
+    }
+
+    async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> anyhow::Result<Option<InvoiceInfo>> {
+        let request = lnrpc::PaymentHash {
+            r_hash: payment_hash.to_vec(),
+            ..Default::default()
+        };
+
+        match self.client.clone().lookup_invoice(request).await {
+            Ok(resp) => Ok(Some(resp.into_inner().try_into()?)),
+            Err(status) if status.message().contains("unable to locate invoice") => Ok(None),
+            Err(status) => Err(status.into()),
+        }
+    }
+
+    async fn subscribe_invoices(
+        &self,
+        settle_index: u64,
+    ) -> anyhow::Result<mpsc::Receiver<InvoiceInfo>> {
+        let sub = lnrpc::InvoiceSubscription {
+            settle_index,
+            ..Default::default()
+        };
+        let mut invoice_stream = self
+            .client
+            .clone()
+            .subscribe_invoices(sub)
+            .await?
+            .into_inner();
+
+        let (tx, rx) = mpsc::channel(100);
+        tokio::spawn(async move {
+            loop {
+                let ln_invoice = match invoice_stream.message().await {
+                    Ok(Some(ln_invoice)) => ln_invoice,
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Invoice subscription failed: {e}");
+                        break;
+                    }
+                };
+                match InvoiceInfo::try_from(ln_invoice) {
+                    Ok(info) => {
+                        if tx.send(info).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => error!("Skipping invalid invoice from LND: {e}"),
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
+    async fn pay_invoice(
+        &self,
+        invoice: &Bolt11Invoice,
+        fee_limit_msats: u64,
+    ) -> anyhow::Result<PaymentResult> {
+        let request = lnrpc::SendRequest {
+            payment_request: invoice.to_string(),
+            fee_limit: Some(lnrpc::FeeLimit {
+                limit: Some(lnrpc::fee_limit::Limit::FixedMsat(fee_limit_msats as i64)),
+            }),
+            ..Default::default()
+        };
+
+        let resp = self
+            .client
+            .clone()
+            .send_payment_sync(request)
+            .await?
+            .into_inner();
+
+        if !resp.payment_error.is_empty() {
+            return Ok(PaymentResult::Failed(resp.payment_error));
+        }
+
+        let Ok(preimage) = resp.payment_preimage.try_into() else {
+            error!(
+                "LND returned an invalid preimage for {}",
+                invoice.payment_hash()
+            );
+            return Err(anyhow::anyhow!("Invalid preimage"));
+        };
+        let fee_msats = resp
+            .payment_route
+            .map(|r| r.total_fees_msat as u64)
+            .unwrap_or(0);
+
+        Ok(PaymentResult::Paid {
+            preimage,
+            fee_msats,
+        })
+    }
+
+    async fn lookup_payment(
+        &self,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<PaymentStatus>> {
+        // lnrpc can't look up a single payment, page back from the newest until it is found
+        let payment_hash = hex::encode(payment_hash);
+        let mut index_offset = 0;
+        loop {
+            let request = lnrpc::ListPaymentsRequest {
+                include_incomplete: true,
+                index_offset,
+                max_payments: PAYMENTS_PAGE_SIZE,
+                reversed: true,
+                ..Default::default()
+            };
+            let resp = self
+                .client
+                .clone()
+                .list_payments(request)
+                .await?
+                .into_inner();
+
+            if let Some(payment) = resp
+                .payments
+                .iter()
+                .find(|p| p.payment_hash == payment_hash)
+            {
+                let status = match LndPaymentStatus::from_i32(payment.status) {
+                    Some(LndPaymentStatus::Succeeded) => PaymentStatus::Paid {
+                        fee_msats: payment.fee_msat as u64,
+                    },
+                    Some(LndPaymentStatus::Failed) => {
+                        PaymentStatus::Failed(format!("{:?}", payment.failure_reason()))
+                    }
+                    _ => PaymentStatus::InFlight,
+                };
+                return Ok(Some(status));
+            }
+
+            if resp.payments.len() < PAYMENTS_PAGE_SIZE as usize || resp.first_index_offset == 0 {
+                return Ok(None);
+            }
+            index_offset = resp.first_index_offset;
+        }
+    }
+
+    async fn node_info(&self) -> anyhow::Result<NodeInfo> {
+        let info = self
+            .client
+            .clone()
+            .get_info(lnrpc::GetInfoRequest {})
+            .await?
+            .into_inner();
+
+        Ok(NodeInfo {
+            pubkey: info.identity_pubkey,
+            alias: info.alias,
+        })
+    }
+
+    fn supports_hold_invoices(&self) -> bool {
+        true
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let mut request = invoicesrpc::AddHoldInvoiceRequest {
+            hash: payment_hash.to_vec(),
+            value_msat: amount_msats as i64,
+            expiry: expiry_secs as i64,
+            ..Default::default()
+        };
+        match description {
+            InvoiceDescription::Memo(memo) => request.memo = memo,
+            InvoiceDescription::Hash(hash) => {
+                request.description_hash = hash.to_byte_array().to_vec()
+            }
+        }
+
+        let resp = self
+            .invoices
+            .clone()
+            .add_hold_invoice(request)
+            .await?
+            .into_inner();
+
+        Ok(Bolt11Invoice::from_str(&resp.payment_request)?)
+    }
+
+    async fn settle_invoice(&self, preimage: [u8; 32]) -> anyhow::Result<()> {
+        let request = invoicesrpc::SettleInvoiceMsg {
+            preimage: preimage.to_vec(),
+        };
+        self.invoices.clone().settle_invoice(request).await?;
+
+        Ok(())
+    }
+
+    async fn cancel_invoice(&self, payment_hash: [u8; 32]) -> anyhow::Result<()> {
+        let request = invoicesrpc::CancelInvoiceMsg {
+            payment_hash: payment_hash.to_vec(),
+        };
+        self.invoices.clone().cancel_invoice(request).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+use crate::lightning::{
+    InvoiceDescription, InvoiceInfo, InvoiceStatus, Lightning, NodeInfo, PaymentResult,
+    PaymentStatus,
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::key::Secp256k1;
+use bitcoin::secp256k1::rand::rngs::OsRng;
+use bitcoin::secp256k1::rand::RngCore;
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use bitcoin::Network;
+use lightning_invoice::{Bolt11Invoice, Currency, InvoiceBuilder, PaymentSecret};
+use log::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+struct MockInvoice {
+    info: InvoiceInfo,
+    /// None for hold invoices, the preimage is given when settling
+    preimage: Option<[u8; 32]>,
+}
+
+#[derive(Default)]
+struct MockState {
+    invoices: HashMap<[u8; 32], MockInvoice>,
+    subscribers: Vec<mpsc::Sender<InvoiceInfo>>,
+    payments: Vec<Bolt11Invoice>,
+    fail_payments: bool,
+    /// How many invoices have been settled, for their settle index
+    settled: u64,
+}
+
+/// In-memory lightning backend for testing the payment flow without a node.
+/// Invoices are real, signed by a random key, but are only paid by calling
+/// [`MockLightning::receive_payment`] or automatically when created with `auto_settle`.
+#[derive(Clone)]
+pub struct MockLightning {
+    network: Network,
+    node_key: SecretKey,
+    auto_settle: bool,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockState {
+    /// Sends an invoice update to every subscriber, dropping the ones that went away
+    fn notify(&mut self, info: &InvoiceInfo) {
+        self.subscribers
+            .retain(|tx| tx.try_send(info.clone()).is_ok());
+    }
+}
+
+impl MockLightning {
+    pub fn new(network: Network, auto_settle: bool) -> Self {
+        Self {
+            network,
+            node_key: SecretKey::new(&mut OsRng),
+            auto_settle,
+            state: Arc::new(Mutex::new(MockState::default())),
+        }
+    }
+
+    /// Pays one of our invoices, as if a customer paid it. Hold invoices are only accepted.
+    pub fn receive_payment(&self, payment_hash: [u8; 32]) -> anyhow::Result<InvoiceInfo> {
+        let mut state = self.state.lock().unwrap();
+        let settle_index = state.settled + 1;
+        let invoice = state
+            .invoices
+            .get_mut(&payment_hash)
+            .ok_or(anyhow!("Unknown invoice"))?;
+        if invoice.info.status != InvoiceStatus::Open {
+            return Err(anyhow!("Invoice is not open"));
+        }
+        match invoice.preimage {
+            Some(preimage) => {
+                invoice.info.status = InvoiceStatus::Settled;
+                invoice.info.preimage = Some(preimage);
+                invoice.info.settle_index = settle_index;
+            }
+            None => invoice.info.status = InvoiceStatus::Accepted,
+        }
+        let info = invoice.info.clone();
+        if info.status == InvoiceStatus::Settled {
+            state.settled = settle_index;
+        }
+        state.notify(&info);
+
+        Ok(info)
+    }
+
+    /// Settles or cancels an accepted hold invoice, open invoices of either kind can be canceled
+    fn resolve_invoice(
+        &self,
+        payment_hash: [u8; 32],
+        status: InvoiceStatus,
+        preimage: Option<[u8; 32]>,
+    ) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let settle_index = state.settled + 1;
+        let invoice = state
+            .invoices
+            .get_mut(&payment_hash)
+            .ok_or(anyhow!("Unknown invoice"))?;
+        // only hold invoices are ever accepted
+        match (invoice.info.status, status) {
+            (InvoiceStatus::Accepted, _) | (InvoiceStatus::Open, InvoiceStatus::Canceled) => {}
+            _ => return Err(anyhow!("Invoice is {:?}", invoice.info.status)),
+        }
+        invoice.info.status = status;
+        invoice.info.preimage = preimage;
+        if status == InvoiceStatus::Settled {
+            invoice.info.settle_index = settle_index;
+        }
+        let info = invoice.info.clone();
+        if status == InvoiceStatus::Settled {
+            state.settled = settle_index;
+        }
+        state.notify(&info);
+
+        Ok(())
+    }
+
+    fn sign_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+        payment_hash: sha256::Hash,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let mut payment_secret = [0u8; 32];
+        OsRng.fill_bytes(&mut payment_secret);
+
+        let builder = InvoiceBuilder::new(Currency::from(self.network));
+        let builder = match description {
+            InvoiceDescription::Memo(memo) => builder.description(memo),
+            InvoiceDescription::Hash(hash) => builder.description_hash(hash),
+        };
+        let invoice = builder
+            .amount_milli_satoshis(amount_msats)
+            .current_timestamp()
+            .expiry_time(Duration::from_secs(expiry_secs))
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(payment_secret))
+            .min_final_cltv_expiry_delta(144)
+            .basic_mpp()
+            .build_signed(|hash| {
+                Secp256k1::signing_only().sign_ecdsa_recoverable(hash, &self.node_key)
+            })?;
+
+        Ok(invoice)
+    }
+
+    fn add_invoice(&self, invoice: &Bolt11Invoice, preimage: Option<[u8; 32]>) {
+        let info = InvoiceInfo {
+            payment_hash: invoice.payment_hash().to_byte_array(),
+            bolt11: invoice.to_string(),
+            amount_msats: invoice.amount_milli_satoshis().unwrap_or(0),
+            status: InvoiceStatus::Open,
+            preimage: None,
+            settle_index: 0,
+        };
+        let payment_hash = info.payment_hash;
+        self.state
+            .lock()
+            .unwrap()
+            .invoices
+            .insert(payment_hash, MockInvoice { info, preimage });
+
+        if self.auto_settle {
+            let mock = self.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                if let Ok(info) = mock.receive_payment(payment_hash) {
+                    info!("Mock paid invoice: {}", info.bolt11);
+                }
+            });
+        }
+    }
+
+    /// Invoices we were asked to pay
+    pub fn payments(&self) -> Vec<Bolt11Invoice> {
+        self.state.lock().unwrap().payments.clone()
+    }
+
+    /// Makes every following payment fail
+    pub fn set_fail_payments(&self, fail: bool) {
+        self.state.lock().unwrap().fail_payments = fail;
+    }
+}
+
+#[async_trait]
+impl Lightning for MockLightning {
+    async fn create_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let mut preimage = [0u8; 32];
+        OsRng.fill_bytes(&mut preimage);
+        let payment_hash = sha256::Hash::hash(&preimage);
+
+        let invoice = self.sign_invoice(amount_msats, description, expiry_secs, payment_hash)?;
+        self.add_invoice(&invoice, Some(preimage));
+        Ok(invoice)
+    }
+
+    fn supports_hold_invoices(&self) -> bool {
+        true
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let payment_hash = sha256::Hash::from_byte_array(payment_hash);
+        let invoice = self.sign_invoice(amount_msats, description, expiry_secs, payment_hash)?;
+        self.add_invoice(&invoice, None);
+        Ok(invoice)
+    }
+
+    async fn settle_invoice(&self, preimage: [u8; 32]) -> anyhow::Result<()> {
+        let payment_hash = sha256::Hash::hash(&preimage).to_byte_array();
+        self.resolve_invoice(payment_hash, InvoiceStatus::Settled, Some(preimage))
+    }
+
+    async fn cancel_invoice(&self, payment_hash: [u8; 32]) -> anyhow::Result<()> {
+        self.resolve_invoice(payment_hash, InvoiceStatus::Canceled, None)
+    }
+
+    async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> anyhow::Result<Option<InvoiceInfo>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.invoices.get(&payment_hash).map(|i| i.info.clone()))
+    }
+
+    async fn subscribe_invoices(
+        &self,
+        settle_index: u64,
+    ) -> anyhow::Result<mpsc::Receiver<InvoiceInfo>> {
+        let (tx, rx) = mpsc::channel(100);
+        let mut state = self.state.lock().unwrap();
+
+        // replay what was settled since, in order
+        let mut missed: Vec<InvoiceInfo> = state
+            .invoices
+            .values()
+            .filter(|i| i.info.settle_index > settle_index)
+            .map(|i| i.info.clone())
+            .collect();
+        missed.sort_by_key(|i| i.settle_index);
+        for info in missed {
+            tx.try_send(info)?;
+        }
+
+        state.subscribers.push(tx);
+        Ok(rx)
+    }
+
+    async fn pay_invoice(
+        &self,
+        invoice: &Bolt11Invoice,
+        _fee_limit_msats: u64,
+    ) -> anyhow::Result<PaymentResult> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail_payments {
+            return Ok(PaymentResult::Failed("mock payment failure".to_string()));
+        }
+        state.payments.push(invoice.clone());
+
+        // the preimage isn't known for invoices from other nodes, make one up
+        let mut preimage = [0u8; 32];
+        OsRng.fill_bytes(&mut preimage);
+        Ok(PaymentResult::Paid {
+            preimage,
+            fee_msats: 0,
+        })
+    }
+
+    async fn lookup_payment(
+        &self,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<PaymentStatus>> {
+        let state = self.state.lock().unwrap();
+        let paid = state
+            .payments
+            .iter()
+            .any(|i| i.payment_hash().to_byte_array() == payment_hash);
+        Ok(paid.then_some(PaymentStatus::Paid { fee_msats: 0 }))
+    }
+
+    async fn node_info(&self) -> anyhow::Result<NodeInfo> {
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &self.node_key);
+        Ok(NodeInfo {
+            pubkey: pubkey.to_string(),
+            alias: "mock".to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MockLightning;
+    use crate::lightning::{
+        InvoiceDescription, InvoiceStatus, Lightning, PaymentResult, PaymentStatus,
+    };
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::Network;
+
+    #[tokio::test]
+    async fn test_mock_invoice_flow() {
+        let mock = MockLightning::new(Network::Regtest, false);
+        let mut invoices = mock.subscribe_invoices(0).await.unwrap();
+
+        let invoice = mock
+            .create_invoice(10_000, InvoiceDescription::Memo("test".to_string()), 3_600)
+            .await
+            .unwrap();
+        assert_eq!(invoice.amount_milli_satoshis(), Some(10_000));
+        assert_eq!(invoice.network(), Network::Regtest);
+
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        let info = mock.lookup_invoice(payment_hash).await.unwrap().unwrap();
+        assert_eq!(info.status, InvoiceStatus::Open);
+        assert!(info.preimage.is_none());
+
+        mock.receive_payment(payment_hash).unwrap();
+        let settled = invoices.recv().await.unwrap();
+        assert_eq!(settled.payment_hash, payment_hash);
+        assert_eq!(settled.status, InvoiceStatus::Settled);
+        assert!(settled.preimage.is_some());
+
+        // can't be paid twice
+        assert!(mock.receive_payment(payment_hash).is_err());
+        assert!(mock.cancel_invoice(payment_hash).await.is_err());
+        assert!(mock.lookup_invoice([0; 32]).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mock_hold_invoice() {
+        let mock = MockLightning::new(Network::Regtest, false);
+        let mut invoices = mock.subscribe_invoices(0).await.unwrap();
+
+        let preimage = [7u8; 32];
+        let payment_hash = sha256::Hash::hash(&preimage).to_byte_array();
+        let invoice = mock
+            .create_hold_invoice(
+                10_000,
+                InvoiceDescription::Memo("test".to_string()),
+                3_600,
+                payment_hash,
+            )
+            .await
+            .unwrap();
+        assert_eq!(invoice.payment_hash().to_byte_array(), payment_hash);
+
+        // a payment is only accepted until we settle it
+        mock.receive_payment(payment_hash).unwrap();
+        let accepted = invoices.recv().await.unwrap();
+        assert_eq!(accepted.status, InvoiceStatus::Accepted);
+        assert!(accepted.preimage.is_none());
+
+        assert!(mock.settle_invoice([8u8; 32]).await.is_err());
+        mock.settle_invoice(preimage).await.unwrap();
+        let settled = invoices.recv().await.unwrap();
+        assert_eq!(settled.status, InvoiceStatus::Settled);
+        assert_eq!(settled.preimage, Some(preimage));
+
+        // settled invoices can't be canceled
+        assert!(mock.cancel_invoice(payment_hash).await.is_err());
+
+        let other = sha256::Hash::hash(&[9u8; 32]).to_byte_array();
+        mock.create_hold_invoice(
+            10_000,
+            InvoiceDescription::Memo("test".to_string()),
+            3_600,
+            other,
+        )
+        .await
+        .unwrap();
+        mock.receive_payment(other).unwrap();
+        invoices.recv().await.unwrap();
+        mock.cancel_invoice(other).await.unwrap();
+        let canceled = invoices.recv().await.unwrap();
+        assert_eq!(canceled.status, InvoiceStatus::Canceled);
+    }
+
+    #[tokio::test]
+    async fn test_mock_payments() {
+        let mock = MockLightning::new(Network::Regtest, false);
+        let invoice = MockLightning::new(Network::Regtest, false)
+            .create_invoice(5_000, InvoiceDescription::Memo("test".to_string()), 3_600)
+            .await
+            .unwrap();
+
+        let result = mock.pay_invoice(&invoice, 1_000).await.unwrap();
+        assert!(matches!(result, PaymentResult::Paid { fee_msats: 0, .. }));
+        assert_eq!(mock.payments(), vec![invoice.clone()]);
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        assert_eq!(
+            mock.lookup_payment(payment_hash).await.unwrap(),
+            Some(PaymentStatus::Paid { fee_msats: 0 })
+        );
+
+        mock.set_fail_payments(true);
+        let result = mock.pay_invoice(&invoice, 1_000).await.unwrap();
+        assert!(matches!(result, PaymentResult::Failed(_)));
+        assert_eq!(mock.payments().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mock_settle_index() {
+        let mock = MockLightning::new(Network::Regtest, false);
+        let mut hashes = vec![];
+        for _ in 0..3 {
+            let invoice = mock
+                .create_invoice(1_000, InvoiceDescription::Memo("test".to_string()), 3_600)
+                .await
+                .unwrap();
+            let payment_hash = invoice.payment_hash().to_byte_array();
+            mock.receive_payment(payment_hash).unwrap();
+            hashes.push(payment_hash);
+        }
+
+        // only what was settled after the index is replayed, in order
+        let mut invoices = mock.subscribe_invoices(1).await.unwrap();
+        let second = invoices.recv().await.unwrap();
+        assert_eq!(second.payment_hash, hashes[1]);
+        assert_eq!(second.settle_index, 2);
+        let third = invoices.recv().await.unwrap();
+        assert_eq!(third.payment_hash, hashes[2]);
+        assert_eq!(third.settle_index, 3);
+        assert!(invoices.try_recv().is_err());
+    }
+}
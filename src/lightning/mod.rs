@@ -0,0 +1,129 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bitcoin::hashes::sha256;
+use lightning_invoice::Bolt11Invoice;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+pub mod lnd;
+#[cfg(any(test, feature = "mock-lightning"))]
+pub mod mock;
+pub mod nwc;
+
+/// What an invoice commits to as its description
+#[derive(Debug, Clone)]
+pub enum InvoiceDescription {
+    Memo(String),
+    Hash(sha256::Hash),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceStatus {
+    Open,
+    Accepted,
+    Settled,
+    Canceled,
+}
+
+/// An invoice created by our node and its current state
+#[derive(Debug, Clone)]
+pub struct InvoiceInfo {
+    pub payment_hash: [u8; 32],
+    pub bolt11: String,
+    pub amount_msats: u64,
+    pub status: InvoiceStatus,
+    /// Only known once the invoice is settled
+    pub preimage: Option<[u8; 32]>,
+    /// Order the invoice was settled in, 0 if it isn't settled or the backend doesn't keep one
+    pub settle_index: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentResult {
+    Paid { preimage: [u8; 32], fee_msats: u64 },
+    Failed(String),
+}
+
+/// Where a payment we sent stands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentStatus {
+    /// Still being routed, it can still succeed or fail
+    InFlight,
+    Paid {
+        fee_msats: u64,
+    },
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub pubkey: String,
+    pub alias: String,
+}
+
+/// The lightning node operations the DVM needs, so different node backends can be used
+#[async_trait]
+pub trait Lightning: Send + Sync {
+    async fn create_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+    ) -> anyhow::Result<Bolt11Invoice>;
+
+    /// Looks up an invoice created by our node, None if the node doesn't know it
+    async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> anyhow::Result<Option<InvoiceInfo>>;
+
+    /// Sends every invoice settled after `settle_index`, if the backend keeps one, and every
+    /// invoice update from now on. The receiver is closed when the subscription ends.
+    async fn subscribe_invoices(
+        &self,
+        settle_index: u64,
+    ) -> anyhow::Result<mpsc::Receiver<InvoiceInfo>>;
+
+    /// Pays the invoice spending at most `fee_limit_msats` on routing fees. Errors mean the
+    /// outcome is unknown, a payment that definitely failed returns [`PaymentResult::Failed`].
+    async fn pay_invoice(
+        &self,
+        invoice: &Bolt11Invoice,
+        fee_limit_msats: u64,
+    ) -> anyhow::Result<PaymentResult>;
+
+    /// Looks up a payment we sent, None if the backend doesn't know it. Used to find out
+    /// what happened to payments whose [`Lightning::pay_invoice`] call errored.
+    async fn lookup_payment(&self, payment_hash: [u8; 32])
+        -> anyhow::Result<Option<PaymentStatus>>;
+
+    async fn node_info(&self) -> anyhow::Result<NodeInfo>;
+
+    /// Whether the backend can hold incoming payments until they are settled or canceled
+    fn supports_hold_invoices(&self) -> bool {
+        false
+    }
+
+    /// Creates an invoice for a payment hash we know the preimage of. Payments to it stay
+    /// [`InvoiceStatus::Accepted`] until [`Lightning::settle_invoice`] or
+    /// [`Lightning::cancel_invoice`] is called.
+    async fn create_hold_invoice(
+        &self,
+        _amount_msats: u64,
+        _description: InvoiceDescription,
+        _expiry_secs: u64,
+        _payment_hash: [u8; 32],
+    ) -> anyhow::Result<Bolt11Invoice> {
+        Err(anyhow!("Hold invoices are not supported"))
+    }
+
+    /// Takes the payment held by an accepted hold invoice
+    async fn settle_invoice(&self, _preimage: [u8; 32]) -> anyhow::Result<()> {
+        Err(anyhow!("Hold invoices are not supported"))
+    }
+
+    /// Gives the payment held by a hold invoice back to the payer, or stops an open invoice
+    /// from being paid
+    async fn cancel_invoice(&self, _payment_hash: [u8; 32]) -> anyhow::Result<()> {
+        Err(anyhow!("Canceling invoices is not supported"))
+    }
+}
+
+pub type LightningClient = Arc<dyn Lightning>;
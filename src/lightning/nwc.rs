@@ -0,0 +1,559 @@
+use crate::lightning::{
+    InvoiceDescription, InvoiceInfo, InvoiceStatus, Lightning, NodeInfo, PaymentResult,
+    PaymentStatus,
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bitcoin::hashes::Hash;
+use futures::StreamExt;
+use lightning_invoice::Bolt11Invoice;
+use log::{debug, error, info};
+use nostr::nips::nip04;
+use nostr::nips::nip47::{
+    ErrorCode, LookupInvoiceRequestParams, LookupInvoiceResponseResult, MakeInvoiceRequestParams,
+    Method, NIP47Error, NostrWalletConnectURI, PayInvoiceRequestParams, Request, RequestParams,
+    Response, ResponseResult,
+};
+use nostr::{EventBuilder, Filter, JsonUtil, Keys, Kind, Tag, Timestamp};
+use nostr_sdk::{Client, RelayPoolNotification};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long to wait for the wallet service to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often unpaid invoices are looked up to see if they were settled
+const INVOICE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most invoice lookups sent to the wallet service at once while polling
+const MAX_CONCURRENT_LOOKUPS: usize = 10;
+
+/// How long after its invoice expired an unsettled payment is counted as failed, in seconds
+const PAYMENT_EXPIRY_GRACE_SECS: u64 = 3_600;
+
+/// Lightning backend using a [NIP-47](https://github.com/nostr-protocol/nips/blob/master/47.md)
+/// Nostr Wallet Connect wallet service. Settlements are found by polling `lookup_invoice`
+/// for the invoices we created. Open invoices that were looked up are watched as well, which
+/// is how the invoices created before a restart are picked up again.
+#[derive(Clone)]
+pub struct NwcBackend {
+    uri: NostrWalletConnectURI,
+    keys: Keys,
+    client: Client,
+    /// Unpaid invoices we created or looked up, by payment hash
+    pending: Arc<Mutex<HashMap<[u8; 32], Bolt11Invoice>>>,
+}
+
+impl NwcBackend {
+    pub async fn new(uri: NostrWalletConnectURI) -> anyhow::Result<Self> {
+        let keys = Keys::new(uri.secret.clone());
+        let client = Client::new(&keys);
+        client.add_relay(uri.relay_url.to_string()).await?;
+        client.connect().await;
+
+        // every response to us, each request picks out its own by the `e` tag
+        let filter = Filter::new()
+            .kind(Kind::WalletConnectResponse)
+            .author(uri.public_key)
+            .pubkey(keys.public_key())
+            .since(Timestamp::now());
+        client.subscribe(vec![filter]).await;
+
+        Ok(Self {
+            uri,
+            keys,
+            client,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Sends a request to the wallet service and waits for its response
+    async fn request(&self, request: Request) -> anyhow::Result<Response> {
+        let content = nip04::encrypt(&self.uri.secret, &self.uri.public_key, request.as_json())?;
+        let event = EventBuilder::new(
+            Kind::WalletConnectRequest,
+            content,
+            [Tag::public_key(self.uri.public_key)],
+        )
+        .to_event(&self.keys)?;
+
+        // listen before sending so a fast response isn't missed
+        let mut notifications = self.client.notifications();
+        self.client.send_event(event.clone()).await?;
+        debug!("Sent NWC {:?} request: {}", request.method, event.id);
+
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            while let Ok(msg) = notifications.recv().await {
+                if let RelayPoolNotification::Event { event: resp, .. } = msg {
+                    let is_response = resp.kind == Kind::WalletConnectResponse
+                        && resp.pubkey == self.uri.public_key
+                        && resp.event_ids().any(|id| *id == event.id);
+                    if is_response {
+                        let json =
+                            nip04::decrypt(&self.uri.secret, &self.uri.public_key, &resp.content)?;
+                        return Ok(Response::from_json(json)?);
+                    }
+                }
+            }
+            Err(anyhow!("Relay connection closed"))
+        })
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for wallet service"))??;
+
+        Ok(response)
+    }
+
+    /// Looks up an invoice by payment hash, None if the wallet service doesn't know it
+    async fn lookup(
+        &self,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<LookupInvoiceResponseResult>> {
+        let request = Request {
+            method: Method::LookupInvoice,
+            params: RequestParams::LookupInvoice(LookupInvoiceRequestParams {
+                payment_hash: Some(hex::encode(payment_hash)),
+                invoice: None,
+            }),
+        };
+
+        let response = self.request(request).await?;
+        match (response.error, response.result) {
+            (Some(error), _) if error.code == ErrorCode::NotFound => Ok(None),
+            (Some(error), _) => Err(wallet_error(error)),
+            (None, Some(ResponseResult::LookupInvoice(res))) => Ok(Some(res)),
+            _ => Err(anyhow!("Unexpected response to lookup_invoice")),
+        }
+    }
+
+    /// Looks up every pending invoice, sending the settled ones and forgetting expired ones.
+    /// Expired invoices are forgotten even if their lookup fails, so a wallet service that
+    /// keeps erroring doesn't have us polling for them forever.
+    async fn poll_pending(&self, tx: &mpsc::Sender<InvoiceInfo>) {
+        let pending: Vec<_> = self.pending.lock().unwrap().clone().into_iter().collect();
+
+        let mut lookups = futures::stream::iter(pending)
+            .map(|(payment_hash, invoice)| async move {
+                let res = self.lookup(payment_hash).await;
+                (payment_hash, invoice, res)
+            })
+            .buffer_unordered(MAX_CONCURRENT_LOOKUPS);
+
+        while let Some((payment_hash, invoice, res)) = lookups.next().await {
+            let info = match res {
+                Ok(Some(res)) => lookup_to_info(res, &invoice),
+                Ok(None) => {
+                    self.pending.lock().unwrap().remove(&payment_hash);
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "Error looking up NWC invoice {}: {e}",
+                        hex::encode(payment_hash)
+                    );
+                    if invoice.is_expired() {
+                        self.pending.lock().unwrap().remove(&payment_hash);
+                    }
+                    continue;
+                }
+            };
+
+            if info.status == InvoiceStatus::Settled {
+                self.pending.lock().unwrap().remove(&payment_hash);
+                if tx.send(info).await.is_err() {
+                    return;
+                }
+            } else if invoice.is_expired() {
+                self.pending.lock().unwrap().remove(&payment_hash);
+            }
+        }
+    }
+}
+
+fn wallet_error(error: NIP47Error) -> anyhow::Error {
+    anyhow!("Wallet service error ({:?}): {}", error.code, error.message)
+}
+
+fn lookup_to_info(res: LookupInvoiceResponseResult, invoice: &Bolt11Invoice) -> InvoiceInfo {
+    let status = if res.settled_at.is_some() {
+        InvoiceStatus::Settled
+    } else {
+        InvoiceStatus::Open
+    };
+    let preimage = match status {
+        InvoiceStatus::Settled => res
+            .preimage
+            .and_then(|p| hex::decode(p).ok())
+            .and_then(|p| p.try_into().ok()),
+        _ => None,
+    };
+
+    InvoiceInfo {
+        payment_hash: invoice.payment_hash().to_byte_array(),
+        bolt11: res.invoice.unwrap_or_else(|| invoice.to_string()),
+        amount_msats: res.amount,
+        status,
+        preimage,
+        settle_index: 0,
+    }
+}
+
+#[async_trait]
+impl Lightning for NwcBackend {
+    async fn create_invoice(
+        &self,
+        amount_msats: u64,
+        description: InvoiceDescription,
+        expiry_secs: u64,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let (description, description_hash) = match description {
+            InvoiceDescription::Memo(memo) => (Some(memo), None),
+            InvoiceDescription::Hash(hash) => (None, Some(hash.to_string())),
+        };
+        let request = Request {
+            method: Method::MakeInvoice,
+            params: RequestParams::MakeInvoice(MakeInvoiceRequestParams {
+                amount: amount_msats,
+                description,
+                description_hash,
+                expiry: Some(expiry_secs),
+            }),
+        };
+
+        let response = self.request(request).await?;
+        let invoice = match (response.error, response.result) {
+            (Some(error), _) => return Err(wallet_error(error)),
+            (None, Some(ResponseResult::MakeInvoice(res))) => {
+                Bolt11Invoice::from_str(&res.invoice)?
+            }
+            _ => return Err(anyhow!("Unexpected response to make_invoice")),
+        };
+
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(invoice.payment_hash().to_byte_array(), invoice.clone());
+
+        Ok(invoice)
+    }
+
+    async fn lookup_invoice(&self, payment_hash: [u8; 32]) -> anyhow::Result<Option<InvoiceInfo>> {
+        let Some(res) = self.lookup(payment_hash).await? else {
+            return Ok(None);
+        };
+        let invoice = match res.invoice.as_ref() {
+            Some(invoice) => Bolt11Invoice::from_str(invoice)?,
+            None => return Err(anyhow!("Wallet service did not return the invoice")),
+        };
+        let info = lookup_to_info(res, &invoice);
+
+        // watch it in case it was created before a restart, when it wasn't pending yet
+        if info.status == InvoiceStatus::Open && !invoice.is_expired() {
+            self.pending.lock().unwrap().insert(payment_hash, invoice);
+        }
+
+        Ok(Some(info))
+    }
+
+    async fn subscribe_invoices(
+        &self,
+        _settle_index: u64,
+    ) -> anyhow::Result<mpsc::Receiver<InvoiceInfo>> {
+        // NIP-47 has no settle index, invoices paid while we were away are found with lookups
+        let (tx, rx) = mpsc::channel(100);
+
+        let nwc = self.clone();
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                nwc.poll_pending(&tx).await;
+                tokio::time::sleep(INVOICE_POLL_INTERVAL).await;
+            }
+        });
+
+        Ok(rx)
+    }
+
+    /// NIP-47 has no per-payment fee limit, so `fee_limit_msats` is not enforced and only the
+    /// wallet service's own budget applies. The fee paid can be more than the limit.
+    async fn pay_invoice(
+        &self,
+        invoice: &Bolt11Invoice,
+        _fee_limit_msats: u64,
+    ) -> anyhow::Result<PaymentResult> {
+        let request = Request {
+            method: Method::PayInvoice,
+            params: RequestParams::PayInvoice(PayInvoiceRequestParams {
+                id: None,
+                invoice: invoice.to_string(),
+                amount: None,
+            }),
+        };
+
+        let response = self.request(request).await?;
+        let preimage = match (response.error, response.result) {
+            // errors from the wallet service other than these mean it didn't pay
+            (Some(error), _) if matches!(error.code, ErrorCode::Internal | ErrorCode::Other) => {
+                return Err(wallet_error(error))
+            }
+            (Some(error), _) => return Ok(PaymentResult::Failed(error.message)),
+            (None, Some(ResponseResult::PayInvoice(res))) => hex::decode(res.preimage)?
+                .try_into()
+                .map_err(|_| anyhow!("Invalid preimage"))?,
+            _ => return Err(anyhow!("Unexpected response to pay_invoice")),
+        };
+
+        // the response doesn't include the fee, not all wallet services can look it up
+        let fee_msats = self
+            .lookup(invoice.payment_hash().to_byte_array())
+            .await
+            .ok()
+            .flatten()
+            .map(|res| res.fees_paid)
+            .unwrap_or(0);
+        info!("Paid invoice over NWC: {}", invoice.payment_hash());
+
+        Ok(PaymentResult::Paid {
+            preimage,
+            fee_msats,
+        })
+    }
+
+    async fn lookup_payment(
+        &self,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<PaymentStatus>> {
+        // lookup_invoice answers for payments we sent as well as invoices we created
+        let Some(res) = self.lookup(payment_hash).await? else {
+            return Ok(None);
+        };
+
+        if res.settled_at.is_some() || res.preimage.is_some() {
+            return Ok(Some(PaymentStatus::Paid {
+                fee_msats: res.fees_paid,
+            }));
+        }
+        // NIP-47 doesn't say when a payment failed, but one can't succeed long after expiry
+        let expired = res
+            .expires_at
+            .is_some_and(|t| t.as_u64() + PAYMENT_EXPIRY_GRACE_SECS < Timestamp::now().as_u64());
+        if expired {
+            return Ok(Some(PaymentStatus::Failed(
+                "Invoice expired without being paid".to_string(),
+            )));
+        }
+
+        Ok(Some(PaymentStatus::InFlight))
+    }
+
+    async fn node_info(&self) -> anyhow::Result<NodeInfo> {
+        let request = Request {
+            method: Method::GetInfo,
+            params: RequestParams::GetInfo,
+        };
+
+        let response = self.request(request).await?;
+        match (response.error, response.result) {
+            (Some(error), _) => Err(wallet_error(error)),
+            (None, Some(ResponseResult::GetInfo(res))) => Ok(NodeInfo {
+                pubkey: res.pubkey,
+                alias: res.alias,
+            }),
+            _ => Err(anyhow!("Unexpected response to get_info")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NwcBackend;
+    use crate::lightning::mock::MockLightning;
+    use crate::lightning::{
+        InvoiceDescription, InvoiceInfo, InvoiceStatus, Lightning, PaymentResult,
+    };
+    use bitcoin::hashes::Hash;
+    use bitcoin::Network;
+    use lightning_invoice::Bolt11Invoice;
+    use nostr::nips::nip04;
+    use nostr::nips::nip47::NostrWalletConnectURI;
+    use nostr::{Event, EventBuilder, Filter, Keys, Kind, Tag, Timestamp};
+    use nostr_sdk::{Client, RelayPoolNotification};
+    use serde_json::{json, Value};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn lookup_result(info: InvoiceInfo) -> Value {
+        json!({
+            "type": "incoming",
+            "invoice": info.bolt11,
+            "payment_hash": hex::encode(info.payment_hash),
+            "preimage": info.preimage.map(hex::encode),
+            "amount": info.amount_msats,
+            "fees_paid": 0,
+            "created_at": Timestamp::now().as_u64(),
+            "settled_at": (info.status == InvoiceStatus::Settled).then(|| Timestamp::now().as_u64()),
+        })
+    }
+
+    /// Answers a request like a wallet service would, errors are NIP-47 error codes
+    async fn handle_request(
+        mock: &MockLightning,
+        method: &str,
+        params: &Value,
+    ) -> Result<Value, &'static str> {
+        match method {
+            "make_invoice" => {
+                let invoice = mock
+                    .create_invoice(
+                        params["amount"].as_u64().unwrap(),
+                        InvoiceDescription::Memo("nwc".to_string()),
+                        params["expiry"].as_u64().unwrap_or(3_600),
+                    )
+                    .await
+                    .unwrap();
+                Ok(json!({
+                    "invoice": invoice.to_string(),
+                    "payment_hash": invoice.payment_hash().to_string(),
+                }))
+            }
+            "lookup_invoice" => {
+                let hash = hex::decode(params["payment_hash"].as_str().unwrap()).unwrap();
+                match mock.lookup_invoice(hash.try_into().unwrap()).await.unwrap() {
+                    Some(info) => Ok(lookup_result(info)),
+                    None => Err("NOT_FOUND"),
+                }
+            }
+            "pay_invoice" => {
+                let invoice = Bolt11Invoice::from_str(params["invoice"].as_str().unwrap()).unwrap();
+                match mock.pay_invoice(&invoice, 0).await.unwrap() {
+                    PaymentResult::Paid { preimage, .. } => {
+                        Ok(json!({ "preimage": hex::encode(preimage) }))
+                    }
+                    PaymentResult::Failed(_) => Err("PAYMENT_FAILED"),
+                }
+            }
+            "get_info" => Ok(json!({ "alias": "stub", "pubkey": "02stub", "methods": [] })),
+            _ => Err("NOT_IMPLEMENTED"),
+        }
+    }
+
+    /// Stub NIP-47 wallet service backed by the mock node, answers requests until the test ends
+    async fn run_wallet_service(relay: String, wallet: Keys, mock: MockLightning) {
+        let client = Client::new(&wallet);
+        client.add_relay(relay).await.unwrap();
+        client.connect().await;
+        let filter = Filter::new()
+            .kind(Kind::WalletConnectRequest)
+            .pubkey(wallet.public_key())
+            .since(Timestamp::now());
+        client.subscribe(vec![filter]).await;
+
+        let mut notifications = client.notifications();
+        while let Ok(msg) = notifications.recv().await {
+            let RelayPoolNotification::Event { event, .. } = msg else {
+                continue;
+            };
+            let secret_key = wallet.secret_key().unwrap();
+            let request: Value = serde_json::from_str(
+                &nip04::decrypt(secret_key, &event.pubkey, &event.content).unwrap(),
+            )
+            .unwrap();
+            let method = request["method"].as_str().unwrap();
+            let response = match handle_request(&mock, method, &request["params"]).await {
+                Ok(result) => json!({ "result_type": method, "result": result }),
+                Err(code) => json!({
+                    "result_type": method,
+                    "error": { "code": code, "message": code.to_lowercase() },
+                }),
+            };
+            let content = nip04::encrypt(secret_key, &event.pubkey, response.to_string()).unwrap();
+            let reply: Event = EventBuilder::new(
+                Kind::WalletConnectResponse,
+                content,
+                [Tag::public_key(event.pubkey), Tag::event(event.id)],
+            )
+            .to_event(&wallet)
+            .unwrap();
+            client.send_event(reply).await.unwrap();
+        }
+    }
+
+    /// Needs a nostr relay in `TEST_RELAY`
+    #[tokio::test]
+    #[ignore = "needs TEST_RELAY"]
+    async fn test_nwc_backend() {
+        let relay = std::env::var("TEST_RELAY").expect("TEST_RELAY must be set");
+
+        let wallet = Keys::generate();
+        let mock = MockLightning::new(Network::Regtest, false);
+        tokio::spawn(run_wallet_service(
+            relay.clone(),
+            wallet.clone(),
+            mock.clone(),
+        ));
+        // give the wallet service time to subscribe
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let uri = NostrWalletConnectURI::from_str(&format!(
+            "nostr+walletconnect://{}?relay={relay}&secret={}",
+            wallet.public_key(),
+            Keys::generate().secret_key().unwrap().to_secret_hex(),
+        ))
+        .unwrap();
+        let nwc = NwcBackend::new(uri.clone()).await.unwrap();
+
+        assert_eq!(nwc.node_info().await.unwrap().alias, "stub");
+
+        let mut invoices = nwc.subscribe_invoices(0).await.unwrap();
+        let invoice = nwc
+            .create_invoice(21_000, InvoiceDescription::Memo("test".to_string()), 3_600)
+            .await
+            .unwrap();
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        let info = nwc.lookup_invoice(payment_hash).await.unwrap().unwrap();
+        assert_eq!(info.status, InvoiceStatus::Open);
+        assert!(nwc.lookup_invoice([0; 32]).await.unwrap().is_none());
+
+        // paid invoices are found by polling
+        mock.receive_payment(payment_hash).unwrap();
+        let settled = tokio::time::timeout(Duration::from_secs(30), invoices.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settled.payment_hash, payment_hash);
+        assert_eq!(settled.status, InvoiceStatus::Settled);
+        assert_eq!(settled.amount_msats, 21_000);
+        assert!(settled.preimage.is_some());
+
+        // after a restart, looking up an open invoice watches it again
+        let invoice = nwc
+            .create_invoice(1_000, InvoiceDescription::Memo("test".to_string()), 3_600)
+            .await
+            .unwrap();
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        let restarted = NwcBackend::new(uri).await.unwrap();
+        let mut invoices = restarted.subscribe_invoices(0).await.unwrap();
+        let info = restarted
+            .lookup_invoice(payment_hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.status, InvoiceStatus::Open);
+        mock.receive_payment(payment_hash).unwrap();
+        let settled = tokio::time::timeout(Duration::from_secs(30), invoices.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(settled.payment_hash, payment_hash);
+        assert_eq!(settled.status, InvoiceStatus::Settled);
+
+        let other = MockLightning::new(Network::Regtest, false)
+            .create_invoice(5_000, InvoiceDescription::Memo("test".to_string()), 3_600)
+            .await
+            .unwrap();
+        let result = nwc.pay_invoice(&other, 1_000).await.unwrap();
+        assert!(matches!(result, PaymentResult::Paid { .. }));
+        assert_eq!(mock.payments(), vec![other]);
+    }
+}
@@ -1,11 +1,30 @@
 #![allow(clippy::too_many_arguments)]
 
-use crate::config::{Config, ServerKeys};
+use crate::admin::{
+    adjust_balance, cancel_trigger, create_plan, create_voucher, delete_pubkey_policy, get_balance,
+    get_policy, get_report, list_failed_jobs, list_plans, list_pubkey_policies, list_triggers,
+    list_vouchers, replay_job, revoke_plan, revoke_voucher, set_policy, set_pubkey_policy,
+};
+use crate::cashu::http::HttpMint;
+use crate::cashu::CashuMint;
+use crate::config::{Command, Config, LightningBackend, ServerKeys};
 use crate::job_listener::{listen_for_jobs, process_schedule_jobs_round};
+use crate::job_queue::{
+    listen_for_notifications, wait_for_queued_jobs, RetryPolicy, LEASE_BATCH, QUEUE_CHANNEL,
+};
+use crate::lightning::lnd::LndBackend;
+#[cfg(feature = "mock-lightning")]
+use crate::lightning::mock::MockLightning;
+use crate::lightning::nwc::NwcBackend;
+use crate::lightning::LightningClient;
 use crate::models::{PostgresStorage, MIGRATIONS};
-use crate::routes::{get_invoice, get_lnurl_pay, get_nip05};
+use crate::policy::{Policy, PolicyLimits};
+use crate::report::Report;
+use crate::routes::{get_balance_statement, get_invoice, get_lnurl_pay, get_nip05, verify_invoice};
+use crate::trigger::{listen_for_triggers, TRIGGER_CHANNEL};
+use crate::withdraw::{get_withdraw_request, request_withdrawal, withdraw_callback};
 use axum::http::{Method, StatusCode, Uri};
-use axum::routing::get;
+use axum::routing::{delete, get, post};
 use axum::{http, Extension, Router};
 use clap::Parser;
 use diesel::r2d2::{ConnectionManager, Pool};
@@ -13,33 +32,52 @@ use diesel::PgConnection;
 use diesel_migrations::MigrationHarness;
 use kormir::Oracle;
 use log::{error, info};
+use nostr::nips::nip47::NostrWalletConnectURI;
 use nostr::{EventBuilder, Keys, Kind, Metadata, Tag, TagKind, ToBech32};
 use nostr_sdk::Client;
-use std::collections::HashSet;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::spawn;
-use tokio::sync::{oneshot, Mutex};
+use tokio::sync::{oneshot, Notify};
 use tokio::time::sleep;
-use tonic_openssl_lnd::lnrpc::{GetInfoRequest, GetInfoResponse};
-use tonic_openssl_lnd::LndLightningClient;
 use tower_http::cors::{Any, CorsLayer};
 
+mod admin;
+mod balance;
+mod cashu;
 mod config;
+mod error;
 mod invoice_subscriber;
 mod job_listener;
+mod job_queue;
+mod lightning;
 mod models;
+mod policy;
+mod reaper;
+mod recurrence;
+mod report;
 mod routes;
+mod trigger;
 mod wasm_handler;
+mod withdraw;
 
 #[derive(Clone)]
 pub struct State {
     pub db_pool: Pool<ConnectionManager<PgConnection>>,
-    pub lnd: LndLightningClient,
+    pub lightning: LightningClient,
     pub keys: Keys,
     pub relays: Vec<String>,
     pub domain: String,
+    pub policy: Policy,
+    pub admin_token: Option<String>,
+    pub min_withdrawal: u64,
+    pub withdrawal_fee_reserve: f64,
+    pub lnurl_names: Vec<String>,
+    pub min_sendable: u64,
+    pub max_sendable: u64,
+    pub comment_allowed: u32,
 }
 
 #[tokio::main]
@@ -61,6 +99,18 @@ async fn main() -> anyhow::Result<()> {
         .expect("migrations could not run");
     drop(conn);
 
+    if let Some(Command::Report {
+        since,
+        until,
+        format,
+    }) = config.command.clone()
+    {
+        let mut conn = db_pool.get()?;
+        let report = Report::generate(&mut conn, since, until)?;
+        println!("{}", report.format(format)?);
+        return Ok(());
+    }
+
     // Create the datadir if it doesn't exist
     let mut path = PathBuf::from(&config.data_dir);
     std::fs::create_dir_all(path.clone())?;
@@ -117,26 +167,51 @@ async fn main() -> anyhow::Result<()> {
         server_keys.write(&keys_path);
     }
 
-    // connect to lnd
-    let mut client = tonic_openssl_lnd::connect(
-        config.lnd_host.clone(),
-        config.lnd_port,
-        config.cert_file(),
-        config.macaroon_file(),
-    )
-    .await
-    .expect("failed to connect");
-
-    let mut ln_client = client.lightning().clone();
-    let lnd_info: GetInfoResponse = ln_client
-        .get_info(GetInfoRequest {})
-        .await
-        .expect("Failed to get lnd info")
-        .into_inner();
+    // connect to the lightning node
+    let lightning: LightningClient = match config.lightning {
+        LightningBackend::Lnd => {
+            let mut client = tonic_openssl_lnd::connect(
+                config.lnd_host.clone(),
+                config.lnd_port,
+                config.cert_file(),
+                config.macaroon_file(),
+            )
+            .await
+            .expect("failed to connect");
+
+            Arc::new(LndBackend::new(
+                client.lightning().clone(),
+                client.invoices().clone(),
+            ))
+        }
+        LightningBackend::Nwc => {
+            let uri = config
+                .nwc_uri
+                .as_deref()
+                .expect("--nwc-uri is required with --lightning nwc");
+            let uri = NostrWalletConnectURI::from_str(uri).expect("Invalid NWC uri");
+            Arc::new(NwcBackend::new(uri).await?)
+        }
+        #[cfg(feature = "mock-lightning")]
+        LightningBackend::Mock => {
+            log::warn!("Using mock lightning backend, invoices are paid automatically");
+            Arc::new(MockLightning::new(config.network, true))
+        }
+        #[cfg(not(feature = "mock-lightning"))]
+        LightningBackend::Mock => {
+            panic!("--lightning mock needs a build with the mock-lightning feature")
+        }
+    };
 
-    let lnd = client.lightning().clone();
+    let node_info = lightning
+        .node_info()
+        .await
+        .expect("Failed to get lightning node info");
 
-    info!("Connected to LND: {}", lnd_info.identity_pubkey);
+    info!(
+        "Connected to lightning node: {} ({})",
+        node_info.pubkey, node_info.alias
+    );
 
     // initialize oracle, need to convert to old rust-bitcoin types
     let oracle = {
@@ -148,33 +223,47 @@ async fn main() -> anyhow::Result<()> {
 
     let http = reqwest::Client::new();
 
-    let invoice_lnd = lnd.clone();
+    let policy = Policy::new(PolicyLimits::from_config(&config));
+
+    let invoice_lightning = lightning.clone();
     let invoice_relays = config.relay.clone();
     let invoice_keys = keys.clone();
     let invoice_db_pool = db_pool.clone();
     let invoice_http = http.clone();
     let invoice_oracle = oracle.clone();
+    let invoice_policy = policy.clone();
+    let failure_fee = config.failure_fee;
     spawn(async move {
         loop {
             if let Err(e) = invoice_subscriber::start_invoice_subscription(
-                invoice_lnd.clone(),
+                invoice_lightning.clone(),
                 invoice_relays.clone(),
                 invoice_keys.clone(),
                 invoice_http.clone(),
                 invoice_db_pool.clone(),
                 invoice_oracle.clone(),
+                invoice_policy.clone(),
+                failure_fee,
             )
             .await
             {
                 error!("Error in invoice loop: {e}");
             }
+            // don't hammer the node while it is unreachable
+            sleep(std::time::Duration::from_secs(5)).await;
         }
     });
 
+    let cashu_mint: Option<CashuMint> = config.cashu_mint.as_deref().map(|url| {
+        info!("Accepting Cashu tokens from {url}");
+        Arc::new(HttpMint::new(url, http.clone())) as CashuMint
+    });
+
     let bech32 = keys.public_key().to_bech32()?;
+    let jobs_policy = policy.clone();
     let jobs_config = config.clone();
     let jobs_keys = keys.clone();
-    let jobs_lnd = lnd.clone();
+    let jobs_lightning = lightning.clone();
     let jobs_db_pool = db_pool.clone();
     let jobs_http = http.clone();
     let jobs_oracle = oracle.clone();
@@ -184,26 +273,45 @@ async fn main() -> anyhow::Result<()> {
             if let Err(e) = listen_for_jobs(
                 &jobs_config,
                 jobs_keys.clone(),
-                jobs_lnd.clone(),
+                jobs_lightning.clone(),
+                cashu_mint.clone(),
                 jobs_db_pool.clone(),
                 jobs_http.clone(),
                 jobs_oracle.clone(),
+                jobs_policy.clone(),
             )
             .await
             {
                 error!("Error listening for jobs: {e}");
             }
+            sleep(std::time::Duration::from_secs(5)).await;
         }
     });
 
-    // listen for scheduled jobs
+    // wake the scheduler when a job is queued, reconnecting if the connection drops
+    let queued = Arc::new(Notify::new());
+    let queue_pg_url = config.pg_url.clone();
+    let queue_notify = queued.clone();
+    spawn(async move {
+        loop {
+            if let Err(e) =
+                listen_for_notifications(&queue_pg_url, QUEUE_CHANNEL, queue_notify.clone()).await
+            {
+                error!("Error listening for scheduled jobs: {e}");
+            }
+            sleep(std::time::Duration::from_secs(5)).await;
+        }
+    });
+
+    // run scheduled jobs, leasing them from the database so other instances don't run them too
+    let retry = RetryPolicy {
+        max_attempts: config.job_max_attempts,
+        backoff_secs: config.job_retry_backoff,
+    };
     let schedule_db_pool = db_pool.clone();
     let schedule_keys = keys.clone();
     let relays = config.relay.clone();
     spawn(async move {
-        // check for scheduled jobs every 10 seconds
-        let duration = std::time::Duration::from_secs(10);
-
         let client = Client::new(&schedule_keys);
         client
             .add_relays(relays)
@@ -211,33 +319,139 @@ async fn main() -> anyhow::Result<()> {
             .unwrap_or_else(|_| panic!("Failed to add relays for scheduled jobs"));
         client.connect().await;
 
-        // Create a lock on the active jobs we are running so we don't run the same job twice
-        let active_jobs: Arc<Mutex<HashSet<i32>>> = Arc::new(Mutex::new(HashSet::new()));
-
         info!("Starting scheduled job loop");
         loop {
-            if let Err(e) = process_schedule_jobs_round(
+            let leased = match process_schedule_jobs_round(
                 &client,
                 schedule_keys.clone(),
                 schedule_db_pool.clone(),
                 http.clone(),
                 oracle.clone(),
-                active_jobs.clone(),
+                retry,
+                failure_fee,
+            )
+            .await
+            {
+                Ok(leased) => leased,
+                Err(e) => {
+                    error!("Error processing scheduled jobs: {e}");
+                    0
+                }
+            };
+
+            // a full batch means more jobs may be waiting
+            if leased as i64 >= LEASE_BATCH {
+                continue;
+            }
+            if let Err(e) = wait_for_queued_jobs(&schedule_db_pool, &queued).await {
+                error!("Error waiting for scheduled jobs: {e}");
+                sleep(std::time::Duration::from_secs(10)).await;
+            }
+        }
+    });
+
+    // run jobs for events matching triggers, resubscribing when a trigger is added or stopped
+    let triggers_changed = Arc::new(Notify::new());
+    let trigger_pg_url = config.pg_url.clone();
+    let trigger_notify = triggers_changed.clone();
+    spawn(async move {
+        loop {
+            if let Err(e) =
+                listen_for_notifications(&trigger_pg_url, TRIGGER_CHANNEL, trigger_notify.clone())
+                    .await
+            {
+                error!("Error listening for trigger changes: {e}");
+            }
+            sleep(std::time::Duration::from_secs(5)).await;
+        }
+    });
+
+    let trigger_db_pool = db_pool.clone();
+    let trigger_keys = keys.clone();
+    let trigger_relays = config.relay.clone();
+    let catch_up_margin = config.catch_up_margin;
+    spawn(async move {
+        loop {
+            if let Err(e) = listen_for_triggers(
+                trigger_relays.clone(),
+                trigger_keys.clone(),
+                trigger_db_pool.clone(),
+                catch_up_margin,
+                triggers_changed.clone(),
             )
             .await
             {
-                error!("Error processing scheduled jobs: {e}");
+                error!("Error listening for triggers: {e}");
             }
+            sleep(std::time::Duration::from_secs(5)).await;
+        }
+    });
+
+    // expire unpaid jobs and clean up old ones
+    let reaper_db_pool = db_pool.clone();
+    let reaper_keys = keys.clone();
+    let reaper_lightning = lightning.clone();
+    let reaper_relays = config.relay.clone();
+    let job_retention_days = config.job_retention_days;
+    spawn(async move {
+        let duration = std::time::Duration::from_secs(600);
+
+        let client = Client::new(&reaper_keys);
+        client
+            .add_relays(reaper_relays)
+            .await
+            .unwrap_or_else(|_| panic!("Failed to add relays for job reaper"));
+        client.connect().await;
+
+        info!("Starting job reaper loop");
+        loop {
+            if let Err(e) = reaper::reap_jobs(
+                &client,
+                &reaper_keys,
+                &reaper_db_pool,
+                reaper_lightning.as_ref(),
+                job_retention_days,
+            )
+            .await
+            {
+                error!("Error reaping jobs: {e}");
+            }
+
+            // withdrawals whose payment errored are left pending until we know what happened
+            let updated_before = chrono::Utc::now().naive_utc() - chrono::Duration::minutes(10);
+            let reconciled = match reaper_db_pool.get() {
+                Ok(mut conn) => {
+                    withdraw::reconcile_withdrawals(
+                        reaper_lightning.as_ref(),
+                        &mut conn,
+                        updated_before,
+                    )
+                    .await
+                }
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = reconciled {
+                error!("Error reconciling withdrawals: {e}");
+            }
+
             sleep(duration).await
         }
     });
 
     let state = State {
         db_pool,
-        lnd,
+        lightning,
         keys,
         relays: config.relay.clone(),
         domain: config.domain.clone(),
+        policy,
+        admin_token: config.admin_token.clone(),
+        min_withdrawal: config.min_withdrawal,
+        withdrawal_fee_reserve: config.withdrawal_fee_reserve,
+        lnurl_names: config.lnurl_name.clone(),
+        min_sendable: config.min_sendable,
+        max_sendable: config.max_sendable,
+        comment_allowed: config.comment_allowed,
     };
 
     let addr: std::net::SocketAddr = format!("{}:{}", config.bind, config.port)
@@ -249,14 +463,41 @@ async fn main() -> anyhow::Result<()> {
     let server_router = Router::new()
         .route("/get-invoice/:hash", get(get_invoice))
         .route("/.well-known/lnurlp/:name", get(get_lnurl_pay))
+        .route("/lnurlp/verify/:payment_hash", get(verify_invoice))
         .route("/.well-known/nostr.json", get(get_nip05))
+        .route("/balance", get(get_balance_statement))
+        .route("/withdraw", post(request_withdrawal))
+        .route("/lnurlw/:k1", get(get_withdraw_request))
+        .route("/lnurlw-callback", get(withdraw_callback))
+        .route("/admin/policy", get(get_policy).post(set_policy))
+        .route("/admin/pubkeys", get(list_pubkey_policies))
+        .route(
+            "/admin/pubkeys/:npub",
+            post(set_pubkey_policy).delete(delete_pubkey_policy),
+        )
+        .route(
+            "/admin/balances/:npub",
+            get(get_balance).post(adjust_balance),
+        )
+        .route("/admin/report", get(get_report))
+        .route("/admin/jobs/failed", get(list_failed_jobs))
+        .route("/admin/jobs/:id/replay", post(replay_job))
+        .route("/admin/triggers", get(list_triggers))
+        .route("/admin/triggers/:id", delete(cancel_trigger))
+        .route("/admin/plans", get(list_plans).post(create_plan))
+        .route("/admin/plans/:id", delete(revoke_plan))
+        .route("/admin/vouchers", get(list_vouchers).post(create_voucher))
+        .route("/admin/vouchers/:id", delete(revoke_voucher))
         .fallback(fallback)
         .layer(Extension(state))
         .layer(
             CorsLayer::new()
                 .allow_origin(Any)
-                .allow_headers(vec![http::header::CONTENT_TYPE])
-                .allow_methods([Method::GET, Method::POST]),
+                .allow_headers(vec![
+                    http::header::CONTENT_TYPE,
+                    http::header::AUTHORIZATION,
+                ])
+                .allow_methods([Method::GET, Method::POST, Method::DELETE]),
         );
 
     let server = axum::Server::bind(&addr).serve(server_router.into_make_service());
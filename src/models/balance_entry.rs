@@ -0,0 +1,386 @@
+use crate::models::schema::balance_entries;
+use diesel::dsl::sql;
+use diesel::sql_types::BigInt;
+use diesel::{
+    ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection, QueryDsl,
+    Queryable, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    /// A paid zap credited to the balance
+    ZapCredit,
+    /// A job paid for from the balance
+    JobDebit,
+    /// A failed job credited back to the balance
+    Refund,
+    /// A manual correction made by the operator
+    AdminAdjustment,
+    /// Paid out with LNURL-withdraw, including the fee reserve
+    Withdrawal,
+    /// The unspent fee reserve, or everything if the withdrawal failed, credited back
+    WithdrawalRefund,
+    /// A redeemed Cashu token credited to the balance
+    CashuCredit,
+    /// An LNURL-pay payment with the npub in its comment
+    LnurlCredit,
+    /// A redeemed voucher code
+    VoucherCredit,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryKind::ZapCredit => write!(f, "zap_credit"),
+            EntryKind::JobDebit => write!(f, "job_debit"),
+            EntryKind::Refund => write!(f, "refund"),
+            EntryKind::AdminAdjustment => write!(f, "admin_adjustment"),
+            EntryKind::Withdrawal => write!(f, "withdrawal"),
+            EntryKind::WithdrawalRefund => write!(f, "withdrawal_refund"),
+            EntryKind::CashuCredit => write!(f, "cashu_credit"),
+            EntryKind::LnurlCredit => write!(f, "lnurl_credit"),
+            EntryKind::VoucherCredit => write!(f, "voucher_credit"),
+        }
+    }
+}
+
+impl FromStr for EntryKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zap_credit" => Ok(EntryKind::ZapCredit),
+            "job_debit" => Ok(EntryKind::JobDebit),
+            "refund" => Ok(EntryKind::Refund),
+            "admin_adjustment" => Ok(EntryKind::AdminAdjustment),
+            "withdrawal" => Ok(EntryKind::Withdrawal),
+            "withdrawal_refund" => Ok(EntryKind::WithdrawalRefund),
+            "cashu_credit" => Ok(EntryKind::CashuCredit),
+            "lnurl_credit" => Ok(EntryKind::LnurlCredit),
+            "voucher_credit" => Ok(EntryKind::VoucherCredit),
+            _ => Err(anyhow::anyhow!("invalid balance entry kind: {s}")),
+        }
+    }
+}
+
+/// An immutable entry in the balance ledger, the balance of a pubkey is the sum of its entries.
+/// Inserting an entry updates the cached balance in `zap_balances` and fails if it would go negative.
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = balance_entries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BalanceEntry {
+    pub id: i32,
+    npub: Vec<u8>,
+    kind: String,
+    pub amount_msats: i64,
+    zap_payment_hash: Option<Vec<u8>>,
+    pub job_id: Option<i32>,
+    pub description: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub withdrawal_id: Option<i32>,
+    cashu_payment_hash: Option<Vec<u8>>,
+    lnurl_payment_hash: Option<Vec<u8>>,
+    pub voucher_id: Option<i32>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = balance_entries)]
+struct NewBalanceEntry<'a> {
+    npub: Vec<u8>,
+    kind: String,
+    amount_msats: i64,
+    zap_payment_hash: Option<Vec<u8>>,
+    job_id: Option<i32>,
+    description: Option<&'a str>,
+    withdrawal_id: Option<i32>,
+    cashu_payment_hash: Option<Vec<u8>>,
+    lnurl_payment_hash: Option<Vec<u8>>,
+    voucher_id: Option<i32>,
+}
+
+impl BalanceEntry {
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        EntryKind::from_str(&self.kind).expect("Invalid kind")
+    }
+
+    pub fn zap_payment_hash(&self) -> Option<[u8; 32]> {
+        self.zap_payment_hash
+            .clone()
+            .map(|h| h.try_into().expect("Invalid length"))
+    }
+
+    pub fn zap_credit(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        payment_hash: [u8; 32],
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::ZapCredit.to_string(),
+                amount_msats: i64::try_from(amount_msats)?,
+                zap_payment_hash: Some(payment_hash.to_vec()),
+                job_id: None,
+                description: None,
+                withdrawal_id: None,
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    pub fn job_debit(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        job_id: i32,
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::JobDebit.to_string(),
+                amount_msats: -i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: Some(job_id),
+                description: None,
+                withdrawal_id: None,
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    pub fn refund(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        job_id: i32,
+        amount_msats: u64,
+        reason: &str,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::Refund.to_string(),
+                amount_msats: i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: Some(job_id),
+                description: Some(reason),
+                withdrawal_id: None,
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    pub fn admin_adjustment(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        amount_msats: i64,
+        reason: &str,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::AdminAdjustment.to_string(),
+                amount_msats,
+                zap_payment_hash: None,
+                job_id: None,
+                description: Some(reason),
+                withdrawal_id: None,
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    pub fn withdrawal(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        withdrawal_id: i32,
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::Withdrawal.to_string(),
+                amount_msats: -i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: None,
+                description: None,
+                withdrawal_id: Some(withdrawal_id),
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    pub fn withdrawal_refund(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        withdrawal_id: i32,
+        amount_msats: u64,
+        reason: &str,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::WithdrawalRefund.to_string(),
+                amount_msats: i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: None,
+                description: Some(reason),
+                withdrawal_id: Some(withdrawal_id),
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    /// Credits a redeemed Cashu token, `payment_hash` is the invoice the mint paid
+    pub fn cashu_credit(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        payment_hash: [u8; 32],
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::CashuCredit.to_string(),
+                amount_msats: i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: None,
+                description: None,
+                withdrawal_id: None,
+                cashu_payment_hash: Some(payment_hash.to_vec()),
+                lnurl_payment_hash: None,
+                voucher_id: None,
+            },
+        )
+    }
+
+    /// Credits an LNURL-pay payment to the npub named in its comment
+    pub fn lnurl_credit(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        payment_hash: [u8; 32],
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::LnurlCredit.to_string(),
+                amount_msats: i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: None,
+                description: None,
+                withdrawal_id: None,
+                cashu_payment_hash: None,
+                lnurl_payment_hash: Some(payment_hash.to_vec()),
+                voucher_id: None,
+            },
+        )
+    }
+
+    /// Credits a redeemed voucher, each npub can only redeem a voucher once
+    pub fn voucher_credit(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        voucher_id: i32,
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            NewBalanceEntry {
+                npub: npub.to_bytes().to_vec(),
+                kind: EntryKind::VoucherCredit.to_string(),
+                amount_msats: i64::try_from(amount_msats)?,
+                zap_payment_hash: None,
+                job_id: None,
+                description: None,
+                withdrawal_id: None,
+                cashu_payment_hash: None,
+                lnurl_payment_hash: None,
+                voucher_id: Some(voucher_id),
+            },
+        )
+    }
+
+    fn insert(conn: &mut PgConnection, new: NewBalanceEntry) -> anyhow::Result<Self> {
+        let res = diesel::insert_into(balance_entries::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// All entries for the pubkey, oldest first
+    pub fn list(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<Vec<Self>> {
+        let res = balance_entries::table
+            .filter(balance_entries::npub.eq(npub.to_bytes().to_vec()))
+            .order_by(balance_entries::id.asc())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// The most recent entries for the pubkey, newest first
+    pub fn list_recent(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = balance_entries::table
+            .filter(balance_entries::npub.eq(npub.to_bytes().to_vec()))
+            .order_by(balance_entries::id.desc())
+            .limit(limit)
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// The entry debiting the job's price from a balance, None if the job wasn't paid from one
+    pub fn get_job_debit(conn: &mut PgConnection, job_id: i32) -> anyhow::Result<Option<Self>> {
+        let res = balance_entries::table
+            .filter(balance_entries::job_id.eq(job_id))
+            .filter(balance_entries::kind.eq(EntryKind::JobDebit.to_string()))
+            .order_by(balance_entries::id.asc())
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Sum of all entries for the pubkey, should always match the cached balance
+    pub fn sum(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<i64> {
+        let res = balance_entries::table
+            .filter(balance_entries::npub.eq(npub.to_bytes().to_vec()))
+            .select(sql::<BigInt>("COALESCE(SUM(amount_msats), 0)::BIGINT"))
+            .first::<i64>(conn)?;
+
+        Ok(res)
+    }
+}
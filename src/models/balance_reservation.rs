@@ -0,0 +1,149 @@
+use crate::models::schema::balance_reservations;
+use diesel::dsl::sql;
+use diesel::sql_types::BigInt;
+use diesel::{
+    ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection, QueryDsl,
+    Queryable, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReservationStatus {
+    /// Counts against the available balance until the job finishes
+    Held,
+    /// The job finished and was charged for what it used
+    Settled,
+    /// The job failed and the hold was released
+    Released,
+}
+
+impl fmt::Display for ReservationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReservationStatus::Held => write!(f, "held"),
+            ReservationStatus::Settled => write!(f, "settled"),
+            ReservationStatus::Released => write!(f, "released"),
+        }
+    }
+}
+
+impl FromStr for ReservationStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "held" => Ok(ReservationStatus::Held),
+            "settled" => Ok(ReservationStatus::Settled),
+            "released" => Ok(ReservationStatus::Released),
+            _ => Err(anyhow::anyhow!("invalid reservation status: {s}")),
+        }
+    }
+}
+
+/// A hold on part of a balance for a job that hasn't finished yet
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = balance_reservations)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct BalanceReservation {
+    pub id: i32,
+    npub: Vec<u8>,
+    pub job_id: i32,
+    amount_msats: i64,
+    status: String,
+    settled_msats: Option<i64>,
+    created_at: chrono::NaiveDateTime,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = balance_reservations)]
+struct NewBalanceReservation {
+    npub: Vec<u8>,
+    job_id: i32,
+    amount_msats: i64,
+}
+
+impl BalanceReservation {
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn amount_msats(&self) -> u64 {
+        self.amount_msats as u64
+    }
+
+    pub fn settled_msats(&self) -> Option<u64> {
+        self.settled_msats.map(|s| s as u64)
+    }
+
+    pub fn status(&self) -> ReservationStatus {
+        ReservationStatus::from_str(&self.status).expect("Invalid status")
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        job_id: i32,
+        amount_msats: u64,
+    ) -> anyhow::Result<Self> {
+        let new = NewBalanceReservation {
+            npub: npub.to_bytes().to_vec(),
+            job_id,
+            amount_msats: i64::try_from(amount_msats)?,
+        };
+
+        let res = diesel::insert_into(balance_reservations::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Gets the held reservation for the job and locks it until the transaction ends
+    pub fn get_held_for_update(
+        conn: &mut PgConnection,
+        job_id: i32,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = balance_reservations::table
+            .filter(balance_reservations::job_id.eq(job_id))
+            .filter(balance_reservations::status.eq(ReservationStatus::Held.to_string()))
+            .for_update()
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Total amount still held for the pubkey
+    pub fn total_held(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<i64> {
+        let res = balance_reservations::table
+            .filter(balance_reservations::npub.eq(npub.to_bytes().to_vec()))
+            .filter(balance_reservations::status.eq(ReservationStatus::Held.to_string()))
+            .select(sql::<BigInt>("COALESCE(SUM(amount_msats), 0)::BIGINT"))
+            .first::<i64>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Closes a held reservation, recording how much of it was charged
+    pub fn close(
+        conn: &mut PgConnection,
+        id: i32,
+        status: ReservationStatus,
+        settled_msats: u64,
+    ) -> anyhow::Result<Self> {
+        let res = diesel::update(balance_reservations::table)
+            .filter(balance_reservations::id.eq(id))
+            .filter(balance_reservations::status.eq(ReservationStatus::Held.to_string()))
+            .set((
+                balance_reservations::status.eq(status.to_string()),
+                balance_reservations::settled_msats.eq(i64::try_from(settled_msats)?),
+            ))
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,100 @@
+use crate::models::schema::cashu_redemptions;
+use bitcoin::hashes::Hash;
+use diesel::{
+    ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use lightning_invoice::Bolt11Invoice;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// An invoice a mint was asked to pay to redeem a Cashu token
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = cashu_redemptions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct CashuRedemption {
+    payment_hash: Vec<u8>,
+    invoice: String,
+    pub amount_msats: i64,
+    /// Who sent the token and gets the redemption credited
+    npub: Vec<u8>,
+    pub mint: String,
+    pub settled_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = cashu_redemptions)]
+struct NewCashuRedemption<'a> {
+    payment_hash: Vec<u8>,
+    invoice: String,
+    amount_msats: i64,
+    npub: Vec<u8>,
+    mint: &'a str,
+}
+
+impl CashuRedemption {
+    pub fn payment_hash(&self) -> [u8; 32] {
+        self.payment_hash
+            .clone()
+            .try_into()
+            .expect("Invalid length")
+    }
+
+    pub fn invoice(&self) -> Bolt11Invoice {
+        Bolt11Invoice::from_str(&self.invoice).expect("Invalid invoice")
+    }
+
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        invoice: &Bolt11Invoice,
+        npub: &nostr::PublicKey,
+        mint: &str,
+    ) -> anyhow::Result<Self> {
+        let new = NewCashuRedemption {
+            payment_hash: invoice.payment_hash().to_byte_array().to_vec(),
+            invoice: invoice.to_string(),
+            amount_msats: invoice.amount_milli_satoshis().expect("Invalid amount") as i64,
+            npub: npub.to_bytes().to_vec(),
+            mint,
+        };
+
+        let res = diesel::insert_into(cashu_redemptions::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Redemptions started after the given time that haven't been settled
+    pub fn list_unsettled(
+        conn: &mut PgConnection,
+        created_after: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = cashu_redemptions::table
+            .filter(cashu_redemptions::settled_at.is_null())
+            .filter(cashu_redemptions::created_at.gt(created_after))
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Records the redemption as settled, None if it is unknown or was already settled
+    pub fn mark_settled(
+        conn: &mut PgConnection,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(cashu_redemptions::table)
+            .filter(cashu_redemptions::payment_hash.eq(payment_hash.to_vec()))
+            .filter(cashu_redemptions::settled_at.is_null())
+            .set(cashu_redemptions::settled_at.eq(diesel::dsl::now))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,33 @@
+use crate::models::schema::dm_requests;
+use diesel::{Insertable, PgConnection, Queryable, RunQueryDsl};
+use nostr::EventId;
+
+/// A DM sent to the DVM's key that was answered
+#[derive(Queryable, Debug, Clone, PartialEq, Eq)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct DmRequest {
+    pub event_id: Vec<u8>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = dm_requests)]
+struct NewDmRequest {
+    event_id: Vec<u8>,
+}
+
+impl DmRequest {
+    /// Records that we are answering the given DM, returns false if it was already recorded.
+    pub fn claim(conn: &mut PgConnection, event_id: EventId) -> anyhow::Result<bool> {
+        let new = NewDmRequest {
+            event_id: event_id.to_bytes().to_vec(),
+        };
+
+        let inserted = diesel::insert_into(dm_requests::table)
+            .values(new)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        Ok(inserted == 1)
+    }
+}
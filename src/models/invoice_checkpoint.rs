@@ -0,0 +1,61 @@
+use crate::models::schema::invoice_checkpoints;
+use diesel::sql_types::BigInt;
+use diesel::{
+    ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+
+/// Highest settle index of the invoices we have handled from a node
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = invoice_checkpoints)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct InvoiceCheckpoint {
+    pub node_pubkey: String,
+    settle_index: i64,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = invoice_checkpoints)]
+struct NewInvoiceCheckpoint<'a> {
+    node_pubkey: &'a str,
+    settle_index: i64,
+}
+
+impl InvoiceCheckpoint {
+    /// The node's settle index to resume from, 0 if we have never handled one of its invoices
+    pub fn get(conn: &mut PgConnection, node_pubkey: &str) -> anyhow::Result<u64> {
+        let res = invoice_checkpoints::table
+            .filter(invoice_checkpoints::node_pubkey.eq(node_pubkey))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res.map_or(0, |c| c.settle_index as u64))
+    }
+
+    /// Moves the checkpoint for the node forward to `settle_index`, never backwards
+    pub fn advance(
+        conn: &mut PgConnection,
+        node_pubkey: &str,
+        settle_index: u64,
+    ) -> anyhow::Result<()> {
+        let new = NewInvoiceCheckpoint {
+            node_pubkey,
+            settle_index: settle_index as i64,
+        };
+
+        diesel::insert_into(invoice_checkpoints::table)
+            .values(new)
+            .on_conflict(invoice_checkpoints::node_pubkey)
+            .do_update()
+            .set(
+                invoice_checkpoints::settle_index.eq(diesel::dsl::sql::<BigInt>(
+                    "GREATEST(invoice_checkpoints.settle_index, excluded.settle_index)",
+                )),
+            )
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
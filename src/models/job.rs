@@ -1,11 +1,116 @@
 use crate::models::schema::jobs;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use diesel::dsl::sql;
+use diesel::sql_types::{BigInt, Bool, Nullable, Text, Timestamp};
 use diesel::{
-    AsChangeset, ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection,
-    QueryDsl, Queryable, RunQueryDsl,
+    AsChangeset, BoolExpressionMethods, Connection, ExpressionMethods, Identifiable, Insertable,
+    OptionalExtension, PgConnection, QueryDsl, Queryable, QueryableByName, RunQueryDsl,
 };
 use nostr::{Event, EventId};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting for the invoice to be paid
+    Unpaid,
+    /// Paid for, or its hold invoice accepted, waiting to run
+    Paid,
+    /// Ran and the result was published
+    Completed,
+    /// Failed after being paid for and the requester was refunded
+    Refunded,
+    /// Failed before its hold invoice was settled, the payment went back to the requester
+    Canceled,
+    /// The invoice expired before it was paid
+    Expired,
+    /// A scheduled job that failed on every attempt, the requester was refunded and it can be replayed
+    Failed,
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobStatus::Unpaid => write!(f, "unpaid"),
+            JobStatus::Paid => write!(f, "paid"),
+            JobStatus::Completed => write!(f, "completed"),
+            JobStatus::Refunded => write!(f, "refunded"),
+            JobStatus::Canceled => write!(f, "canceled"),
+            JobStatus::Expired => write!(f, "expired"),
+            JobStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unpaid" => Ok(JobStatus::Unpaid),
+            "paid" => Ok(JobStatus::Paid),
+            "completed" => Ok(JobStatus::Completed),
+            "refunded" => Ok(JobStatus::Refunded),
+            "canceled" => Ok(JobStatus::Canceled),
+            "expired" => Ok(JobStatus::Expired),
+            "failed" => Ok(JobStatus::Failed),
+            _ => Err(anyhow::anyhow!("invalid job status: {s}")),
+        }
+    }
+}
+
+/// What job totals are grouped by in a report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportGroup {
+    /// UTC day the job was requested, as `YYYY-MM-DD`
+    Day,
+    /// Checksum of the wasm module
+    Module,
+    /// Hex pubkey of the requester
+    Requester,
+}
+
+impl ReportGroup {
+    fn key_sql(&self) -> &'static str {
+        match self {
+            ReportGroup::Day => "to_char(created_at, 'YYYY-MM-DD')",
+            ReportGroup::Module => "COALESCE(module_checksum, 'unknown')",
+            ReportGroup::Requester => "request->>'pubkey'",
+        }
+    }
+}
+
+impl fmt::Display for ReportGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportGroup::Day => write!(f, "day"),
+            ReportGroup::Module => write!(f, "module"),
+            ReportGroup::Requester => write!(f, "requester"),
+        }
+    }
+}
+
+/// Totals for the paid jobs sharing a report key
+#[derive(QueryableByName, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobTotals {
+    #[diesel(sql_type = Text)]
+    pub key: String,
+    #[diesel(sql_type = BigInt)]
+    pub jobs: i64,
+    #[diesel(sql_type = BigInt)]
+    pub completed: i64,
+    /// Jobs that were refunded or had their hold invoice canceled
+    #[diesel(sql_type = BigInt)]
+    pub failed: i64,
+    #[diesel(sql_type = BigInt)]
+    pub revenue_msats: i64,
+    #[diesel(sql_type = BigInt)]
+    pub runtime_ms: i64,
+}
 
 #[derive(
     Queryable,
@@ -26,9 +131,29 @@ pub struct Job {
     payment_hash: Vec<u8>,
     request: Value,
     response_id: Option<Vec<u8>>,
-    created_at: chrono::NaiveDateTime,
+    pub created_at: chrono::NaiveDateTime,
     updated_at: chrono::NaiveDateTime,
     scheduled_at: Option<chrono::NaiveDateTime>,
+    status: String,
+    price_msats: Option<i64>,
+    refunded_msats: Option<i64>,
+    pub refund_reason: Option<String>,
+    preimage: Option<Vec<u8>>,
+    /// Checksum of the wasm module the job ran
+    pub module_checksum: Option<String>,
+    runtime_ms: Option<i64>,
+    charged_msats: Option<i64>,
+    /// Code of the error the job failed with
+    pub error_code: Option<String>,
+    /// Until when a worker holds the job, it can be leased again after
+    pub locked_until: Option<chrono::NaiveDateTime>,
+    /// How many times a worker has leased the job
+    pub attempts: i32,
+    /// Last error a scheduled job failed with
+    pub last_error: Option<String>,
+    /// Trigger the job is a run of
+    pub trigger_id: Option<i32>,
+    trigger_event: Option<Value>,
 }
 
 #[derive(Insertable, AsChangeset)]
@@ -37,14 +162,9 @@ struct NewJob {
     payment_hash: Vec<u8>,
     request: Value,
     scheduled_at: Option<chrono::NaiveDateTime>,
-}
-
-#[derive(Insertable, AsChangeset)]
-#[diesel(table_name = jobs)]
-struct CompletedJob {
-    payment_hash: Vec<u8>,
-    request: Value,
-    response_id: Vec<u8>,
+    status: String,
+    price_msats: Option<i64>,
+    preimage: Option<Vec<u8>>,
 }
 
 impl Job {
@@ -58,61 +178,198 @@ impl Job {
             .map(|v| EventId::from_slice(v).expect("invalid response id"))
     }
 
+    pub fn status(&self) -> JobStatus {
+        JobStatus::from_str(&self.status).expect("invalid status")
+    }
+
+    /// The event that triggered the job, which is its input
+    pub fn trigger_event(&self) -> Option<Event> {
+        self.trigger_event
+            .as_ref()
+            .map(|e| serde_json::from_value(e.clone()).expect("Invalid event"))
+    }
+
+    /// When the job is scheduled to run, in seconds from epoch
+    pub fn scheduled_at(&self) -> Option<u64> {
+        self.scheduled_at.map(|t| t.timestamp() as u64)
+    }
+
+    /// What the requester paid for the job, None for jobs from before we tracked prices
+    pub fn price_msats(&self) -> Option<u64> {
+        self.price_msats.map(|p| p as u64)
+    }
+
+    pub fn payment_hash(&self) -> [u8; 32] {
+        self.payment_hash
+            .clone()
+            .try_into()
+            .expect("invalid payment hash")
+    }
+
+    /// Preimage of the job's hold invoice, None if it was paid some other way
+    pub fn preimage(&self) -> Option<[u8; 32]> {
+        self.preimage
+            .clone()
+            .map(|p| p.try_into().expect("invalid preimage"))
+    }
+
+    /// How much of the price was given back after the job failed
+    pub fn refunded_msats(&self) -> Option<u64> {
+        self.refunded_msats.map(|p| p as u64)
+    }
+
+    /// How long the wasm function ran, None if it didn't run to completion
+    pub fn runtime_ms(&self) -> Option<u64> {
+        self.runtime_ms.map(|r| r as u64)
+    }
+
+    /// What we kept for the job once it finished, None if it hasn't finished
+    pub fn charged_msats(&self) -> Option<u64> {
+        self.charged_msats.map(|c| c as u64)
+    }
+
+    /// Creates a job waiting for its invoice to be paid, `preimage` is set for hold invoices
     pub fn create(
         conn: &mut PgConnection,
         payment_hash: [u8; 32],
+        preimage: Option<[u8; 32]>,
         request: &Event,
         scheduled_at: Option<u64>,
+        price_msats: u64,
     ) -> anyhow::Result<Self> {
-        let scheduled_at = scheduled_at
-            .map(|t| {
-                chrono::NaiveDateTime::from_timestamp_opt(t as i64, 0)
-                    .ok_or(anyhow::anyhow!("invalid timestamp"))
-            })
-            .transpose()?;
+        Self::insert(
+            conn,
+            payment_hash,
+            preimage,
+            request,
+            scheduled_at,
+            JobStatus::Unpaid,
+            price_msats,
+        )
+    }
 
-        let new_job = NewJob {
-            payment_hash: payment_hash.to_vec(),
-            request: serde_json::to_value(request)?,
+    /// Creates a job that was paid for up front, keyed by its request id
+    pub fn create_paid(
+        conn: &mut PgConnection,
+        request: &Event,
+        scheduled_at: Option<u64>,
+        price_msats: u64,
+    ) -> anyhow::Result<Self> {
+        Self::insert(
+            conn,
+            request.id.to_bytes(),
+            None,
+            request,
             scheduled_at,
-        };
+            JobStatus::Paid,
+            price_msats,
+        )
+    }
 
-        let res = diesel::insert_into(jobs::table)
-            .values(new_job)
-            .get_result::<Self>(conn)?;
+    /// Creates a paid later run of a repeating job, keyed by its request id and run number
+    pub fn create_occurrence(
+        conn: &mut PgConnection,
+        request: &Event,
+        run: u32,
+        scheduled_at: u64,
+        price_msats: u64,
+    ) -> anyhow::Result<Self> {
+        let mut engine = sha256::Hash::engine();
+        engine.input(request.id.as_bytes());
+        engine.input(&run.to_be_bytes());
+        let key = sha256::Hash::from_engine(engine).to_byte_array();
 
-        Ok(res)
+        Self::insert(
+            conn,
+            key,
+            None,
+            request,
+            Some(scheduled_at),
+            JobStatus::Paid,
+            price_msats,
+        )
     }
 
-    pub fn create_scheduled(
+    /// Creates a paid run of a trigger to run now, keyed by its request id and the event
+    /// that triggered it
+    pub fn create_triggered(
         conn: &mut PgConnection,
         request: &Event,
-        scheduled_at: u64,
+        trigger_id: i32,
+        event: &Event,
+        price_msats: u64,
     ) -> anyhow::Result<Self> {
-        let scheduled_at = chrono::NaiveDateTime::from_timestamp_opt(scheduled_at as i64, 0)
-            .ok_or(anyhow::anyhow!("invalid timestamp"))?;
-        let new_job = NewJob {
-            payment_hash: request.id.to_bytes().to_vec(),
-            request: serde_json::to_value(request)?,
-            scheduled_at: Some(scheduled_at),
-        };
+        let key = Self::trigger_key(request.id, event.id);
+        let job = Self::insert(conn, key, None, request, None, JobStatus::Paid, price_msats)?;
 
-        let res = diesel::insert_into(jobs::table)
-            .values(new_job)
+        // scheduled with the database's clock, which rejects run dates in the past
+        let res = diesel::update(jobs::table)
+            .filter(jobs::id.eq(job.id))
+            .set((
+                jobs::trigger_id.eq(trigger_id),
+                jobs::trigger_event.eq(serde_json::to_value(event)?),
+                jobs::scheduled_at.eq(sql::<Nullable<Timestamp>>("NOW()")),
+            ))
             .get_result::<Self>(conn)?;
 
         Ok(res)
     }
 
-    pub fn create_completed(
+    /// The run of a trigger for the event, if it was already created
+    pub fn get_triggered(
+        conn: &mut PgConnection,
+        request_id: EventId,
+        event_id: EventId,
+    ) -> anyhow::Result<Option<Self>> {
+        let key = Self::trigger_key(request_id, event_id);
+        Self::get_by_payment_hash(conn, &key.to_vec())
+    }
+
+    fn trigger_key(request_id: EventId, event_id: EventId) -> [u8; 32] {
+        let mut engine = sha256::Hash::engine();
+        engine.input(request_id.as_bytes());
+        engine.input(event_id.as_bytes());
+        sha256::Hash::from_engine(engine).to_byte_array()
+    }
+
+    /// How many runs of the trigger were created since the given time
+    pub fn count_trigger_runs_since(
         conn: &mut PgConnection,
+        trigger_id: i32,
+        since: chrono::NaiveDateTime,
+    ) -> anyhow::Result<u64> {
+        let count = jobs::table
+            .filter(jobs::trigger_id.eq(trigger_id))
+            .filter(jobs::created_at.ge(since))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        Ok(count as u64)
+    }
+
+    fn insert(
+        conn: &mut PgConnection,
+        payment_hash: [u8; 32],
+        preimage: Option<[u8; 32]>,
         request: &Event,
-        response_id: &EventId,
+        scheduled_at: Option<u64>,
+        status: JobStatus,
+        price_msats: u64,
     ) -> anyhow::Result<Self> {
-        let new_job = CompletedJob {
-            payment_hash: request.id.to_bytes().to_vec(),
+        let scheduled_at = scheduled_at
+            .map(|t| {
+                chrono::NaiveDateTime::from_timestamp_opt(t as i64, 0)
+                    .ok_or(anyhow::anyhow!("invalid timestamp"))
+            })
+            .transpose()?;
+
+        let new_job = NewJob {
+            payment_hash: payment_hash.to_vec(),
             request: serde_json::to_value(request)?,
-            response_id: response_id.to_bytes().to_vec(),
+            scheduled_at,
+            status: status.to_string(),
+            price_msats: Some(price_msats as i64),
+            preimage: preimage.map(|p| p.to_vec()),
         };
 
         let res = diesel::insert_into(jobs::table)
@@ -122,6 +379,11 @@ impl Job {
         Ok(res)
     }
 
+    pub fn get_by_id(conn: &mut PgConnection, id: i32) -> anyhow::Result<Self> {
+        let res = jobs::table.find(id).first::<Self>(conn)?;
+        Ok(res)
+    }
+
     pub fn get_by_payment_hash(
         conn: &mut PgConnection,
         payment_hash: &Vec<u8>,
@@ -134,6 +396,20 @@ impl Job {
         Ok(res)
     }
 
+    pub fn get_by_request_id(
+        conn: &mut PgConnection,
+        request_id: EventId,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = jobs::table
+            .filter(sql::<Bool>("request->>'id' = ").bind::<Text, _>(request_id.to_hex()))
+            // later runs of a repeating job share the request, the first run is the original job
+            .order_by(jobs::id.asc())
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
     pub fn set_response_id(
         conn: &mut PgConnection,
         id: i32,
@@ -147,13 +423,393 @@ impl Job {
         Ok(job)
     }
 
-    /// Get jobs that we haven't run and who's scheduled time is in the past
-    pub fn get_ready_to_run_jobs(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+    /// Marks an unpaid job as paid, returns None if it was not waiting for payment
+    pub fn mark_paid(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::status.eq(JobStatus::Unpaid.to_string()))
+            .set(jobs::status.eq(JobStatus::Paid.to_string()))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    /// Marks an unpaid job as paid by a zap instead of its invoice, the job is no longer
+    /// treated as paid with a hold invoice. Returns None if it was not waiting for payment.
+    pub fn mark_paid_by_zap(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::status.eq(JobStatus::Unpaid.to_string()))
+            .set((
+                jobs::status.eq(JobStatus::Paid.to_string()),
+                jobs::preimage.eq(None::<Vec<u8>>),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    pub fn set_completed(
+        conn: &mut PgConnection,
+        id: i32,
+        response_id: EventId,
+    ) -> anyhow::Result<Self> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .set((
+                jobs::status.eq(JobStatus::Completed.to_string()),
+                jobs::response_id.eq(response_id.as_bytes()),
+            ))
+            .get_result::<Self>(conn)?;
+
+        Ok(job)
+    }
+
+    /// Records what a finished job ran, for how long, what it was charged and the error it
+    /// failed with, if any
+    pub fn record_outcome(
+        conn: &mut PgConnection,
+        id: i32,
+        module_checksum: Option<&str>,
+        runtime_ms: Option<u64>,
+        charged_msats: u64,
+        error_code: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .set((
+                jobs::module_checksum.eq(module_checksum),
+                jobs::runtime_ms.eq(runtime_ms.map(|r| r as i64)),
+                jobs::charged_msats.eq(charged_msats as i64),
+                jobs::error_code.eq(error_code),
+            ))
+            .get_result::<Self>(conn)?;
+
+        Ok(job)
+    }
+
+    /// Marks a paid job as refunded, returns None if the job was not in the paid state
+    /// so a job can never be refunded twice.
+    pub fn set_refunded(
+        conn: &mut PgConnection,
+        id: i32,
+        refunded_msats: u64,
+        reason: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::status.eq(JobStatus::Paid.to_string()))
+            .set((
+                jobs::status.eq(JobStatus::Refunded.to_string()),
+                jobs::refunded_msats.eq(refunded_msats as i64),
+                jobs::refund_reason.eq(reason),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    /// Marks a paid job whose hold invoice was canceled, returns None if the job was not in the
+    /// paid state
+    pub fn set_canceled(
+        conn: &mut PgConnection,
+        id: i32,
+        reason: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::status.eq(JobStatus::Paid.to_string()))
+            .set((
+                jobs::status.eq(JobStatus::Canceled.to_string()),
+                jobs::refund_reason.eq(reason),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    /// Marks unpaid jobs created before the given time as expired, returns the expired jobs
+    pub fn expire_unpaid(
+        conn: &mut PgConnection,
+        created_before: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = diesel::update(jobs::table)
+            .filter(jobs::status.eq(JobStatus::Unpaid.to_string()))
+            .filter(jobs::created_at.lt(created_before))
+            .set(jobs::status.eq(JobStatus::Expired.to_string()))
+            .get_results::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Jobs still waiting for their invoice to be paid
+    pub fn list_unpaid(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = jobs::table
+            .filter(jobs::status.eq(JobStatus::Unpaid.to_string()))
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Jobs whose hold invoice was accepted but that never got a response, because we
+    /// stopped while they ran
+    pub fn list_held_unanswered(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = jobs::table
+            .filter(jobs::status.eq(JobStatus::Paid.to_string()))
+            .filter(jobs::preimage.is_not_null())
+            .filter(jobs::response_id.is_null())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Deletes jobs that expired before the given time, returns how many were deleted
+    pub fn delete_expired(
+        conn: &mut PgConnection,
+        expired_before: chrono::NaiveDateTime,
+    ) -> anyhow::Result<usize> {
+        let res = diesel::delete(jobs::table)
+            .filter(jobs::status.eq(JobStatus::Expired.to_string()))
+            .filter(jobs::updated_at.lt(expired_before))
+            .execute(conn)?;
+
+        Ok(res)
+    }
+
+    /// Number of scheduled jobs from the given requester that haven't run yet
+    pub fn count_pending_scheduled(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+    ) -> anyhow::Result<i64> {
+        let res = jobs::table
+            .filter(
+                jobs::status.eq_any([JobStatus::Unpaid.to_string(), JobStatus::Paid.to_string()]),
+            )
+            .filter(jobs::response_id.is_null())
+            .filter(jobs::scheduled_at.is_not_null())
+            .filter(sql::<Bool>("request->>'pubkey' = ").bind::<Text, _>(npub.to_hex()))
+            .count()
+            .get_result(conn)?;
+
+        Ok(res)
+    }
+
+    /// The most recent jobs the requester paid for, newest first
+    pub fn list_paid_by_npub(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Self>> {
         let res = jobs::table
+            .filter(jobs::status.ne_all([
+                JobStatus::Unpaid.to_string(),
+                JobStatus::Expired.to_string(),
+            ]))
+            .filter(sql::<Bool>("request->>'pubkey' = ").bind::<Text, _>(npub.to_hex()))
+            .order_by(jobs::id.desc())
+            .limit(limit)
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Leases up to `limit` paid jobs that we haven't run and who's scheduled time is in the past,
+    /// for `lease_secs`. Jobs leased by another worker are skipped until their lease runs out,
+    /// so a crashed worker's jobs are picked up again.
+    pub fn lease_ready_jobs(
+        conn: &mut PgConnection,
+        lease_secs: u64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Self>> {
+        conn.transaction::<_, anyhow::Error, _>(|conn| {
+            let ids = jobs::table
+                .select(jobs::id)
+                .filter(jobs::status.eq(JobStatus::Paid.to_string()))
+                .filter(jobs::response_id.is_null())
+                .filter(jobs::scheduled_at.lt(diesel::dsl::now))
+                .filter(
+                    jobs::locked_until
+                        .is_null()
+                        .or(jobs::locked_until.lt(diesel::dsl::now)),
+                )
+                .order_by(jobs::scheduled_at.asc())
+                .limit(limit)
+                .for_update()
+                .skip_locked()
+                .load::<i32>(conn)?;
+
+            let res = diesel::update(jobs::table)
+                .filter(jobs::id.eq_any(ids))
+                .set((
+                    jobs::locked_until.eq(sql::<Nullable<Timestamp>>(&format!(
+                        "NOW() + INTERVAL '{lease_secs} seconds'"
+                    ))),
+                    jobs::attempts.eq(jobs::attempts + 1),
+                ))
+                .get_results::<Self>(conn)?;
+
+            Ok(res)
+        })
+    }
+
+    /// Pushes the lease on a job we are still running further out
+    pub fn renew_lease(conn: &mut PgConnection, id: i32, lease_secs: u64) -> anyhow::Result<()> {
+        diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::locked_until.is_not_null())
+            .set(jobs::locked_until.eq(sql::<Nullable<Timestamp>>(&format!(
+                "NOW() + INTERVAL '{lease_secs} seconds'"
+            ))))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Puts a scheduled job that failed back in the queue to run again after `delay_secs`,
+    /// returns None if the job was finished in the meantime
+    pub fn retry_later(
+        conn: &mut PgConnection,
+        id: i32,
+        error: &str,
+        delay_secs: u64,
+    ) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::status.eq(JobStatus::Paid.to_string()))
             .filter(jobs::response_id.is_null())
-            .filter(jobs::scheduled_at.lt(diesel::dsl::now))
+            .set((
+                jobs::scheduled_at.eq(sql::<Nullable<Timestamp>>(&format!(
+                    "NOW() + INTERVAL '{delay_secs} seconds'"
+                ))),
+                jobs::locked_until.eq(None::<chrono::NaiveDateTime>),
+                jobs::last_error.eq(error),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    /// Marks a scheduled job that failed on its last attempt, after it was refunded,
+    /// returns None if it had already completed
+    pub fn set_failed(
+        conn: &mut PgConnection,
+        id: i32,
+        error: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(
+                jobs::status.eq_any([JobStatus::Paid.to_string(), JobStatus::Refunded.to_string()]),
+            )
+            .set((
+                jobs::status.eq(JobStatus::Failed.to_string()),
+                jobs::locked_until.eq(None::<chrono::NaiveDateTime>),
+                jobs::last_error.eq(error),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    /// Records an error that happened after a job was finished, such as a failed attestation
+    pub fn set_last_error(conn: &mut PgConnection, id: i32, error: &str) -> anyhow::Result<()> {
+        diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .set(jobs::last_error.eq(error))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Scheduled jobs that failed on every attempt, most recent first
+    pub fn list_failed(conn: &mut PgConnection, limit: i64) -> anyhow::Result<Vec<Self>> {
+        let res = jobs::table
+            .filter(jobs::status.eq(JobStatus::Failed.to_string()))
+            .order_by(jobs::updated_at.desc())
+            .limit(limit)
             .load::<Self>(conn)?;
 
         Ok(res)
     }
+
+    /// Queues a failed job to run again now. The requester was refunded when it failed,
+    /// so the replay runs for free. Returns None if the job had not failed.
+    pub fn replay(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let job = diesel::update(jobs::table)
+            .filter(jobs::id.eq(id))
+            .filter(jobs::status.eq(JobStatus::Failed.to_string()))
+            .set((
+                jobs::status.eq(JobStatus::Paid.to_string()),
+                jobs::scheduled_at.eq(sql::<Nullable<Timestamp>>("NOW()")),
+                jobs::response_id.eq(None::<Vec<u8>>),
+                jobs::price_msats.eq(Some(0)),
+                jobs::locked_until.eq(None::<chrono::NaiveDateTime>),
+                jobs::attempts.eq(0),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(job)
+    }
+
+    /// Whether a later run of the same repeating job was already scheduled
+    pub fn has_later_run(conn: &mut PgConnection, job: &Job) -> anyhow::Result<bool> {
+        let count = jobs::table
+            .filter(sql::<Bool>("request->>'id' = ").bind::<Text, _>(job.request().id.to_hex()))
+            .filter(jobs::id.gt(job.id))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        Ok(count > 0)
+    }
+
+    /// When the next scheduled job that isn't leased becomes ready to run
+    pub fn next_scheduled_at(
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<Option<chrono::NaiveDateTime>> {
+        let res = jobs::table
+            .select(sql::<Nullable<Timestamp>>(
+                "MIN(GREATEST(scheduled_at, COALESCE(locked_until, scheduled_at)))",
+            ))
+            .filter(jobs::status.eq(JobStatus::Paid.to_string()))
+            .filter(jobs::response_id.is_null())
+            .filter(jobs::scheduled_at.is_not_null())
+            .first::<Option<chrono::NaiveDateTime>>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Totals of the jobs requested between `since` and `until` that were paid for
+    pub fn totals(
+        conn: &mut PgConnection,
+        group: ReportGroup,
+        since: chrono::NaiveDateTime,
+        until: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<JobTotals>> {
+        let key = group.key_sql();
+        let query = format!(
+            "SELECT {key} AS key, \
+                COUNT(*) AS jobs, \
+                COUNT(*) FILTER (WHERE status = 'completed') AS completed, \
+                COUNT(*) FILTER (WHERE status IN ('refunded', 'canceled', 'failed')) AS failed, \
+                COALESCE(SUM(charged_msats), 0)::BIGINT AS revenue_msats, \
+                COALESCE(SUM(runtime_ms), 0)::BIGINT AS runtime_ms \
+            FROM jobs \
+            WHERE created_at >= $1 AND created_at < $2 AND status NOT IN ('unpaid', 'expired') \
+            GROUP BY 1 ORDER BY 1"
+        );
+
+        let res = diesel::sql_query(query)
+            .bind::<Timestamp, _>(since)
+            .bind::<Timestamp, _>(until)
+            .load::<JobTotals>(conn)?;
+
+        Ok(res)
+    }
 }
@@ -0,0 +1,128 @@
+use crate::models::schema::job_requests;
+use diesel::dsl::sql;
+use diesel::sql_types::{Bool, Text};
+use diesel::{
+    AsChangeset, ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection,
+    QueryDsl, Queryable, RunQueryDsl,
+};
+use nostr::{Event, EventId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(
+    Queryable,
+    Insertable,
+    Identifiable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+)]
+#[diesel(primary_key(event_id))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct JobRequest {
+    event_id: Vec<u8>,
+    reply_event: Option<Value>,
+    created_at: chrono::NaiveDateTime,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = job_requests)]
+struct NewJobRequest {
+    event_id: Vec<u8>,
+}
+
+impl JobRequest {
+    pub fn event_id(&self) -> EventId {
+        EventId::from_slice(&self.event_id).expect("invalid event id")
+    }
+
+    pub fn reply_event(&self) -> Option<Event> {
+        self.reply_event
+            .clone()
+            .map(|v| serde_json::from_value(v).expect("invalid reply event"))
+    }
+
+    /// Whether this request was first seen less than `window_secs` seconds ago
+    pub fn is_within_window(&self, window_secs: u64) -> bool {
+        let age = chrono::Utc::now().naive_utc() - self.created_at;
+        age.num_seconds() < window_secs as i64
+    }
+
+    /// Records that we are handling the given request, returns false if it was already recorded.
+    pub fn claim(conn: &mut PgConnection, event_id: EventId) -> anyhow::Result<bool> {
+        let new = NewJobRequest {
+            event_id: event_id.to_bytes().to_vec(),
+        };
+
+        let inserted = diesel::insert_into(job_requests::table)
+            .values(new)
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        Ok(inserted == 1)
+    }
+
+    /// Gives back the claim on a request we failed to handle, so another copy of it is handled
+    /// again. Claims that already have a reply or a job are kept, returns whether it was released.
+    pub fn release(conn: &mut PgConnection, event_id: EventId) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(job_requests::table)
+            .filter(job_requests::event_id.eq(event_id.to_bytes().to_vec()))
+            .filter(job_requests::reply_event.is_null())
+            .filter(sql::<Bool>(
+                "NOT EXISTS (SELECT 1 FROM jobs \
+                 WHERE jobs.request->>'id' = encode(job_requests.event_id, 'hex'))",
+            ))
+            .execute(conn)?;
+
+        Ok(deleted == 1)
+    }
+
+    pub fn get(conn: &mut PgConnection, event_id: EventId) -> anyhow::Result<Option<Self>> {
+        let res = job_requests::table
+            .filter(job_requests::event_id.eq(event_id.to_bytes().to_vec()))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Finds the request we sent the given reply to
+    pub fn get_by_reply_id(
+        conn: &mut PgConnection,
+        reply_id: EventId,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = job_requests::table
+            .filter(sql::<Bool>("reply_event->>'id' = ").bind::<Text, _>(reply_id.to_hex()))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Sets the reply for a request, creating the record if the request predates it.
+    pub fn set_reply(
+        conn: &mut PgConnection,
+        event_id: EventId,
+        reply: &Event,
+    ) -> anyhow::Result<Self> {
+        let event_id = event_id.to_bytes().to_vec();
+        let reply = serde_json::to_value(reply)?;
+
+        let res = diesel::insert_into(job_requests::table)
+            .values((
+                job_requests::event_id.eq(&event_id),
+                job_requests::reply_event.eq(&reply),
+            ))
+            .on_conflict(job_requests::event_id)
+            .do_update()
+            .set(job_requests::reply_event.eq(&reply))
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+}
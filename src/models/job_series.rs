@@ -0,0 +1,204 @@
+use crate::models::schema::job_series;
+use crate::recurrence::Recurrence;
+use diesel::{
+    ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection, QueryDsl,
+    Queryable, RunQueryDsl,
+};
+use nostr::{Event, EventId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesStatus {
+    /// More runs will be scheduled
+    Active,
+    /// Reached its end, ran out of runs or could not be paid for
+    Finished,
+    /// The requester deleted the request
+    Canceled,
+}
+
+impl fmt::Display for SeriesStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SeriesStatus::Active => write!(f, "active"),
+            SeriesStatus::Finished => write!(f, "finished"),
+            SeriesStatus::Canceled => write!(f, "canceled"),
+        }
+    }
+}
+
+impl FromStr for SeriesStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(SeriesStatus::Active),
+            "finished" => Ok(SeriesStatus::Finished),
+            "canceled" => Ok(SeriesStatus::Canceled),
+            _ => Err(anyhow::anyhow!("invalid series status: {s}")),
+        }
+    }
+}
+
+/// A repeating scheduled job, each run is its own job for the same request
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = job_series)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct JobSeries {
+    pub id: i32,
+    request_id: Vec<u8>,
+    npub: Vec<u8>,
+    cron: Option<String>,
+    interval_secs: Option<i64>,
+    end_at: Option<chrono::NaiveDateTime>,
+    max_runs: Option<i32>,
+    /// Runs created so far, including the first
+    pub runs: i32,
+    run_price_msats: i64,
+    status: String,
+    pub stop_reason: Option<String>,
+    created_at: chrono::NaiveDateTime,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = job_series)]
+struct NewJobSeries {
+    request_id: Vec<u8>,
+    npub: Vec<u8>,
+    cron: Option<String>,
+    interval_secs: Option<i64>,
+    end_at: Option<chrono::NaiveDateTime>,
+    max_runs: Option<i32>,
+    run_price_msats: i64,
+}
+
+impl JobSeries {
+    pub fn request_id(&self) -> EventId {
+        EventId::from_slice(&self.request_id).expect("Invalid event id")
+    }
+
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn status(&self) -> SeriesStatus {
+        SeriesStatus::from_str(&self.status).expect("Invalid status")
+    }
+
+    pub fn recurrence(&self) -> anyhow::Result<Recurrence> {
+        match (self.cron.as_deref(), self.interval_secs) {
+            (Some(cron), _) => Recurrence::cron(cron),
+            (None, Some(secs)) => Ok(Recurrence::Interval(secs as u64)),
+            (None, None) => Err(anyhow::anyhow!("Series {} has no recurrence", self.id)),
+        }
+    }
+
+    /// Last time a run can be scheduled for, in seconds from epoch
+    pub fn end_at(&self) -> Option<u64> {
+        self.end_at.map(|t| t.timestamp() as u64)
+    }
+
+    pub fn max_runs(&self) -> Option<u32> {
+        self.max_runs.map(|r| r as u32)
+    }
+
+    /// What each run is charged, fixed when the series was requested
+    pub fn run_price_msats(&self) -> u64 {
+        self.run_price_msats as u64
+    }
+
+    /// Creates the series for a request whose first run was already scheduled,
+    /// returns the existing one if the request was seen before
+    pub fn create(
+        conn: &mut PgConnection,
+        request: &Event,
+        recurrence: &Recurrence,
+        end_at: Option<u64>,
+        max_runs: Option<u32>,
+        run_price_msats: u64,
+    ) -> anyhow::Result<Self> {
+        let (cron, interval_secs) = recurrence.parts();
+        let end_at = end_at
+            .map(|t| {
+                chrono::NaiveDateTime::from_timestamp_opt(t as i64, 0)
+                    .ok_or(anyhow::anyhow!("invalid timestamp"))
+            })
+            .transpose()?;
+        let new = NewJobSeries {
+            request_id: request.id.to_bytes().to_vec(),
+            npub: request.pubkey.to_bytes().to_vec(),
+            cron,
+            interval_secs: interval_secs.map(|s| s as i64),
+            end_at,
+            max_runs: max_runs.map(|r| r as i32),
+            run_price_msats: i64::try_from(run_price_msats)?,
+        };
+
+        diesel::insert_into(job_series::table)
+            .values(new)
+            .on_conflict(job_series::request_id)
+            .do_nothing()
+            .execute(conn)?;
+
+        Self::get_by_request_id(conn, request.id)?
+            .ok_or(anyhow::anyhow!("Missing series for {}", request.id))
+    }
+
+    pub fn get_by_request_id(
+        conn: &mut PgConnection,
+        request_id: EventId,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = job_series::table
+            .filter(job_series::request_id.eq(request_id.to_bytes().to_vec()))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Locks an active series until the end of the transaction, None if it isn't active
+    pub fn get_active_for_update(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let res = job_series::table
+            .filter(job_series::id.eq(id))
+            .filter(job_series::status.eq(SeriesStatus::Active.to_string()))
+            .for_update()
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Counts another run of the series
+    pub fn increment_runs(conn: &mut PgConnection, id: i32) -> anyhow::Result<Self> {
+        let res = diesel::update(job_series::table)
+            .filter(job_series::id.eq(id))
+            .set(job_series::runs.eq(job_series::runs + 1))
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Stops an active series, None if it was already stopped
+    pub fn stop(
+        conn: &mut PgConnection,
+        id: i32,
+        status: SeriesStatus,
+        reason: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(job_series::table)
+            .filter(job_series::id.eq(id))
+            .filter(job_series::status.eq(SeriesStatus::Active.to_string()))
+            .set((
+                job_series::status.eq(status.to_string()),
+                job_series::stop_reason.eq(reason),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
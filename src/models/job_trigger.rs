@@ -0,0 +1,249 @@
+use crate::models::schema::job_triggers;
+use crate::wasm_handler::TriggerParams;
+use diesel::{
+    ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection, QueryDsl,
+    Queryable, RunQueryDsl,
+};
+use nostr::{Event, EventId, Filter};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TriggerStatus {
+    /// Matching events run the job
+    Active,
+    /// Expired, ran out of runs or could not be paid for
+    Finished,
+    /// The requester deleted the request or an operator canceled it
+    Canceled,
+}
+
+impl fmt::Display for TriggerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TriggerStatus::Active => write!(f, "active"),
+            TriggerStatus::Finished => write!(f, "finished"),
+            TriggerStatus::Canceled => write!(f, "canceled"),
+        }
+    }
+}
+
+impl FromStr for TriggerStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(TriggerStatus::Active),
+            "finished" => Ok(TriggerStatus::Finished),
+            "canceled" => Ok(TriggerStatus::Canceled),
+            _ => Err(anyhow::anyhow!("invalid trigger status: {s}")),
+        }
+    }
+}
+
+/// A request that runs its job for every nostr event matching its filter, each run is its
+/// own job for the same request
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = job_triggers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct JobTrigger {
+    pub id: i32,
+    request_id: Vec<u8>,
+    npub: Vec<u8>,
+    request: Value,
+    filter: Value,
+    expires_at: chrono::NaiveDateTime,
+    max_runs: Option<i32>,
+    max_runs_per_hour: i32,
+    /// Runs created so far
+    pub runs: i32,
+    run_price_msats: i64,
+    status: String,
+    pub stop_reason: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = job_triggers)]
+struct NewJobTrigger {
+    request_id: Vec<u8>,
+    npub: Vec<u8>,
+    request: Value,
+    filter: Value,
+    expires_at: chrono::NaiveDateTime,
+    max_runs: Option<i32>,
+    max_runs_per_hour: i32,
+    run_price_msats: i64,
+}
+
+impl JobTrigger {
+    pub fn request_id(&self) -> EventId {
+        EventId::from_slice(&self.request_id).expect("Invalid event id")
+    }
+
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn request(&self) -> Event {
+        serde_json::from_value(self.request.clone()).expect("Invalid event")
+    }
+
+    pub fn filter(&self) -> Filter {
+        serde_json::from_value(self.filter.clone()).expect("Invalid filter")
+    }
+
+    pub fn status(&self) -> TriggerStatus {
+        TriggerStatus::from_str(&self.status).expect("Invalid status")
+    }
+
+    /// When the trigger stops, in seconds from epoch
+    pub fn expires_at(&self) -> u64 {
+        self.expires_at.timestamp() as u64
+    }
+
+    pub fn max_runs(&self) -> Option<u32> {
+        self.max_runs.map(|r| r as u32)
+    }
+
+    pub fn max_runs_per_hour(&self) -> u32 {
+        self.max_runs_per_hour as u32
+    }
+
+    /// What each run is charged, fixed when the trigger was requested
+    pub fn run_price_msats(&self) -> u64 {
+        self.run_price_msats as u64
+    }
+
+    /// Creates the trigger for a request, returns the existing one if the request was seen before
+    pub fn create(
+        conn: &mut PgConnection,
+        request: &Event,
+        params: &TriggerParams,
+        max_runs_per_hour: u32,
+        run_price_msats: u64,
+    ) -> anyhow::Result<Self> {
+        let expires_at = chrono::NaiveDateTime::from_timestamp_opt(params.expires_at as i64, 0)
+            .ok_or(anyhow::anyhow!("invalid timestamp"))?;
+        let new = NewJobTrigger {
+            request_id: request.id.to_bytes().to_vec(),
+            npub: request.pubkey.to_bytes().to_vec(),
+            request: serde_json::to_value(request)?,
+            filter: serde_json::to_value(&params.filter)?,
+            expires_at,
+            max_runs: params.max_runs.map(|r| r as i32),
+            max_runs_per_hour: max_runs_per_hour as i32,
+            run_price_msats: i64::try_from(run_price_msats)?,
+        };
+
+        diesel::insert_into(job_triggers::table)
+            .values(new)
+            .on_conflict(job_triggers::request_id)
+            .do_nothing()
+            .execute(conn)?;
+
+        Self::get_by_request_id(conn, request.id)?
+            .ok_or(anyhow::anyhow!("Missing trigger for {}", request.id))
+    }
+
+    pub fn get_by_request_id(
+        conn: &mut PgConnection,
+        request_id: EventId,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = job_triggers::table
+            .filter(job_triggers::request_id.eq(request_id.to_bytes().to_vec()))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Every trigger, newest first
+    pub fn list(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = job_triggers::table
+            .order_by(job_triggers::id.desc())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn list_active(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = job_triggers::table
+            .filter(job_triggers::status.eq(TriggerStatus::Active.to_string()))
+            .order_by(job_triggers::id.asc())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// How many active triggers the pubkey has
+    pub fn count_active(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<u64> {
+        let count = job_triggers::table
+            .filter(job_triggers::npub.eq(npub.to_bytes().to_vec()))
+            .filter(job_triggers::status.eq(TriggerStatus::Active.to_string()))
+            .count()
+            .get_result::<i64>(conn)?;
+
+        Ok(count as u64)
+    }
+
+    /// Locks an active trigger until the end of the transaction, None if it isn't active
+    pub fn get_active_for_update(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let res = job_triggers::table
+            .filter(job_triggers::id.eq(id))
+            .filter(job_triggers::status.eq(TriggerStatus::Active.to_string()))
+            .for_update()
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Counts another run of the trigger
+    pub fn increment_runs(conn: &mut PgConnection, id: i32) -> anyhow::Result<Self> {
+        let res = diesel::update(job_triggers::table)
+            .filter(job_triggers::id.eq(id))
+            .set(job_triggers::runs.eq(job_triggers::runs + 1))
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Stops an active trigger, None if it was already stopped
+    pub fn stop(
+        conn: &mut PgConnection,
+        id: i32,
+        status: TriggerStatus,
+        reason: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(job_triggers::table)
+            .filter(job_triggers::id.eq(id))
+            .filter(job_triggers::status.eq(TriggerStatus::Active.to_string()))
+            .set((
+                job_triggers::status.eq(status.to_string()),
+                job_triggers::stop_reason.eq(reason),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Finishes the active triggers that expired, returns them
+    pub fn finish_expired(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = diesel::update(job_triggers::table)
+            .filter(job_triggers::status.eq(TriggerStatus::Active.to_string()))
+            .filter(job_triggers::expires_at.le(diesel::dsl::now))
+            .set((
+                job_triggers::status.eq(TriggerStatus::Finished.to_string()),
+                job_triggers::stop_reason.eq("Trigger expired"),
+            ))
+            .get_results::<Self>(conn)?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,115 @@
+use crate::models::schema::lnurl_invoices;
+use bitcoin::hashes::Hash;
+use diesel::{
+    ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use lightning_invoice::Bolt11Invoice;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// An invoice handed out over LNURL-pay
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = lnurl_invoices)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct LnurlInvoice {
+    payment_hash: Vec<u8>,
+    invoice: String,
+    pub amount_msats: i64,
+    /// LUD-12 comment sent with the payment
+    pub comment: Option<String>,
+    /// Who to credit the payment to, from an npub in the comment
+    credit_npub: Option<Vec<u8>>,
+    pub settled_at: Option<chrono::NaiveDateTime>,
+    created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = lnurl_invoices)]
+struct NewLnurlInvoice<'a> {
+    payment_hash: Vec<u8>,
+    invoice: String,
+    amount_msats: i64,
+    comment: Option<&'a str>,
+    credit_npub: Option<Vec<u8>>,
+}
+
+impl LnurlInvoice {
+    pub fn payment_hash(&self) -> [u8; 32] {
+        self.payment_hash
+            .clone()
+            .try_into()
+            .expect("Invalid length")
+    }
+
+    pub fn invoice(&self) -> Bolt11Invoice {
+        Bolt11Invoice::from_str(&self.invoice).expect("Invalid invoice")
+    }
+
+    pub fn credit_npub(&self) -> Option<nostr::PublicKey> {
+        self.credit_npub
+            .as_ref()
+            .map(|npub| nostr::PublicKey::from_slice(npub).expect("Invalid key"))
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        invoice: &Bolt11Invoice,
+        comment: Option<&str>,
+        credit_npub: Option<nostr::PublicKey>,
+    ) -> anyhow::Result<Self> {
+        let new = NewLnurlInvoice {
+            payment_hash: invoice.payment_hash().to_byte_array().to_vec(),
+            invoice: invoice.to_string(),
+            amount_msats: invoice.amount_milli_satoshis().expect("Invalid amount") as i64,
+            comment,
+            credit_npub: credit_npub.map(|npub| npub.to_bytes().to_vec()),
+        };
+
+        let res = diesel::insert_into(lnurl_invoices::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn get_by_payment_hash(
+        conn: &mut PgConnection,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<Self>> {
+        let res = lnurl_invoices::table
+            .filter(lnurl_invoices::payment_hash.eq(payment_hash.to_vec()))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Invoices created after the given time that haven't been settled
+    pub fn list_unsettled(
+        conn: &mut PgConnection,
+        created_after: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = lnurl_invoices::table
+            .filter(lnurl_invoices::settled_at.is_null())
+            .filter(lnurl_invoices::created_at.gt(created_after))
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Records the invoice as settled, None if it is unknown or was already settled
+    pub fn mark_settled(
+        conn: &mut PgConnection,
+        payment_hash: [u8; 32],
+    ) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(lnurl_invoices::table)
+            .filter(lnurl_invoices::payment_hash.eq(payment_hash.to_vec()))
+            .filter(lnurl_invoices::settled_at.is_null())
+            .set(lnurl_invoices::settled_at.eq(diesel::dsl::now))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
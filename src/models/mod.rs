@@ -1,8 +1,21 @@
+use crate::models::balance_entry::BalanceEntry;
+use crate::models::balance_reservation::{BalanceReservation, ReservationStatus};
+use crate::models::cashu_redemption::CashuRedemption;
 use crate::models::event::NewEvent;
 use crate::models::event_nonce::{EventNonce, NewEventNonce};
+use crate::models::job::{Job, JobStatus};
+use crate::models::job_request::JobRequest;
+use crate::models::job_series::{JobSeries, SeriesStatus};
+use crate::models::job_trigger::{JobTrigger, TriggerStatus};
+use crate::models::lnurl_invoice::LnurlInvoice;
+use crate::models::plan::Plan;
+use crate::models::plan_usage::PlanUsage;
+use crate::models::voucher::Voucher;
+use crate::models::withdrawal::{Withdrawal, WithdrawalStatus};
 use crate::models::zap::Zap;
 use crate::models::zap_balance::ZapBalance;
 use anyhow::anyhow;
+use chrono::{Datelike, NaiveDate};
 use diesel::r2d2::{ConnectionManager, Pool};
 use diesel::{Connection, ExpressionMethods, PgConnection, RunQueryDsl};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations};
@@ -17,17 +30,33 @@ use std::collections::HashMap;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
+pub mod balance_entry;
+pub mod balance_reservation;
+pub mod cashu_redemption;
+pub mod dm_request;
 pub mod event;
 pub mod event_job;
 pub mod event_nonce;
+pub mod invoice_checkpoint;
 pub mod job;
+pub mod job_request;
+pub mod job_series;
+pub mod job_trigger;
+pub mod lnurl_invoice;
 pub mod oracle_metadata;
+pub mod plan;
+pub mod plan_usage;
+pub mod pubkey_policy;
+pub mod relay_checkpoint;
 mod schema;
+pub mod voucher;
+pub mod withdrawal;
 pub mod zap;
 pub mod zap_balance;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
 
+/// Creates a zap, linking it to the job its `e` tag points at if that job is waiting for payment
 pub fn create_zap(
     conn: &mut PgConnection,
     invoice: &Bolt11Invoice,
@@ -35,32 +64,556 @@ pub fn create_zap(
     for_npub: nostr::PublicKey,
 ) -> anyhow::Result<Zap> {
     conn.transaction(|conn| {
-        let bal = ZapBalance::get(conn, &for_npub)?;
-        if bal.is_none() {
-            ZapBalance::create(conn, for_npub)?;
-        }
+        ZapBalance::get_or_create(conn, for_npub)?;
+        let job = find_zapped_job(conn, request)?;
 
-        Zap::create(conn, invoice, request, &for_npub)
+        Zap::create(conn, invoice, request, &for_npub, job.map(|j| j.id))
     })
 }
 
+/// Finds the unpaid job a zap request is for, its `e` tag can point at the job
+/// request or at the payment-required feedback we sent for it
+pub fn find_zapped_job(
+    conn: &mut PgConnection,
+    zap_request: &Event,
+) -> anyhow::Result<Option<Job>> {
+    for event_id in zap_request.event_ids() {
+        let request_id = match JobRequest::get_by_reply_id(conn, *event_id)? {
+            Some(job_request) => job_request.event_id(),
+            None => *event_id,
+        };
+        if let Some(job) = Job::get_by_request_id(conn, request_id)? {
+            if job.status() == JobStatus::Unpaid {
+                return Ok(Some(job));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Credits a paid zap to the zapper's balance. If the zap is for a job and covers its
+/// price, the job is paid from it and the rest stays in the balance. Returns the job
+/// to run, if any.
 pub fn mark_zap_paid(
     conn: &mut PgConnection,
     payment_hash: Vec<u8>,
     note_id: EventId,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Option<Job>> {
     conn.transaction(|conn| {
         let zap = Zap::update_note_id(conn, payment_hash, note_id)?;
         let npub = zap.npub();
-        let bal = ZapBalance::get(conn, &npub)?;
-        if let Some(mut bal) = bal {
-            bal.update_balance(conn, zap.amount_msats)?;
+        let amount_msats = zap.amount_msats as u64;
+        ZapBalance::get_or_create(conn, npub)?;
+        BalanceEntry::zap_credit(conn, &npub, zap.payment_hash(), amount_msats)?;
+
+        let mut paid_job = None;
+        if let Some(job_id) = zap.job_id {
+            let job = Job::get_by_id(conn, job_id)?;
+            match job.price_msats() {
+                Some(price) if price <= amount_msats => {
+                    // the job may have been paid with its invoice in the meantime
+                    if let Some(job) = Job::mark_paid_by_zap(conn, job_id)? {
+                        BalanceEntry::job_debit(conn, &npub, job.id, price)?;
+                        info!("Zap paid {price}msats for job {}", job.id);
+                        paid_job = Some(job);
+                    }
+                }
+                _ => info!("Zap too small to pay for job {job_id}, kept as balance"),
+            }
+        }
+
+        let bal = ZapBalance::get(conn, &npub)?.ok_or(anyhow!("Missing balance"))?;
+        info!(
+            "Updated balance for {}: {}msats",
+            npub.to_bech32()?,
+            bal.balance_msats
+        );
+
+        Ok(paid_job)
+    })
+}
+
+/// Balance the pubkey can spend, what it has minus what is held for running jobs
+pub fn available_balance(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<i64> {
+    let balance = ZapBalance::get(conn, npub)?.map_or(0, |b| b.balance_msats);
+    let held = BalanceReservation::total_held(conn, npub)?;
+    Ok(balance - held)
+}
+
+/// Creates a paid job for the request and holds `max_msats` of the requester's balance for it.
+/// The balance row is locked while checking, so concurrent requests can't spend the same msats.
+/// Returns None if the requester doesn't have enough available balance.
+pub fn reserve_balance(
+    conn: &mut PgConnection,
+    request: &Event,
+    scheduled_at: Option<u64>,
+    max_msats: u64,
+) -> anyhow::Result<Option<Job>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        if !lock_available_balance(conn, &request.pubkey, max_msats)? {
+            return Ok(None);
+        }
+
+        let job = Job::create_paid(conn, request, scheduled_at, max_msats)?;
+        BalanceReservation::create(conn, &request.pubkey, job.id, max_msats)?;
+
+        Ok(Some(job))
+    })
+}
+
+/// Locks the pubkey's balance row and checks `msats` of it is available, must be called
+/// in a transaction
+fn lock_available_balance(
+    conn: &mut PgConnection,
+    npub: &nostr::PublicKey,
+    msats: u64,
+) -> anyhow::Result<bool> {
+    let Some(bal) = ZapBalance::get_for_update(conn, npub)? else {
+        return Ok(false);
+    };
+
+    let held = BalanceReservation::total_held(conn, npub)?;
+    let available = bal.balance_msats - held;
+    Ok(available >= i64::try_from(msats)?)
+}
+
+/// Charges a finished job for what it actually cost, capped at what was reserved,
+/// and releases the rest of the hold. Returns the amount charged, or None if the
+/// job was not paid for with a reservation.
+pub fn settle_reservation(
+    conn: &mut PgConnection,
+    job: &Job,
+    cost_msats: u64,
+) -> anyhow::Result<Option<u64>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(reservation) = BalanceReservation::get_held_for_update(conn, job.id)? else {
+            return Ok(None);
+        };
+
+        let charged = cost_msats.min(reservation.amount_msats());
+        if charged > 0 {
+            BalanceEntry::job_debit(conn, &reservation.npub(), job.id, charged)?;
+        }
+        BalanceReservation::close(conn, reservation.id, ReservationStatus::Settled, charged)?;
+
+        Ok(Some(charged))
+    })
+}
+
+/// Credits the price of a failed job, minus the failure fee, back to the balance it was paid from,
+/// the zapper's for jobs paid by zap, otherwise the requester's. Jobs paid with a reservation only
+/// have the failure fee charged and the rest of the hold released.
+/// Returns the amount refunded, or None if the job was not paid for or was already refunded.
+pub fn refund_job(
+    conn: &mut PgConnection,
+    job: &Job,
+    failure_fee_msats: u64,
+    reason: &str,
+) -> anyhow::Result<Option<u64>> {
+    let Some(price_msats) = job.price_msats() else {
+        return Ok(None);
+    };
+
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        if let Some(reservation) = BalanceReservation::get_held_for_update(conn, job.id)? {
+            let npub = reservation.npub();
+            let fee = failure_fee_msats.min(reservation.amount_msats());
+            let released = reservation.amount_msats() - fee;
+            if Job::set_refunded(conn, job.id, released, reason)?.is_none() {
+                return Ok(None);
+            }
+            if fee > 0 {
+                BalanceEntry::job_debit(conn, &npub, job.id, fee)?;
+            }
+            BalanceReservation::close(conn, reservation.id, ReservationStatus::Released, fee)?;
             info!(
-                "Updated balance for {}: {}msats",
+                "Released {released}msats held for {} for failed job {}",
                 npub.to_bech32()?,
-                bal.balance_msats
+                job.id
             );
+
+            return Ok(Some(released));
+        }
+
+        // a zap can pay for someone else's job, the refund goes back to whoever paid
+        let npub = match BalanceEntry::get_job_debit(conn, job.id)? {
+            Some(debit) => debit.npub(),
+            None => job.request().pubkey,
+        };
+        let refund_msats = price_msats.saturating_sub(failure_fee_msats);
+        if Job::set_refunded(conn, job.id, refund_msats, reason)?.is_none() {
+            return Ok(None);
+        }
+        // failed jobs don't count against the requester's plan
+        if PlanUsage::release(conn, job.id)? {
+            info!("Released plan allowance for failed job {}", job.id);
+            return Ok(Some(0));
+        }
+
+        if refund_msats > 0 {
+            ZapBalance::get_or_create(conn, npub)?;
+            BalanceEntry::refund(conn, &npub, job.id, refund_msats, reason)?;
         }
+        info!(
+            "Refunded {refund_msats}msats to {} for failed job {}",
+            npub.to_bech32()?,
+            job.id
+        );
+
+        Ok(Some(refund_msats))
+    })
+}
+
+/// Records a settled Cashu redemption invoice and credits it to whoever sent the token.
+/// Returns None if the invoice isn't a redemption or was already credited.
+pub fn settle_cashu_redemption(
+    conn: &mut PgConnection,
+    payment_hash: [u8; 32],
+) -> anyhow::Result<Option<CashuRedemption>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(redemption) = CashuRedemption::mark_settled(conn, payment_hash)? else {
+            return Ok(None);
+        };
+
+        let npub = redemption.npub();
+        let amount_msats = redemption.amount_msats as u64;
+        ZapBalance::get_or_create(conn, npub)?;
+        BalanceEntry::cashu_credit(conn, &npub, payment_hash, amount_msats)?;
+        info!(
+            "Credited Cashu redemption of {amount_msats}msats to {}",
+            npub.to_bech32()?
+        );
+
+        Ok(Some(redemption))
+    })
+}
+
+/// Records a settled LNURL-pay invoice and credits it to the npub from its comment, if any.
+/// Returns None if the invoice isn't an LNURL-pay invoice or was already recorded.
+pub fn settle_lnurl_invoice(
+    conn: &mut PgConnection,
+    payment_hash: [u8; 32],
+) -> anyhow::Result<Option<LnurlInvoice>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(invoice) = LnurlInvoice::mark_settled(conn, payment_hash)? else {
+            return Ok(None);
+        };
+
+        if let Some(npub) = invoice.credit_npub() {
+            let amount_msats = invoice.amount_msats as u64;
+            ZapBalance::get_or_create(conn, npub)?;
+            BalanceEntry::lnurl_credit(conn, &npub, payment_hash, amount_msats)?;
+            info!(
+                "Credited LNURL payment of {amount_msats}msats to {}",
+                npub.to_bech32()?
+            );
+        }
+
+        Ok(Some(invoice))
+    })
+}
+
+/// Creates a paid job for the request if the requester has a plan with allowance left
+/// this month. Plans are locked while checking, so concurrent requests can't go over it.
+/// Returns None if no plan covers the job.
+pub fn use_plan(
+    conn: &mut PgConnection,
+    request: &Event,
+    scheduled_at: Option<u64>,
+    compute_ms: u64,
+) -> anyhow::Result<Option<Job>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(plan_id) = lock_plan_with_allowance(conn, &request.pubkey, compute_ms)? else {
+            return Ok(None);
+        };
+
+        // nothing is charged, so nothing is refunded if it fails
+        let job = Job::create_paid(conn, request, scheduled_at, 0)?;
+        PlanUsage::create(conn, plan_id, job.id, compute_ms)?;
+        Ok(Some(job))
+    })
+}
+
+/// Locks the pubkey's active plans and finds one with room for another job of `compute_ms`
+/// this month, must be called in a transaction
+fn lock_plan_with_allowance(
+    conn: &mut PgConnection,
+    npub: &nostr::PublicKey,
+    compute_ms: u64,
+) -> anyhow::Result<Option<i32>> {
+    let plans = Plan::get_active_for_update(conn, npub)?;
+    if plans.is_empty() {
+        return Ok(None);
+    }
+
+    let today = chrono::Utc::now().date_naive();
+    let month_start = NaiveDate::from_ymd_opt(today.year(), today.month(), 1)
+        .ok_or(anyhow!("Invalid date"))?
+        .and_hms_opt(0, 0, 0)
+        .ok_or(anyhow!("Invalid date"))?;
+
+    for plan in plans {
+        let (runs, used_ms) = PlanUsage::totals_since(conn, plan.id, month_start)?;
+        if plan.monthly_runs().is_some_and(|max| runs >= max) {
+            continue;
+        }
+        if plan
+            .monthly_compute_ms()
+            .is_some_and(|max| used_ms + compute_ms > max)
+        {
+            continue;
+        }
+        return Ok(Some(plan.id));
+    }
+
+    Ok(None)
+}
+
+/// What happened when scheduling the next run of a repeating job
+#[derive(Debug)]
+pub enum NextRun {
+    Scheduled(Job),
+    /// The series was stopped in the meantime
+    Stopped,
+    /// Neither the requester's plan nor balance covers the run, the series was stopped
+    Unpaid,
+}
+
+/// Expires unpaid jobs created before the given time, returns the expired jobs. A repeating
+/// job's series is created with its first run, so it is finished if that run was never paid.
+pub fn expire_unpaid_jobs(
+    conn: &mut PgConnection,
+    created_before: chrono::NaiveDateTime,
+) -> anyhow::Result<Vec<Job>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let expired = Job::expire_unpaid(conn, created_before)?;
+
+        for job in expired.iter() {
+            let Some(series) = JobSeries::get_by_request_id(conn, job.request().id)? else {
+                continue;
+            };
+            let reason = "First run was never paid";
+            if JobSeries::stop(conn, series.id, SeriesStatus::Finished, reason)?.is_some() {
+                info!("Series {} finished: {reason}", series.id);
+            }
+        }
+
+        Ok(expired)
+    })
+}
+
+/// Creates the next run of a repeating job, paid from the requester's plan or else held from
+/// their balance, like a new request would be. The series is locked so each run is only
+/// created once.
+pub fn schedule_next_run(
+    conn: &mut PgConnection,
+    series_id: i32,
+    request: &Event,
+    scheduled_at: u64,
+    compute_ms: u64,
+) -> anyhow::Result<NextRun> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(series) = JobSeries::get_active_for_update(conn, series_id)? else {
+            return Ok(NextRun::Stopped);
+        };
+        let run = series.runs as u32 + 1;
+
+        let job =
+            if let Some(plan_id) = lock_plan_with_allowance(conn, &request.pubkey, compute_ms)? {
+                let job = Job::create_occurrence(conn, request, run, scheduled_at, 0)?;
+                PlanUsage::create(conn, plan_id, job.id, compute_ms)?;
+                job
+            } else if lock_available_balance(conn, &request.pubkey, series.run_price_msats())? {
+                let price = series.run_price_msats();
+                let job = Job::create_occurrence(conn, request, run, scheduled_at, price)?;
+                BalanceReservation::create(conn, &request.pubkey, job.id, price)?;
+                job
+            } else {
+                JobSeries::stop(
+                    conn,
+                    series.id,
+                    SeriesStatus::Finished,
+                    "Not enough balance for the next run",
+                )?;
+                return Ok(NextRun::Unpaid);
+            };
+
+        JobSeries::increment_runs(conn, series.id)?;
+        Ok(NextRun::Scheduled(job))
+    })
+}
+
+/// What happened when an event matched a trigger
+#[derive(Debug)]
+pub enum TriggerRun {
+    /// The run was queued to run now
+    Queued(Job),
+    /// The event already ran the trigger, such as when several relays send it
+    Duplicate,
+    /// The trigger already ran as often as it may this hour, the event was skipped
+    Limited,
+    /// The trigger was stopped, expired or ran out of runs
+    Stopped,
+    /// Neither the requester's plan nor balance covers the run, the trigger was stopped
+    Unpaid,
+}
+
+/// Creates a run of a trigger for an event that matched it, paid from the requester's plan or
+/// else held from their balance. The trigger is locked so each event runs it only once.
+pub fn queue_trigger_run(
+    conn: &mut PgConnection,
+    trigger_id: i32,
+    event: &Event,
+    compute_ms: u64,
+) -> anyhow::Result<TriggerRun> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(trigger) = JobTrigger::get_active_for_update(conn, trigger_id)? else {
+            return Ok(TriggerRun::Stopped);
+        };
+
+        let now = chrono::Utc::now().naive_utc();
+        let stop_reason = if trigger.expires_at() <= now.timestamp() as u64 {
+            Some("Trigger expired")
+        } else if trigger
+            .max_runs()
+            .is_some_and(|max| trigger.runs as u32 >= max)
+        {
+            Some("Ran the maximum number of times")
+        } else {
+            None
+        };
+        if let Some(reason) = stop_reason {
+            JobTrigger::stop(conn, trigger.id, TriggerStatus::Finished, reason)?;
+            return Ok(TriggerRun::Stopped);
+        }
+
+        let request = trigger.request();
+        if Job::get_triggered(conn, request.id, event.id)?.is_some() {
+            return Ok(TriggerRun::Duplicate);
+        }
+        let hour_ago = now - chrono::Duration::hours(1);
+        if Job::count_trigger_runs_since(conn, trigger.id, hour_ago)?
+            >= trigger.max_runs_per_hour() as u64
+        {
+            return Ok(TriggerRun::Limited);
+        }
+
+        let job =
+            if let Some(plan_id) = lock_plan_with_allowance(conn, &request.pubkey, compute_ms)? {
+                let job = Job::create_triggered(conn, &request, trigger.id, event, 0)?;
+                PlanUsage::create(conn, plan_id, job.id, compute_ms)?;
+                job
+            } else if lock_available_balance(conn, &request.pubkey, trigger.run_price_msats())? {
+                let price = trigger.run_price_msats();
+                let job = Job::create_triggered(conn, &request, trigger.id, event, price)?;
+                BalanceReservation::create(conn, &request.pubkey, job.id, price)?;
+                job
+            } else {
+                JobTrigger::stop(
+                    conn,
+                    trigger.id,
+                    TriggerStatus::Finished,
+                    "Not enough balance for the next run",
+                )?;
+                return Ok(TriggerRun::Unpaid);
+            };
+
+        JobTrigger::increment_runs(conn, trigger.id)?;
+        Ok(TriggerRun::Queued(job))
+    })
+}
+
+/// Redeems a voucher code into the pubkey's balance, returns the amount credited.
+/// Errors if the code can't be used or was already redeemed by the pubkey.
+pub fn redeem_voucher(
+    conn: &mut PgConnection,
+    npub: &nostr::PublicKey,
+    code: &str,
+) -> anyhow::Result<u64> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let voucher = Voucher::use_code(conn, code)?
+            .ok_or(anyhow!("Voucher is invalid, expired or fully redeemed"))?;
+
+        let amount_msats = voucher.amount_msats as u64;
+        ZapBalance::get_or_create(conn, *npub)?;
+        BalanceEntry::voucher_credit(conn, npub, voucher.id, amount_msats)
+            .map_err(|_| anyhow!("Voucher was already redeemed"))?;
+        info!(
+            "Redeemed voucher {} for {amount_msats}msats to {}",
+            voucher.id,
+            npub.to_bech32()?
+        );
+
+        Ok(amount_msats)
+    })
+}
+
+/// Uses a withdraw link to pay the invoice and debits the amount plus the fee reserve
+/// from the balance. Returns None if the link was already used or expired, errors if
+/// the balance can't cover the withdrawal.
+pub fn start_withdrawal(
+    conn: &mut PgConnection,
+    k1: &[u8],
+    invoice: &Bolt11Invoice,
+    amount_msats: u64,
+    fee_reserve_msats: u64,
+) -> anyhow::Result<Option<Withdrawal>> {
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let Some(withdrawal) =
+            Withdrawal::claim(conn, k1, invoice, amount_msats, fee_reserve_msats)?
+        else {
+            return Ok(None);
+        };
+
+        let npub = withdrawal.npub();
+        ZapBalance::get_for_update(conn, &npub)?;
+        let total = amount_msats + fee_reserve_msats;
+        if available_balance(conn, &npub)? < i64::try_from(total)? {
+            return Err(anyhow!("Insufficient balance"));
+        }
+        BalanceEntry::withdrawal(conn, &npub, withdrawal.id, total)?;
+
+        Ok(Some(withdrawal))
+    })
+}
+
+/// Records the outcome of a withdrawal payment, crediting back the unused fee reserve
+/// if it was paid or everything if it failed.
+pub fn finish_withdrawal(
+    conn: &mut PgConnection,
+    withdrawal: &Withdrawal,
+    fee_msats: Option<u64>,
+) -> anyhow::Result<()> {
+    let amount = withdrawal.amount_msats().unwrap_or(0);
+    let reserve = withdrawal.fee_reserve_msats().unwrap_or(0);
+
+    conn.transaction::<_, anyhow::Error, _>(|conn| {
+        let npub = withdrawal.npub();
+        let (status, refund, reason) = match fee_msats {
+            Some(fee) => (
+                WithdrawalStatus::Paid,
+                reserve.saturating_sub(fee),
+                "Unused fee reserve",
+            ),
+            None => (
+                WithdrawalStatus::Failed,
+                amount + reserve,
+                "Withdrawal failed",
+            ),
+        };
+
+        if Withdrawal::finish(conn, withdrawal.id, status, fee_msats)?.is_none() {
+            return Ok(());
+        }
+        if refund > 0 {
+            BalanceEntry::withdrawal_refund(conn, &npub, withdrawal.id, refund, reason)?;
+        }
+        info!(
+            "Withdrawal {} for {} {status}, credited back {refund}msats",
+            withdrawal.id,
+            npub.to_bech32()?,
+        );
 
         Ok(())
     })
@@ -299,3 +852,753 @@ impl Storage for PostgresStorage {
         .map_err(|_| Error::StorageFailure)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::models::balance_entry::BalanceEntry;
+    use diesel_migrations::MigrationHarness;
+    use nostr::{EventBuilder, Keys, Kind, Tag};
+    use std::thread;
+
+    /// Database tests need a postgres instance in `DATABASE_URL`, they are ignored unless
+    /// run with `cargo test -- --include-ignored`
+    pub(crate) fn test_pool() -> Pool<ConnectionManager<PgConnection>> {
+        let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let pool = Pool::builder()
+            .max_size(16)
+            .build(ConnectionManager::<PgConnection>::new(url))
+            .expect("Could not build connection pool");
+        pool.get()
+            .unwrap()
+            .run_pending_migrations(MIGRATIONS)
+            .expect("migrations could not run");
+        pool
+    }
+
+    fn fund(conn: &mut PgConnection, keys: &Keys, amount_msats: i64) {
+        ZapBalance::get_or_create(conn, keys.public_key()).unwrap();
+        BalanceEntry::admin_adjustment(conn, &keys.public_key(), amount_msats, "test").unwrap();
+    }
+
+    fn job_request(keys: &Keys, i: usize) -> Event {
+        EventBuilder::new(Kind::JobRequest(5600), format!("test {i}"), [])
+            .to_event(keys)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_concurrent_reservations() {
+        let pool = test_pool();
+        let keys = Keys::generate();
+        fund(&mut pool.get().unwrap(), &keys, 1_000);
+
+        // only 3 jobs of 300 msats fit in a balance of 1000
+        let handles = (0..10)
+            .map(|i| {
+                let pool = pool.clone();
+                let event = job_request(&keys, i);
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    reserve_balance(&mut conn, &event, None, 300).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let jobs = handles
+            .into_iter()
+            .filter_map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(jobs.len(), 3);
+
+        let mut conn = pool.get().unwrap();
+        let held = BalanceReservation::total_held(&mut conn, &keys.public_key()).unwrap();
+        assert_eq!(held, 900);
+
+        // settling all of them at once only ever spends what was held
+        let handles = jobs
+            .into_iter()
+            .map(|job| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    settle_reservation(&mut conn, &job, 200).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            assert_eq!(h.join().unwrap(), Some(200));
+        }
+
+        let bal = ZapBalance::get(&mut conn, &keys.public_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(bal.balance_msats, 400);
+        assert_eq!(
+            BalanceEntry::sum(&mut conn, &keys.public_key()).unwrap(),
+            400
+        );
+        let held = BalanceReservation::total_held(&mut conn, &keys.public_key()).unwrap();
+        assert_eq!(held, 0);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_claim_job_request() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+
+        // copies of a request from other relays are duplicates
+        let request = job_request(&keys, 0);
+        assert!(JobRequest::claim(&mut conn, request.id).unwrap());
+        assert!(!JobRequest::claim(&mut conn, request.id).unwrap());
+
+        // a request that failed before it was answered can be claimed again
+        assert!(JobRequest::release(&mut conn, request.id).unwrap());
+        assert!(JobRequest::claim(&mut conn, request.id).unwrap());
+
+        // once answered, the claim is kept so duplicates get the same reply
+        let reply = EventBuilder::new(Kind::JobFeedback, "", [])
+            .to_event(&keys)
+            .unwrap();
+        JobRequest::set_reply(&mut conn, request.id, &reply).unwrap();
+        assert!(!JobRequest::release(&mut conn, request.id).unwrap());
+        assert!(!JobRequest::claim(&mut conn, request.id).unwrap());
+        let saved = JobRequest::get(&mut conn, request.id).unwrap().unwrap();
+        assert_eq!(saved.reply_event(), Some(reply));
+
+        // and so is one that has a job, even without a reply
+        let request = job_request(&keys, 0);
+        assert!(JobRequest::claim(&mut conn, request.id).unwrap());
+        let payment_hash = Keys::generate().secret_key().unwrap().secret_bytes();
+        Job::create(&mut conn, payment_hash, None, &request, None, 1_000).unwrap();
+        assert!(!JobRequest::release(&mut conn, request.id).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_relay_checkpoint_advance() {
+        use crate::models::relay_checkpoint::RelayCheckpoint;
+        use nostr::Timestamp;
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let url = format!("wss://{}.example.com/", Keys::generate().public_key());
+        let last_seen = |conn: &mut PgConnection| {
+            let checkpoints = RelayCheckpoint::get_by_urls(conn, &[url.clone()]).unwrap();
+            assert_eq!(checkpoints.len(), 1);
+            checkpoints[0].last_seen_at().as_u64()
+        };
+
+        RelayCheckpoint::advance(&mut conn, &url, Timestamp::from(1_000)).unwrap();
+        assert_eq!(last_seen(&mut conn), 1_000);
+
+        RelayCheckpoint::advance(&mut conn, &url, Timestamp::from(2_000)).unwrap();
+        assert_eq!(last_seen(&mut conn), 2_000);
+
+        // an older event from the relay doesn't move it back
+        RelayCheckpoint::advance(&mut conn, &url, Timestamp::from(1_500)).unwrap();
+        assert_eq!(last_seen(&mut conn), 2_000);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_temporary_block() {
+        use crate::models::pubkey_policy::{PolicyStatus, PubkeyPolicy};
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let npub = Keys::generate().public_key();
+        let now = chrono::Utc::now().naive_utc();
+
+        let until = now + chrono::Duration::hours(1);
+        PubkeyPolicy::upsert(&mut conn, &npub, PolicyStatus::Blocked, None, Some(until)).unwrap();
+        let policy = PubkeyPolicy::get(&mut conn, &npub).unwrap().unwrap();
+        assert_eq!(policy.status(), PolicyStatus::Blocked);
+
+        // a block that ran out no longer applies
+        let until = now - chrono::Duration::seconds(1);
+        PubkeyPolicy::upsert(&mut conn, &npub, PolicyStatus::Blocked, None, Some(until)).unwrap();
+        assert!(PubkeyPolicy::get(&mut conn, &npub).unwrap().is_none());
+
+        // setting the policy by hand makes it permanent
+        PubkeyPolicy::upsert(&mut conn, &npub, PolicyStatus::Blocked, None, None).unwrap();
+        let policy = PubkeyPolicy::get(&mut conn, &npub).unwrap().unwrap();
+        assert_eq!(policy.blocked_until, None);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_settle_and_release() {
+        let pool = test_pool();
+        let keys = Keys::generate();
+        let mut conn = pool.get().unwrap();
+        fund(&mut conn, &keys, 1_000);
+
+        let first = reserve_balance(&mut conn, &job_request(&keys, 0), None, 400)
+            .unwrap()
+            .unwrap();
+        let second = reserve_balance(&mut conn, &job_request(&keys, 1), None, 400)
+            .unwrap()
+            .unwrap();
+        // 200 msats available, not enough for another job
+        assert!(
+            reserve_balance(&mut conn, &job_request(&keys, 2), None, 400)
+                .unwrap()
+                .is_none()
+        );
+
+        // charged at most what was reserved, and only once
+        assert_eq!(
+            settle_reservation(&mut conn, &first, 1_000).unwrap(),
+            Some(400)
+        );
+        assert_eq!(settle_reservation(&mut conn, &first, 1_000).unwrap(), None);
+
+        // a failed job only pays the failure fee
+        assert_eq!(
+            refund_job(&mut conn, &second, 50, "failed").unwrap(),
+            Some(350)
+        );
+        assert_eq!(refund_job(&mut conn, &second, 50, "failed").unwrap(), None);
+
+        let bal = ZapBalance::get(&mut conn, &keys.public_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(bal.balance_msats, 550);
+        assert_eq!(
+            BalanceEntry::sum(&mut conn, &keys.public_key()).unwrap(),
+            550
+        );
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_expire_unpaid_jobs() {
+        use crate::recurrence::Recurrence;
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        let event = job_request(&keys, 0);
+        let later = chrono::Utc::now().timestamp() as u64 + 3_600;
+        let job = Job::create(
+            &mut conn,
+            event.id.to_bytes(),
+            None,
+            &event,
+            Some(later),
+            1_000,
+        )
+        .unwrap();
+        let recurrence = Recurrence::interval(3_600).unwrap();
+        let series = JobSeries::create(&mut conn, &event, &recurrence, None, None, 1_000).unwrap();
+        assert_eq!(
+            Job::count_pending_scheduled(&mut conn, &keys.public_key()).unwrap(),
+            1
+        );
+
+        // not old enough yet
+        let cutoff = job.created_at - chrono::Duration::seconds(1);
+        let expired = expire_unpaid_jobs(&mut conn, cutoff).unwrap();
+        assert!(expired.iter().all(|j| j.id != job.id));
+
+        let cutoff = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1);
+        let expired = expire_unpaid_jobs(&mut conn, cutoff).unwrap();
+        let expired = expired.into_iter().find(|j| j.id == job.id).unwrap();
+        assert_eq!(expired.status(), JobStatus::Expired);
+
+        // the series never had a paid run, so it won't get any more
+        let series = JobSeries::get_by_request_id(&mut conn, series.request_id())
+            .unwrap()
+            .unwrap();
+        assert_eq!(series.status(), SeriesStatus::Finished);
+
+        // an expired job can't be paid and no longer counts as waiting to run
+        assert!(Job::mark_paid(&mut conn, job.id).unwrap().is_none());
+        assert_eq!(
+            Job::count_pending_scheduled(&mut conn, &keys.public_key()).unwrap(),
+            0
+        );
+
+        let deleted =
+            Job::delete_expired(&mut conn, cutoff + chrono::Duration::seconds(1)).unwrap();
+        assert!(deleted >= 1);
+        assert!(
+            Job::get_by_payment_hash(&mut conn, &event.id.to_bytes().to_vec())
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    fn test_invoice(amount_msats: u64) -> Bolt11Invoice {
+        use bitcoin::hashes::{sha256, Hash};
+        use bitcoin::key::Secp256k1;
+        use bitcoin::secp256k1::SecretKey;
+        use lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+
+        let preimage = Keys::generate().secret_key().unwrap().secret_bytes();
+        InvoiceBuilder::new(Currency::Regtest)
+            .amount_milli_satoshis(amount_msats)
+            .description("test".to_string())
+            .current_timestamp()
+            .payment_hash(sha256::Hash::hash(&preimage))
+            .payment_secret(PaymentSecret([0; 32]))
+            .min_final_cltv_expiry_delta(144)
+            .build_signed(|hash| {
+                let key = SecretKey::from_slice(&[1; 32]).unwrap();
+                Secp256k1::signing_only().sign_ecdsa_recoverable(hash, &key)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_zap_pays_for_job() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        let request = job_request(&keys, 0);
+        let payment_hash = Keys::generate().secret_key().unwrap().secret_bytes();
+        let job = Job::create(&mut conn, payment_hash, None, &request, None, 1_000).unwrap();
+
+        // zapping the payment-required feedback pays for the job, the rest goes to the balance
+        let feedback = EventBuilder::new(Kind::JobFeedback, "", [])
+            .to_event(&keys)
+            .unwrap();
+        JobRequest::set_reply(&mut conn, request.id, &feedback).unwrap();
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "", [Tag::event(feedback.id)])
+            .to_event(&keys)
+            .unwrap();
+        let invoice = test_invoice(1_500);
+        let zap = create_zap(&mut conn, &invoice, &zap_request, keys.public_key()).unwrap();
+        assert_eq!(zap.job_id, Some(job.id));
+
+        let paid = mark_zap_paid(&mut conn, zap.payment_hash().to_vec(), zap_request.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(paid.id, job.id);
+        assert_eq!(paid.status(), JobStatus::Paid);
+        let bal = ZapBalance::get(&mut conn, &keys.public_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(bal.balance_msats, 500);
+
+        // the job is paid now, another zap of the request is just a top-up
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "", [Tag::event(request.id)])
+            .to_event(&keys)
+            .unwrap();
+        let invoice = test_invoice(2_000);
+        let zap = create_zap(&mut conn, &invoice, &zap_request, keys.public_key()).unwrap();
+        assert_eq!(zap.job_id, None);
+        assert!(
+            mark_zap_paid(&mut conn, zap.payment_hash().to_vec(), zap_request.id)
+                .unwrap()
+                .is_none()
+        );
+        let bal = ZapBalance::get(&mut conn, &keys.public_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(bal.balance_msats, 2_500);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_refund_zap_paid_job_to_zapper() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let requester = Keys::generate();
+        let zapper = Keys::generate();
+        let request = job_request(&requester, 0);
+        let payment_hash = Keys::generate().secret_key().unwrap().secret_bytes();
+        let job = Job::create(&mut conn, payment_hash, None, &request, None, 1_000).unwrap();
+
+        let zap_request = EventBuilder::new(Kind::ZapRequest, "", [Tag::event(request.id)])
+            .to_event(&zapper)
+            .unwrap();
+        let invoice = test_invoice(1_000);
+        let zap = create_zap(&mut conn, &invoice, &zap_request, zapper.public_key()).unwrap();
+        let paid = mark_zap_paid(&mut conn, zap.payment_hash().to_vec(), zap_request.id)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            refund_job(&mut conn, &paid, 100, "failed").unwrap(),
+            Some(900)
+        );
+        let bal = ZapBalance::get(&mut conn, &zapper.public_key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(bal.balance_msats, 900);
+        assert!(ZapBalance::get(&mut conn, &requester.public_key())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_cashu_credit_pays_for_job() {
+        use bitcoin::hashes::Hash;
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        let invoice = test_invoice(5_000);
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        CashuRedemption::create(&mut conn, &invoice, &keys.public_key(), "https://mint.test")
+            .unwrap();
+
+        let redemption = settle_cashu_redemption(&mut conn, payment_hash)
+            .unwrap()
+            .unwrap();
+        assert_eq!(redemption.npub(), keys.public_key());
+        // a redemption is only credited once
+        assert!(settle_cashu_redemption(&mut conn, payment_hash)
+            .unwrap()
+            .is_none());
+
+        // the job is paid from the credit and the excess stays in the balance
+        let request = job_request(&keys, 0);
+        let job = reserve_balance(&mut conn, &request, None, 3_000)
+            .unwrap()
+            .unwrap();
+        settle_reservation(&mut conn, &job, 3_000).unwrap();
+        assert_eq!(
+            available_balance(&mut conn, &keys.public_key()).unwrap(),
+            2_000
+        );
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_lnurl_comment_credit() {
+        use bitcoin::hashes::Hash;
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+
+        let invoice = test_invoice(2_000);
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        LnurlInvoice::create(&mut conn, &invoice, Some("for me"), Some(keys.public_key())).unwrap();
+
+        // credited once, no matter how often the settlement is seen
+        assert!(settle_lnurl_invoice(&mut conn, payment_hash)
+            .unwrap()
+            .is_some());
+        assert!(settle_lnurl_invoice(&mut conn, payment_hash)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            available_balance(&mut conn, &keys.public_key()).unwrap(),
+            2_000
+        );
+
+        // payments without an npub only record the settlement
+        let other = test_invoice(1_000);
+        LnurlInvoice::create(&mut conn, &other, None, None).unwrap();
+        let settled = settle_lnurl_invoice(&mut conn, other.payment_hash().to_byte_array())
+            .unwrap()
+            .unwrap();
+        assert!(settled.settled_at.is_some());
+        assert!(settle_lnurl_invoice(&mut conn, [0; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_plan_allowance() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+
+        // no plan, nothing covered
+        assert!(use_plan(&mut conn, &job_request(&keys, 0), None, 100)
+            .unwrap()
+            .is_none());
+
+        let plan = Plan::create(
+            &mut conn,
+            &keys.public_key(),
+            Some(2),
+            Some(500),
+            None,
+            None,
+        )
+        .unwrap();
+        let job = use_plan(&mut conn, &job_request(&keys, 1), None, 300)
+            .unwrap()
+            .unwrap();
+        assert_eq!(job.price_msats(), Some(0));
+
+        // over the compute allowance
+        assert!(use_plan(&mut conn, &job_request(&keys, 2), None, 300)
+            .unwrap()
+            .is_none());
+        // a failed job gives its allowance back
+        assert_eq!(refund_job(&mut conn, &job, 100, "test").unwrap(), Some(0));
+        use_plan(&mut conn, &job_request(&keys, 3), None, 300)
+            .unwrap()
+            .unwrap();
+        use_plan(&mut conn, &job_request(&keys, 4), None, 100)
+            .unwrap()
+            .unwrap();
+        // out of runs
+        assert!(use_plan(&mut conn, &job_request(&keys, 5), None, 10)
+            .unwrap()
+            .is_none());
+
+        Plan::revoke(&mut conn, plan.id).unwrap().unwrap();
+        assert!(Plan::revoke(&mut conn, plan.id).unwrap().is_none());
+        assert_eq!(BalanceEntry::sum(&mut conn, &keys.public_key()).unwrap(), 0);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_redeem_voucher() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        let other = Keys::generate();
+        let code = hex::encode(Keys::generate().public_key().to_bytes());
+
+        let voucher = Voucher::create(&mut conn, &code, 5_000, 2, None, None).unwrap();
+        assert_eq!(
+            redeem_voucher(&mut conn, &keys.public_key(), &code).unwrap(),
+            5_000
+        );
+        // once per pubkey, and the failed attempt doesn't use up a redemption
+        assert!(redeem_voucher(&mut conn, &keys.public_key(), &code).is_err());
+        redeem_voucher(&mut conn, &other.public_key(), &code).unwrap();
+        assert!(redeem_voucher(&mut conn, &Keys::generate().public_key(), &code).is_err());
+        assert_eq!(
+            available_balance(&mut conn, &keys.public_key()).unwrap(),
+            5_000
+        );
+
+        let revoked = hex::encode(Keys::generate().public_key().to_bytes());
+        let voucher2 = Voucher::create(&mut conn, &revoked, 1_000, 10, None, None).unwrap();
+        Voucher::revoke(&mut conn, voucher2.id).unwrap().unwrap();
+        assert!(redeem_voucher(&mut conn, &keys.public_key(), &revoked).is_err());
+        assert!(redeem_voucher(&mut conn, &keys.public_key(), "unknown").is_err());
+        assert!(Voucher::revoke(&mut conn, voucher.id).unwrap().is_some());
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_schedule_next_run() {
+        use crate::recurrence::Recurrence;
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        fund(&mut conn, &keys, 250);
+
+        let request = job_request(&keys, 0);
+        let recurrence = Recurrence::interval(3_600).unwrap();
+        let series =
+            JobSeries::create(&mut conn, &request, &recurrence, None, Some(10), 100).unwrap();
+        // seeing the request again doesn't start another series
+        let again =
+            JobSeries::create(&mut conn, &request, &recurrence, None, Some(10), 100).unwrap();
+        assert_eq!(again.id, series.id);
+
+        // each run holds its price from the balance
+        let now = chrono::Utc::now().timestamp() as u64;
+        for i in 1..=2 {
+            match schedule_next_run(&mut conn, series.id, &request, now + i * 3_600, 10).unwrap() {
+                NextRun::Scheduled(job) => assert_eq!(job.price_msats(), Some(100)),
+                res => panic!("unexpected {res:?}"),
+            }
+        }
+        assert_eq!(
+            available_balance(&mut conn, &keys.public_key()).unwrap(),
+            50
+        );
+
+        // the series stops once the balance can't cover a run
+        assert!(matches!(
+            schedule_next_run(&mut conn, series.id, &request, now + 3 * 3_600, 10).unwrap(),
+            NextRun::Unpaid
+        ));
+        let series = JobSeries::get_by_request_id(&mut conn, request.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(series.status(), SeriesStatus::Finished);
+        assert_eq!(series.runs, 3);
+        assert!(matches!(
+            schedule_next_run(&mut conn, series.id, &request, now + 4 * 3_600, 10).unwrap(),
+            NextRun::Stopped
+        ));
+    }
+
+    /// A paid scheduled job that is due now. It is inserted for later and then moved to the
+    /// database's clock, which rejects run dates in the past.
+    fn ready_job(conn: &mut PgConnection, request: &Event, price_msats: u64) -> Job {
+        use crate::models::schema::jobs;
+        use diesel::dsl::sql;
+        use diesel::sql_types::{Nullable, Timestamp};
+
+        let later = chrono::Utc::now().timestamp() as u64 + 60;
+        let job = Job::create_paid(conn, request, Some(later), price_msats).unwrap();
+        diesel::update(jobs::table)
+            .filter(jobs::id.eq(job.id))
+            .set(jobs::scheduled_at.eq(sql::<Nullable<Timestamp>>("NOW()")))
+            .get_result::<Job>(conn)
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_lease_ready_jobs() {
+        use crate::models::schema::jobs;
+
+        let pool = test_pool();
+        let keys = Keys::generate();
+        let request = job_request(&keys, 0);
+        let job = ready_job(&mut pool.get().unwrap(), &request, 0);
+
+        // workers leasing at the same time never get the same job
+        let handles = (0..4)
+            .map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    Job::lease_ready_jobs(&mut conn, 60, 1_000).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let leased = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .filter(|j| j.id == job.id)
+            .collect::<Vec<_>>();
+        assert_eq!(leased.len(), 1);
+        assert_eq!(leased[0].attempts, 1);
+
+        let mut conn = pool.get().unwrap();
+        let leased = Job::lease_ready_jobs(&mut conn, 60, 1_000).unwrap();
+        assert!(leased.iter().all(|j| j.id != job.id));
+
+        // once the lease runs out, as if the worker crashed, another worker takes the job
+        diesel::update(jobs::table)
+            .filter(jobs::id.eq(job.id))
+            .set(
+                jobs::locked_until
+                    .eq(chrono::Utc::now().naive_utc() - chrono::Duration::seconds(1)),
+            )
+            .execute(&mut conn)
+            .unwrap();
+        let leased = Job::lease_ready_jobs(&mut conn, 60, 1_000).unwrap();
+        let reclaimed = leased.iter().find(|j| j.id == job.id).unwrap();
+        assert_eq!(reclaimed.attempts, 2);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_retry_and_replay_failed_job() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        let request = job_request(&keys, 0);
+        let job = ready_job(&mut conn, &request, 1_000);
+        let leased = Job::lease_ready_jobs(&mut conn, 60, 1_000).unwrap();
+        assert!(leased.iter().any(|j| j.id == job.id));
+
+        // a retried job waits out its backoff
+        let retried = Job::retry_later(&mut conn, job.id, "download failed", 3_600)
+            .unwrap()
+            .unwrap();
+        assert_eq!(retried.last_error.as_deref(), Some("download failed"));
+        let leased = Job::lease_ready_jobs(&mut conn, 60, 1_000).unwrap();
+        assert!(leased.iter().all(|j| j.id != job.id));
+
+        // out of attempts it is refunded and dead-lettered
+        refund_job(&mut conn, &job, 0, "download failed").unwrap();
+        let failed = Job::set_failed(&mut conn, job.id, "download failed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(failed.status(), JobStatus::Failed);
+        assert_eq!(failed.refunded_msats(), Some(1_000));
+        let list = Job::list_failed(&mut conn, 1_000).unwrap();
+        assert!(list.iter().any(|j| j.id == job.id));
+
+        // a replay runs it again now, for free
+        let replayed = Job::replay(&mut conn, job.id).unwrap().unwrap();
+        assert_eq!(replayed.status(), JobStatus::Paid);
+        assert_eq!(replayed.price_msats(), Some(0));
+        assert!(Job::replay(&mut conn, job.id).unwrap().is_none());
+        let leased = Job::lease_ready_jobs(&mut conn, 60, 1_000).unwrap();
+        let leased = leased.iter().find(|j| j.id == job.id).unwrap();
+        assert_eq!(leased.attempts, 1);
+    }
+
+    #[test]
+    #[ignore = "needs DATABASE_URL"]
+    fn test_queue_trigger_run() {
+        use crate::wasm_handler::TriggerParams;
+
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let keys = Keys::generate();
+        fund(&mut conn, &keys, 250);
+
+        let params = TriggerParams {
+            filter: nostr::Filter::new().kind(Kind::TextNote),
+            expires_at: chrono::Utc::now().timestamp() as u64 + 3_600,
+            max_runs: None,
+            max_runs_per_hour: None,
+        };
+        let request = job_request(&keys, 0);
+        let trigger = JobTrigger::create(&mut conn, &request, &params, 2, 100).unwrap();
+        let notes = (0..4)
+            .map(|i| {
+                EventBuilder::new(Kind::TextNote, format!("note {i}"), [])
+                    .to_event(&Keys::generate())
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        // each event runs the trigger once, paid from the balance
+        match queue_trigger_run(&mut conn, trigger.id, &notes[0], 10).unwrap() {
+            TriggerRun::Queued(job) => {
+                assert_eq!(job.price_msats(), Some(100));
+                assert_eq!(job.trigger_event(), Some(notes[0].clone()));
+            }
+            res => panic!("unexpected {res:?}"),
+        }
+        assert!(matches!(
+            queue_trigger_run(&mut conn, trigger.id, &notes[0], 10).unwrap(),
+            TriggerRun::Duplicate
+        ));
+        assert!(matches!(
+            queue_trigger_run(&mut conn, trigger.id, &notes[1], 10).unwrap(),
+            TriggerRun::Queued(_)
+        ));
+        // only twice an hour
+        assert!(matches!(
+            queue_trigger_run(&mut conn, trigger.id, &notes[2], 10).unwrap(),
+            TriggerRun::Limited
+        ));
+        assert_eq!(
+            available_balance(&mut conn, &keys.public_key()).unwrap(),
+            50
+        );
+
+        // the trigger stops once the balance can't cover a run
+        let request = job_request(&keys, 1);
+        let trigger = JobTrigger::create(&mut conn, &request, &params, 10, 100).unwrap();
+        assert!(matches!(
+            queue_trigger_run(&mut conn, trigger.id, &notes[2], 10).unwrap(),
+            TriggerRun::Unpaid
+        ));
+        let trigger = JobTrigger::get_by_request_id(&mut conn, request.id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(trigger.status(), TriggerStatus::Finished);
+        assert!(matches!(
+            queue_trigger_run(&mut conn, trigger.id, &notes[3], 10).unwrap(),
+            TriggerRun::Stopped
+        ));
+    }
+}
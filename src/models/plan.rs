@@ -0,0 +1,111 @@
+use crate::models::schema::plans;
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Identifiable, Insertable, OptionalExtension,
+    PgConnection, QueryDsl, Queryable, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+
+/// A prepaid plan granting an npub a monthly allowance of job runs and/or compute time
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = plans)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Plan {
+    pub id: i32,
+    npub: Vec<u8>,
+    monthly_runs: Option<i32>,
+    monthly_compute_ms: Option<i64>,
+    pub description: Option<String>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = plans)]
+struct NewPlan<'a> {
+    npub: Vec<u8>,
+    monthly_runs: Option<i32>,
+    monthly_compute_ms: Option<i64>,
+    description: Option<&'a str>,
+    expires_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Plan {
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    /// Job runs allowed per month, None if unlimited
+    pub fn monthly_runs(&self) -> Option<u64> {
+        self.monthly_runs.map(|r| r as u64)
+    }
+
+    /// Compute time allowed per month, None if unlimited
+    pub fn monthly_compute_ms(&self) -> Option<u64> {
+        self.monthly_compute_ms.map(|c| c as u64)
+    }
+
+    pub fn create(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        monthly_runs: Option<u64>,
+        monthly_compute_ms: Option<u64>,
+        description: Option<&str>,
+        expires_at: Option<chrono::NaiveDateTime>,
+    ) -> anyhow::Result<Self> {
+        let new = NewPlan {
+            npub: npub.to_bytes().to_vec(),
+            monthly_runs: monthly_runs.map(i32::try_from).transpose()?,
+            monthly_compute_ms: monthly_compute_ms.map(i64::try_from).transpose()?,
+            description,
+            expires_at,
+        };
+
+        let res = diesel::insert_into(plans::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn list(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = plans::table
+            .order_by(plans::created_at.desc())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// The npub's plans that are neither revoked nor expired, locked until the end of
+    /// the transaction so concurrent requests can't use the same allowance
+    pub fn get_active_for_update(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = plans::table
+            .filter(plans::npub.eq(npub.to_bytes().to_vec()))
+            .filter(plans::revoked_at.is_null())
+            .filter(
+                plans::expires_at
+                    .is_null()
+                    .or(plans::expires_at.gt(diesel::dsl::now)),
+            )
+            .order_by(plans::id.asc())
+            .for_update()
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Revokes the plan, None if it doesn't exist or was already revoked
+    pub fn revoke(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(plans::table)
+            .filter(plans::id.eq(id))
+            .filter(plans::revoked_at.is_null())
+            .set(plans::revoked_at.eq(diesel::dsl::now))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
@@ -0,0 +1,73 @@
+use crate::models::schema::plan_usages;
+use diesel::dsl::sql;
+use diesel::sql_types::BigInt;
+use diesel::{ExpressionMethods, Insertable, PgConnection, QueryDsl, Queryable, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+/// A job run under a plan, counted against its allowance
+#[derive(Queryable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = plan_usages)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PlanUsage {
+    pub id: i32,
+    pub plan_id: i32,
+    pub job_id: i32,
+    compute_ms: i64,
+    created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = plan_usages)]
+struct NewPlanUsage {
+    plan_id: i32,
+    job_id: i32,
+    compute_ms: i64,
+}
+
+impl PlanUsage {
+    pub fn create(
+        conn: &mut PgConnection,
+        plan_id: i32,
+        job_id: i32,
+        compute_ms: u64,
+    ) -> anyhow::Result<Self> {
+        let new = NewPlanUsage {
+            plan_id,
+            job_id,
+            compute_ms: i64::try_from(compute_ms)?,
+        };
+
+        let res = diesel::insert_into(plan_usages::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Runs and compute time used under the plan since the given time
+    pub fn totals_since(
+        conn: &mut PgConnection,
+        plan_id: i32,
+        since: chrono::NaiveDateTime,
+    ) -> anyhow::Result<(u64, u64)> {
+        let (runs, compute_ms) = plan_usages::table
+            .filter(plan_usages::plan_id.eq(plan_id))
+            .filter(plan_usages::created_at.ge(since))
+            .select((
+                sql::<BigInt>("COUNT(*)"),
+                sql::<BigInt>("COALESCE(SUM(compute_ms), 0)::BIGINT"),
+            ))
+            .first::<(i64, i64)>(conn)?;
+
+        Ok((runs as u64, compute_ms as u64))
+    }
+
+    /// Gives the job's allowance back to its plan, for jobs that failed
+    pub fn release(conn: &mut PgConnection, job_id: i32) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(plan_usages::table)
+            .filter(plan_usages::job_id.eq(job_id))
+            .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+}
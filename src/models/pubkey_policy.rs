@@ -0,0 +1,135 @@
+use crate::models::schema::pubkey_policies;
+use diesel::{
+    AsChangeset, BoolExpressionMethods, ExpressionMethods, Identifiable, Insertable,
+    OptionalExtension, PgConnection, QueryDsl, Queryable, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyStatus {
+    Allowed,
+    Blocked,
+}
+
+impl fmt::Display for PolicyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyStatus::Allowed => write!(f, "allowed"),
+            PolicyStatus::Blocked => write!(f, "blocked"),
+        }
+    }
+}
+
+impl FromStr for PolicyStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allowed" => Ok(PolicyStatus::Allowed),
+            "blocked" => Ok(PolicyStatus::Blocked),
+            _ => Err(anyhow::anyhow!("invalid policy status: {s}")),
+        }
+    }
+}
+
+#[derive(
+    Queryable,
+    Insertable,
+    Identifiable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+)]
+#[diesel(primary_key(npub))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct PubkeyPolicy {
+    npub: Vec<u8>,
+    status: String,
+    pub reason: Option<String>,
+    created_at: chrono::NaiveDateTime,
+    updated_at: chrono::NaiveDateTime,
+    /// When a temporary block runs out, None for permanent policies
+    pub blocked_until: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = pubkey_policies)]
+#[diesel(treat_none_as_null = true)]
+struct NewPubkeyPolicy<'a> {
+    npub: Vec<u8>,
+    status: String,
+    reason: Option<&'a str>,
+    blocked_until: Option<chrono::NaiveDateTime>,
+}
+
+impl PubkeyPolicy {
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn status(&self) -> PolicyStatus {
+        PolicyStatus::from_str(&self.status).expect("Invalid status")
+    }
+
+    /// The policy for the given key, temporary blocks that ran out are ignored
+    pub fn get(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<Option<Self>> {
+        let res = pubkey_policies::table
+            .filter(pubkey_policies::npub.eq(npub.to_bytes().to_vec()))
+            .filter(
+                pubkey_policies::blocked_until
+                    .is_null()
+                    .or(pubkey_policies::blocked_until.gt(diesel::dsl::now)),
+            )
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    pub fn list(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = pubkey_policies::table
+            .order_by(pubkey_policies::created_at.desc())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn upsert(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        status: PolicyStatus,
+        reason: Option<&str>,
+        blocked_until: Option<chrono::NaiveDateTime>,
+    ) -> anyhow::Result<Self> {
+        let new = NewPubkeyPolicy {
+            npub: npub.to_bytes().to_vec(),
+            status: status.to_string(),
+            reason,
+            blocked_until,
+        };
+
+        let res = diesel::insert_into(pubkey_policies::table)
+            .values(&new)
+            .on_conflict(pubkey_policies::npub)
+            .do_update()
+            .set(&new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn delete(conn: &mut PgConnection, npub: &nostr::PublicKey) -> anyhow::Result<bool> {
+        let deleted = diesel::delete(pubkey_policies::table)
+            .filter(pubkey_policies::npub.eq(npub.to_bytes().to_vec()))
+            .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+}
@@ -0,0 +1,74 @@
+use crate::models::schema::relay_checkpoints;
+use diesel::sql_types::BigInt;
+use diesel::{
+    AsChangeset, ExpressionMethods, Identifiable, Insertable, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use nostr::Timestamp;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Queryable,
+    Insertable,
+    Identifiable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+)]
+#[diesel(primary_key(relay_url))]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RelayCheckpoint {
+    pub relay_url: String,
+    last_seen_at: i64,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable, AsChangeset)]
+#[diesel(table_name = relay_checkpoints)]
+struct NewRelayCheckpoint<'a> {
+    relay_url: &'a str,
+    last_seen_at: i64,
+}
+
+impl RelayCheckpoint {
+    pub fn last_seen_at(&self) -> Timestamp {
+        Timestamp::from(self.last_seen_at as u64)
+    }
+
+    pub fn get_by_urls(conn: &mut PgConnection, urls: &[String]) -> anyhow::Result<Vec<Self>> {
+        let res = relay_checkpoints::table
+            .filter(relay_checkpoints::relay_url.eq_any(urls))
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Moves the checkpoint for the relay forward to `seen_at`, never backwards
+    pub fn advance(
+        conn: &mut PgConnection,
+        relay_url: &str,
+        seen_at: Timestamp,
+    ) -> anyhow::Result<()> {
+        let new = NewRelayCheckpoint {
+            relay_url,
+            last_seen_at: seen_at.as_u64() as i64,
+        };
+
+        diesel::insert_into(relay_checkpoints::table)
+            .values(new)
+            .on_conflict(relay_checkpoints::relay_url)
+            .do_update()
+            .set(
+                relay_checkpoints::last_seen_at.eq(diesel::dsl::sql::<BigInt>(
+                    "GREATEST(relay_checkpoints.last_seen_at, excluded.last_seen_at)",
+                )),
+            )
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
@@ -1,5 +1,54 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    balance_entries (id) {
+        id -> Int4,
+        npub -> Bytea,
+        kind -> Text,
+        amount_msats -> Int8,
+        zap_payment_hash -> Nullable<Bytea>,
+        job_id -> Nullable<Int4>,
+        description -> Nullable<Text>,
+        created_at -> Timestamp,
+        withdrawal_id -> Nullable<Int4>,
+        cashu_payment_hash -> Nullable<Bytea>,
+        lnurl_payment_hash -> Nullable<Bytea>,
+        voucher_id -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    balance_reservations (id) {
+        id -> Int4,
+        npub -> Bytea,
+        job_id -> Int4,
+        amount_msats -> Int8,
+        status -> Text,
+        settled_msats -> Nullable<Int8>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    cashu_redemptions (payment_hash) {
+        payment_hash -> Bytea,
+        invoice -> Text,
+        amount_msats -> Int8,
+        npub -> Bytea,
+        mint -> Text,
+        settled_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    dm_requests (event_id) {
+        event_id -> Bytea,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     event_jobs (job_id) {
         job_id -> Int4,
@@ -34,6 +83,60 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    invoice_checkpoints (node_pubkey) {
+        node_pubkey -> Text,
+        settle_index -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    job_requests (event_id) {
+        event_id -> Bytea,
+        reply_event -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    job_series (id) {
+        id -> Int4,
+        request_id -> Bytea,
+        npub -> Bytea,
+        cron -> Nullable<Text>,
+        interval_secs -> Nullable<Int8>,
+        end_at -> Nullable<Timestamp>,
+        max_runs -> Nullable<Int4>,
+        runs -> Int4,
+        run_price_msats -> Int8,
+        status -> Text,
+        stop_reason -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    job_triggers (id) {
+        id -> Int4,
+        request_id -> Bytea,
+        npub -> Bytea,
+        request -> Jsonb,
+        filter -> Jsonb,
+        expires_at -> Timestamp,
+        max_runs -> Nullable<Int4>,
+        max_runs_per_hour -> Int4,
+        runs -> Int4,
+        run_price_msats -> Int8,
+        status -> Text,
+        stop_reason -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     jobs (id) {
         id -> Int4,
@@ -43,6 +146,32 @@ diesel::table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         scheduled_at -> Nullable<Timestamp>,
+        status -> Text,
+        price_msats -> Nullable<Int8>,
+        refunded_msats -> Nullable<Int8>,
+        refund_reason -> Nullable<Text>,
+        preimage -> Nullable<Bytea>,
+        module_checksum -> Nullable<Text>,
+        runtime_ms -> Nullable<Int8>,
+        charged_msats -> Nullable<Int8>,
+        error_code -> Nullable<Text>,
+        locked_until -> Nullable<Timestamp>,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        trigger_id -> Nullable<Int4>,
+        trigger_event -> Nullable<Jsonb>,
+    }
+}
+
+diesel::table! {
+    lnurl_invoices (payment_hash) {
+        payment_hash -> Bytea,
+        invoice -> Text,
+        amount_msats -> Int8,
+        comment -> Nullable<Text>,
+        credit_npub -> Nullable<Bytea>,
+        settled_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
     }
 }
 
@@ -56,10 +185,83 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    plan_usages (id) {
+        id -> Int4,
+        plan_id -> Int4,
+        job_id -> Int4,
+        compute_ms -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    plans (id) {
+        id -> Int4,
+        npub -> Bytea,
+        monthly_runs -> Nullable<Int4>,
+        monthly_compute_ms -> Nullable<Int8>,
+        description -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pubkey_policies (npub) {
+        npub -> Bytea,
+        status -> Text,
+        reason -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        blocked_until -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    relay_checkpoints (relay_url) {
+        relay_url -> Text,
+        last_seen_at -> Int8,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    vouchers (id) {
+        id -> Int4,
+        code -> Text,
+        amount_msats -> Int8,
+        max_redemptions -> Int4,
+        redemptions -> Int4,
+        description -> Nullable<Text>,
+        expires_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    withdrawals (id) {
+        id -> Int4,
+        npub -> Bytea,
+        k1 -> Bytea,
+        auth_event_id -> Bytea,
+        status -> Text,
+        invoice -> Nullable<Text>,
+        amount_msats -> Nullable<Int8>,
+        fee_reserve_msats -> Nullable<Int8>,
+        fee_msats -> Nullable<Int8>,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     zap_balances (npub) {
         npub -> Bytea,
-        balance_msats -> Int4,
+        balance_msats -> Int8,
         created_at -> Timestamp,
     }
 }
@@ -68,25 +270,54 @@ diesel::table! {
     zaps (payment_hash) {
         payment_hash -> Bytea,
         invoice -> Text,
-        amount_msats -> Int4,
+        amount_msats -> Int8,
         request -> Jsonb,
         npub -> Bytea,
         note_id -> Nullable<Bytea>,
         created_at -> Timestamp,
+        job_id -> Nullable<Int4>,
     }
 }
 
+diesel::joinable!(balance_entries -> jobs (job_id));
+diesel::joinable!(balance_entries -> lnurl_invoices (lnurl_payment_hash));
+diesel::joinable!(balance_entries -> zap_balances (npub));
+diesel::joinable!(balance_entries -> zaps (zap_payment_hash));
+diesel::joinable!(balance_reservations -> jobs (job_id));
+diesel::joinable!(balance_reservations -> zap_balances (npub));
+diesel::joinable!(balance_entries -> withdrawals (withdrawal_id));
+diesel::joinable!(balance_entries -> vouchers (voucher_id));
 diesel::joinable!(event_jobs -> events (event_id));
 diesel::joinable!(event_jobs -> jobs (job_id));
 diesel::joinable!(event_nonces -> events (event_id));
+diesel::joinable!(jobs -> job_triggers (trigger_id));
+diesel::joinable!(plan_usages -> jobs (job_id));
+diesel::joinable!(plan_usages -> plans (plan_id));
+diesel::joinable!(withdrawals -> zap_balances (npub));
+diesel::joinable!(zaps -> jobs (job_id));
 diesel::joinable!(zaps -> zap_balances (npub));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    balance_entries,
+    balance_reservations,
+    cashu_redemptions,
+    dm_requests,
     event_jobs,
     event_nonces,
     events,
+    invoice_checkpoints,
+    job_requests,
+    job_series,
+    job_triggers,
     jobs,
+    lnurl_invoices,
     oracle_metadata,
+    plan_usages,
+    plans,
+    pubkey_policies,
+    relay_checkpoints,
+    vouchers,
+    withdrawals,
     zap_balances,
     zaps,
 );
@@ -0,0 +1,97 @@
+use crate::models::schema::vouchers;
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, Identifiable, Insertable, OptionalExtension,
+    PgConnection, QueryDsl, Queryable, RunQueryDsl,
+};
+use serde::{Deserialize, Serialize};
+
+/// A code that credits a balance when redeemed
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = vouchers)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Voucher {
+    pub id: i32,
+    pub code: String,
+    pub amount_msats: i64,
+    /// How many npubs can redeem it
+    pub max_redemptions: i32,
+    pub redemptions: i32,
+    pub description: Option<String>,
+    pub expires_at: Option<chrono::NaiveDateTime>,
+    pub revoked_at: Option<chrono::NaiveDateTime>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = vouchers)]
+struct NewVoucher<'a> {
+    code: &'a str,
+    amount_msats: i64,
+    max_redemptions: i32,
+    description: Option<&'a str>,
+    expires_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Voucher {
+    pub fn create(
+        conn: &mut PgConnection,
+        code: &str,
+        amount_msats: u64,
+        max_redemptions: u32,
+        description: Option<&str>,
+        expires_at: Option<chrono::NaiveDateTime>,
+    ) -> anyhow::Result<Self> {
+        let new = NewVoucher {
+            code,
+            amount_msats: i64::try_from(amount_msats)?,
+            max_redemptions: i32::try_from(max_redemptions)?,
+            description,
+            expires_at,
+        };
+
+        let res = diesel::insert_into(vouchers::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn list(conn: &mut PgConnection) -> anyhow::Result<Vec<Self>> {
+        let res = vouchers::table
+            .order_by(vouchers::created_at.desc())
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Counts a redemption of the code, None if it is unknown, revoked, expired
+    /// or has no redemptions left
+    pub fn use_code(conn: &mut PgConnection, code: &str) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(vouchers::table)
+            .filter(vouchers::code.eq(code))
+            .filter(vouchers::revoked_at.is_null())
+            .filter(
+                vouchers::expires_at
+                    .is_null()
+                    .or(vouchers::expires_at.gt(diesel::dsl::now)),
+            )
+            .filter(vouchers::redemptions.lt(vouchers::max_redemptions))
+            .set(vouchers::redemptions.eq(vouchers::redemptions + 1))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Revokes the voucher, None if it doesn't exist or was already revoked
+    pub fn revoke(conn: &mut PgConnection, id: i32) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(vouchers::table)
+            .filter(vouchers::id.eq(id))
+            .filter(vouchers::revoked_at.is_null())
+            .set(vouchers::revoked_at.eq(diesel::dsl::now))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
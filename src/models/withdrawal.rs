@@ -0,0 +1,196 @@
+use crate::models::schema::withdrawals;
+use diesel::{
+    ExpressionMethods, Identifiable, Insertable, OptionalExtension, PgConnection, QueryDsl,
+    Queryable, RunQueryDsl,
+};
+use lightning_invoice::Bolt11Invoice;
+use nostr::EventId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WithdrawalStatus {
+    /// Link issued, waiting for the wallet to send an invoice
+    Open,
+    /// Balance debited, paying the invoice
+    Pending,
+    /// Invoice paid
+    Paid,
+    /// Payment failed and the balance was credited back
+    Failed,
+}
+
+impl fmt::Display for WithdrawalStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WithdrawalStatus::Open => write!(f, "open"),
+            WithdrawalStatus::Pending => write!(f, "pending"),
+            WithdrawalStatus::Paid => write!(f, "paid"),
+            WithdrawalStatus::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+impl FromStr for WithdrawalStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(WithdrawalStatus::Open),
+            "pending" => Ok(WithdrawalStatus::Pending),
+            "paid" => Ok(WithdrawalStatus::Paid),
+            "failed" => Ok(WithdrawalStatus::Failed),
+            _ => Err(anyhow::anyhow!("invalid withdrawal status: {s}")),
+        }
+    }
+}
+
+/// A one-time LNURL-withdraw link, identified by its random `k1`
+#[derive(Queryable, Identifiable, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = withdrawals)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Withdrawal {
+    pub id: i32,
+    npub: Vec<u8>,
+    k1: Vec<u8>,
+    auth_event_id: Vec<u8>,
+    status: String,
+    invoice: Option<String>,
+    amount_msats: Option<i64>,
+    fee_reserve_msats: Option<i64>,
+    fee_msats: Option<i64>,
+    pub expires_at: chrono::NaiveDateTime,
+    created_at: chrono::NaiveDateTime,
+    updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = withdrawals)]
+struct NewWithdrawal {
+    npub: Vec<u8>,
+    k1: Vec<u8>,
+    auth_event_id: Vec<u8>,
+    expires_at: chrono::NaiveDateTime,
+}
+
+impl Withdrawal {
+    pub fn npub(&self) -> nostr::PublicKey {
+        nostr::PublicKey::from_slice(&self.npub).expect("Invalid key")
+    }
+
+    pub fn k1(&self) -> [u8; 32] {
+        self.k1.clone().try_into().expect("Invalid length")
+    }
+
+    pub fn status(&self) -> WithdrawalStatus {
+        WithdrawalStatus::from_str(&self.status).expect("Invalid status")
+    }
+
+    pub fn invoice(&self) -> Option<Bolt11Invoice> {
+        self.invoice
+            .as_ref()
+            .map(|i| Bolt11Invoice::from_str(i).expect("Invalid invoice"))
+    }
+
+    pub fn amount_msats(&self) -> Option<u64> {
+        self.amount_msats.map(|a| a as u64)
+    }
+
+    pub fn fee_reserve_msats(&self) -> Option<u64> {
+        self.fee_reserve_msats.map(|a| a as u64)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at < chrono::Utc::now().naive_utc()
+    }
+
+    /// Creates a link, fails if the auth event was already used for another link
+    pub fn create(
+        conn: &mut PgConnection,
+        npub: &nostr::PublicKey,
+        k1: [u8; 32],
+        auth_event_id: EventId,
+        expires_at: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Self> {
+        let new = NewWithdrawal {
+            npub: npub.to_bytes().to_vec(),
+            k1: k1.to_vec(),
+            auth_event_id: auth_event_id.to_bytes().to_vec(),
+            expires_at,
+        };
+
+        let res = diesel::insert_into(withdrawals::table)
+            .values(new)
+            .get_result::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    pub fn get_by_k1(conn: &mut PgConnection, k1: &[u8]) -> anyhow::Result<Option<Self>> {
+        let res = withdrawals::table
+            .filter(withdrawals::k1.eq(k1))
+            .first::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Uses an open, unexpired link for the invoice, returns None if it was already used
+    pub fn claim(
+        conn: &mut PgConnection,
+        k1: &[u8],
+        invoice: &Bolt11Invoice,
+        amount_msats: u64,
+        fee_reserve_msats: u64,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(withdrawals::table)
+            .filter(withdrawals::k1.eq(k1))
+            .filter(withdrawals::status.eq(WithdrawalStatus::Open.to_string()))
+            .filter(withdrawals::expires_at.gt(diesel::dsl::now))
+            .set((
+                withdrawals::status.eq(WithdrawalStatus::Pending.to_string()),
+                withdrawals::invoice.eq(invoice.to_string()),
+                withdrawals::amount_msats.eq(i64::try_from(amount_msats)?),
+                withdrawals::fee_reserve_msats.eq(i64::try_from(fee_reserve_msats)?),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+
+    /// Withdrawals still paying that haven't changed since the given time
+    pub fn list_pending(
+        conn: &mut PgConnection,
+        updated_before: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = withdrawals::table
+            .filter(withdrawals::status.eq(WithdrawalStatus::Pending.to_string()))
+            .filter(withdrawals::updated_at.lt(updated_before))
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
+    /// Finishes a pending withdrawal, returns None if it was not pending
+    pub fn finish(
+        conn: &mut PgConnection,
+        id: i32,
+        status: WithdrawalStatus,
+        fee_msats: Option<u64>,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = diesel::update(withdrawals::table)
+            .filter(withdrawals::id.eq(id))
+            .filter(withdrawals::status.eq(WithdrawalStatus::Pending.to_string()))
+            .set((
+                withdrawals::status.eq(status.to_string()),
+                withdrawals::fee_msats.eq(fee_msats.map(|f| f as i64)),
+            ))
+            .get_result::<Self>(conn)
+            .optional()?;
+
+        Ok(res)
+    }
+}
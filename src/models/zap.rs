@@ -27,11 +27,13 @@ use std::str::FromStr;
 pub struct Zap {
     payment_hash: Vec<u8>,
     invoice: String,
-    pub amount_msats: i32,
+    pub amount_msats: i64,
     request: Value,
     npub: Vec<u8>,
     pub note_id: Option<Vec<u8>>,
     created_at: chrono::NaiveDateTime,
+    /// The job the zap is paying for, None for balance top-ups
+    pub job_id: Option<i32>,
 }
 
 #[derive(Insertable, AsChangeset)]
@@ -39,9 +41,10 @@ pub struct Zap {
 struct NewZap {
     payment_hash: Vec<u8>,
     invoice: String,
-    amount_msats: i32,
+    amount_msats: i64,
     request: Value,
     npub: Vec<u8>,
+    job_id: Option<i32>,
 }
 
 impl Zap {
@@ -75,13 +78,15 @@ impl Zap {
         invoice: &Bolt11Invoice,
         request: &Event,
         for_npub: &nostr::PublicKey,
+        job_id: Option<i32>,
     ) -> anyhow::Result<Self> {
         let new = NewZap {
             payment_hash: invoice.payment_hash().into_32().to_vec(),
             invoice: invoice.to_string(),
-            amount_msats: invoice.amount_milli_satoshis().expect("Invalid amount") as i32,
+            amount_msats: invoice.amount_milli_satoshis().expect("Invalid amount") as i64,
             request: serde_json::to_value(request)?,
             npub: for_npub.to_bytes().to_vec(),
+            job_id,
         };
 
         let res = diesel::insert_into(zaps::table)
@@ -103,6 +108,19 @@ impl Zap {
         Ok(res)
     }
 
+    /// Zaps created after the given time that haven't been paid
+    pub fn list_unpaid(
+        conn: &mut PgConnection,
+        created_after: chrono::NaiveDateTime,
+    ) -> anyhow::Result<Vec<Self>> {
+        let res = zaps::table
+            .filter(zaps::note_id.is_null())
+            .filter(zaps::created_at.gt(created_after))
+            .load::<Self>(conn)?;
+
+        Ok(res)
+    }
+
     pub fn update_note_id(
         conn: &mut PgConnection,
         payment_hash: Vec<u8>,
@@ -21,7 +21,7 @@ use serde::{Deserialize, Serialize};
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct ZapBalance {
     npub: Vec<u8>,
-    pub balance_msats: i32,
+    pub balance_msats: i64,
     created_at: chrono::NaiveDateTime,
 }
 
@@ -57,22 +57,25 @@ impl ZapBalance {
         Ok(res)
     }
 
-    pub fn update_balance(
-        &mut self,
+    /// Gets the balance and locks it until the transaction ends, so concurrent
+    /// reservations against the same balance are serialized.
+    pub fn get_for_update(
         conn: &mut PgConnection,
-        amount_msats: i32,
-    ) -> anyhow::Result<Self> {
-        self.balance_msats = self.balance_msats.saturating_add(amount_msats);
-
-        if self.balance_msats < 0 {
-            anyhow::bail!("Insufficient balance");
-        }
-
-        let res = diesel::update(zap_balances::table)
-            .filter(zap_balances::npub.eq(&self.npub))
-            .set(zap_balances::balance_msats.eq(self.balance_msats))
-            .get_result::<Self>(conn)?;
+        npub: &nostr::PublicKey,
+    ) -> anyhow::Result<Option<Self>> {
+        let res = zap_balances::table
+            .filter(zap_balances::npub.eq(npub.to_bytes().to_vec()))
+            .for_update()
+            .first::<Self>(conn)
+            .optional()?;
 
         Ok(res)
     }
+
+    pub fn get_or_create(conn: &mut PgConnection, npub: nostr::PublicKey) -> anyhow::Result<Self> {
+        match Self::get(conn, &npub)? {
+            Some(bal) => Ok(bal),
+            None => Self::create(conn, npub),
+        }
+    }
 }
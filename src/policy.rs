@@ -0,0 +1,392 @@
+use crate::config::Config;
+use crate::models::job::Job;
+use crate::models::pubkey_policy::{PolicyStatus, PubkeyPolicy};
+use diesel::PgConnection;
+use log::warn;
+use nostr::{PublicKey, ToBech32};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Window used for request rate limits and abuse detection
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a pubkey that crossed the abuse threshold stays blocked
+const AUTO_BLOCK_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Limits applied to every requester that is not on the allowlist,
+/// these can be changed at runtime through the admin api.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyLimits {
+    /// Maximum job requests per pubkey per minute
+    pub requests_per_minute: u32,
+    /// Maximum jobs a single pubkey can have running at once
+    pub max_concurrent_jobs: u32,
+    /// Maximum scheduled jobs a single pubkey can have waiting to run
+    pub max_scheduled_jobs: u32,
+    /// Rejected requests per minute after which a pubkey is blocked
+    pub abuse_threshold: u32,
+    /// Only serve pubkeys on the allowlist
+    pub allowlist_only: bool,
+}
+
+impl PolicyLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            requests_per_minute: config.requests_per_minute,
+            max_concurrent_jobs: config.max_concurrent_jobs,
+            max_scheduled_jobs: config.max_scheduled_jobs,
+            abuse_threshold: config.abuse_threshold,
+            allowlist_only: config.allowlist_only,
+        }
+    }
+}
+
+/// What to do with a job request
+pub enum PolicyDecision {
+    /// Handle the request, the permit must be held while the job is being handled
+    Allow(JobPermit),
+    /// Reject the request with a rate-limit feedback event
+    Reject(String),
+    /// Drop the request without sending anything
+    Ignore,
+}
+
+#[derive(Default)]
+struct PubkeyUsage {
+    requests: VecDeque<Instant>,
+    rejections: VecDeque<Instant>,
+    running: u32,
+}
+
+/// Policy layer in front of job handling, rate limits requesters and
+/// enforces the allow and block lists stored in postgres.
+#[derive(Clone)]
+pub struct Policy {
+    limits: Arc<RwLock<PolicyLimits>>,
+    usage: Arc<Mutex<HashMap<PublicKey, PubkeyUsage>>>,
+    /// Woken whenever a permit is dropped
+    released: Arc<Notify>,
+}
+
+impl Policy {
+    pub fn new(limits: PolicyLimits) -> Self {
+        Self {
+            limits: Arc::new(RwLock::new(limits)),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn limits(&self) -> PolicyLimits {
+        *self.limits.read().unwrap()
+    }
+
+    pub fn set_limits(&self, limits: PolicyLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Decides whether a job request from the given pubkey should be handled
+    pub fn check(
+        &self,
+        conn: &mut PgConnection,
+        pubkey: PublicKey,
+    ) -> anyhow::Result<PolicyDecision> {
+        let limits = self.limits();
+
+        match PubkeyPolicy::get(conn, &pubkey)?.map(|p| p.status()) {
+            Some(PolicyStatus::Blocked) => return Ok(PolicyDecision::Ignore),
+            Some(PolicyStatus::Allowed) => {
+                self.usage
+                    .lock()
+                    .unwrap()
+                    .entry(pubkey)
+                    .or_default()
+                    .running += 1;
+                return Ok(PolicyDecision::Allow(JobPermit {
+                    policy: self.clone(),
+                    pubkey,
+                }));
+            }
+            None if limits.allowlist_only => return Ok(PolicyDecision::Ignore),
+            None => {}
+        }
+
+        let decision = self.check_limits(pubkey, &limits, Instant::now());
+
+        if let PolicyDecision::Ignore = decision {
+            warn!("Blocking {} for exceeding rate limits", pubkey.to_bech32()?);
+            let blocked_until =
+                chrono::Utc::now().naive_utc() + chrono::Duration::from_std(AUTO_BLOCK_DURATION)?;
+            PubkeyPolicy::upsert(
+                conn,
+                &pubkey,
+                PolicyStatus::Blocked,
+                Some("Automatically blocked for exceeding rate limits"),
+                Some(blocked_until),
+            )?;
+        }
+
+        Ok(decision)
+    }
+
+    /// Waits until the pubkey has fewer than the allowed number of jobs running, for jobs
+    /// whose request was let through earlier and that were paid for since. A paid job is
+    /// never rejected, it waits for one of the requester's other jobs to finish.
+    pub async fn acquire(
+        &self,
+        conn: &mut PgConnection,
+        pubkey: PublicKey,
+    ) -> anyhow::Result<JobPermit> {
+        let unlimited =
+            PubkeyPolicy::get(conn, &pubkey)?.is_some_and(|p| p.status() == PolicyStatus::Allowed);
+        Ok(self.wait_for_slot(pubkey, unlimited).await)
+    }
+
+    async fn wait_for_slot(&self, pubkey: PublicKey, unlimited: bool) -> JobPermit {
+        loop {
+            // created before checking so a permit dropped in between still wakes us
+            let released = self.released.notified();
+            {
+                let max_concurrent_jobs = self.limits().max_concurrent_jobs;
+                let mut usage = self.usage.lock().unwrap();
+                let entry = usage.entry(pubkey).or_default();
+                if unlimited || entry.running < max_concurrent_jobs {
+                    entry.running += 1;
+                    return JobPermit {
+                        policy: self.clone(),
+                        pubkey,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Checks the in memory rate limits for the pubkey, returns [`PolicyDecision::Ignore`]
+    /// when the pubkey has crossed the abuse threshold and should be blocked.
+    fn check_limits(
+        &self,
+        pubkey: PublicKey,
+        limits: &PolicyLimits,
+        now: Instant,
+    ) -> PolicyDecision {
+        let mut usage = self.usage.lock().unwrap();
+
+        // forget about pubkeys we haven't heard from within the window
+        usage.retain(|_, u| {
+            u.running > 0
+                || u.requests
+                    .back()
+                    .into_iter()
+                    .chain(u.rejections.back())
+                    .any(|t| now.duration_since(*t) <= RATE_WINDOW)
+        });
+
+        let entry = usage.entry(pubkey).or_default();
+
+        while entry
+            .requests
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > RATE_WINDOW)
+        {
+            entry.requests.pop_front();
+        }
+        while entry
+            .rejections
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > RATE_WINDOW)
+        {
+            entry.rejections.pop_front();
+        }
+
+        let reason = if entry.requests.len() >= limits.requests_per_minute as usize {
+            Some(format!(
+                "Rate limited, at most {} requests per minute",
+                limits.requests_per_minute
+            ))
+        } else if entry.running >= limits.max_concurrent_jobs {
+            Some(format!(
+                "Rate limited, at most {} jobs can run at once",
+                limits.max_concurrent_jobs
+            ))
+        } else {
+            None
+        };
+
+        match reason {
+            None => {
+                entry.requests.push_back(now);
+                entry.running += 1;
+                PolicyDecision::Allow(JobPermit {
+                    policy: self.clone(),
+                    pubkey,
+                })
+            }
+            Some(reason) => {
+                entry.rejections.push_back(now);
+                if entry.rejections.len() >= limits.abuse_threshold as usize {
+                    PolicyDecision::Ignore
+                } else {
+                    PolicyDecision::Reject(reason)
+                }
+            }
+        }
+    }
+
+    /// Checks the scheduled job cap for the pubkey, returns the rejection reason if it is reached
+    pub fn check_scheduled(
+        &self,
+        conn: &mut PgConnection,
+        pubkey: &PublicKey,
+    ) -> anyhow::Result<Option<String>> {
+        let limits = self.limits();
+        if PubkeyPolicy::get(conn, pubkey)?.is_some_and(|p| p.status() == PolicyStatus::Allowed) {
+            return Ok(None);
+        }
+
+        let pending = Job::count_pending_scheduled(conn, pubkey)?;
+        if pending >= limits.max_scheduled_jobs as i64 {
+            return Ok(Some(format!(
+                "Rate limited, at most {} scheduled jobs can be waiting to run",
+                limits.max_scheduled_jobs
+            )));
+        }
+
+        Ok(None)
+    }
+}
+
+/// Counts towards the requester's concurrent jobs until dropped
+pub struct JobPermit {
+    policy: Policy,
+    pubkey: PublicKey,
+}
+
+impl Drop for JobPermit {
+    fn drop(&mut self) {
+        let mut usage = self.policy.usage.lock().unwrap();
+        if let Some(entry) = usage.get_mut(&self.pubkey) {
+            entry.running = entry.running.saturating_sub(1);
+            if entry.running == 0 && entry.requests.is_empty() && entry.rejections.is_empty() {
+                usage.remove(&self.pubkey);
+            }
+        }
+        drop(usage);
+        self.policy.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Policy, PolicyDecision, PolicyLimits};
+    use nostr::Keys;
+    use std::time::{Duration, Instant};
+
+    const LIMITS: PolicyLimits = PolicyLimits {
+        requests_per_minute: 2,
+        max_concurrent_jobs: 10,
+        max_scheduled_jobs: 10,
+        abuse_threshold: 3,
+        allowlist_only: false,
+    };
+
+    #[test]
+    fn test_rate_limit_and_abuse() {
+        let policy = Policy::new(LIMITS);
+        let pubkey = Keys::generate().public_key();
+        let now = Instant::now();
+
+        let mut permits = vec![];
+        for _ in 0..2 {
+            match policy.check_limits(pubkey, &LIMITS, now) {
+                PolicyDecision::Allow(permit) => permits.push(permit),
+                _ => panic!("should be allowed"),
+            }
+        }
+
+        assert!(matches!(
+            policy.check_limits(pubkey, &LIMITS, now),
+            PolicyDecision::Reject(_)
+        ));
+        assert!(matches!(
+            policy.check_limits(pubkey, &LIMITS, now),
+            PolicyDecision::Reject(_)
+        ));
+        assert!(matches!(
+            policy.check_limits(pubkey, &LIMITS, now),
+            PolicyDecision::Ignore
+        ));
+
+        // window passed, allowed again
+        let later = now + Duration::from_secs(61);
+        assert!(matches!(
+            policy.check_limits(pubkey, &LIMITS, later),
+            PolicyDecision::Allow(_)
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_limit() {
+        let limits = PolicyLimits {
+            requests_per_minute: 100,
+            max_concurrent_jobs: 1,
+            ..LIMITS
+        };
+        let policy = Policy::new(limits);
+        let pubkey = Keys::generate().public_key();
+        let now = Instant::now();
+
+        let permit = match policy.check_limits(pubkey, &limits, now) {
+            PolicyDecision::Allow(permit) => permit,
+            _ => panic!("should be allowed"),
+        };
+        assert!(matches!(
+            policy.check_limits(pubkey, &limits, now),
+            PolicyDecision::Reject(_)
+        ));
+
+        drop(permit);
+        assert!(matches!(
+            policy.check_limits(pubkey, &limits, now),
+            PolicyDecision::Allow(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_paid_job_waits_for_slot() {
+        let limits = PolicyLimits {
+            requests_per_minute: 100,
+            max_concurrent_jobs: 1,
+            ..LIMITS
+        };
+        let policy = Policy::new(limits);
+        let pubkey = Keys::generate().public_key();
+
+        let permit = match policy.check_limits(pubkey, &limits, Instant::now()) {
+            PolicyDecision::Allow(permit) => permit,
+            _ => panic!("should be allowed"),
+        };
+
+        // a paid job waits for the running one to finish instead of being rejected
+        let waiting = policy.clone();
+        let handle = tokio::spawn(async move { waiting.wait_for_slot(pubkey, false).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!handle.is_finished());
+
+        drop(permit);
+        let _permit = tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            policy.check_limits(pubkey, &limits, Instant::now()),
+            PolicyDecision::Reject(_)
+        ));
+
+        // allowlisted keys aren't limited
+        let _unlimited = policy.wait_for_slot(pubkey, true).await;
+    }
+}
@@ -0,0 +1,58 @@
+use crate::error::JobError;
+use crate::job_listener::{send_reply, JOB_INVOICE_EXPIRY};
+use crate::lightning::Lightning;
+use crate::models::expire_unpaid_jobs;
+use crate::models::job::Job;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use log::{debug, info, warn};
+use nostr::Keys;
+use nostr_sdk::Client;
+
+/// Extra time after an invoice expires before its job is expired, so a payment
+/// that was in flight at expiry is still picked up
+const EXPIRY_GRACE_SECS: i64 = 300;
+
+/// Expires jobs whose invoice was never paid, along with the series of repeating ones,
+/// cancels their invoices and tells the requester, then deletes expired jobs older than the retention period.
+pub async fn reap_jobs(
+    client: &Client,
+    keys: &Keys,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    lightning: &dyn Lightning,
+    retention_days: u64,
+) -> anyhow::Result<()> {
+    let mut conn = db_pool.get()?;
+
+    let now = chrono::Utc::now().naive_utc();
+    let cutoff = now - chrono::Duration::seconds(JOB_INVOICE_EXPIRY as i64 + EXPIRY_GRACE_SECS);
+    let expired = expire_unpaid_jobs(&mut conn, cutoff)?;
+
+    for job in expired {
+        info!("Expired unpaid job {}", job.id);
+
+        // not every backend can cancel invoices, they expire on their own either way
+        if let Err(e) = lightning.cancel_invoice(job.payment_hash()).await {
+            debug!("Could not cancel invoice for job {}: {e}", job.id);
+        }
+
+        let request = job.request();
+        let feedback = JobError::Expired.to_feedback(&request);
+        match send_reply(client, keys, &mut conn, request.id, feedback).await {
+            Ok(reply) => {
+                Job::set_response_id(&mut conn, job.id, reply.id)?;
+            }
+            Err(e) => warn!("Failed to send expired feedback for job {}: {e}", job.id),
+        }
+    }
+
+    if retention_days > 0 {
+        let expired_before = now - chrono::Duration::days(retention_days as i64);
+        let deleted = Job::delete_expired(&mut conn, expired_before)?;
+        if deleted > 0 {
+            info!("Deleted {deleted} expired jobs");
+        }
+    }
+
+    Ok(())
+}
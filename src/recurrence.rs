@@ -0,0 +1,175 @@
+use crate::wasm_handler::ScheduledParams;
+use anyhow::anyhow;
+use cron::Schedule;
+use std::str::FromStr;
+
+/// Shortest time allowed between runs of a repeating job, in seconds
+pub const MIN_INTERVAL: u64 = 60;
+
+/// How a scheduled job repeats after its first run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recurrence {
+    Cron(Schedule),
+    /// Seconds between runs
+    Interval(u64),
+}
+
+impl Recurrence {
+    /// The recurrence of the schedule, None if it only runs once. Errors if it repeats
+    /// without an end date or a maximum number of runs, so it can't run forever.
+    pub fn from_params(schedule: &ScheduledParams) -> anyhow::Result<Option<Self>> {
+        let recurrence = match (schedule.cron.as_deref(), schedule.interval) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => return Err(anyhow!("Only one of cron or interval can be set")),
+            (Some(cron), None) => Self::cron(cron)?,
+            (None, Some(interval)) => Self::interval(interval)?,
+        };
+
+        if schedule.end_date.is_none() && schedule.max_runs.is_none() {
+            return Err(anyhow!(
+                "Repeating jobs need an end date or a maximum number of runs"
+            ));
+        }
+        if schedule.max_runs == Some(0) {
+            return Err(anyhow!("Maximum number of runs must be at least 1"));
+        }
+        if schedule
+            .end_date
+            .is_some_and(|end| end <= schedule.run_date)
+        {
+            return Err(anyhow!("End date must be after the first run date"));
+        }
+        if schedule.expected_outputs.is_some() {
+            return Err(anyhow!(
+                "Oracle announcements are not supported for repeating jobs"
+            ));
+        }
+
+        Ok(Some(recurrence))
+    }
+
+    /// Parses a cron expression, the seconds field is optional
+    pub fn cron(expr: &str) -> anyhow::Result<Self> {
+        let expr = expr.trim();
+        let expr = if expr.split_whitespace().count() == 5 {
+            format!("0 {expr}")
+        } else {
+            expr.to_string()
+        };
+        let schedule =
+            Schedule::from_str(&expr).map_err(|e| anyhow!("Invalid cron expression: {e}"))?;
+
+        // runs closer together than the interval minimum are not allowed either
+        let mut upcoming = schedule.upcoming(chrono::Utc).take(2);
+        match (upcoming.next(), upcoming.next()) {
+            (Some(a), Some(b)) if (b - a).num_seconds() < MIN_INTERVAL as i64 => Err(anyhow!(
+                "Runs must be at least {MIN_INTERVAL} seconds apart"
+            )),
+            (None, _) => Err(anyhow!("Cron expression never runs")),
+            _ => Ok(Recurrence::Cron(schedule)),
+        }
+    }
+
+    pub fn interval(secs: u64) -> anyhow::Result<Self> {
+        if secs < MIN_INTERVAL {
+            return Err(anyhow!("Interval must be at least {MIN_INTERVAL} seconds"));
+        }
+        Ok(Recurrence::Interval(secs))
+    }
+
+    /// When to run next after a run scheduled for `last_run`, skipping any runs that
+    /// were missed before `now`. Times are in seconds from epoch.
+    pub fn next_run(&self, last_run: u64, now: u64) -> Option<u64> {
+        match self {
+            Recurrence::Interval(secs) => {
+                let missed = now.saturating_sub(last_run) / secs;
+                Some(last_run + (missed + 1) * secs)
+            }
+            Recurrence::Cron(schedule) => {
+                let after = chrono::DateTime::from_timestamp(last_run.max(now) as i64, 0)?;
+                schedule
+                    .after(&after)
+                    .next()
+                    .map(|next| next.timestamp() as u64)
+            }
+        }
+    }
+
+    /// How the recurrence is stored, as its cron expression or interval in seconds
+    pub fn parts(&self) -> (Option<String>, Option<u64>) {
+        match self {
+            Recurrence::Cron(schedule) => (Some(schedule.to_string()), None),
+            Recurrence::Interval(secs) => (None, Some(*secs)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Recurrence;
+    use crate::wasm_handler::ScheduledParams;
+
+    fn schedule(
+        cron: Option<&str>,
+        interval: Option<u64>,
+        max_runs: Option<u32>,
+    ) -> ScheduledParams {
+        ScheduledParams {
+            expected_outputs: None,
+            run_date: 1_700_000_000,
+            name: None,
+            cron: cron.map(|c| c.to_string()),
+            interval,
+            end_date: None,
+            max_runs,
+        }
+    }
+
+    #[test]
+    fn test_recurrence_params() {
+        assert_eq!(
+            Recurrence::from_params(&schedule(None, None, None)).unwrap(),
+            None
+        );
+        assert!(
+            Recurrence::from_params(&schedule(None, Some(3_600), Some(24)))
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            Recurrence::from_params(&schedule(Some("0 * * * *"), None, Some(24)))
+                .unwrap()
+                .is_some()
+        );
+
+        // must end
+        assert!(Recurrence::from_params(&schedule(None, Some(3_600), None)).is_err());
+        // only one way to repeat
+        assert!(
+            Recurrence::from_params(&schedule(Some("0 * * * *"), Some(3_600), Some(2))).is_err()
+        );
+        // too often
+        assert!(Recurrence::from_params(&schedule(None, Some(10), Some(2))).is_err());
+        assert!(Recurrence::from_params(&schedule(Some("* * * * * *"), None, Some(2))).is_err());
+        assert!(Recurrence::from_params(&schedule(Some("not cron"), None, Some(2))).is_err());
+    }
+
+    #[test]
+    fn test_next_run() {
+        let hourly = Recurrence::interval(3_600).unwrap();
+        assert_eq!(hourly.next_run(10_000, 10_000), Some(13_600));
+        // missed runs are skipped
+        assert_eq!(hourly.next_run(10_000, 20_000), Some(20_800));
+
+        // every day at midnight
+        let daily = Recurrence::cron("0 0 * * *").unwrap();
+        assert_eq!(
+            daily.next_run(1_700_000_000, 1_700_000_000),
+            Some(1_700_006_400)
+        );
+        assert_eq!(
+            daily.next_run(1_700_006_400, 1_700_006_400),
+            Some(1_700_092_800)
+        );
+    }
+}
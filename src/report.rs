@@ -0,0 +1,212 @@
+use crate::models::job::{Job, JobTotals, ReportGroup};
+use anyhow::anyhow;
+use diesel::PgConnection;
+use nostr::{PublicKey, ToBech32};
+use serde::{Deserialize, Serialize};
+
+/// How far back a report goes if no start is given, in days
+const DEFAULT_REPORT_DAYS: i64 = 30;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+/// Totals for one day, module or requester
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReportRow {
+    pub group: ReportGroup,
+    pub key: String,
+    pub jobs: u64,
+    pub completed: u64,
+    pub failed: u64,
+    /// Share of the finished jobs that failed
+    pub failure_rate: f64,
+    /// What we kept from the jobs after refunds
+    pub revenue_msats: u64,
+    pub runtime_ms: u64,
+}
+
+impl ReportRow {
+    fn new(group: ReportGroup, totals: JobTotals) -> Self {
+        let finished = totals.completed + totals.failed;
+        let failure_rate = if finished == 0 {
+            0.0
+        } else {
+            totals.failed as f64 / finished as f64
+        };
+
+        // requesters are easier to recognize as npubs
+        let key = match group {
+            ReportGroup::Requester => PublicKey::from_hex(&totals.key)
+                .ok()
+                .and_then(|p| p.to_bech32().ok())
+                .unwrap_or(totals.key),
+            _ => totals.key,
+        };
+
+        Self {
+            group,
+            key,
+            jobs: totals.jobs as u64,
+            completed: totals.completed as u64,
+            failed: totals.failed as u64,
+            failure_rate,
+            revenue_msats: totals.revenue_msats as u64,
+            runtime_ms: totals.runtime_ms as u64,
+        }
+    }
+}
+
+/// Revenue and usage of the jobs requested in a time range
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub since: chrono::NaiveDateTime,
+    pub until: chrono::NaiveDateTime,
+    pub by_day: Vec<ReportRow>,
+    pub by_module: Vec<ReportRow>,
+    pub by_requester: Vec<ReportRow>,
+}
+
+impl Report {
+    /// Builds the report for jobs requested between the unix timestamps `since` and `until`,
+    /// defaulting to the last 30 days
+    pub fn generate(
+        conn: &mut PgConnection,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let until = match until {
+            Some(t) => timestamp(t)?,
+            None => chrono::Utc::now().naive_utc(),
+        };
+        let since = match since {
+            Some(t) => timestamp(t)?,
+            None => until - chrono::Duration::days(DEFAULT_REPORT_DAYS),
+        };
+        if since >= until {
+            return Err(anyhow!("Report start must be before its end"));
+        }
+
+        let mut rows = |group: ReportGroup| -> anyhow::Result<Vec<ReportRow>> {
+            let mut rows = Job::totals(conn, group, since, until)?
+                .into_iter()
+                .map(|t| ReportRow::new(group, t))
+                .collect::<Vec<_>>();
+            // days read best in order, everything else by what it earned
+            if group != ReportGroup::Day {
+                rows.sort_by(|a, b| b.revenue_msats.cmp(&a.revenue_msats));
+            }
+            Ok(rows)
+        };
+
+        Ok(Self {
+            since,
+            until,
+            by_day: rows(ReportGroup::Day)?,
+            by_module: rows(ReportGroup::Module)?,
+            by_requester: rows(ReportGroup::Requester)?,
+        })
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &ReportRow> {
+        self.by_day
+            .iter()
+            .chain(self.by_module.iter())
+            .chain(self.by_requester.iter())
+    }
+
+    /// Every row of the report in one table, with the grouping in the first column
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            "group,key,jobs,completed,failed,failure_rate,revenue_msats,runtime_ms\n".to_string();
+        for row in self.rows() {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{:.4},{},{}\n",
+                row.group,
+                csv_field(&row.key),
+                row.jobs,
+                row.completed,
+                row.failed,
+                row.failure_rate,
+                row.revenue_msats,
+                row.runtime_ms
+            ));
+        }
+        csv
+    }
+
+    pub fn format(&self, format: ReportFormat) -> anyhow::Result<String> {
+        match format {
+            ReportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            ReportFormat::Csv => Ok(self.to_csv()),
+        }
+    }
+}
+
+fn timestamp(secs: u64) -> anyhow::Result<chrono::NaiveDateTime> {
+    chrono::NaiveDateTime::from_timestamp_opt(i64::try_from(secs)?, 0)
+        .ok_or(anyhow!("Invalid timestamp: {secs}"))
+}
+
+/// Quotes a field if it would break the csv
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Report, ReportRow};
+    use crate::models::job::{JobTotals, ReportGroup};
+
+    fn totals(key: &str, completed: i64, failed: i64, revenue_msats: i64) -> JobTotals {
+        JobTotals {
+            key: key.to_string(),
+            jobs: completed + failed,
+            completed,
+            failed,
+            revenue_msats,
+            runtime_ms: completed * 100,
+        }
+    }
+
+    #[test]
+    fn test_report_csv() {
+        let requester = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let report = Report {
+            since: chrono::NaiveDateTime::from_timestamp_opt(0, 0).unwrap(),
+            until: chrono::NaiveDateTime::from_timestamp_opt(86_400, 0).unwrap(),
+            by_day: vec![ReportRow::new(
+                ReportGroup::Day,
+                totals("1970-01-01", 3, 1, 5_000),
+            )],
+            by_module: vec![ReportRow::new(
+                ReportGroup::Module,
+                totals("odd,\"name\"", 0, 0, 0),
+            )],
+            by_requester: vec![ReportRow::new(
+                ReportGroup::Requester,
+                totals(requester, 3, 1, 5_000),
+            )],
+        };
+        assert_eq!(report.by_day[0].failure_rate, 0.25);
+        assert!(report.by_requester[0].key.starts_with("npub1"));
+
+        let csv = report.to_csv();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "group,key,jobs,completed,failed,failure_rate,revenue_msats,runtime_ms"
+        );
+        assert_eq!(lines[1], "day,1970-01-01,4,3,1,0.2500,5000,300");
+        assert_eq!(lines[2], "module,\"odd,\"\"name\"\"\",0,0,0,0.0000,0,0");
+        assert!(lines[3].starts_with("requester,npub1"));
+    }
+}
@@ -1,30 +1,77 @@
+use crate::balance::{verify_http_auth, BalanceStatement};
+use crate::lightning::{InvoiceDescription, InvoiceStatus};
 use crate::models::create_zap;
+use crate::models::lnurl_invoice::LnurlInvoice;
 use crate::State;
 use anyhow::anyhow;
 use axum::extract::{Path, Query};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::{Extension, Json};
 use bitcoin::hashes::{sha256, Hash};
-use bitcoin::secp256k1::ThirtyTwoByteHash;
 use lightning_invoice::Bolt11Invoice;
 use lnurl::pay::PayResponse;
 use lnurl::Tag;
 use nostr::nips::nip57;
-use nostr::{Event, JsonUtil};
+use nostr::{Event, FromBech32, JsonUtil, PublicKey};
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::str::FromStr;
-use tonic_openssl_lnd::lnrpc;
+
+/// The npub a LUD-12 comment names to credit the payment to, like `for npub1...`
+fn comment_npub(comment: &str) -> Option<PublicKey> {
+    comment.split_whitespace().find_map(|word| {
+        let word = word.trim_matches(|c: char| !c.is_ascii_alphanumeric() && c != ':');
+        let word = word.strip_prefix("nostr:").unwrap_or(word);
+        PublicKey::from_bech32(word).ok()
+    })
+}
+
+/// LUD-06 metadata of the pay endpoint for one of our lnurl names
+fn lnurl_metadata(name: &str, domain: &str) -> String {
+    format!("[[\"text/identifier\",\"{name}@{domain}\"],[\"text/plain\",\"Sats for {name}\"]]")
+}
+
+/// Whether the hash is of the metadata of one of our lnurl names
+fn is_lnurl_metadata_hash(names: &[String], domain: &str, hash: &sha256::Hash) -> bool {
+    names
+        .iter()
+        .any(|name| sha256::Hash::hash(lnurl_metadata(name, domain).as_bytes()) == *hash)
+}
 
 pub(crate) async fn get_invoice_impl(
     state: State,
     hash: String,
     amount_msats: u64,
     zap_request: Option<Event>,
-) -> anyhow::Result<String> {
-    let mut lnd = state.lnd.clone();
+    comment: Option<String>,
+) -> anyhow::Result<Bolt11Invoice> {
+    if amount_msats < state.min_sendable || amount_msats > state.max_sendable {
+        return Err(anyhow!(
+            "Amount must be between {} and {} msats",
+            state.min_sendable,
+            state.max_sendable
+        ));
+    }
+
+    let comment = comment.filter(|c| !c.is_empty());
+    if comment
+        .as_ref()
+        .is_some_and(|c| c.chars().count() > state.comment_allowed as usize)
+    {
+        return Err(anyhow!(
+            "Comment must be at most {} characters",
+            state.comment_allowed
+        ));
+    }
+
+    // only invoices for the pay endpoints we serve, the hash is taken from their callback url
+    let metadata_hash = sha256::Hash::from_str(&hash)?;
+    if !is_lnurl_metadata_hash(&state.lnurl_names, &state.domain, &metadata_hash) {
+        return Err(anyhow!("Unknown description hash"));
+    }
+
     let desc_hash = match zap_request.as_ref() {
-        None => sha256::Hash::from_str(&hash)?,
+        None => metadata_hash,
         Some(event) => {
             if event.kind != nostr::Kind::ZapRequest {
                 return Err(anyhow!("Invalid zap request"));
@@ -33,34 +80,34 @@ pub(crate) async fn get_invoice_impl(
         }
     };
 
-    let request = lnrpc::Invoice {
-        value_msat: amount_msats as i64,
-        description_hash: desc_hash.into_32().to_vec(),
-        expiry: 86_400,
-        ..Default::default()
-    };
-
-    let resp = lnd.add_invoice(request).await?.into_inner();
+    let invoice = state
+        .lightning
+        .create_invoice(amount_msats, InvoiceDescription::Hash(desc_hash), 86_400)
+        .await?;
 
-    if let Some(zap_request) = zap_request {
-        let invoice = Bolt11Invoice::from_str(&resp.payment_request)?;
+    let mut conn = state.db_pool.get()?;
+    // zaps are credited to the zapper, the comment only decides who gets other payments
+    let credit_npub = match zap_request {
+        Some(zap_request) => {
+            // handle private zaps
+            let private_zap = nip57::decrypt_received_private_zap_message(
+                state.keys.secret_key().unwrap(),
+                &zap_request,
+            )
+            .ok()
+            .map(|e| e.pubkey);
 
-        // handle private zaps
-        let private_zap = nip57::decrypt_received_private_zap_message(
-            state.keys.secret_key().unwrap(),
-            &zap_request,
-        )
-        .ok()
-        .map(|e| e.pubkey);
+            // if it is a private zap, use that npub, otherwise use the pubkey from the zap request
+            let for_npub = private_zap.unwrap_or(zap_request.pubkey);
 
-        // if it is a private zap, use that npub, otherwise use the pubkey from the zap request
-        let for_npub = private_zap.unwrap_or(zap_request.pubkey);
-
-        let mut conn = state.db_pool.get()?;
-        create_zap(&mut conn, &invoice, &zap_request, for_npub)?;
-    }
+            create_zap(&mut conn, &invoice, &zap_request, for_npub)?;
+            None
+        }
+        None => comment.as_deref().and_then(comment_npub),
+    };
+    LnurlInvoice::create(&mut conn, &invoice, comment.as_deref(), credit_npub)?;
 
-    Ok(resp.payment_request)
+    Ok(invoice)
 }
 
 pub async fn get_invoice(
@@ -99,10 +146,13 @@ pub async fn get_invoice(
         }
     }?;
 
-    match get_invoice_impl(state, hash, amount_msats, zap_request).await {
+    let comment = params.get("comment").cloned();
+    let domain = state.domain.clone();
+    match get_invoice_impl(state, hash, amount_msats, zap_request, comment).await {
         Ok(invoice) => Ok(Json(json!({
-            "pr": invoice,
-            "routers": []
+            "pr": invoice.to_string(),
+            "routers": [],
+            "verify": format!("https://{domain}/lnurlp/verify/{}", invoice.payment_hash()),
         }))),
         Err(e) => Err(handle_anyhow_error(e)),
     }
@@ -112,21 +162,27 @@ pub async fn get_lnurl_pay(
     Path(name): Path<String>,
     Extension(state): Extension<State>,
 ) -> Result<Json<PayResponse>, (StatusCode, Json<Value>)> {
-    let metadata = format!(
-        "[[\"text/identifier\",\"{name}@{}\"],[\"text/plain\",\"Sats for {name}\"]]",
-        state.domain,
-    );
+    if !state.lnurl_names.contains(&name) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "status": "ERROR",
+                "reason": format!("Unknown name: {name}"),
+            })),
+        ));
+    }
 
+    let metadata = lnurl_metadata(&name, &state.domain);
     let hash = sha256::Hash::hash(metadata.as_bytes());
     let callback = format!("https://{}/get-invoice/{hash}", state.domain);
 
     let resp = PayResponse {
         callback,
-        min_sendable: 1_000,
-        max_sendable: 11_000_000_000,
+        min_sendable: state.min_sendable,
+        max_sendable: state.max_sendable,
         tag: Tag::PayRequest,
         metadata,
-        comment_allowed: None,
+        comment_allowed: Some(state.comment_allowed).filter(|c| *c > 0),
         allows_nostr: Some(true),
         nostr_pubkey: Some(*state.keys.public_key()),
     };
@@ -134,6 +190,49 @@ pub async fn get_lnurl_pay(
     Ok(Json(resp))
 }
 
+async fn verify_invoice_impl(state: &State, payment_hash: &str) -> anyhow::Result<Option<Value>> {
+    let payment_hash: [u8; 32] = hex::decode(payment_hash)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid payment hash"))?;
+    let mut conn = state.db_pool.get()?;
+    let Some(lnurl_invoice) = LnurlInvoice::get_by_payment_hash(&mut conn, payment_hash)? else {
+        return Ok(None);
+    };
+    drop(conn);
+
+    let info = state.lightning.lookup_invoice(payment_hash).await?;
+    let settled = lnurl_invoice.settled_at.is_some()
+        || info
+            .as_ref()
+            .is_some_and(|i| i.status == InvoiceStatus::Settled);
+    let preimage = info.and_then(|i| i.preimage).map(hex::encode);
+
+    Ok(Some(json!({
+        "status": "OK",
+        "settled": settled,
+        "preimage": preimage,
+        "pr": lnurl_invoice.invoice().to_string(),
+    })))
+}
+
+/// LUD-21 verify, tells whether an invoice from LNURL-pay was paid without relying on nostr
+pub async fn verify_invoice(
+    Path(payment_hash): Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    match verify_invoice_impl(&state, &payment_hash).await {
+        Ok(Some(res)) => Ok(Json(res)),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "status": "ERROR",
+                "reason": "Not found",
+            })),
+        )),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
 pub async fn get_nip05(
     Extension(state): Extension<State>,
 ) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
@@ -150,6 +249,33 @@ pub async fn get_nip05(
     Ok(Json(json))
 }
 
+/// Balance statement for the requester, authenticated with NIP-98 HTTP auth
+pub async fn get_balance_statement(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<BalanceStatement>, (StatusCode, Json<Value>)> {
+    let url = format!("https://{}/balance", state.domain);
+    let event = verify_http_auth(&headers, &url, "GET").map_err(|e| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "status": "ERROR",
+                "reason": format!("{e}"),
+            })),
+        )
+    })?;
+
+    let get = || -> anyhow::Result<BalanceStatement> {
+        let mut conn = state.db_pool.get()?;
+        BalanceStatement::get(&mut conn, &event.pubkey)
+    };
+
+    match get() {
+        Ok(statement) => Ok(Json(statement)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
 pub(crate) fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, Json<Value>) {
     let err = json!({
         "status": "ERROR",
@@ -157,3 +283,41 @@ pub(crate) fn handle_anyhow_error(err: anyhow::Error) -> (StatusCode, Json<Value
     });
     (StatusCode::BAD_REQUEST, Json(err))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{comment_npub, is_lnurl_metadata_hash, lnurl_metadata};
+    use bitcoin::hashes::{sha256, Hash};
+    use nostr::{Keys, ToBech32};
+
+    #[test]
+    fn test_comment_npub() {
+        let keys = Keys::generate();
+        let npub = keys.public_key().to_bech32().unwrap();
+
+        assert_eq!(comment_npub(&npub), Some(keys.public_key()));
+        assert_eq!(
+            comment_npub(&format!("top up for nostr:{npub}!")),
+            Some(keys.public_key())
+        );
+        assert_eq!(comment_npub("great work"), None);
+        assert_eq!(comment_npub("npub1invalid"), None);
+    }
+
+    #[test]
+    fn test_lnurl_metadata_hash() {
+        let names = vec!["dvm".to_string(), "tips".to_string()];
+        let known = |name: &str, domain: &str| {
+            let hash = sha256::Hash::hash(lnurl_metadata(name, domain).as_bytes());
+            is_lnurl_metadata_hash(&names, "example.com", &hash)
+        };
+
+        assert!(known("dvm", "example.com"));
+        assert!(known("tips", "example.com"));
+        assert!(!known("other", "example.com"));
+        assert!(!known("dvm", "other.com"));
+
+        let arbitrary = sha256::Hash::hash(b"arbitrary description");
+        assert!(!is_lnurl_metadata_hash(&names, "example.com", &arbitrary));
+    }
+}
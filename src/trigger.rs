@@ -0,0 +1,264 @@
+use crate::error::JobError;
+use crate::job_listener::get_job_params;
+use crate::models::job_trigger::{JobTrigger, TriggerStatus};
+use crate::models::{queue_trigger_run, TriggerRun};
+use crate::wasm_handler::TriggerParams;
+use anyhow::anyhow;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::PgConnection;
+use log::{debug, error, info};
+use nostr::{Event, Filter, Keys, Kind, Timestamp};
+use nostr_sdk::{Client, RelayPoolNotification, RelayStatus};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Channel the database notifies when a trigger is added or stopped
+pub const TRIGGER_CHANNEL: &str = "job_triggers";
+
+/// Longest a trigger can run for, in seconds
+const MAX_TRIGGER_LIFETIME: u64 = 30 * 86_400;
+
+/// Most times a trigger can run its job in an hour, also the default
+const MAX_RUNS_PER_HOUR: u32 = 60;
+
+/// Most active triggers a pubkey can have
+pub const MAX_TRIGGERS_PER_PUBKEY: u64 = 5;
+
+/// How often to check for expired triggers
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Checks the trigger of a request can be registered, returns how often it may run in an hour
+pub fn validate_trigger(trigger: &TriggerParams, now: u64) -> anyhow::Result<u32> {
+    if trigger.filter.is_empty() {
+        return Err(anyhow!("Trigger filter must not be empty"));
+    }
+    if trigger.expires_at <= now {
+        return Err(anyhow!("Trigger expiry must be in the future"));
+    }
+    if trigger.expires_at > now + MAX_TRIGGER_LIFETIME {
+        return Err(anyhow!("Trigger can't run for more than 30 days"));
+    }
+    if trigger.max_runs == Some(0) {
+        return Err(anyhow!("Trigger max runs must be at least 1"));
+    }
+
+    match trigger.max_runs_per_hour {
+        None => Ok(MAX_RUNS_PER_HOUR),
+        Some(0) => Err(anyhow!("Trigger max runs per hour must be at least 1")),
+        Some(n) if n > MAX_RUNS_PER_HOUR => Err(anyhow!(
+            "Trigger can't run more than {MAX_RUNS_PER_HOUR} times per hour"
+        )),
+        Some(n) => Ok(n),
+    }
+}
+
+/// Subscribes to the filters of the active triggers and queues a run of the job for every
+/// event matching one of them. The subscription is renewed whenever a trigger is added or
+/// stopped, `changed` is notified for that.
+pub async fn listen_for_triggers(
+    relays: Vec<String>,
+    keys: Keys,
+    db_pool: Pool<ConnectionManager<PgConnection>>,
+    catch_up_margin: u64,
+    changed: Arc<Notify>,
+) -> anyhow::Result<()> {
+    let client = Client::new(&keys);
+    client.add_relays(relays).await?;
+    client.connect().await;
+
+    let mut triggers = subscribe_triggers(&client, &db_pool, catch_up_margin).await?;
+    let mut notifications = client.notifications();
+    let mut disconnected = HashSet::new();
+    let mut expiry_check = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            msg = notifications.recv() => {
+                let Ok(msg) = msg else {
+                    break;
+                };
+                match msg {
+                    RelayPoolNotification::Event { event, .. } => {
+                        if let Err(e) =
+                            handle_trigger_event(&client, &keys, &db_pool, &triggers, &event).await
+                        {
+                            error!("Error handling trigger event {}: {e}", event.id);
+                        }
+                    }
+                    RelayPoolNotification::RelayStatus { relay_url, status } => match status {
+                        RelayStatus::Disconnected => {
+                            disconnected.insert(relay_url);
+                        }
+                        RelayStatus::Connected if disconnected.remove(&relay_url) => {
+                            info!("Relay {relay_url} reconnected, resubscribing to triggers");
+                            triggers =
+                                subscribe_triggers(&client, &db_pool, catch_up_margin).await?;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+            _ = changed.notified() => {
+                triggers = subscribe_triggers(&client, &db_pool, catch_up_margin).await?;
+            }
+            _ = expiry_check.tick() => {
+                let mut conn = db_pool.get()?;
+                let expired = JobTrigger::finish_expired(&mut conn)?;
+                drop(conn);
+                if !expired.is_empty() {
+                    info!("{} triggers expired", expired.len());
+                    triggers = subscribe_triggers(&client, &db_pool, catch_up_margin).await?;
+                }
+            }
+        }
+    }
+
+    client.disconnect().await?;
+
+    Ok(())
+}
+
+/// Subscribes to the events of every active trigger and to deletions of their requests,
+/// returns the active triggers. Events from a little before a restart are included so
+/// none are missed, an event never runs a trigger twice.
+async fn subscribe_triggers(
+    client: &Client,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    catch_up_margin: u64,
+) -> anyhow::Result<Vec<JobTrigger>> {
+    let mut conn = db_pool.get()?;
+    let triggers = JobTrigger::list_active(&mut conn)?;
+    drop(conn);
+
+    if triggers.is_empty() {
+        client.unsubscribe().await;
+        return Ok(triggers);
+    }
+
+    let now = Timestamp::now().as_u64();
+    let mut filters = triggers
+        .iter()
+        .map(|t| {
+            let since = (t.created_at.timestamp() as u64).max(now.saturating_sub(catch_up_margin));
+            t.filter().since(Timestamp::from(since))
+        })
+        .collect::<Vec<_>>();
+    filters.push(
+        Filter::new()
+            .kind(Kind::EventDeletion)
+            .authors(triggers.iter().map(|t| t.npub()))
+            .events(triggers.iter().map(|t| t.request_id()))
+            .since(Timestamp::from(now.saturating_sub(catch_up_margin))),
+    );
+
+    client.subscribe(filters).await;
+    info!("Subscribed to {} triggers", triggers.len());
+
+    Ok(triggers)
+}
+
+/// Cancels the triggers whose request the event deletes, and queues a run of every trigger
+/// the event matches
+async fn handle_trigger_event(
+    client: &Client,
+    keys: &Keys,
+    db_pool: &Pool<ConnectionManager<PgConnection>>,
+    triggers: &[JobTrigger],
+    event: &Event,
+) -> anyhow::Result<()> {
+    // our own results could match a trigger and run it forever
+    if event.pubkey == keys.public_key() {
+        return Ok(());
+    }
+
+    let mut conn = db_pool.get()?;
+
+    if event.kind == Kind::EventDeletion {
+        for trigger in triggers {
+            if trigger.npub() == event.pubkey
+                && event.event_ids().any(|id| *id == trigger.request_id())
+            {
+                let reason = "Request was deleted";
+                if JobTrigger::stop(&mut conn, trigger.id, TriggerStatus::Canceled, reason)?
+                    .is_some()
+                {
+                    info!("Canceled trigger {} for deleted request", trigger.id);
+                }
+            }
+        }
+    }
+
+    for trigger in triggers.iter().filter(|t| t.filter().match_event(event)) {
+        let request = trigger.request();
+        let (params, _) = get_job_params(&request, keys)?;
+
+        match queue_trigger_run(&mut conn, trigger.id, event, params.time)? {
+            TriggerRun::Queued(job) => {
+                info!(
+                    "Queued job {} as a run of trigger {} for event {}",
+                    job.id, trigger.id, event.id
+                )
+            }
+            TriggerRun::Duplicate => {
+                debug!("Event {} already ran trigger {}", event.id, trigger.id)
+            }
+            TriggerRun::Limited => {
+                debug!(
+                    "Trigger {} ran too often this hour, skipping event {}",
+                    trigger.id, event.id
+                )
+            }
+            TriggerRun::Stopped => debug!("Trigger {} was stopped", trigger.id),
+            TriggerRun::Unpaid => {
+                let error = JobError::InsufficientBalance(
+                    "Not enough balance for the next run, the trigger was stopped".to_string(),
+                );
+                client
+                    .send_event_builder(error.to_feedback(&request))
+                    .await?;
+                info!("Stopped trigger {}, not enough balance", trigger.id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_trigger, MAX_RUNS_PER_HOUR};
+    use crate::wasm_handler::TriggerParams;
+    use nostr::{Filter, Kind};
+
+    #[test]
+    fn test_validate_trigger() {
+        let now = 1_700_000_000;
+        let trigger =
+            |filter: Filter, expires_at: u64, max_runs_per_hour: Option<u32>| TriggerParams {
+                filter,
+                expires_at,
+                max_runs: None,
+                max_runs_per_hour,
+            };
+        let notes = Filter::new().kind(Kind::TextNote);
+
+        assert_eq!(
+            validate_trigger(&trigger(notes.clone(), now + 3_600, None), now).unwrap(),
+            MAX_RUNS_PER_HOUR
+        );
+        assert_eq!(
+            validate_trigger(&trigger(notes.clone(), now + 3_600, Some(5)), now).unwrap(),
+            5
+        );
+
+        // a filter matching everything would run on every event
+        assert!(validate_trigger(&trigger(Filter::new(), now + 3_600, None), now).is_err());
+        assert!(validate_trigger(&trigger(notes.clone(), now, None), now).is_err());
+        assert!(validate_trigger(&trigger(notes.clone(), now + 31 * 86_400, None), now).is_err());
+        assert!(validate_trigger(&trigger(notes.clone(), now + 3_600, Some(0)), now).is_err());
+        assert!(validate_trigger(&trigger(notes, now + 3_600, Some(1_000)), now).is_err());
+    }
+}
@@ -1,7 +1,7 @@
-use anyhow::anyhow;
+use crate::error::JobError;
 use extism::{Manifest, Plugin, Wasm};
 use log::{debug, info};
-use nostr::EventId;
+use nostr::{EventId, Filter};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -23,6 +23,26 @@ pub struct ScheduledParams {
     pub run_date: u64,
     /// What to name the announcement
     pub name: Option<String>,
+    /// Cron expression, in UTC, to repeat the job on after its first run
+    pub cron: Option<String>,
+    /// Seconds between runs to repeat the job every after its first run
+    pub interval: Option<u64>,
+    /// Time in seconds from epoch after which a repeating job stops
+    pub end_date: Option<u64>,
+    /// Most times a repeating job runs, including its first run
+    pub max_runs: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerParams {
+    /// Nostr filter of the events that run the job, each matching event is the job's input
+    pub filter: Filter,
+    /// Time in seconds from epoch after which the trigger stops
+    pub expires_at: u64,
+    /// Most times the trigger runs the job
+    pub max_runs: Option<u32>,
+    /// Most times the trigger runs the job in an hour, more matching events are skipped
+    pub max_runs_per_hour: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,61 +53,83 @@ pub struct JobParams {
     pub time: u64,
     pub checksum: String,
     pub schedule: Option<ScheduledParams>,
+    pub trigger: Option<TriggerParams>,
+}
+
+/// Output of a wasm function along with how long it took to run
+#[derive(Debug, Clone)]
+pub struct WasmOutput {
+    pub output: String,
+    pub runtime: Duration,
 }
 
 pub async fn download_and_run_wasm(
     job_params: JobParams,
     event_id: EventId,
     http: &reqwest::Client,
-) -> anyhow::Result<String> {
-    let url = Url::parse(&job_params.url)?;
-    let temp_dir = tempfile::tempdir()?;
+) -> Result<WasmOutput, JobError> {
+    let url = Url::parse(&job_params.url).map_err(|e| JobError::DownloadFailed(e.to_string()))?;
+    let temp_dir = tempfile::tempdir().map_err(|e| JobError::Internal(e.into()))?;
     let file_path = temp_dir.path().join(format!("{event_id}.wasm"));
 
-    let response = http.get(url).send().await?;
+    let response = http
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| JobError::DownloadFailed(e.to_string()))?;
 
     if response.status().is_success() {
         // if length larger than 25mb, error
         if response.content_length().unwrap_or(0) > MAX_WASM_FILE_SIZE {
-            anyhow::bail!("File too large");
+            return Err(JobError::DownloadFailed("File too large".to_string()));
         }
 
-        let mut dest = File::create(&file_path)?;
+        let mut dest = File::create(&file_path).map_err(|e| JobError::Internal(e.into()))?;
 
-        let bytes = response.bytes().await?;
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|e| JobError::DownloadFailed(e.to_string()))?;
         if bytes.len() as u64 > MAX_WASM_FILE_SIZE {
-            anyhow::bail!("File too large");
+            return Err(JobError::DownloadFailed("File too large".to_string()));
         }
         let mut content = Cursor::new(bytes);
 
         let mut hasher = Sha256::new();
-        io::copy(&mut content, &mut hasher)?;
+        io::copy(&mut content, &mut hasher).map_err(|e| JobError::Internal(e.into()))?;
         let result = hasher.finalize();
         let hex_result = format!("{result:x}");
         if job_params.checksum.to_lowercase() != hex_result {
-            std::fs::remove_file(&file_path)?;
-            anyhow::bail!(
-                "Checksum mismatch expected: {} got: {hex_result}",
-                job_params.checksum
-            );
+            std::fs::remove_file(&file_path).map_err(|e| JobError::Internal(e.into()))?;
+            return Err(JobError::ChecksumMismatch {
+                expected: job_params.checksum,
+                got: hex_result,
+            });
         }
 
         // write the file to disk
-        content.rewind()?;
-        io::copy(&mut content, &mut dest)?;
+        content.rewind().map_err(|e| JobError::Internal(e.into()))?;
+        io::copy(&mut content, &mut dest).map_err(|e| JobError::Internal(e.into()))?;
     } else {
-        anyhow::bail!("Failed to download file: HTTP {}", response.status())
+        return Err(JobError::DownloadFailed(format!(
+            "HTTP {}",
+            response.status()
+        )));
     };
 
     info!("Running wasm for event: {event_id}");
     run_wasm(file_path, job_params).await
 }
 
-pub async fn run_wasm(file_path: PathBuf, job_params: JobParams) -> anyhow::Result<String> {
+pub async fn run_wasm(file_path: PathBuf, job_params: JobParams) -> Result<WasmOutput, JobError> {
     let wasm = Wasm::file(file_path);
     let mut manifest = Manifest::new([wasm]);
     manifest.allowed_hosts = Some(vec!["*".to_string()]);
-    let mut plugin = Plugin::new(manifest, [], true)?;
+    let mut plugin =
+        Plugin::new(manifest, [], true).map_err(|e| JobError::ExecutionFailed(e.to_string()))?;
+    if !plugin.function_exists(&job_params.function) {
+        return Err(JobError::FunctionNotFound(job_params.function));
+    }
     let cancel_handle = plugin.cancel_handle();
     let start = Instant::now();
     let fut = tokio::task::spawn_blocking(move || {
@@ -101,13 +143,15 @@ pub async fn run_wasm(file_path: PathBuf, job_params: JobParams) -> anyhow::Resu
 
     select! {
         result = fut => {
-            let result = result?;
-            debug!("Complete, time elapsed: {}ms", start.elapsed().as_millis());
-            result
+            let result = result.map_err(|e| JobError::Internal(e.into()))?;
+            let runtime = start.elapsed();
+            debug!("Complete, time elapsed: {}ms", runtime.as_millis());
+            let output = result.map_err(|e| JobError::ExecutionFailed(e.to_string()))?;
+            Ok(WasmOutput { output, runtime })
         }
         _ = sleep => {
-            cancel_handle.cancel()?;
-            Err(anyhow!("Timeout"))
+            cancel_handle.cancel().map_err(JobError::Internal)?;
+            Err(JobError::Timeout)
         }
     }
 }
@@ -115,7 +159,7 @@ pub async fn run_wasm(file_path: PathBuf, job_params: JobParams) -> anyhow::Resu
 #[cfg(test)]
 mod test {
     use super::{download_and_run_wasm, JobParams};
-    use nostr::EventId;
+    use nostr::{EventId, Filter};
     use serde_json::Value;
 
     #[tokio::test]
@@ -129,13 +173,14 @@ mod test {
             checksum: "93898457953d30d016f712ccf4336ce7e9971db5f7f3aff1edd252764f75d5d7"
                 .to_string(),
             schedule: None,
+            trigger: None,
         };
         let result = download_and_run_wasm(params, EventId::all_zeros(), &reqwest::Client::new())
             .await
             .unwrap();
 
         assert_eq!(
-            result,
+            result.output,
             "{\"count\":3,\"total\":3,\"vowels\":\"aeiouAEIOU\"}"
         );
     }
@@ -150,16 +195,37 @@ mod test {
             checksum: "fe7ff8aaf45d67dd0d6b9fdfe3aa871e658a83adcf19c8f016013c29e8857f03"
                 .to_string(),
             schedule: None,
+            trigger: None,
         };
         let result = download_and_run_wasm(params, EventId::all_zeros(), &reqwest::Client::new())
             .await
             .unwrap();
 
-        let json = serde_json::from_str::<Value>(&result);
+        let json = serde_json::from_str::<Value>(&result.output);
 
         assert!(json.is_ok());
     }
 
+    #[tokio::test]
+    async fn test_function_not_found() {
+        let params = JobParams {
+            url: "https://github.com/extism/plugins/releases/download/v0.5.0/count_vowels.wasm"
+                .to_string(),
+            function: "count_consonants".to_string(),
+            input: "Hello World".to_string(),
+            time: 500,
+            checksum: "93898457953d30d016f712ccf4336ce7e9971db5f7f3aff1edd252764f75d5d7"
+                .to_string(),
+            schedule: None,
+            trigger: None,
+        };
+        let err = download_and_run_wasm(params, EventId::all_zeros(), &reqwest::Client::new())
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.code(), "function_not_found");
+    }
+
     #[tokio::test]
     async fn test_timeout_infinite_loop() {
         let params = JobParams {
@@ -171,11 +237,14 @@ mod test {
             checksum: "6e6386b9194f2298b5e55e88c25fe66dda454f0e2604da6964735ab1c554b513"
                 .to_string(),
             schedule: None,
+            trigger: None,
         };
         let err =
             download_and_run_wasm(params, EventId::all_zeros(), &reqwest::Client::new()).await;
 
         assert!(err.is_err());
-        assert_eq!(err.unwrap_err().to_string(), "Timeout");
+        let err = err.unwrap_err();
+        assert_eq!(err.to_string(), "Timeout");
+        assert_eq!(err.code(), "timeout");
     }
 }
@@ -0,0 +1,352 @@
+use crate::balance::verify_http_auth;
+use crate::lightning::{Lightning, LightningClient, PaymentResult, PaymentStatus};
+use crate::models::withdrawal::{Withdrawal, WithdrawalStatus};
+use crate::models::zap_balance::ZapBalance;
+use crate::models::{available_balance, finish_withdrawal, start_withdrawal};
+use crate::routes::handle_anyhow_error;
+use crate::State;
+use anyhow::anyhow;
+use axum::extract::{Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use axum::{Extension, Json};
+use bitcoin::bech32::{self, ToBase32, Variant};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::rand::rngs::OsRng;
+use bitcoin::secp256k1::rand::RngCore;
+use diesel::PgConnection;
+use lightning_invoice::Bolt11Invoice;
+use log::{error, info, warn};
+use nostr::{EventId, PublicKey};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+/// How long a withdraw link can be used for, in seconds
+const WITHDRAW_LINK_EXPIRY: i64 = 600;
+
+/// Smallest fee reserve held for a withdrawal, in millisats
+const MIN_FEE_RESERVE_MSATS: u64 = 1_000;
+
+/// Fee reserve held while paying a withdrawal, the unused part is credited back after
+pub fn fee_reserve(amount_msats: u64, fee_reserve_percent: f64) -> u64 {
+    let reserve = (amount_msats as f64 * fee_reserve_percent / 100.0).ceil() as u64;
+    reserve.max(MIN_FEE_RESERVE_MSATS)
+}
+
+/// Largest amount that can be withdrawn so the amount plus its fee reserve fits in the balance
+pub fn max_withdrawable(available_msats: u64, fee_reserve_percent: f64) -> u64 {
+    let max = (available_msats as f64 / (1.0 + fee_reserve_percent / 100.0)).floor() as u64;
+    let mut max = max.min(available_msats.saturating_sub(MIN_FEE_RESERVE_MSATS));
+    while max > 0 && max + fee_reserve(max, fee_reserve_percent) > available_msats {
+        max -= 1;
+    }
+    max
+}
+
+/// Creates a one-time LNURL-withdraw link for the pubkey's balance, returns the bech32 lnurl.
+/// The event that authorized it can only be used once.
+pub fn create_withdraw_link(
+    conn: &mut PgConnection,
+    npub: &PublicKey,
+    auth_event_id: EventId,
+    domain: &str,
+) -> anyhow::Result<String> {
+    if ZapBalance::get(conn, npub)?.is_none() {
+        return Err(anyhow!("No balance to withdraw"));
+    }
+
+    let mut k1 = [0u8; 32];
+    OsRng.fill_bytes(&mut k1);
+    let expires_at =
+        chrono::Utc::now().naive_utc() + chrono::Duration::seconds(WITHDRAW_LINK_EXPIRY);
+
+    Withdrawal::create(conn, npub, k1, auth_event_id, expires_at)
+        .map_err(|_| anyhow!("Withdraw request was already used"))?;
+
+    let url = format!("https://{domain}/lnurlw/{}", hex::encode(k1));
+    let lnurl = bech32::encode("lnurl", url.as_bytes().to_base32(), Variant::Bech32)?;
+    Ok(lnurl)
+}
+
+/// Creates a withdraw link, authorized with NIP-98 HTTP auth
+pub async fn request_withdrawal(
+    headers: HeaderMap,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let url = format!("https://{}/withdraw", state.domain);
+    let event = verify_http_auth(&headers, &url, "POST").map_err(|e| {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "status": "ERROR",
+                "reason": format!("{e}"),
+            })),
+        )
+    })?;
+
+    let create = || -> anyhow::Result<String> {
+        let mut conn = state.db_pool.get()?;
+        create_withdraw_link(&mut conn, &event.pubkey, event.id, &state.domain)
+    };
+
+    match create() {
+        Ok(lnurl) => Ok(Json(json!({ "lnurl": lnurl }))),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+/// First step of LNURL-withdraw, tells the wallet how much it can withdraw
+pub async fn get_withdraw_request(
+    Path(k1): Path<String>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let get = || -> anyhow::Result<Value> {
+        let k1 = hex::decode(&k1)?;
+        let mut conn = state.db_pool.get()?;
+        let withdrawal = Withdrawal::get_by_k1(&mut conn, &k1)?
+            .filter(|w| w.status() == WithdrawalStatus::Open && !w.is_expired())
+            .ok_or(anyhow!("Withdraw link already used or expired"))?;
+
+        let available = available_balance(&mut conn, &withdrawal.npub())?.max(0) as u64;
+        let max = max_withdrawable(available, state.withdrawal_fee_reserve);
+        if max < state.min_withdrawal {
+            return Err(anyhow!(
+                "Balance too low, the minimum withdrawal is {} msats",
+                state.min_withdrawal
+            ));
+        }
+
+        Ok(json!({
+            "tag": "withdrawRequest",
+            "callback": format!("https://{}/lnurlw-callback", state.domain),
+            "k1": hex::encode(withdrawal.k1()),
+            "defaultDescription": "Wasm DVM balance withdrawal",
+            "minWithdrawable": state.min_withdrawal,
+            "maxWithdrawable": max,
+        }))
+    };
+
+    match get() {
+        Ok(res) => Ok(Json(res)),
+        Err(e) => Err(handle_anyhow_error(e)),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WithdrawCallbackParams {
+    pub k1: String,
+    pub pr: String,
+}
+
+/// Second step of LNURL-withdraw, debits the balance and pays the wallet's invoice
+pub async fn withdraw_callback(
+    Query(params): Query<WithdrawCallbackParams>,
+    Extension(state): Extension<State>,
+) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+    let start = || -> anyhow::Result<Withdrawal> {
+        let k1 = hex::decode(&params.k1)?;
+        let invoice = Bolt11Invoice::from_str(&params.pr)?;
+        if invoice.is_expired() {
+            return Err(anyhow!("Invoice is expired"));
+        }
+        let amount = invoice
+            .amount_milli_satoshis()
+            .ok_or(anyhow!("Invoice must have an amount"))?;
+        if amount < state.min_withdrawal {
+            return Err(anyhow!(
+                "The minimum withdrawal is {} msats",
+                state.min_withdrawal
+            ));
+        }
+
+        let mut conn = state.db_pool.get()?;
+        let withdrawal = Withdrawal::get_by_k1(&mut conn, &k1)?
+            .ok_or(anyhow!("Withdraw link already used or expired"))?;
+        let available = available_balance(&mut conn, &withdrawal.npub())?.max(0) as u64;
+        if amount > max_withdrawable(available, state.withdrawal_fee_reserve) {
+            return Err(anyhow!("Amount is more than the available balance"));
+        }
+
+        let reserve = fee_reserve(amount, state.withdrawal_fee_reserve);
+        start_withdrawal(&mut conn, &k1, &invoice, amount, reserve)?
+            .ok_or(anyhow!("Withdraw link already used or expired"))
+    };
+
+    let withdrawal = match start() {
+        Ok(withdrawal) => withdrawal,
+        Err(e) => return Err(handle_anyhow_error(e)),
+    };
+
+    // wallets expect a quick response, pay the invoice in the background
+    let lightning = state.lightning.clone();
+    let db_pool = state.db_pool.clone();
+    tokio::spawn(async move {
+        let result = match db_pool.get() {
+            Ok(mut conn) => pay_withdrawal(lightning, &mut conn, withdrawal).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Error paying withdrawal: {e}");
+        }
+    });
+
+    Ok(Json(json!({ "status": "OK" })))
+}
+
+async fn pay_withdrawal(
+    lightning: LightningClient,
+    conn: &mut PgConnection,
+    withdrawal: Withdrawal,
+) -> anyhow::Result<()> {
+    let invoice = withdrawal
+        .invoice()
+        .expect("pending withdrawal has an invoice");
+    let reserve = withdrawal.fee_reserve_msats().unwrap_or(0);
+
+    // if the node errors we don't know if the payment went through, so the
+    // withdrawal stays pending until `reconcile_withdrawals` finds out
+    match lightning.pay_invoice(&invoice, reserve).await? {
+        PaymentResult::Paid { fee_msats, .. } => {
+            info!(
+                "Paid withdrawal {} with {fee_msats}msats fee",
+                withdrawal.id
+            );
+            finish_withdrawal(conn, &withdrawal, Some(fee_msats))
+        }
+        PaymentResult::Failed(reason) => {
+            error!("Withdrawal {} payment failed: {reason}", withdrawal.id);
+            finish_withdrawal(conn, &withdrawal, None)
+        }
+    }
+}
+
+/// Looks up the payments of withdrawals that were left pending since before `updated_before`,
+/// because paying them errored or we stopped while paying, and settles or refunds them.
+/// Payments the node doesn't know are only refunded once their invoice expired.
+pub async fn reconcile_withdrawals(
+    lightning: &dyn Lightning,
+    conn: &mut PgConnection,
+    updated_before: chrono::NaiveDateTime,
+) -> anyhow::Result<()> {
+    for withdrawal in Withdrawal::list_pending(conn, updated_before)? {
+        let invoice = withdrawal
+            .invoice()
+            .expect("pending withdrawal has an invoice");
+        let payment_hash = invoice.payment_hash().to_byte_array();
+        let status = match lightning.lookup_payment(payment_hash).await {
+            Ok(status) => status,
+            Err(e) => {
+                warn!(
+                    "Failed to look up withdrawal {} payment: {e}",
+                    withdrawal.id
+                );
+                continue;
+            }
+        };
+
+        match status {
+            Some(PaymentStatus::Paid { fee_msats }) => {
+                info!("Withdrawal {} turned out paid", withdrawal.id);
+                finish_withdrawal(conn, &withdrawal, Some(fee_msats))?;
+            }
+            Some(PaymentStatus::Failed(reason)) => {
+                info!("Withdrawal {} turned out failed: {reason}", withdrawal.id);
+                finish_withdrawal(conn, &withdrawal, None)?;
+            }
+            None if invoice.is_expired() => {
+                info!("Withdrawal {} was never paid", withdrawal.id);
+                finish_withdrawal(conn, &withdrawal, None)?;
+            }
+            Some(PaymentStatus::InFlight) | None => {}
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fee_reserve, max_withdrawable, reconcile_withdrawals};
+    use crate::lightning::mock::MockLightning;
+    use crate::lightning::{InvoiceDescription, Lightning};
+    use crate::models::balance_entry::BalanceEntry;
+    use crate::models::test::test_pool;
+    use crate::models::withdrawal::{Withdrawal, WithdrawalStatus};
+    use crate::models::zap_balance::ZapBalance;
+    use crate::models::{available_balance, start_withdrawal};
+    use bitcoin::Network;
+    use nostr::{EventId, Keys};
+
+    #[test]
+    fn test_max_withdrawable() {
+        assert_eq!(fee_reserve(10_000, 1.0), 1_000);
+        assert_eq!(fee_reserve(1_000_000, 1.0), 10_000);
+
+        for available in [0, 500, 1_000, 1_001, 50_000, 1_000_000, 123_456_789] {
+            let max = max_withdrawable(available, 1.0);
+            if max > 0 {
+                assert!(max + fee_reserve(max, 1.0) <= available);
+                // one more msat wouldn't fit
+                assert!(max + 1 + fee_reserve(max + 1, 1.0) > available);
+            }
+        }
+        assert_eq!(max_withdrawable(1_000, 1.0), 0);
+        assert_eq!(max_withdrawable(101_000, 1.0), 100_000);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_reconcile_withdrawals() {
+        let pool = test_pool();
+        let mut conn = pool.get().unwrap();
+        let mock = MockLightning::new(Network::Regtest, false);
+        let wallet = MockLightning::new(Network::Regtest, false);
+        let keys = Keys::generate();
+        ZapBalance::get_or_create(&mut conn, keys.public_key()).unwrap();
+        BalanceEntry::admin_adjustment(&mut conn, &keys.public_key(), 100_000, "test").unwrap();
+
+        let mut withdrawals = vec![];
+        for expiry_secs in [3_600, 1] {
+            let invoice = wallet
+                .create_invoice(
+                    10_000,
+                    InvoiceDescription::Memo("test".to_string()),
+                    expiry_secs,
+                )
+                .await
+                .unwrap();
+            let k1 = Keys::generate().secret_key().unwrap().secret_bytes();
+            let auth_event_id = EventId::from_slice(&k1).unwrap();
+            let expires_at = chrono::Utc::now().naive_utc() + chrono::Duration::minutes(10);
+            Withdrawal::create(&mut conn, &keys.public_key(), k1, auth_event_id, expires_at)
+                .unwrap();
+            let withdrawal = start_withdrawal(&mut conn, &k1, &invoice, 10_000, 1_000)
+                .unwrap()
+                .unwrap();
+            withdrawals.push((withdrawal, invoice));
+        }
+
+        // the first payment went out although paying it errored, the second never did
+        mock.pay_invoice(&withdrawals[0].1, 1_000).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+
+        let updated_before = chrono::Utc::now().naive_utc() + chrono::Duration::seconds(1);
+        reconcile_withdrawals(&mock, &mut conn, updated_before)
+            .await
+            .unwrap();
+
+        let paid = Withdrawal::get_by_k1(&mut conn, &withdrawals[0].0.k1())
+            .unwrap()
+            .unwrap();
+        assert_eq!(paid.status(), WithdrawalStatus::Paid);
+        let failed = Withdrawal::get_by_k1(&mut conn, &withdrawals[1].0.k1())
+            .unwrap()
+            .unwrap();
+        assert_eq!(failed.status(), WithdrawalStatus::Failed);
+        // only the paid amount is gone, both fee reserves were credited back
+        assert_eq!(
+            available_balance(&mut conn, &keys.public_key()).unwrap(),
+            90_000
+        );
+    }
+}